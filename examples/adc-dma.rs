@@ -0,0 +1,61 @@
+//! Continuously samples PA0 into a ring buffer and prints the average of
+//! every new batch of samples
+//!
+//! ADC1 requests go to DMA2 stream 0, channel 0.
+
+#![deny(warnings)]
+#![feature(proc_macro)]
+#![no_std]
+
+extern crate bsp;
+#[macro_use]
+extern crate cortex_m_semihosting as semihosting;
+extern crate cortex_m_rtfm as rtfm;
+
+use bsp::adc2::{Adc, PA0};
+use bsp::dma2::DmaExt;
+use rtfm::{app, Threshold};
+
+app! {
+    device: bsp::stm32f411,
+
+    idle: {
+        resources: [ADC1, ADC_COMMON, DMA2, GPIOA, RCC],
+    },
+}
+
+static mut SAMPLES: [u16; 256] = [0; 256];
+
+fn init(p: init::Peripherals) {
+    p.RCC.ahb1enr.modify(|_, w| w.gpioaen().set_bit());
+}
+
+fn idle(_t: &mut Threshold, r: idle::Resources) -> ! {
+    let rcc = &**r.RCC;
+    let pa0 = PA0::into_analog(&**r.GPIOA);
+    let streams = r.DMA2.split(rcc).unwrap();
+
+    let adc = Adc(&**r.ADC1);
+    adc.init(rcc, &**r.ADC_COMMON);
+
+    // NOTE(unsafe) the only reference ever taken to `SAMPLES`
+    let mut sampler = adc.circular(&pa0, &streams.s0, unsafe { &mut SAMPLES });
+
+    loop {
+        let result = sampler.read_available(|a, b| {
+            let n = a.len() + b.len();
+            let sum = a.iter().chain(b.iter()).map(|x| u32::from(*x)).sum::<u32>();
+            (n, sum)
+        });
+
+        match result {
+            Ok((0, _)) => {}
+            Ok((n, sum)) => {
+                hprintln!("{} samples, average {}", n, sum / n as u32);
+            }
+            Err(e) => {
+                hprintln!("{}", e);
+            }
+        }
+    }
+}
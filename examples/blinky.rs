@@ -1,91 +1,61 @@
-//! Turns the user LED on
+//! Blinks the user LED (LD2 on PA5 of the Nucleo-F411RE) at 1 Hz
 
-#![feature(const_fn)]
-#![feature(used)]
+#![deny(warnings)]
+#![feature(proc_macro)]
 #![no_std]
 
-extern crate blue_pill;
-
-extern crate embedded_hal as hal;
-
-// version = "0.2.3"
-extern crate cortex_m_rt;
-
-// version = "0.1.0"
-#[macro_use]
+extern crate bsp;
 extern crate cortex_m_rtfm as rtfm;
+extern crate embedded_hal as hal;
 
-use blue_pill::Timer;
-use blue_pill::led::{self, Green};
-use blue_pill::stm32f103xx;
-use blue_pill::time::Hertz;
-use hal::prelude::*;
-use rtfm::{Local, P0, P1, T0, T1, TMax};
-use stm32f103xx::interrupt::TIM1_UP_TIM10;
+use bsp::Timer;
+use bsp::gpio::{Input, N5, Output, TypedPin};
+use bsp::stm32f411::{GPIOA, TIM3, tim3};
+use bsp::time::Hertz;
+use rtfm::{app, Threshold};
 
 // CONFIGURATION
-const FREQUENCY: Hertz = Hertz(1);
+const FREQUENCY: Hertz = Hertz(2);
 
-// RESOURCES
-peripherals!(stm32f103xx, {
-    GPIOC: Peripheral {
-        ceiling: C0,
-    },
-    RCC: Peripheral {
-        ceiling: C0,
+app! {
+    device: bsp::stm32f411,
+
+    resources: {
+        // PA5, configured by `init`
+        static LED: Option<TypedPin<GPIOA, N5, Output>> = None;
     },
-    TIM1: Peripheral {
-        ceiling: C1,
+
+    tasks: {
+        TIM3: {
+            path: toggle,
+            resources: [GPIOA, LED, TIM3],
+        },
     },
-});
+}
 
-// INITIALIZATION PHASE
-fn init(ref prio: P0, thr: &TMax) {
-    let gpioc = &GPIOC.access(prio, thr);
-    let rcc = &RCC.access(prio, thr);
-    let tim1 = TIM1.access(prio, thr);
+fn init(p: init::Peripherals, r: init::Resources) {
+    p.RCC.ahb1enr.modify(|_, w| w.gpioaen().set_bit());
+    p.RCC.apb1enr.modify(|_, w| w.tim3en().set_bit());
 
-    let timer = Timer(&*tim1);
+    **r.LED = Some(TypedPin::<GPIOA, N5, Input>::new().into_output(p.GPIOA));
 
-    led::init(gpioc, rcc);
-    timer.init(FREQUENCY.invert(), rcc);
-    timer.resume();
+    let timer = Timer::<TIM3, tim3::RegisterBlock>::new(p.TIM3);
+    timer.init(FREQUENCY.invert());
+    hal::Timer::resume(&timer);
 }
 
-// IDLE LOOP
-fn idle(_prio: P0, _thr: T0) -> ! {
-    // Sleep
+fn idle() -> ! {
     loop {
         rtfm::wfi();
     }
 }
 
-// TASKS
-tasks!(stm32f103xx, {
-    blink: Task {
-        interrupt: TIM1_UP_TIM10,
-        priority: P1,
-        enabled: true,
-    },
-});
-
-fn blink(mut task: TIM1_UP_TIM10, ref prio: P1, ref thr: T1) {
-    static STATE: Local<bool, TIM1_UP_TIM10> = Local::new(false);
-
-    let tim1 = TIM1.access(prio, thr);
-
-    let timer = Timer(&*tim1);
-
-    // NOTE(wait) timeout should have already occurred
-    timer.wait().unwrap();
-
-    let state = STATE.borrow_mut(&mut task);
-
-    *state = !*state;
+fn toggle(_t: &mut Threshold, r: TIM3::Resources) {
+    let timer = Timer::<TIM3, tim3::RegisterBlock>::new(&**r.TIM3);
+    // NOTE(wait) the timeout has already occurred
+    hal::Timer::wait(&timer).unwrap();
 
-    if *state {
-        Green.on();
-    } else {
-        Green.off();
+    if let Some(ref led) = **r.LED {
+        led.toggle(&**r.GPIOA);
     }
 }
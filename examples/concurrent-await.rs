@@ -66,7 +66,7 @@ fn init(ref prio: P0, thr: &TMax) {
     let usart1 = USART1.access(prio, thr);
 
     let timer = Timer(&*tim3);
-    let serial = Serial(&*usart1);
+    let serial = Serial::new(&*usart1);
 
     led::init(gpioc, rcc);
 
@@ -82,7 +82,7 @@ fn idle(ref prio: P0, ref thr: T0) -> ! {
     let usart1 = USART1.access(prio, thr);
 
     let timer = Timer(&*tim3);
-    let serial = Serial(&*usart1);
+    let serial = Serial::new(&*usart1);
 
     // Tasks
     let mut blinky = (|| {
@@ -60,7 +60,7 @@ fn init(ref prio: P0, thr: &TMax) {
     let usart1 = USART1.access(prio, thr);
     let tim1 = TIM1.access(prio, thr);
 
-    let serial = Serial(&*usart1);
+    let serial = Serial::new(&*usart1);
     let timer = Timer(&*tim1);
 
     led::init(gpioc, rcc);
@@ -117,7 +117,7 @@ fn blinky(ref mut task: TIM1_UP_TIM10, ref prio: P1, ref thr: T1) {
 fn loopback(_task: USART1, ref prio: P1, ref thr: T1) {
     let usart1 = USART1.access(prio, thr);
 
-    let serial = Serial(&*usart1);
+    let serial = Serial::new(&*usart1);
 
     match serial.read().and_then(|byte| serial.write(byte)) {
         Err(nb::Error::Other(e)) => panic!("{:?}", e),
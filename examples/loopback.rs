@@ -48,7 +48,7 @@ fn init(ref prio: P0, thr: &TMax) {
     let rcc = &RCC.access(prio, thr);
     let usart1 = USART1.access(prio, thr);
 
-    let serial = Serial(&*usart1);
+    let serial = Serial::new(&*usart1);
 
     serial.init(BAUD_RATE.invert(), afio, None, gpioa, rcc);
     serial.listen(Event::Rxne);
@@ -74,7 +74,7 @@ tasks!(stm32f103xx, {
 fn loopback(_task: USART1, ref prio: P1, ref thr: T1) {
     let usart1 = USART1.access(prio, thr);
 
-    let serial = Serial(&*usart1);
+    let serial = Serial::new(&*usart1);
 
     let byte = serial.read().unwrap();
     serial.write(byte).unwrap();
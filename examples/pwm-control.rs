@@ -61,7 +61,7 @@ fn init(ref prio: P0, thr: &TMax) {
     let usart1 = USART1.access(prio, thr);
 
     let pwm = Pwm(&*tim2);
-    let serial = Serial(&*usart1);
+    let serial = Serial::new(&*usart1);
 
     serial.init(BAUD_RATE.invert(), afio, None, gpioa, rcc);
 
@@ -93,7 +93,7 @@ fn rx(_task: USART1, ref prio: P1, ref thr: T1) {
     let usart1 = USART1.access(prio, thr);
 
     let pwm = Pwm(&*tim2);
-    let serial = Serial(&*usart1);
+    let serial = Serial::new(&*usart1);
 
     let byte = serial.read().unwrap();
     // Echo back to signal we are alive
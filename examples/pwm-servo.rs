@@ -0,0 +1,86 @@
+//! Sweeps a hobby servo back and forth
+//!
+//! The servo signal is TIM1 channel 1 on PA8: 50 Hz, 1 ms (0 degrees) to
+//! 2 ms (180 degrees) pulses. TIM3 paces the sweep.
+
+#![deny(warnings)]
+#![feature(proc_macro)]
+#![no_std]
+
+extern crate bsp;
+extern crate cortex_m_rtfm as rtfm;
+extern crate embedded_hal as hal;
+
+use bsp::Timer;
+use bsp::gpio::{AF1, Pin, Speed};
+use bsp::pwm2::Pwm;
+use bsp::stm32f411::{GPIOA, TIM1, TIM3, tim3};
+use bsp::time::{Hertz, Milliseconds};
+use bsp::timer::Channel;
+use rtfm::{app, Threshold};
+
+// CONFIGURATION
+const PERIOD: Milliseconds = Milliseconds(20);
+const STEP_RATE: Hertz = Hertz(50);
+const TIM1_AF: AF1 = AF1;
+
+app! {
+    device: bsp::stm32f411,
+
+    resources: {
+        static ANGLE: u8 = 0;
+        static RISING: bool = true;
+    },
+
+    tasks: {
+        TIM3: {
+            path: step,
+            resources: [ANGLE, RISING, TIM1, TIM3],
+        },
+    },
+}
+
+fn init(p: init::Peripherals, _r: init::Resources) {
+    p.RCC.ahb1enr.modify(|_, w| w.gpioaen().set_bit());
+    p.RCC.apb1enr.modify(|_, w| w.tim3en().set_bit());
+    p.RCC.apb2enr.modify(|_, w| w.tim1en().set_bit());
+
+    Pin::<GPIOA>::new(8).set_alternate(p.GPIOA, TIM1_AF, Speed::Low);
+
+    let pwm = Pwm(p.TIM1);
+    pwm.init(PERIOD);
+    set_angle(&pwm, 0);
+    hal::Pwm::enable(&pwm, Channel::_1);
+
+    let timer = Timer::<TIM3, tim3::RegisterBlock>::new(p.TIM3);
+    timer.init(STEP_RATE.invert());
+    hal::Timer::resume(&timer);
+}
+
+fn idle() -> ! {
+    loop {
+        rtfm::wfi();
+    }
+}
+
+fn step(_t: &mut Threshold, r: TIM3::Resources) {
+    let timer = Timer::<TIM3, tim3::RegisterBlock>::new(&**r.TIM3);
+    hal::Timer::wait(&timer).unwrap();
+
+    let angle = &mut **r.ANGLE;
+    let rising = &mut **r.RISING;
+    if *rising {
+        *angle += 1;
+        *rising = *angle < 180;
+    } else {
+        *angle -= 1;
+        *rising = *angle == 0;
+    }
+
+    set_angle(&Pwm(&**r.TIM1), *angle);
+}
+
+/// 1 ms + angle / 180 ms out of the 20 ms period
+fn set_angle(pwm: &Pwm<TIM1>, angle: u8) {
+    pwm.set_duty_fraction(Channel::_1, 180 + u16::from(angle), 180 * 20);
+}
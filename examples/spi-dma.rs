@@ -0,0 +1,81 @@
+//! Sends a 64 byte buffer over SPI1 using DMA, over and over
+//!
+//! SPI1 is on PA5 (SCK), PA6 (MISO) and PA7 (MOSI), hook a logic analyzer to
+//! see the frames. The TX requests of SPI1 go to DMA2 stream 3, channel 3.
+
+#![deny(warnings)]
+#![feature(proc_macro)]
+#![no_std]
+
+extern crate bsp;
+extern crate cortex_m_rtfm as rtfm;
+
+use bsp::dma2::{Buffer, DMAStream, Dma, DmaExt};
+use bsp::gpio::{PA5, PA6, PA7};
+use bsp::rcc::Clocks;
+use bsp::spi2::{self, Role, Spi};
+use bsp::stm32f411::{DMA2, SPI1};
+use bsp::time::Hertz;
+use rtfm::{app, Threshold};
+
+// CONFIGURATION
+const FREQUENCY: Hertz = Hertz(1_000_000);
+const SPI1_TX_CHANNEL: u32 = 3;
+
+app! {
+    device: bsp::stm32f411,
+
+    resources: {
+        static BUFFER: Buffer<[u8; 64]> = Buffer::new([0; 64], DMAStream::Stream3);
+        // the TX stream, handed over by `init`
+        static TX: Option<Dma<'static, DMA2>> = None;
+    },
+
+    tasks: {
+        DMA2_STREAM3: {
+            path: done,
+            resources: [BUFFER, DMA2, SPI1, TX],
+        },
+    },
+}
+
+fn init(p: init::Peripherals, r: init::Resources) {
+    p.RCC.ahb1enr.modify(|_, w| w.gpioaen().set_bit());
+    p.RCC.apb2enr.modify(|_, w| w.spi1en().set_bit());
+
+    spi2::route(p.SPI1, PA5(p.GPIOA), PA6(p.GPIOA), PA7(p.GPIOA));
+
+    let streams = p.DMA2.split(p.RCC).unwrap();
+    // CHSEL, MINC, memory to peripheral, transfer complete interrupt
+    streams.s3.with_raw(|dma2| {
+        dma2.s3cr.write(|w| unsafe {
+            w.bits((SPI1_TX_CHANNEL << 25) | (1 << 10) | (0b01 << 6) | (1 << 4))
+        })
+    });
+    **r.TX = Some(streams.s3);
+
+    let spi = Spi::new(p.SPI1, Role::MASTER, None, r.TX.as_ref());
+    let clocks = Clocks::read(p.RCC, p.FLASH, None);
+    spi.set_frequency(&clocks, FREQUENCY).unwrap();
+    // SSM + SSI so the master doesn't fault on a floating NSS
+    p.SPI1.cr1.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 9) | (1 << 8)) });
+    spi.enable();
+
+    for (i, byte) in r.BUFFER.borrow_mut().iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    spi.send_dma(r.BUFFER).unwrap();
+}
+
+fn idle() -> ! {
+    loop {
+        rtfm::wfi();
+    }
+}
+
+fn done(_t: &mut Threshold, r: DMA2_STREAM3::Resources) {
+    let spi = Spi::new(&**r.SPI1, Role::MASTER, None, r.TX.as_ref());
+
+    r.BUFFER.release(&**r.DMA2).unwrap();
+    spi.send_dma(r.BUFFER).unwrap();
+}
@@ -0,0 +1,60 @@
+//! Echoes back every byte received on USART2
+//!
+//! USART2 is on PA2 (TX) and PA3 (RX), wired to the ST-LINK virtual COM
+//! port on Nucleo boards. 115200 bps, 8N1.
+
+#![deny(warnings)]
+#![feature(proc_macro)]
+#![no_std]
+
+extern crate bsp;
+extern crate cortex_m_rtfm as rtfm;
+extern crate embedded_hal as hal;
+
+use bsp::gpio::{PA2, PA3};
+use bsp::serial::{self, Event, Serial};
+use bsp::time::U32Ext;
+use hal::serial::{Read, Write};
+use rtfm::{app, Threshold};
+
+// CONFIGURATION
+const BAUD_RATE: u32 = 115_200;
+
+app! {
+    device: bsp::stm32f411,
+
+    tasks: {
+        USART2: {
+            path: echo,
+            resources: [USART2],
+        },
+    },
+}
+
+fn init(p: init::Peripherals) {
+    p.RCC.ahb1enr.modify(|_, w| w.gpioaen().set_bit());
+    p.RCC.apb1enr.modify(|_, w| w.usart2en().set_bit());
+
+    serial::route(p.USART2, PA2(p.GPIOA), PA3(p.GPIOA));
+
+    let serial = Serial::new(p.USART2);
+    serial.init(BAUD_RATE.hz().invert()).unwrap();
+    serial.listen(Event::Rxne);
+}
+
+fn idle() -> ! {
+    loop {
+        rtfm::wfi();
+    }
+}
+
+fn echo(_t: &mut Threshold, r: USART2::Resources) {
+    let serial = Serial::new(&**r.USART2);
+
+    // RXNE is set, so the TX data register has had a whole frame to drain
+    match serial.read() {
+        Ok(byte) => serial.write(byte).unwrap(),
+        // Drop the byte on framing / noise / overrun errors
+        Err(_) => {}
+    }
+}
@@ -57,7 +57,7 @@ fn init(ref prio: P0, thr: &TMax) {
     let usart1 = USART1.access(prio, thr);
     let buffer = BUFFER.access(prio, thr);
 
-    let serial = Serial(&*usart1);
+    let serial = Serial::new(&*usart1);
 
     serial.init(BAUD_RATE.invert(), afio, Some(dma1), gpioa, rcc);
     buffer.borrow_mut().clone_from_slice(b"Hello, world!\n");
@@ -50,7 +50,7 @@ fn init(ref prio: P0, thr: &TMax) {
     let rcc = &RCC.access(prio, thr);
     let usart1 = USART1.access(prio, thr);
 
-    let serial = Serial(&*usart1);
+    let serial = Serial::new(&*usart1);
 
     serial.init(BAUD_RATE.invert(), afio, None, gpioa, rcc);
 
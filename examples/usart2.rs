@@ -51,7 +51,7 @@ fn init(ref prio: P0, thr: &TMax) {
     let rcc = &RCC.access(prio, thr);
     let usart2 = USART2.access(prio, thr);
 
-    let serial = Serial(&*usart2);
+    let serial = Serial::new(&*usart2);
 
     serial.init(BAUD_RATE.invert(), afio, None, gpioa, rcc);
 
@@ -51,7 +51,7 @@ fn init(ref prio: P0, thr: &TMax) {
     let rcc = &RCC.access(prio, thr);
     let usart3 = USART3.access(prio, thr);
 
-    let serial = Serial(&*usart3);
+    let serial = Serial::new(&*usart3);
 
     serial.init(BAUD_RATE.invert(), afio, None, gpiob, rcc);
 
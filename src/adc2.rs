@@ -0,0 +1,190 @@
+//! Analog to Digital Converter
+//!
+//! Single conversions on ADC1. Only pins with an analog function can be
+//! converted: put one in analog mode with `into_analog` and pass it to
+//! `read`.
+//!
+//! ``` ignore
+//! let pa1 = PA1::into_analog(gpioa);
+//! adc.init(rcc, adc_common);
+//! let value = adc.read(&pa1);
+//! ```
+
+use core::any::Any;
+
+use stm32f411::{ADC1, ADC_COMMON, GPIOA, GPIOB, GPIOC, RCC};
+
+use circular::CircularSampler;
+use dma2::{DMA, Dma};
+use gpio::{Mode, Pin};
+
+/// Pin with an analog function
+///
+/// Implemented only for pins in analog mode, so `Adc::read` can't be called
+/// with anything else
+pub unsafe trait AdcChannel {
+    /// ADC1 input channel
+    const CHANNEL: u8;
+}
+
+macro_rules! analog_pins {
+    ($($PXi:ident: ($GPIOX:ident, $i:expr, $channel:expr),)+) => {
+        $(
+            /// Pin in analog mode
+            pub struct $PXi {
+                _0: (),
+            }
+
+            impl $PXi {
+                /// Puts the pin in analog mode
+                pub fn into_analog(port: &$GPIOX) -> Self {
+                    Pin::<$GPIOX>::new($i).set_mode(port, Mode::Analog);
+                    $PXi { _0: () }
+                }
+            }
+
+            unsafe impl AdcChannel for $PXi {
+                const CHANNEL: u8 = $channel;
+            }
+        )+
+    }
+}
+
+analog_pins! {
+    PA0: (GPIOA, 0, 0),
+    PA1: (GPIOA, 1, 1),
+    PA2: (GPIOA, 2, 2),
+    PA3: (GPIOA, 3, 3),
+    PA4: (GPIOA, 4, 4),
+    PA5: (GPIOA, 5, 5),
+    PA6: (GPIOA, 6, 6),
+    PA7: (GPIOA, 7, 7),
+    PB0: (GPIOB, 0, 8),
+    PB1: (GPIOB, 1, 9),
+    PC0: (GPIOC, 0, 10),
+    PC1: (GPIOC, 1, 11),
+    PC2: (GPIOC, 2, 12),
+    PC3: (GPIOC, 3, 13),
+    PC4: (GPIOC, 4, 14),
+    PC5: (GPIOC, 5, 15),
+}
+
+/// Channel number of `pin`, for APIs that take raw channel lists
+pub fn channel<P>(_pin: &P) -> u8
+    where P: AdcChannel
+{
+    P::CHANNEL
+}
+
+/// Internal temperature sensor
+pub struct Temperature {
+    _0: (),
+}
+
+unsafe impl AdcChannel for Temperature {
+    const CHANNEL: u8 = 18;
+}
+
+/// Internal reference voltage
+pub struct Vrefint {
+    _0: (),
+}
+
+unsafe impl AdcChannel for Vrefint {
+    const CHANNEL: u8 = 17;
+}
+
+/// Sampling time, in ADC clock cycles
+#[derive(Clone, Copy, Debug)]
+pub enum SampleTime {
+    Cycles3 = 0,
+    Cycles15 = 1,
+    Cycles28 = 2,
+    Cycles56 = 3,
+    Cycles84 = 4,
+    Cycles112 = 5,
+    Cycles144 = 6,
+    Cycles480 = 7,
+}
+
+/// ADC1
+pub struct Adc<'a>(pub &'a ADC1);
+
+impl<'a> Adc<'a> {
+    /// Powers up the ADC: 12-bit resolution, right aligned, PCLK2 / 4
+    ///
+    /// Every channel gets a 84 cycles sampling time
+    pub fn init(&self, rcc: &RCC, common: &ADC_COMMON) {
+        let adc = self.0;
+
+        rcc.apb2enr.modify(|_, w| w.adc1en().set_bit());
+
+        // ADCPRE = PCLK2 / 4, keeps ADCCLK under 36 MHz up to 100 MHz
+        common.ccr.modify(|r, w| unsafe {
+            w.bits((r.bits() & !(0b11 << 16)) | (0b01 << 16))
+        });
+
+        adc.cr1.write(|w| unsafe { w.bits(0) });
+        adc.cr2.write(|w| unsafe { w.bits(0) });
+        self.set_sample_time(SampleTime::Cycles84);
+
+        adc.cr2.modify(|r, w| unsafe { w.bits(r.bits() | 1) });
+    }
+
+    /// Enables the temperature sensor and the internal reference
+    pub fn enable_internal(&self, common: &ADC_COMMON) -> (Temperature, Vrefint) {
+        // TSVREFE
+        common.ccr.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 23)) });
+        (Temperature { _0: () }, Vrefint { _0: () })
+    }
+
+    /// Sets the sampling time of every channel
+    pub fn set_sample_time(&self, time: SampleTime) {
+        let mut bits = 0;
+        for i in 0..10 {
+            bits |= (time as u32) << (3 * i);
+        }
+        self.0.smpr1.write(|w| unsafe { w.bits(bits & 0x07FF_FFFF) });
+        self.0.smpr2.write(|w| unsafe { w.bits(bits) });
+    }
+
+    /// Converts `pin` continuously, the results are moved into `buffer` by
+    /// DMA
+    ///
+    /// `dma` must be DMA2 stream 0 or 4, channel 0
+    pub fn circular<'d, P, D>(&self, _pin: &P, dma: &'d Dma<'d, D>, buffer: &'static mut [u16])
+        -> CircularSampler<'d, D, u16>
+        where P: AdcChannel,
+              D: Any + DMA
+    {
+        let adc = self.0;
+
+        adc.sqr1.write(|w| unsafe { w.bits(0) });
+        adc.sqr3.write(|w| unsafe { w.bits(u32::from(P::CHANNEL)) });
+
+        let sampler = CircularSampler::start(dma, &adc.dr as *const _ as u32, buffer);
+
+        // CONT, DMA, DDS, then SWSTART
+        adc.cr2.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 1) | (1 << 8) | (1 << 9)) });
+        adc.cr2.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 30)) });
+
+        sampler
+    }
+
+    /// Converts `pin`, blocking until the result is available
+    pub fn read<P>(&self, _pin: &P) -> u16
+        where P: AdcChannel
+    {
+        let adc = self.0;
+
+        // One conversion in the regular sequence
+        adc.sqr1.write(|w| unsafe { w.bits(0) });
+        adc.sqr3.write(|w| unsafe { w.bits(u32::from(P::CHANNEL)) });
+
+        // SWSTART
+        adc.cr2.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 30)) });
+        while adc.sr.read().eoc().bit_is_clear() {}
+
+        (adc.dr.read().bits() & 0xFFF) as u16
+    }
+}
@@ -0,0 +1,220 @@
+//! Timer paced DMA output on a GPIO port
+//!
+//! TIM1 update events trigger DMA2 stream 5 (channel 6) which copies one
+//! word of the buffer to the port's BSRR per event. Every pin of the port can
+//! change state on each tick, which makes this suitable for parallel buses
+//! (8080 style LCD writes) or driving WS2812 strips on many pins at once.
+//!
+//! ``` ignore
+//! static BUFFER: Buffer<[u32; 96]> = Buffer::new([0; 96], DMAStream::Stream5);
+//!
+//! let n = bitbang::encode_ws2812(0x00FF, &slices, &mut *BUFFER.borrow_mut());
+//! let out = ParallelOut::new(tim1, &streams.s5, gpiob)?;
+//! out.init(&clocks, 2_400_000.hz())?;
+//! out.start(BUFFER)?;
+//! block!(out.wait(BUFFER))?;
+//! ```
+//!
+//! NOTE The transfer always covers the whole buffer.
+
+use core::any::Any;
+use core::fmt;
+use core::marker::Unsize;
+use core::ops::Deref;
+
+use cast::u16;
+use nb;
+use static_ref::Static;
+use stm32f411::{DMA2, TIM1, gpioa};
+
+use dma2::{self, Buffer, DMAStream, Dma, dma_len};
+use rcc::{ClockError, Clocks};
+use time::Hertz;
+
+/// AHB cycles a timer triggered single word transfer takes, arbitration
+/// included; used to estimate the maximum rate
+const AHB_CYCLES_PER_TRANSFER: u32 = 10;
+
+/// Highest update rate the DMA can keep up with
+///
+/// This is an estimate, heavy traffic on other DMA2 streams or the CPU
+/// hammering the AHB bus lowers it
+pub fn max_rate(clocks: &Clocks) -> Hertz {
+    let dma = clocks.hclk().0 / AHB_CYCLES_PER_TRANSFER;
+    let timer = clocks.timclk2().0 / 2;
+    Hertz(if dma < timer { dma } else { timer })
+}
+
+/// Encodes port values into BSRR words
+///
+/// The pins in `mask` take the state of the matching bits of each value, the
+/// other pins are left alone. Returns the number of words written.
+pub fn encode_port(mask: u16, values: &[u16], out: &mut [u32]) -> usize {
+    let mut n = 0;
+    for (word, value) in out.iter_mut().zip(values.iter()) {
+        *word = bsrr(mask, *value);
+        n += 1;
+    }
+    n
+}
+
+/// Encodes 8080 style writes: each byte is presented on the 8 pins starting
+/// at `data_pin` while the `wr_pin` strobe goes low, then latched on its
+/// rising edge
+///
+/// Takes 2 words per byte. Returns the number of words written.
+pub fn encode_8080(data_pin: u8, wr_pin: u8, data: &[u8], out: &mut [u32]) -> usize {
+    assert!(data_pin <= 8);
+
+    let data_mask = 0xFFu16 << data_pin;
+    let wr = 1u16 << wr_pin;
+    let mask = data_mask | wr;
+
+    let mut n = 0;
+    for (words, byte) in out.chunks_mut(2).zip(data.iter()) {
+        if words.len() < 2 {
+            break;
+        }
+        let value = u16::from(*byte) << data_pin;
+        words[0] = bsrr(mask, value);
+        words[1] = bsrr(wr, wr);
+        n += 2;
+    }
+    n
+}
+
+/// Encodes WS2812 bit slices for the pins in `mask`
+///
+/// Bit `i` of each slice is the next data bit of the strip on pin `i`, MSB
+/// of each color byte first. Every bit takes 3 words: the line goes high,
+/// stays high for a `1` (or drops for a `0`), then goes low, so the timer
+/// must run at 3 x 800 kHz. Returns the number of words written.
+pub fn encode_ws2812(mask: u16, slices: &[u16], out: &mut [u32]) -> usize {
+    let mut n = 0;
+    for (words, slice) in out.chunks_mut(3).zip(slices.iter()) {
+        if words.len() < 3 {
+            break;
+        }
+        words[0] = bsrr(mask, mask);
+        words[1] = bsrr(mask, *slice);
+        words[2] = bsrr(mask, 0);
+        n += 3;
+    }
+    n
+}
+
+fn bsrr(mask: u16, value: u16) -> u32 {
+    let set = u32::from(value & mask);
+    let reset = u32::from(!value & mask);
+    (reset << 16) | set
+}
+
+/// Parallel output error
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The DMA handle isn't DMA2 stream 5, the one TIM1_UP is wired to
+    WrongStream,
+    #[doc(hidden)]
+    _Extensible,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::WrongStream => f.write_str("TIM1_UP requests are routed to DMA2 stream 5"),
+            Error::_Extensible => f.write_str("unknown error"),
+        }
+    }
+}
+
+/// Timer paced DMA output
+pub struct ParallelOut<'a, P>
+    where P: 'a + Any + Deref<Target = gpioa::RegisterBlock>
+{
+    tim: &'a TIM1,
+    dma: &'a Dma<'a, DMA2>,
+    port: &'a P,
+}
+
+impl<'a, P> ParallelOut<'a, P>
+    where P: Any + Deref<Target = gpioa::RegisterBlock>
+{
+    /// `dma` must be DMA2 stream 5, the one TIM1_UP is wired to, else this
+    /// fails with `WrongStream`
+    pub fn new(tim: &'a TIM1, dma: &'a Dma<'a, DMA2>, port: &'a P) -> Result<Self, Error> {
+        match dma.stream() {
+            DMAStream::Stream5 => {}
+            _ => return Err(Error::WrongStream),
+        }
+
+        Ok(ParallelOut { tim: tim, dma: dma, port: port })
+    }
+
+    /// Configures the timer for `rate` updates per second and the DMA stream
+    ///
+    /// Returns the achieved rate. The TIM1 and DMA2 clocks must be enabled.
+    pub fn init(&self, clocks: &Clocks, rate: Hertz) -> Result<Hertz, ClockError> {
+        let timclk = clocks.timclk2().0;
+        if rate.0 == 0 || rate.0 > max_rate(clocks).0 {
+            return Err(ClockError::FrequencyOutOfRange);
+        }
+
+        let ticks = timclk / rate.0;
+        let psc = u16((ticks - 1) / (1 << 16)).map_err(|_| ClockError::FrequencyOutOfRange)?;
+        let arr = u16(ticks / (u32::from(psc) + 1) - 1)
+            .map_err(|_| ClockError::FrequencyOutOfRange)?;
+
+        let tim = self.tim;
+        tim.cr1.write(|w| unsafe { w.bits(0) });
+        tim.psc.write(|w| unsafe { w.psc().bits(psc) });
+        tim.arr.write(|w| unsafe { w.arr().bits(arr) });
+        // UDE: DMA request on update
+        tim.dier.write(|w| unsafe { w.bits(1 << 8) });
+
+        // CHSEL = 6, PL = high, word sized, MINC, memory to peripheral
+        self.dma.disable();
+        self.dma.reg.scr(DMAStream::Stream5).write(|w| unsafe {
+            w.bits((6 << 25) | (0b10 << 16) | (0b10 << 13) | (0b10 << 11) | (1 << 10) |
+                   (0b01 << 6))
+        });
+
+        Ok(Hertz(timclk / ((u32::from(psc) + 1) * (u32::from(arr) + 1))))
+    }
+
+    /// Starts streaming `buffer` to the port
+    pub fn start<B>(&self, buffer: &Static<Buffer<B>>) -> Result<(), dma2::Error>
+        where B: Unsize<[u32]>
+    {
+        if self.dma.is_enabled() {
+            return Err(dma2::Error::InUse);
+        }
+
+        buffer.check_stream(self.dma.stream())?;
+        let slice: &[u32] = buffer.try_lock()?;
+        let len = match dma_len(slice.len()) {
+            Ok(len) => len,
+            Err(e) => {
+                buffer.cancel_lock();
+                return Err(e);
+            }
+        };
+        self.dma.clear_flags();
+        self.dma.set_config(slice.as_ptr() as u32, &self.port.bsrr as *const _ as u32, len);
+        self.dma.enable();
+
+        let tim = self.tim;
+        unsafe { tim.cnt.write(|w| w.bits(0)) };
+        tim.cr1.modify(|r, w| unsafe { w.bits(r.bits() | 1) });
+        Ok(())
+    }
+
+    /// Waits for the end of the transfer and stops the timer
+    pub fn wait<B>(&self, buffer: &Static<Buffer<B>>) -> nb::Result<(), dma2::Error> {
+        let result = buffer.release(self.dma.reg);
+        match result {
+            Err(nb::Error::WouldBlock) => {}
+            _ => self.tim.cr1.modify(|r, w| unsafe { w.bits(r.bits() & !1) }),
+        }
+        result
+    }
+}
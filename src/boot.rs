@@ -0,0 +1,170 @@
+//! System bootloader entry
+//!
+//! The STM32F411 ROM bootloader (AN2606) lives in system memory at
+//! `0x1FFF_0000` and offers USART, I2C, SPI and USB DFU update paths.
+//!
+//! Two ways in are provided:
+//!
+//! - `jump_to_system_bootloader` puts the chip back in a reset-like state and
+//!   jumps straight into ROM.
+//! - `request_dfu_on_next_reset` leaves a magic value in RTC backup register
+//!   0 and resets; `enter_bootloader_if_requested`, called first thing in
+//!   `init`, finds it and performs the jump from a pristine state. This is
+//!   the more robust option since no driver state survives the reset.
+
+use core::ptr;
+
+use cortex_m;
+use stm32f411::{NVIC, PWR, RCC, RTC, SCB, SYSCFG, SYST};
+
+/// Base address of the system memory
+pub const SYSTEM_MEMORY: u32 = 0x1FFF_0000;
+
+/// Value stored in `RTC_BKP0R` to request the bootloader
+const DFU_MAGIC: u32 = 0xB007_DF00;
+
+/// Reset value of `RCC_PLLCFGR`
+const PLLCFGR_RESET: u32 = 0x2400_3010;
+
+/// Puts the peripherals back in their reset state and jumps to the ROM
+/// bootloader. Never returns
+///
+/// Interrupts are disabled in the NVIC, SysTick is stopped, the clock tree
+/// falls back to the 16 MHz HSI, system memory is remapped at address 0 and
+/// VTOR, MSP and PC are loaded from the bootloader vector table.
+pub fn jump_to_system_bootloader(
+    nvic: &NVIC,
+    rcc: &RCC,
+    scb: &SCB,
+    syscfg: &SYSCFG,
+    syst: &SYST,
+) -> ! {
+    cortex_m::interrupt::disable();
+
+    syst.disable_counter();
+    syst.disable_interrupt();
+
+    // Disable and unpend every interrupt
+    for i in 0..8 {
+        unsafe {
+            nvic.icer[i].write(0xFFFF_FFFF);
+            nvic.icpr[i].write(0xFFFF_FFFF);
+        }
+    }
+
+    deinit_clocks(rcc);
+
+    // Reset every peripheral, except the backup domain
+    unsafe {
+        rcc.ahb1rstr.write(|w| w.bits(0xFFFF_FFFF));
+        rcc.ahb1rstr.write(|w| w.bits(0));
+        rcc.ahb2rstr.write(|w| w.bits(0xFFFF_FFFF));
+        rcc.ahb2rstr.write(|w| w.bits(0));
+        rcc.apb1rstr.write(|w| w.bits(0xFFFF_FFFF));
+        rcc.apb1rstr.write(|w| w.bits(0));
+        rcc.apb2rstr.write(|w| w.bits(0xFFFF_FFFF));
+        rcc.apb2rstr.write(|w| w.bits(0));
+    }
+
+    // Map system memory at 0x0000_0000
+    rcc.apb2enr.modify(|_, w| w.syscfgen().set_bit());
+    syscfg.memrm.modify(|r, w| unsafe { w.bits((r.bits() & !0b11) | 0b01) });
+
+    unsafe {
+        scb.vtor.write(SYSTEM_MEMORY);
+
+        let msp = ptr::read_volatile(SYSTEM_MEMORY as *const u32);
+        let reset = ptr::read_volatile((SYSTEM_MEMORY + 4) as *const u32);
+
+        // NOTE the stack switch and the branch must be a single asm block:
+        // once MSP moves, compiled code may still reload locals from the old
+        // stack. The bootloader expects interrupts enabled, nothing is
+        // pending or enabled in the NVIC at this point
+        asm!("msr MSP, $0
+              cpsie i
+              bx $1"
+             :
+             : "r"(msp), "r"(reset)
+             :
+             : "volatile");
+    }
+
+    loop {}
+}
+
+/// Stores the bootloader request in the backup domain
+///
+/// The request survives a system reset (but not a power cycle). Follow with
+/// `reset` or any other reset source.
+pub fn request_dfu_on_next_reset(pwr: &PWR, rcc: &RCC, rtc: &RTC) {
+    write_backup(pwr, rcc, rtc, DFU_MAGIC);
+}
+
+/// Checks for, and consumes, a request left by `request_dfu_on_next_reset`
+pub fn dfu_requested(pwr: &PWR, rcc: &RCC, rtc: &RTC) -> bool {
+    if rtc.bkp0r.read().bits() == DFU_MAGIC {
+        write_backup(pwr, rcc, rtc, 0);
+        true
+    } else {
+        false
+    }
+}
+
+/// Jumps to the bootloader if it was requested before the last reset
+///
+/// Call this at the very beginning of `init`, before any clock or peripheral
+/// configuration
+pub fn enter_bootloader_if_requested(
+    nvic: &NVIC,
+    pwr: &PWR,
+    rcc: &RCC,
+    rtc: &RTC,
+    scb: &SCB,
+    syscfg: &SYSCFG,
+    syst: &SYST,
+) {
+    if dfu_requested(pwr, rcc, rtc) {
+        jump_to_system_bootloader(nvic, rcc, scb, syscfg, syst);
+    }
+}
+
+/// Requests a system reset. Never returns
+pub fn reset(scb: &SCB) -> ! {
+    unsafe {
+        // VECTKEY | SYSRESETREQ, keep the priority grouping
+        scb.aircr.modify(|r| (0x05FA << 16) | (r & 0x0700) | (1 << 2));
+    }
+    loop {}
+}
+
+fn write_backup(pwr: &PWR, rcc: &RCC, rtc: &RTC, value: u32) {
+    // Backup domain write access
+    rcc.apb1enr.modify(|_, w| w.pwren().set_bit());
+    pwr.cr.modify(|_, w| w.dbp().set_bit());
+
+    rtc.bkp0r.write(|w| unsafe { w.bits(value) });
+
+    pwr.cr.modify(|_, w| w.dbp().clear_bit());
+}
+
+/// Switches back to HSI and turns off HSE and the PLLs
+fn deinit_clocks(rcc: &RCC) {
+    rcc.cr.modify(|_, w| w.hsion().set_bit());
+    while rcc.cr.read().hsirdy().bit_is_clear() {}
+
+    // SW = HSI, no prescalers, no MCO
+    rcc.cfgr.write(|w| unsafe { w.bits(0) });
+    while rcc.cfgr.read().sws().bits() != 0 {}
+
+    rcc.cr.modify(|_, w| {
+        w.hseon().clear_bit()
+            .csson().clear_bit()
+            .pllon().clear_bit()
+            .plli2son().clear_bit()
+    });
+    while rcc.cr.read().pllrdy().bit_is_set() {}
+    rcc.cr.modify(|_, w| w.hsebyp().clear_bit());
+
+    rcc.pllcfgr.write(|w| unsafe { w.bits(PLLCFGR_RESET) });
+    rcc.cir.write(|w| unsafe { w.bits(0) });
+}
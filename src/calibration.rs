@@ -0,0 +1,188 @@
+//! Run-time sysclk calibration against the LSE
+//!
+//! The HSI is only 1% accurate at 25 C and drifts with temperature; the
+//! bit-banged protocols (`soft_uart`, `soft_i2c`, `input` timing through
+//! `delay::CyclesToTime`) assume the nominal `sysclk`. `Calibrator`
+//! measures the actual core clock, counted by the DWT cycle counter,
+//! against the RTC running from the 32.768 kHz crystal, and publishes the
+//! ratio; every `CyclesToTime` applies it to its delays.
+//!
+//! Call `poll` from a low priority periodic task, every 1 - 30 s: each call
+//! timestamps an RTC subsecond edge and compares it with the previous one.
+//!
+//! ``` ignore
+//! // RTC from the LSE, e.g. through `LowPowerTicker::new(.., RtcClock::Lse)`
+//! let calibrator = Calibrator::new(rtc, dcb, dwt, &clocks);
+//!
+//! // every 10 s
+//! if let Some(sysclk) = calibrator.poll() {
+//!     log!("sysclk {} Hz", sysclk.0);
+//! }
+//! ```
+//!
+//! `retrim` goes one step further and moves HSITRIM so the oscillator
+//! itself is back at 16 MHz, which also keeps the USART / timer clocks on
+//! target.
+
+use core::cell::Cell;
+use core::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+
+use stm32f411::{DCB, DWT, RCC, RTC};
+
+use rcc::{self, Clocks};
+use time::Hertz;
+
+/// `ratio` of an uncalibrated clock
+const ONE: u32 = 1 << 16;
+
+/// Measured / nominal sysclk, Q16; 0 until the first measurement
+static RATIO: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Shortest interval between two `poll`s that gives a measurement, 1 s
+const MIN_SECONDS: u32 = 1;
+
+/// Measurements further than 10% off are a stopped or missing LSE, not
+/// clock drift
+const MAX_ERROR_PERCENT: u32 = 10;
+
+/// HSITRIM step, ~0.3% of 16 MHz (Hz)
+const TRIM_STEP: u32 = 48_000;
+
+/// Measured / nominal sysclk ratio, Q16 (65536 is 1.0)
+///
+/// 65536 until a measurement was made.
+pub fn ratio() -> u32 {
+    match RATIO.load(Ordering::Relaxed) as u32 {
+        0 => ONE,
+        ratio => ratio,
+    }
+}
+
+/// `nominal` Hz corrected by the measured ratio
+pub fn correct(nominal: u32) -> u32 {
+    ((u64::from(nominal) * u64::from(ratio())) >> 16) as u32
+}
+
+/// Forgets the measurement, e.g. after switching to the HSE
+pub fn clear() {
+    RATIO.store(0, Ordering::Relaxed);
+}
+
+/// Measures sysclk against the RTC
+pub struct Calibrator<'a> {
+    rtc: &'a RTC,
+    dwt: &'a DWT,
+    nominal: u32,
+    /// Cycle count and RTC ticks of the last edge
+    last: Cell<Option<(u32, u32)>>,
+}
+
+impl<'a> Calibrator<'a> {
+    /// Starts the DWT cycle counter
+    ///
+    /// The RTC must run from the LSE. `clocks` gives the nominal sysclk.
+    pub fn new(rtc: &'a RTC, dcb: &DCB, dwt: &'a DWT, clocks: &Clocks) -> Self {
+        // TRCENA, the DWT is off without it
+        unsafe { dcb.demcr.write(dcb.demcr.read() | (1 << 24)) }
+        dwt.enable_cycle_counter();
+
+        Calibrator { rtc: rtc, dwt: dwt, nominal: clocks.sysclk().0, last: Cell::new(None) }
+    }
+
+    /// Takes a timestamp and, if the previous one is at least a second
+    /// old, updates the ratio
+    ///
+    /// Returns the measured sysclk. Busy-waits for the next RTC subsecond
+    /// edge, up to 4 ms. Calls more than ~40 s apart (the cycle counter
+    /// wraps at 100 MHz) start over.
+    pub fn poll(&self) -> Option<Hertz> {
+        let (cycles, ticks) = self.timestamp();
+        let apre = self.apre_clock();
+
+        let (last_cycles, last_ticks) = match self.last.get() {
+            Some(last) => last,
+            None => {
+                self.last.set(Some((cycles, ticks)));
+                return None;
+            }
+        };
+
+        // RTC ticks within the hour
+        let hour = 3600 * apre;
+        let elapsed_ticks = (ticks + hour - last_ticks) % hour;
+        if elapsed_ticks < MIN_SECONDS * apre {
+            return None;
+        }
+        self.last.set(Some((cycles, ticks)));
+
+        // The cycle counter may have wrapped more than once
+        let expected = u64::from(self.nominal) * u64::from(elapsed_ticks) / u64::from(apre);
+        if expected > u64::from(u32::max_value()) {
+            return None;
+        }
+        let elapsed_cycles = cycles.wrapping_sub(last_cycles);
+
+        let measured = u64::from(elapsed_cycles) * u64::from(apre) / u64::from(elapsed_ticks);
+        let measured = measured as u32;
+        let error = if measured > self.nominal {
+            measured - self.nominal
+        } else {
+            self.nominal - measured
+        };
+        if error > self.nominal / 100 * MAX_ERROR_PERCENT {
+            return None;
+        }
+
+        let ratio = (u64::from(measured) << 16) / u64::from(self.nominal);
+        RATIO.store(ratio as usize, Ordering::Relaxed);
+        Some(Hertz(measured))
+    }
+
+    /// Moves HSITRIM towards the measured error, then clears the ratio
+    ///
+    /// Only meaningful when sysclk comes from the HSI. Returns the new trim
+    /// value; the next two `poll`s measure the result.
+    pub fn retrim(&self, rcc: &RCC) -> u8 {
+        let trim = i32::from(rcc::hsi_trim(rcc));
+        // HSI error in Hz, at 16 MHz
+        let error = (i64::from(ratio()) - i64::from(ONE)) * 16_000_000 >> 16;
+        let steps = (error / i64::from(TRIM_STEP)) as i32;
+
+        let trim = trim - steps;
+        let trim = if trim < 0 { 0 } else if trim > 31 { 31 } else { trim as u8 };
+        rcc::set_hsi_trim(rcc, trim);
+
+        clear();
+        self.last.set(None);
+        trim
+    }
+
+    /// Cycle count and RTC time (ticks within the hour) right after a
+    /// subsecond edge
+    fn timestamp(&self) -> (u32, u32) {
+        let rtc = self.rtc;
+        let ssr = rtc.ssr.read().bits();
+        while rtc.ssr.read().bits() == ssr {}
+        let cycles = self.dwt.cyccnt.read();
+
+        // NOTE reading SSR freezes TR / DR until DR is read, the ones the
+        // loop froze are stale
+        let _ = rtc.dr.read();
+        let ssr = rtc.ssr.read().bits();
+        let tr = rtc.tr.read().bits();
+        let _ = rtc.dr.read();
+
+        let seconds = bcd(tr & 0x7F) + 60 * bcd((tr >> 8) & 0x7F);
+        let prediv_s = rtc.prer.read().bits() & 0x7FFF;
+        (cycles, seconds * (prediv_s + 1) + (prediv_s - ssr))
+    }
+
+    /// Rate of the subsecond counter
+    fn apre_clock(&self) -> u32 {
+        (self.rtc.prer.read().bits() & 0x7FFF) + 1
+    }
+}
+
+fn bcd(bits: u32) -> u32 {
+    (bits >> 4) * 10 + (bits & 0xF)
+}
@@ -0,0 +1,157 @@
+//! Circular DMA reception from a peripheral data register
+//!
+//! `CircularSampler` keeps a DMA stream writing into a ring buffer and hands
+//! out whatever arrived since the last call. `Serial`, `Spi` and `Adc` have
+//! constructors for it; anything else can use `CircularSampler::start` with
+//! the address of its data register.
+//!
+//! ``` ignore
+//! static mut RX: [u8; 64] = [0; 64];
+//!
+//! let mut rx = serial.circular_rx(&streams.s5, unsafe { &mut RX });
+//! loop {
+//!     rx.read_available(|a, b| { parse(a); parse(b); })?;
+//! }
+//! ```
+//!
+//! The stream must be disabled and its channel selected beforehand.
+
+use core::any::Any;
+use core::fmt;
+use core::sync::atomic::{self, Ordering};
+
+use cast::u16;
+
+use dma2::{DMA, Dma};
+
+/// DMA transfer unit
+pub unsafe trait Word: Copy {
+    #[doc(hidden)]
+    const SIZE: u32;
+}
+
+unsafe impl Word for u8 {
+    const SIZE: u32 = 0b00;
+}
+
+unsafe impl Word for u16 {
+    const SIZE: u32 = 0b01;
+}
+
+unsafe impl Word for u32 {
+    const SIZE: u32 = 0b10;
+}
+
+/// Circular buffer error
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The DMA wrapped around and overwrote data that hadn't been read; the
+    /// reader was resynchronized and that data is lost
+    Overrun,
+    /// Transfer error, the stream was stopped
+    Transfer,
+    #[doc(hidden)]
+    _Extensible,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Error::Overrun => "circular buffer overrun, data lost",
+            Error::Transfer => "DMA transfer error",
+            Error::_Extensible => "unknown error",
+        })
+    }
+}
+
+/// Ring buffer continuously filled by a DMA stream
+pub struct CircularSampler<'a, U, W>
+    where U: 'a + Any + DMA,
+          W: 'static + Word
+{
+    dma: &'a Dma<'a, U>,
+    buffer: &'static mut [W],
+    read: usize,
+}
+
+impl<'a, U, W> CircularSampler<'a, U, W>
+    where U: Any + DMA,
+          W: 'static + Word
+{
+    /// Starts receiving from the register at `address` into `buffer`
+    ///
+    /// The peripheral must be set up to issue DMA requests separately
+    pub fn start(dma: &'a Dma<'a, U>, address: u32, buffer: &'static mut [W]) -> Self {
+        assert!(!dma.is_enabled());
+
+        // Peripheral to memory, circular, MINC, PSIZE = MSIZE = W. The
+        // channel, priority and interrupt enables are left alone
+        dma.reg.scr(dma.stream()).modify(|r, w| unsafe {
+            let bits = r.bits() & !((0b11 << 6) | (0b11111 << 8) | (0b11 << 13) | (1 << 5));
+            w.bits(bits | (1 << 8) | (1 << 10) | (W::SIZE << 11) | (W::SIZE << 13))
+        });
+
+        dma.clear_flags();
+        dma.set_config(address, buffer.as_ptr() as u32, u16(buffer.len()).unwrap());
+        dma.enable();
+
+        CircularSampler { dma: dma, buffer: buffer, read: 0 }
+    }
+
+    /// Passes the data received since the last call to `f`, oldest first,
+    /// as two slices (the second one is non empty when the data wraps
+    /// around the end of the buffer)
+    ///
+    /// Call this at least once per half buffer, a writer that laps the
+    /// reader more than once between calls can't be detected.
+    pub fn read_available<F, R>(&mut self, f: F) -> Result<R, Error>
+        where F: FnOnce(&[W], &[W]) -> R
+    {
+        let len = self.buffer.len();
+
+        if self.dma.has_transfer_error() {
+            self.dma.disable();
+            return Err(Error::Transfer);
+        }
+
+        // NOTE(retry) a wrap between reading the flag and NDTR would make
+        // them disagree
+        let (wrapped, ndtr) = loop {
+            let before = self.dma.is_transfer_complete();
+            let ndtr = self.dma.reg.sndtr(self.dma.stream()).read().bits() as usize;
+            if self.dma.is_transfer_complete() == before {
+                break (before, ndtr);
+            }
+        };
+        if wrapped {
+            self.dma.clear_transfer_complete();
+        }
+        let write = (len - ndtr) % len;
+
+        // The DMA wrote the buffer behind the compiler's back
+        atomic::compiler_fence(Ordering::SeqCst);
+
+        let read = self.read;
+        if wrapped && write >= read {
+            // The writer went around and caught up with the reader
+            self.read = write;
+            return Err(Error::Overrun);
+        }
+
+        self.read = write;
+        let buffer = &*self.buffer;
+        Ok(if write >= read && !wrapped {
+            f(&buffer[read..write], &[])
+        } else {
+            f(&buffer[read..], &buffer[..write])
+        })
+    }
+
+    /// Stops the stream and gives the buffer back
+    pub fn stop(self) -> &'static mut [W] {
+        self.dma.disable();
+        while self.dma.is_enabled() {}
+        self.dma.clear_flags();
+        self.buffer
+    }
+}
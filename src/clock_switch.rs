@@ -0,0 +1,103 @@
+//! Switching the clock tree at run time
+//!
+//! Battery powered applications run fast while busy and slow while idle.
+//! The configurations are validated once, up front, so a switch can't fail
+//! halfway:
+//!
+//! ``` ignore
+//! let active = Cfgr::preset_100mhz_hse25().validate()?;
+//! let idle = Cfgr::preset_low_power_16mhz().validate()?;
+//! let clocks = Cfgr::preset_100mhz_hse25().freeze(rcc, flash, pwr);
+//!
+//! // nothing to do for a while
+//! let clocks = clock_switch::switch(&idle, rcc, flash, pwr, syst, &[&serial, &spi, &pwm]);
+//! ```
+//!
+//! `switch` reprograms the flash wait states and the regulator in the safe
+//! order, updates `Clocks::get` and then tells every `ClockListener` so it
+//! can recompute its prescalers: `Serial` keeps its baud rate, `Spi` its SCK
+//! frequency (or the closest lower one), `Pwm` its PWM frequency, `Timer`
+//! its timeout period and `MicroTimer` its 1 MHz count. The busy-wait
+//! delays (`delay::CyclesToTime`, and the `soft_uart`, `soft_i2c`,
+//! `onewire` and `usb_host` drivers built on it) follow the new sysclk.
+//! Drivers left out of `listeners` keep the old settings.
+//!
+//! The `ahb`, `apb1`, `apb2` and `sysclk` `Ticks` are constants for the
+//! 16 MHz reset clock; they're already wrong once `freeze` changes the
+//! clocks, and stay wrong after a switch. Convert time with the `Clocks`
+//! returned by `switch` (`time::Hertz`) instead.
+//!
+//! A running SysTick keeps its tick period: the reload value is scaled to
+//! the new core clock, and the tick in progress restarts, so a monotonic
+//! count built on the SysTick interrupt stays within one tick. The
+//! `delay::delay_us` / `delay_ms` helpers assume the reset clock and are
+//! not adjusted.
+//!
+//! The switch stalls the system for the PLL lock time, ~100 µs, and
+//! peripherals see their clock change under them: switch between frames,
+//! not in the middle of a transfer. The HSE keeps running when a profile
+//! doesn't use it, and USB stops working without the PLL.
+
+use cortex_m::interrupt;
+use stm32f411::{FLASH, PWR, RCC, SYST};
+
+use calibration;
+use rcc::{ClockProfile, Clocks};
+
+/// Driver whose settings derive from the clocks
+pub trait ClockListener {
+    /// Recomputes the settings for the `new` clocks, `old` are the ones
+    /// they were computed for
+    fn clocks_changed(&self, old: &Clocks, new: &Clocks);
+}
+
+/// Applies `profile` and notifies `listeners`
+///
+/// Call it from thread mode: interrupt handlers that read `Clocks::get`
+/// must not run in the middle of the update. Returns the new clocks.
+pub fn switch(
+    profile: &ClockProfile,
+    rcc: &RCC,
+    flash: &FLASH,
+    pwr: &PWR,
+    syst: &SYST,
+    listeners: &[&ClockListener],
+) -> Clocks {
+    // NOTE without `freeze` an HSE frequency is unknown, and reads as 0
+    let old = match Clocks::get() {
+        Some(clocks) => clocks,
+        None => Clocks::read(rcc, flash, None),
+    };
+
+    let new = interrupt::free(|_| {
+        let new = profile.apply(rcc, flash, pwr);
+        new.republish();
+        rescale_systick(syst, &old, &new);
+        new
+    });
+
+    // The measured ratio belonged to the previous source
+    calibration::clear();
+
+    for listener in listeners {
+        listener.clocks_changed(&old, &new);
+    }
+    new
+}
+
+/// Keeps the SysTick period across a core clock change
+fn rescale_systick(syst: &SYST, old: &Clocks, new: &Clocks) {
+    // ENABLE
+    if syst.csr.read() & 1 == 0 || old.hclk().0 == 0 {
+        return;
+    }
+
+    // The SysTick clock is HCLK or HCLK / 8, the ratio is the same
+    let reload = u64::from(syst.rvr.read() & 0x00FF_FFFF) + 1;
+    let reload = reload * u64::from(new.hclk().0) / u64::from(old.hclk().0);
+    let reload = if reload > 0x0100_0000 { 0x0100_0000 } else { reload };
+    let reload = if reload < 2 { 2 } else { reload };
+
+    syst.set_reload(reload as u32 - 1);
+    syst.clear_current();
+}
@@ -0,0 +1,148 @@
+//! Comparator emulation with the ADC analog watchdog
+//!
+//! The F411 has no analog comparator. `SoftComparator` gets close: ADC1
+//! converts one channel continuously and the analog watchdog raises the
+//! `ADC` interrupt when the input leaves a window, without any CPU work in
+//! between. The window is moved after every crossing to implement the
+//! hysteresis, so a noisy input doesn't interrupt at every conversion.
+//!
+//! ``` ignore
+//! let pa1 = PA1::into_analog(gpioa);
+//! adc.init(rcc, adc_common);
+//! let cmp = SoftComparator::start(adc, &pa1, 2048, 100);
+//! cmp.listen_nvic(nvic, 3);
+//!
+//! // ADC task
+//! cmp.handle(|| motor.stop(), || motor.start());
+//! ```
+//!
+//! The response time is a few conversions, ~10 us with the 84 cycles
+//! sampling time `Adc::init` sets. The ADC can't be used for anything else
+//! while the comparator runs.
+
+use core::cell::Cell;
+
+use stm32f411::ADC1;
+
+use adc2::{Adc, AdcChannel};
+
+// CR1
+const AWDIE: u32 = 1 << 6;
+const AWDSGL: u32 = 1 << 9;
+const AWDEN: u32 = 1 << 23;
+
+// CR2
+const CONT: u32 = 1 << 1;
+const SWSTART: u32 = 1 << 30;
+
+// SR
+const AWD: u32 = 1 << 0;
+
+/// Full scale of a 12-bit conversion
+const MAX: u16 = 0xFFF;
+
+/// Threshold crossing
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Crossing {
+    /// The input rose above `threshold + hysteresis`
+    Above,
+    /// The input fell below `threshold - hysteresis`
+    Below,
+}
+
+/// ADC1 watchdog acting as a comparator with hysteresis
+pub struct SoftComparator<'a> {
+    adc: &'a ADC1,
+    threshold: u16,
+    hysteresis: u16,
+    above: Cell<bool>,
+}
+
+impl<'a> SoftComparator<'a> {
+    /// Starts converting `pin` and watching it against `threshold`
+    ///
+    /// The output switches when the input goes `hysteresis` counts past the
+    /// threshold. The initial state comes from a first conversion. `adc`
+    /// must be initialized.
+    pub fn start<P>(adc: Adc<'a>, pin: &P, threshold: u16, hysteresis: u16) -> Self
+        where P: AdcChannel
+    {
+        assert!(threshold <= MAX);
+
+        let initial = adc.read(pin);
+        let adc = adc.0;
+
+        let comparator = SoftComparator {
+            adc: adc,
+            threshold: threshold,
+            hysteresis: hysteresis,
+            above: Cell::new(initial > threshold),
+        };
+        comparator.arm();
+
+        // Single channel watchdog on the converted channel
+        adc.cr1.modify(|r, w| unsafe {
+            w.bits((r.bits() & !0x1F) | u32::from(P::CHANNEL) | AWDSGL | AWDEN | AWDIE)
+        });
+        adc.cr2.modify(|r, w| unsafe { w.bits(r.bits() | CONT) });
+        adc.cr2.modify(|r, w| unsafe { w.bits(r.bits() | SWSTART) });
+
+        comparator
+    }
+
+    /// Comparator output: whether the input is above the threshold
+    pub fn is_above(&self) -> bool {
+        self.above.get()
+    }
+
+    /// Handles the `ADC` interrupt, returns the crossing that raised it
+    ///
+    /// `None` for other ADC events.
+    pub fn on_interrupt(&self) -> Option<Crossing> {
+        if self.adc.sr.read().bits() & AWD == 0 {
+            return None;
+        }
+
+        let above = !self.above.get();
+        self.above.set(above);
+        self.arm();
+        // NOTE the SR flags are cleared by writing 0
+        self.adc.sr.write(|w| unsafe { w.bits(!AWD) });
+
+        Some(if above { Crossing::Above } else { Crossing::Below })
+    }
+
+    /// Like `on_interrupt`, calling `above` or `below` on a crossing
+    pub fn handle<A, B>(&self, above: A, below: B)
+        where A: FnOnce(),
+              B: FnOnce()
+    {
+        match self.on_interrupt() {
+            Some(Crossing::Above) => above(),
+            Some(Crossing::Below) => below(),
+            None => {}
+        }
+    }
+
+    /// Stops the conversions and the watchdog, giving the ADC back
+    pub fn stop(self) -> Adc<'a> {
+        let adc = self.adc;
+        adc.cr1.modify(|r, w| unsafe { w.bits(r.bits() & !(AWDSGL | AWDEN | AWDIE)) });
+        adc.cr2.modify(|r, w| unsafe { w.bits(r.bits() & !CONT) });
+        Adc(adc)
+    }
+
+    /// Programs the watchdog window for the current state: only the
+    /// crossing in the opposite direction leaves it
+    fn arm(&self) {
+        let (low, high) = if self.above.get() {
+            (self.threshold.saturating_sub(self.hysteresis), MAX)
+        } else {
+            (0, self.threshold.saturating_add(self.hysteresis).min(MAX))
+        };
+        unsafe {
+            self.adc.ltr.write(|w| w.bits(u32::from(low)));
+            self.adc.htr.write(|w| w.bits(u32::from(high)));
+        }
+    }
+}
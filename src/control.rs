@@ -0,0 +1,146 @@
+//! Fixed-point PID controller
+//!
+//! Signals are Q15 (`i16`, 32768 = 1.0): scale the ADC readings and the PWM
+//! duty cycles to that range. Gains are Q15 too, but stored in `i32` so
+//! they can go above 1.0. The integrator keeps Q31 precision, small errors
+//! still add up with a small `ki`. One `update` is a handful of 64-bit
+//! multiplies, cheap enough for the ADC / timer interrupt that samples the
+//! loop:
+//!
+//! ``` ignore
+//! // Duty cycles aren't negative
+//! let mut pid = Pid::new(q15(1, 2), q15(1, 100), 0).output_limits(0, 32767);
+//!
+//! // ADC interrupt, every sample period (12-bit reading to Q15)
+//! let current = (adc.read(&pa1) as i16) << 3;
+//! let duty = pid.update(setpoint, current);
+//! pwm.set_duty_fraction(Channel::_1, duty as u16, 32768);
+//! ```
+//!
+//! The gains are per sample: `ki` is the continuous gain times the sample
+//! period, `kd` the continuous gain divided by it.
+//!
+//! - Anti-windup: the integrator is clamped to the output range and stops
+//!   integrating while the output saturates in the direction of the error.
+//! - The derivative acts on the measurement, not the error, so setpoint
+//!   steps don't kick the output, and goes through a first order low-pass
+//!   filter (`derivative_filter`).
+
+/// 1.0 in Q15, as a gain
+pub const ONE: i32 = 1 << 15;
+
+/// Q15 value of `num / den`, e.g. a gain: `q15(3, 2)` is 1.5
+pub const fn q15(num: i32, den: i32) -> i32 {
+    num * ONE / den
+}
+
+/// PID controller with Q15 signals
+#[derive(Clone, Copy, Debug)]
+pub struct Pid {
+    kp: i32,
+    ki: i32,
+    kd: i32,
+    filter: i32,
+    out_min: i16,
+    out_max: i16,
+    /// Q31 scaled output units
+    integral: i32,
+    /// Filtered derivative term, Q15
+    derivative: i32,
+    measurement: Option<i16>,
+}
+
+impl Pid {
+    /// Controller with Q15 gains (`ONE` is 1.0), full output range and no
+    /// derivative filtering
+    pub const fn new(kp: i32, ki: i32, kd: i32) -> Self {
+        Pid {
+            kp: kp,
+            ki: ki,
+            kd: kd,
+            filter: ONE,
+            out_min: -32768,
+            out_max: 32767,
+            integral: 0,
+            derivative: 0,
+            measurement: None,
+        }
+    }
+
+    /// Limits the output, e.g. to `0 .. 32767` for a duty cycle
+    pub fn output_limits(mut self, min: i16, max: i16) -> Self {
+        assert!(min < max);
+        self.out_min = min;
+        self.out_max = max;
+        self
+    }
+
+    /// Low-pass filters the derivative term
+    ///
+    /// `alpha` (Q15) is the weight of the new sample: `ONE` disables the
+    /// filter, smaller values filter more. The cut-off is roughly
+    /// `alpha / (2 pi)` times the sample rate.
+    pub fn derivative_filter(mut self, alpha: i32) -> Self {
+        assert!(alpha > 0 && alpha <= ONE);
+        self.filter = alpha;
+        self
+    }
+
+    /// Changes the gains, keeping the state
+    pub fn set_gains(&mut self, kp: i32, ki: i32, kd: i32) {
+        self.kp = kp;
+        self.ki = ki;
+        self.kd = kd;
+    }
+
+    /// Clears the integrator and the derivative history
+    pub fn reset(&mut self) {
+        self.integral = 0;
+        self.derivative = 0;
+        self.measurement = None;
+    }
+
+    /// Runs one sample period, returns the new output
+    pub fn update(&mut self, setpoint: i16, measurement: i16) -> i16 {
+        let error = i64::from(setpoint) - i64::from(measurement);
+        let min = i64::from(self.out_min);
+        let max = i64::from(self.out_max);
+
+        let p = (i64::from(self.kp) * error) >> 15;
+
+        // No derivative on the first sample, there's no history
+        let previous = self.measurement.unwrap_or(measurement);
+        self.measurement = Some(measurement);
+        let raw = -((i64::from(self.kd) * (i64::from(measurement) - i64::from(previous))) >> 15);
+        let derivative = i64::from(self.derivative);
+        let derivative = derivative + (((raw - derivative) * i64::from(self.filter)) >> 15);
+        self.derivative = clamp(derivative, -max_i32(), max_i32()) as i32;
+        let d = i64::from(self.derivative);
+
+        // Q15 x Q15 = Q30, to Q31
+        let integral = i64::from(self.integral) + ((i64::from(self.ki) * error) << 1);
+        let integral = clamp(integral, min << 16, (max << 16) | 0xFFFF);
+
+        let output = p + (integral >> 16) + d;
+        let winding_up = (output > max && error > 0) || (output < min && error < 0);
+        if !winding_up {
+            self.integral = integral as i32;
+        }
+
+        clamp(p + (i64::from(self.integral) >> 16) + d, min, max) as i16
+    }
+}
+
+fn clamp(x: i64, min: i64, max: i64) -> i64 {
+    if x < min {
+        min
+    } else if x > max {
+        max
+    } else {
+        x
+    }
+}
+
+fn max_i32() -> i64 {
+    i64::from(i32::max_value())
+}
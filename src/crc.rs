@@ -0,0 +1,51 @@
+//! CRC calculation unit
+//!
+//! Fixed CRC-32 (Ethernet polynomial `0x04C1_1DB7`), initial value
+//! `0xFFFF_FFFF`, no reflection and no final XOR. The unit consumes 32-bit
+//! words MSB first, so over a little endian byte stream the result matches
+//! CRC-32/MPEG-2 computed on each word byte-swapped.
+//!
+//! ``` ignore
+//! let crc = Crc(crc);
+//! crc.init(rcc);
+//! crc.reset();
+//! crc.feed(&words);
+//! let checksum = crc.result();
+//! ```
+
+use stm32f411::{CRC, RCC};
+
+/// CRC calculation unit
+pub struct Crc<'a>(pub &'a CRC);
+
+impl<'a> Crc<'a> {
+    /// Enables the CRC clock
+    pub fn init(&self, rcc: &RCC) {
+        rcc.ahb1enr.modify(|_, w| w.crcen().set_bit());
+    }
+
+    /// Restarts the calculation from `0xFFFF_FFFF`
+    pub fn reset(&self) {
+        // RESET
+        self.0.cr.write(|w| unsafe { w.bits(1) });
+    }
+
+    /// Feeds `words` to the calculation
+    pub fn feed(&self, words: &[u32]) {
+        for word in words {
+            self.0.dr.write(|w| unsafe { w.bits(*word) });
+        }
+    }
+
+    /// CRC of the words fed since the last `reset`
+    pub fn result(&self) -> u32 {
+        self.0.dr.read().bits()
+    }
+
+    /// CRC of `words` alone, resets the unit first
+    pub fn checksum(&self, words: &[u32]) -> u32 {
+        self.reset();
+        self.feed(words);
+        self.result()
+    }
+}
@@ -0,0 +1,143 @@
+//! Register dumps for post-mortem debugging
+//!
+//! Formats the state of the peripherals a hung transfer usually involves
+//! into any `fmt::Write` sink (ITM, a `Serial` that still works, a RAM
+//! buffer printed later), e.g. from a watchdog or timeout handler:
+//!
+//! ``` ignore
+//! debug::dump(&mut out, rcc, flash)?;
+//! debug::dma(&mut out, dma2, DMAStream::Stream4)?;
+//! debug::spi(&mut out, "SPI1", spi1)?;
+//! ```
+//!
+//! Please attach the output to bug reports against the drivers.
+//!
+//! `Itm` makes an ITM stimulus port such a sink: `&mut Itm(&itm.stim[0])`.
+//!
+//! Only side-effect free registers are read: the status flags that are
+//! cleared by a read sequence (USART, SPI) need the data register to be
+//! read too, which these functions never do.
+
+use core::fmt::{self, Write};
+
+use cortex_m::itm;
+use cortex_m::peripheral::Stim;
+use stm32f411::{FLASH, RCC, i2s2ext, usart1};
+
+#[cfg(feature = "dma")]
+use dma2::{DMA, DMAStream};
+use rcc::Clocks;
+
+/// `fmt::Write` sink on an ITM stimulus port
+///
+/// Blocks while the port FIFO is full; with no debugger attached the ITM
+/// is disabled and the output is dropped.
+pub struct Itm<'a>(pub &'a Stim);
+
+impl<'a> Write for Itm<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        itm::write_str(self.0, s);
+        Ok(())
+    }
+}
+
+/// Writes the clock tree: raw RCC / FLASH registers and the frozen
+/// `Clocks`, if any
+pub fn dump<W>(w: &mut W, rcc: &RCC, flash: &FLASH) -> fmt::Result
+    where W: Write
+{
+    writeln!(
+        w,
+        "RCC CR={:#010x} PLLCFGR={:#010x} CFGR={:#010x} FLASH ACR={:#010x}",
+        rcc.cr.read().bits(),
+        rcc.pllcfgr.read().bits(),
+        rcc.cfgr.read().bits(),
+        flash.acr.read().bits()
+    )?;
+    writeln!(
+        w,
+        "RCC AHB1ENR={:#010x} APB1ENR={:#010x} APB2ENR={:#010x}",
+        rcc.ahb1enr.read().bits(),
+        rcc.apb1enr.read().bits(),
+        rcc.apb2enr.read().bits()
+    )?;
+
+    match Clocks::get() {
+        Some(clocks) => writeln!(
+            w,
+            "clocks sysclk={} hclk={} pclk1={} pclk2={} latency={}",
+            clocks.sysclk().0,
+            clocks.hclk().0,
+            clocks.pclk1().0,
+            clocks.pclk2().0,
+            clocks.flash_latency()
+        ),
+        None => writeln!(w, "clocks not frozen"),
+    }
+}
+
+/// Writes the configuration, counter and event flags of a DMA stream
+#[cfg(feature = "dma")]
+pub fn dma<W, D>(w: &mut W, dma: &D, stream: DMAStream) -> fmt::Result
+    where W: Write,
+          D: DMA
+{
+    let isr = if stream.is_high() {
+        dma.hisr.read().bits()
+    } else {
+        dma.lisr.read().bits()
+    };
+    let flags = (isr >> stream.flag_offset()) & 0x3F;
+
+    writeln!(
+        w,
+        "DMA S{} CR={:#010x} NDTR={} PAR={:#010x} M0AR={:#010x} FCR={:#04x} ISR={:#08b}",
+        stream as u8,
+        dma.scr(stream).read().bits(),
+        dma.sndtr(stream).read().bits(),
+        dma.spar(stream).read().bits(),
+        dma.sm0ar(stream).read().bits(),
+        dma.sfcr(stream).read().bits(),
+        flags
+    )?;
+    // TCIF, HTIF, TEIF, DMEIF, FEIF
+    writeln!(
+        w,
+        "    TC={} HT={} TE={} DME={} FE={}",
+        (flags >> 5) & 1,
+        (flags >> 4) & 1,
+        (flags >> 3) & 1,
+        (flags >> 2) & 1,
+        flags & 1
+    )
+}
+
+/// Writes the status and configuration of a USART; `name` labels the line
+pub fn usart<W>(w: &mut W, name: &str, usart: &usart1::RegisterBlock) -> fmt::Result
+    where W: Write
+{
+    writeln!(
+        w,
+        "{} SR={:#06x} BRR={:#06x} CR1={:#06x} CR2={:#06x} CR3={:#06x}",
+        name,
+        usart.sr.read().bits(),
+        usart.brr.read().bits(),
+        usart.cr1.read().bits(),
+        usart.cr2.read().bits(),
+        usart.cr3.read().bits()
+    )
+}
+
+/// Writes the status and configuration of a SPI; `name` labels the line
+pub fn spi<W>(w: &mut W, name: &str, spi: &i2s2ext::RegisterBlock) -> fmt::Result
+    where W: Write
+{
+    writeln!(
+        w,
+        "{} SR={:#06x} CR1={:#06x} CR2={:#06x}",
+        name,
+        spi.sr.read().bits(),
+        spi.cr1.read().bits(),
+        spi.cr2.read().bits()
+    )
+}
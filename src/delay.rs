@@ -1,6 +1,17 @@
+use core::cell::Cell;
+
 use stm32f411::SYST;
 use cortex_m::peripheral::SystClkSource;
 
+use calibration;
+use clock_switch::ClockListener;
+use rcc::Clocks;
+use time::Microseconds;
+
+/// Core cycles taken by one iteration of the `asm_delay` loop when the
+/// instruction fetch doesn't stall
+const LOOP_CYCLES: u32 = 3;
+
 
 pub fn delay_us(syst: &SYST, delay: ::time::Microseconds) {
     setup_counter(syst, delay);
@@ -27,4 +38,108 @@ fn setup_counter<T: Into<::sysclk::Ticks>>(syst: &SYST, ticks: T)
 
 pub fn init_systick(syst: &SYST) {
     syst.set_clock_source(SystClkSource::Core);
-}
\ No newline at end of file
+}
+
+/// Busy-waits for roughly `cycles` core cycles
+///
+/// Doesn't use SysTick or DWT. The loop is calibrated for zero wait state
+/// execution, use `CyclesToTime` to account for the actual flash settings.
+pub fn asm_delay(cycles: u32) {
+    let mut n = cycles / LOOP_CYCLES;
+    if n == 0 {
+        return;
+    }
+
+    unsafe {
+        asm!("1:
+              subs $0, $0, #1
+              bne 1b"
+             : "+r"(n)
+             :
+             : "cc"
+             : "volatile");
+    }
+}
+
+/// Converts time into `asm_delay` cycles for a given clock configuration
+///
+/// With the instruction cache off every taken branch refetches from flash,
+/// so each loop iteration pays the flash wait states on top of the pipeline
+/// refill; the prefetch buffer doesn't help across branches. With the cache
+/// on the loop runs from the cache after the first iteration.
+///
+/// Delays follow the sysclk measured by `calibration`, if any, and the
+/// clocks of a `clock_switch::switch` that lists it (or its owner).
+#[derive(Clone, Debug)]
+pub struct CyclesToTime {
+    sysclk: Cell<u32>,
+    loop_cycles: Cell<u32>,
+}
+
+impl CyclesToTime {
+    pub fn new(clocks: &Clocks) -> Self {
+        let delay = CyclesToTime {
+            sysclk: Cell::new(0),
+            loop_cycles: Cell::new(LOOP_CYCLES),
+        };
+        delay.set_clocks(clocks);
+        delay
+    }
+
+    fn set_clocks(&self, clocks: &Clocks) {
+        let stall = if clocks.flash_icache() {
+            0
+        } else {
+            clocks.flash_latency() as u32
+        };
+
+        self.sysclk.set(clocks.sysclk().0);
+        self.loop_cycles.set(LOOP_CYCLES + stall);
+    }
+
+    /// `asm_delay` argument that busy-waits for `ns` nanoseconds
+    pub fn ns(&self, ns: u32) -> u32 {
+        self.scale(ns as u64 * self.sysclk() as u64 / 1_000_000_000)
+    }
+
+    /// `asm_delay` argument that busy-waits for `us`
+    pub fn us(&self, us: Microseconds) -> u32 {
+        self.scale(us.0 as u64 * self.sysclk() as u64 / 1_000_000)
+    }
+
+    /// Busy-waits for `ns` nanoseconds
+    ///
+    /// Short delays are rounded up to one loop iteration
+    pub fn delay_ns(&self, ns: u32) {
+        asm_delay(self.ns(ns))
+    }
+
+    /// Busy-waits for `us`
+    pub fn delay_us(&self, us: Microseconds) {
+        asm_delay(self.us(us))
+    }
+
+    /// Actual sysclk, as last measured by `calibration::Calibrator`
+    fn sysclk(&self) -> u32 {
+        calibration::correct(self.sysclk.get())
+    }
+
+    /// Shrinks real cycles into ideal loop cycles, rounding up
+    fn scale(&self, cycles: u64) -> u32 {
+        let loop_cycles = self.loop_cycles.get() as u64;
+        let iterations = (cycles + loop_cycles - 1) / loop_cycles;
+        let cycles = iterations.max(1) * LOOP_CYCLES as u64;
+        if cycles > u32::max_value() as u64 {
+            u32::max_value()
+        } else {
+            cycles as u32
+        }
+    }
+}
+
+impl ClockListener for CyclesToTime {
+    /// Follows the new sysclk and flash wait states
+    fn clocks_changed(&self, _old: &Clocks, new: &Clocks) {
+        self.set_clocks(new);
+    }
+}
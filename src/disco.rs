@@ -0,0 +1,126 @@
+//! 32F411EDISCOVERY board
+//!
+//! Pin assignments of the on-board parts. Pin numbers are relative to the
+//! port named in each item; the port clocks must be enabled by the caller.
+//!
+//! NOTE The F411 has no GPIOF / GPIOG, unlike the F429 Discovery the motion
+//! sensors are on SPI1 and I2C1.
+
+use core::ops::Deref;
+
+use stm32f411::gpioa;
+
+use gpio::{Input, Io, Mode, N3, Output, Pin, Pupd, Speed, TypedPin};
+
+/// User LEDs, on GPIOD, active high
+pub mod led {
+    /// LD4
+    pub const GREEN: u8 = 12;
+    /// LD3
+    pub const ORANGE: u8 = 13;
+    /// LD5
+    pub const RED: u8 = 14;
+    /// LD6
+    pub const BLUE: u8 = 15;
+}
+
+/// User button B1 on PA0, active high with an external pull down
+pub const USER_BUTTON: u8 = 0;
+
+/// L3GD20 gyroscope (I3G4250D on rev. D boards) on SPI1
+pub mod gyro {
+    use gpio::AF5;
+
+    /// SPI1 alternate function
+    pub const AF: AF5 = AF5;
+    /// SCK, on GPIOA
+    pub const SCK: u8 = 5;
+    /// MISO, on GPIOA
+    pub const MISO: u8 = 6;
+    /// MOSI, on GPIOA
+    pub const MOSI: u8 = 7;
+    /// Chip select, on GPIOE, active low
+    pub const CS: u8 = 3;
+    /// INT1, on GPIOE
+    pub const INT1: u8 = 0;
+    /// INT2 / data ready, on GPIOE
+    pub const INT2: u8 = 1;
+}
+
+/// LSM303DLHC accelerometer / magnetometer (LSM303AGR on rev. D boards) on
+/// I2C1
+pub mod compass {
+    use gpio::AF4;
+
+    /// I2C1 alternate function
+    pub const AF: AF4 = AF4;
+    /// SCL, on GPIOB
+    pub const SCL: u8 = 6;
+    /// SDA, on GPIOB
+    pub const SDA: u8 = 9;
+    /// Magnetometer data ready, on GPIOE
+    pub const DRDY: u8 = 2;
+    /// Accelerometer INT1, on GPIOE
+    pub const INT1: u8 = 4;
+    /// Accelerometer INT2, on GPIOE
+    pub const INT2: u8 = 5;
+}
+
+/// Configures the four user LEDs as outputs, turned off
+pub fn init_leds<D>(gpiod: &D)
+    where D: Deref<Target = gpioa::RegisterBlock>
+{
+    for pin in [led::GREEN, led::ORANGE, led::RED, led::BLUE].iter() {
+        let pin = Pin::new(*pin);
+        pin.set(gpiod, Io::Low);
+        pin.set_mode(gpiod, Mode::Output);
+    }
+}
+
+/// Configures the user button as input
+pub fn init_user_button<A>(gpioa: &A)
+    where A: Deref<Target = gpioa::RegisterBlock>
+{
+    let pin = Pin::new(USER_BUTTON);
+    pin.set_pupd(gpioa, Pupd::No);
+    pin.set_mode(gpioa, Mode::Input);
+}
+
+/// Routes SPI1 to the gyroscope and deselects it
+///
+/// Returns the chip select pin. SPI mode 3, up to 10 MHz
+pub fn init_gyro<A, E>(gpioa: &A, gpioe: &E) -> TypedPin<E, N3, Output>
+    where A: Deref<Target = gpioa::RegisterBlock>,
+          E: Deref<Target = gpioa::RegisterBlock>
+{
+    for pin in [gyro::SCK, gyro::MISO, gyro::MOSI].iter() {
+        Pin::new(*pin).set_alternate(gpioa, gyro::AF, Speed::High);
+    }
+
+    Pin::new(gyro::CS).set(gpioe, Io::High);
+    let cs = TypedPin::<E, N3, Input>::new().into_output(gpioe);
+
+    for pin in [gyro::INT1, gyro::INT2].iter() {
+        Pin::new(*pin).set_mode(gpioe, Mode::Input);
+    }
+
+    cs
+}
+
+/// Routes I2C1 to the accelerometer / magnetometer
+///
+/// The bus has external pull ups
+pub fn init_compass<B, E>(gpiob: &B, gpioe: &E)
+    where B: Deref<Target = gpioa::RegisterBlock>,
+          E: Deref<Target = gpioa::RegisterBlock>
+{
+    for pin in [compass::SCL, compass::SDA].iter() {
+        let pin = Pin::new(*pin);
+        pin.set_open_drain(gpiob, true);
+        pin.set_alternate(gpiob, compass::AF, Speed::Medium);
+    }
+
+    for pin in [compass::DRDY, compass::INT1, compass::INT2].iter() {
+        Pin::new(*pin).set_mode(gpioe, Mode::Input);
+    }
+}
@@ -1,13 +1,64 @@
-//! Direct Memroy Access (DMA)
+//! Direct Memory Access (DMA)
+//!
+//! Two ways of handing memory to a stream share the register access of
+//! `Dma`:
+//!
+//! - `Buffer`, a `static` cell that is locked while the DMA uses it and
+//!   `release`d afterwards. Convenient with RTFM resources.
+//! - `Transfer`, which takes ownership of a `&'static mut` buffer and gives
+//!   it back from `wait`, so the buffer can't be touched during the
+//!   transfer at all.
+//! - `PoolTransfer`, the same with a block of a `pool::Pool`, which goes
+//!   back to the pool once the transfer and its buffer are done with.
+//! - `Chain`, which sends a list of byte slices back-to-back (e.g. a
+//!   protocol header and a payload) without copying them into one buffer.
+//!   The stream is re-armed with the next slice from its transfer complete
+//!   interrupt.
+//!
+//! `Buffer` suits long lived buffers in interrupt driven applications: the
+//! buffer is an RTFM resource, the task that starts the transfer locks it
+//! and the DMA interrupt releases it.
+//!
+//! ``` ignore
+//! app! {
+//!     resources: {
+//!         static BUFFER: Buffer<[u8; 64]> = Buffer::new([0; 64], DMAStream::Stream4);
+//!     },
+//!     ..
+//! }
+//!
+//! // fill the buffer and start the transfer
+//! r.BUFFER.borrow_mut().copy_from_slice(&frame);
+//! spi.send_dma(r.BUFFER)?;
+//!
+//! // DMA2_STREAM4 task (TCIE / TEIE enabled)
+//! match r.BUFFER.release(&**r.DMA2) {
+//!     Ok(()) => { /* BUFFER can be borrowed again */ }
+//!     Err(nb::Error::Other(e)) => { /* transfer failed, BUFFER unlocked */ }
+//!     Err(nb::Error::WouldBlock) => { /* some other event of the stream */ }
+//! }
+//! ```
+//!
+//! With `memory_burst` / `peripheral_burst` the buffer has to be aligned to
+//! the burst size and hold a whole number of bursts, and the burst has to
+//! fit the FIFO threshold; otherwise the stream moves the wrong data without
+//! raising any flag. `transfer` and `start_chain` fail with
+//! `Error::Config` instead, see `Dma::check_config`, and `dma_buffer!`
+//! checks the length at compile time.
 
 use core::cell::{Cell, UnsafeCell};
-use core::marker::PhantomData;
+use core::fmt;
+use core::marker::{PhantomData, Unsize};
 use core::ops::Deref;
 use core::ops;
 use core::any::Any;
+use core::sync::atomic::{self, ATOMIC_BOOL_INIT, AtomicBool, Ordering};
 
 use nb;
-use stm32f411::{DMA1, DMA2, dma2};
+use stm32f411::{DMA1, DMA2, RCC, dma2};
+
+use fault::{self, Fault};
+use pool::{PoolBuffer, PoolTransfer};
 
 pub use stm32f411::dma2::scr::CHSELW as Channel;
 pub use stm32f411::dma2::scr::DIRW as Direction;
@@ -20,20 +71,64 @@ pub struct DMA1Stream0();
 pub struct DMA2Stream1();
 pub struct DMA2Stream4();
 
-#[derive(Copy, Clone)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum DMAStream {
     Stream0,
     Stream1,
     Stream2,
     Stream3,
     Stream4,
-    // Stream5,
-    // Stream6,
-    // Stream7,
+    Stream5,
+    Stream6,
+    Stream7,
+}
+
+impl DMAStream {
+    /// Whether the stream's flags live in HISR / HIFCR
+    pub(crate) fn is_high(self) -> bool {
+        match self {
+            DMAStream::Stream0 | DMAStream::Stream1 |
+            DMAStream::Stream2 | DMAStream::Stream3 => false,
+            DMAStream::Stream4 | DMAStream::Stream5 |
+            DMAStream::Stream6 | DMAStream::Stream7 => true,
+        }
+    }
+
+    /// Position of the stream's flags in the (L/H)ISR and (L/H)IFCR registers
+    pub(crate) fn flag_offset(self) -> u32 {
+        match self {
+            DMAStream::Stream0 | DMAStream::Stream4 => 0,
+            DMAStream::Stream1 | DMAStream::Stream5 => 6,
+            DMAStream::Stream2 | DMAStream::Stream6 => 16,
+            DMAStream::Stream3 | DMAStream::Stream7 => 22,
+        }
+    }
+}
+
+/// Stream interrupt event
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Event {
+    /// Transfer complete (TCIE)
+    TransferComplete,
+    /// Half of the items transferred (HTIE)
+    HalfTransfer,
+    /// Bus error, the stream is disabled (TEIE)
+    TransferError,
+}
+
+impl Event {
+    /// Enable bit in SxCR
+    fn bit(self) -> u32 {
+        match self {
+            Event::TransferComplete => 1 << 4,
+            Event::HalfTransfer => 1 << 3,
+            Event::TransferError => 1 << 2,
+        }
+    }
 }
 
 /// DMA error
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Error {
     /// DMA channel in use
     InUse,
@@ -42,6 +137,61 @@ pub enum Error {
     Overrun,
     /// Transfer error
     Transfer,
+    /// Driver misuse, with `FaultPolicy::Report`; see `fault`
+    Fault(Fault),
+    /// The buffer doesn't suit the data sizes, bursts or FIFO of the stream
+    Config(ConfigError),
+    #[doc(hidden)]
+    _Extensible,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::InUse => f.write_str("DMA stream in use"),
+            Error::Overrun => f.write_str("DMA overrun"),
+            Error::Transfer => f.write_str("DMA transfer error"),
+            Error::Fault(ref e) => e.fmt(f),
+            Error::Config(ref e) => e.fmt(f),
+            Error::_Extensible => f.write_str("unknown error"),
+        }
+    }
+}
+
+/// Stream configuration the hardware can't carry out, see
+/// `Dma::check_config`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConfigError {
+    /// An address isn't aligned to its data size, or to the burst size
+    /// when the address increments
+    Misaligned,
+    /// The number of items isn't a whole number of bursts, or of memory
+    /// data items
+    Length,
+    /// The memory burst doesn't divide the FIFO threshold
+    FifoThreshold,
+    /// Bursts need the FIFO, the stream is in direct mode
+    DirectMode,
+    #[doc(hidden)]
+    _Extensible,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ConfigError::Misaligned => "DMA address not aligned to the data / burst size",
+            ConfigError::Length => "DMA length not a multiple of the burst",
+            ConfigError::FifoThreshold => "DMA burst doesn't fit the FIFO threshold",
+            ConfigError::DirectMode => "DMA burst in direct mode",
+            ConfigError::_Extensible => "unknown error",
+        })
+    }
+}
+
+impl From<ConfigError> for Error {
+    fn from(e: ConfigError) -> Self {
+        Error::Config(e)
+    }
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -62,6 +212,12 @@ pub enum Mode {
 }
 
 pub unsafe trait DMA: Deref<Target = dma2::RegisterBlock> {
+    /// Enables or disables the controller clock
+    fn set_clock(&self, rcc: &RCC, enable: bool);
+
+    /// Set while the controller is split, see `DmaExt::split`
+    fn split_flag(&self) -> &'static AtomicBool;
+
     fn scr(&self, stream: DMAStream) -> &dma2::SCR {
         match stream {
             DMAStream::Stream0 => &self.s0cr,
@@ -69,6 +225,9 @@ pub unsafe trait DMA: Deref<Target = dma2::RegisterBlock> {
             DMAStream::Stream2 => &self.s2cr,
             DMAStream::Stream3 => &self.s3cr,
             DMAStream::Stream4 => &self.s4cr,
+            DMAStream::Stream5 => &self.s5cr,
+            DMAStream::Stream6 => &self.s6cr,
+            DMAStream::Stream7 => &self.s7cr,
         }
     }
 
@@ -79,6 +238,9 @@ pub unsafe trait DMA: Deref<Target = dma2::RegisterBlock> {
             DMAStream::Stream2 => &self.s2ndtr,
             DMAStream::Stream3 => &self.s3ndtr,
             DMAStream::Stream4 => &self.s4ndtr,
+            DMAStream::Stream5 => &self.s5ndtr,
+            DMAStream::Stream6 => &self.s6ndtr,
+            DMAStream::Stream7 => &self.s7ndtr,
         }
     }
 
@@ -89,6 +251,9 @@ pub unsafe trait DMA: Deref<Target = dma2::RegisterBlock> {
             DMAStream::Stream2 => &self.s2par,
             DMAStream::Stream3 => &self.s3par,
             DMAStream::Stream4 => &self.s4par,
+            DMAStream::Stream5 => &self.s5par,
+            DMAStream::Stream6 => &self.s6par,
+            DMAStream::Stream7 => &self.s7par,
         }
     }
 
@@ -99,6 +264,9 @@ pub unsafe trait DMA: Deref<Target = dma2::RegisterBlock> {
             DMAStream::Stream2 => &self.s2m0ar,
             DMAStream::Stream3 => &self.s3m0ar,
             DMAStream::Stream4 => &self.s4m0ar,
+            DMAStream::Stream5 => &self.s5m0ar,
+            DMAStream::Stream6 => &self.s6m0ar,
+            DMAStream::Stream7 => &self.s7m0ar,
         }
     }
 
@@ -109,6 +277,9 @@ pub unsafe trait DMA: Deref<Target = dma2::RegisterBlock> {
             DMAStream::Stream2 => &self.s2m1ar,
             DMAStream::Stream3 => &self.s3m1ar,
             DMAStream::Stream4 => &self.s4m1ar,
+            DMAStream::Stream5 => &self.s5m1ar,
+            DMAStream::Stream6 => &self.s6m1ar,
+            DMAStream::Stream7 => &self.s7m1ar,
         }
     }
 
@@ -119,19 +290,112 @@ pub unsafe trait DMA: Deref<Target = dma2::RegisterBlock> {
             DMAStream::Stream2 => &self.s2fcr,
             DMAStream::Stream3 => &self.s3fcr,
             DMAStream::Stream4 => &self.s4fcr,
+            DMAStream::Stream5 => &self.s5fcr,
+            DMAStream::Stream6 => &self.s6fcr,
+            DMAStream::Stream7 => &self.s7fcr,
         }
     }
 }
 
-unsafe impl DMA for DMA1 {}
+static DMA1_SPLIT: AtomicBool = ATOMIC_BOOL_INIT;
+static DMA2_SPLIT: AtomicBool = ATOMIC_BOOL_INIT;
+
+unsafe impl DMA for DMA1 {
+    fn set_clock(&self, rcc: &RCC, enable: bool) {
+        rcc.ahb1enr.modify(|_, w| w.dma1en().bit(enable));
+    }
+
+    fn split_flag(&self) -> &'static AtomicBool {
+        &DMA1_SPLIT
+    }
+}
+
+unsafe impl DMA for DMA2 {
+    fn set_clock(&self, rcc: &RCC, enable: bool) {
+        rcc.ahb1enr.modify(|_, w| w.dma2en().bit(enable));
+    }
+
+    fn split_flag(&self) -> &'static AtomicBool {
+        &DMA2_SPLIT
+    }
+}
+
+/// `DMA1` / `DMA2` extension trait
+pub trait DmaExt: Any + DMA + Sized {
+    /// Enables the controller clock and splits it into its streams
+    ///
+    /// `None` if the controller is already split: `join` the `Streams`
+    /// first.
+    fn split<'a>(&'a self, rcc: &RCC) -> Option<Streams<'a, Self>>;
+}
+
+impl<U> DmaExt for U
+    where U: Any + DMA
+{
+    fn split<'a>(&'a self, rcc: &RCC) -> Option<Streams<'a, U>> {
+        if self.split_flag().swap(true, Ordering::Acquire) {
+            return None;
+        }
+        self.set_clock(rcc, true);
+
+        Some(Streams {
+            s0: Dma::new(self, DMAStream::Stream0),
+            s1: Dma::new(self, DMAStream::Stream1),
+            s2: Dma::new(self, DMAStream::Stream2),
+            s3: Dma::new(self, DMAStream::Stream3),
+            s4: Dma::new(self, DMAStream::Stream4),
+            s5: Dma::new(self, DMAStream::Stream5),
+            s6: Dma::new(self, DMAStream::Stream6),
+            s7: Dma::new(self, DMAStream::Stream7),
+        })
+    }
+}
+
+/// The streams of a DMA controller
+///
+/// `split` hands out one set per controller, so each `Dma` is the only
+/// handle to its stream; hand them out to the drivers that need them and
+/// gather them back to `join` the controller.
+pub struct Streams<'a, U>
+where
+    U: 'a + Any + DMA,
+{
+    pub s0: Dma<'a, U>,
+    pub s1: Dma<'a, U>,
+    pub s2: Dma<'a, U>,
+    pub s3: Dma<'a, U>,
+    pub s4: Dma<'a, U>,
+    pub s5: Dma<'a, U>,
+    pub s6: Dma<'a, U>,
+    pub s7: Dma<'a, U>,
+}
 
-unsafe impl DMA for DMA2 {}
+impl<'a, U> Streams<'a, U>
+where
+    U: Any + DMA,
+{
+    /// Disables every stream and the controller clock, giving back the
+    /// controller, which can then be split again
+    pub fn join(self, rcc: &RCC) -> &'a U {
+        let reg = self.s0.reg;
+        for stream in [
+            &self.s0, &self.s1, &self.s2, &self.s3,
+            &self.s4, &self.s5, &self.s6, &self.s7,
+        ].iter() {
+            stream.disable();
+            while stream.is_enabled() {}
+        }
+        reg.set_clock(rcc, false);
+        reg.split_flag().store(false, Ordering::Release);
+        reg
+    }
+}
 
 pub struct Dma<'a, U>
 where
     U: Any + DMA,
 {
-    pub reg: &'a U,
+    pub(crate) reg: &'a U,
     stream: DMAStream,
 }
 /*
@@ -149,15 +413,30 @@ impl<'a, U> Dma<'a, U>
 where
     U: Any + DMA,
 {
-    pub fn new(reg: &'a U, stream: DMAStream) -> Dma<U> {
+    /// NOTE handles come from `DmaExt::split`, one per stream
+    pub(crate) fn new(reg: &'a U, stream: DMAStream) -> Dma<U> {
         Dma {
             reg: reg,
             stream: stream,
         }
     }
 
-    pub fn init(&mut self, stream: DMAStream) {
-        self.stream = stream;
+    /// Runs `f` on the DMA controller registers, for features this driver
+    /// lacks
+    ///
+    /// Only touch this stream's registers (`scr`, `sfcr`, `sm1ar`, ... with
+    /// `stream()`), e.g. double buffer mode or the FIFO setup, and only while
+    /// the stream is disabled. The flag registers are shared by all the
+    /// streams, use `clear_flags` and the `is_` / `has_` methods instead.
+    pub fn with_raw<F, R>(&self, f: F) -> R
+        where F: FnOnce(&U) -> R
+    {
+        f(self.reg)
+    }
+
+    /// Stream driven by this instance
+    pub fn stream(&self) -> DMAStream {
+        self.stream
     }
 
     pub fn channel(&self, channel: dma2::scr::CHSELW) {
@@ -255,9 +534,79 @@ where
         }
     }
 
+    /// Checks that `length` items between the `peripheral` and `memory`
+    /// addresses suit the stream's data sizes, bursts and FIFO threshold
+    ///
+    /// A configuration that fails this doesn't raise an error flag, the
+    /// stream silently moves the wrong data. `transfer`, `transfer_pooled`
+    /// and `start_chain` run the check; `set_config` doesn't, use
+    /// `try_set_config` after setting up bursts. Call it after the
+    /// direction, data sizes, increments, bursts and FIFO are configured.
+    pub fn check_config(&self, peripheral: u32, memory: u32, length: u16)
+        -> Result<(), ConfigError>
+    {
+        let cr = self.reg.scr(self.stream).read().bits();
+        let fcr = self.reg.sfcr(self.stream).read().bits();
+
+        // NOTE memory to memory transfers always go through the FIFO
+        let direct = fcr & SFCR_DMDIS == 0 && (cr >> 6) & 0b11 != 0b10;
+        let psize = 1 << ((cr >> 11) & 0b11);
+        let pbeats = burst_beats(cr >> 21);
+        // NOTE in direct mode MSIZE is ignored and bursts are single
+        let (msize, mbeats) = if direct {
+            (psize, 1)
+        } else {
+            (1 << ((cr >> 13) & 0b11), burst_beats(cr >> 23))
+        };
+
+        if direct && (burst_beats(cr >> 21) != 1 || burst_beats(cr >> 23) != 1) {
+            return Err(ConfigError::DirectMode);
+        }
+
+        // PINC, MINC; a burst must not cross a 1 KB boundary, which an
+        // address aligned to the burst size never does
+        let pstep = if cr & (1 << 9) != 0 { psize * pbeats } else { psize };
+        let mstep = if cr & (1 << 10) != 0 { msize * mbeats } else { msize };
+        if peripheral % pstep != 0 || memory % mstep != 0 {
+            return Err(ConfigError::Misaligned);
+        }
+
+        // NDTR counts peripheral items
+        let bytes = u32::from(length) * psize;
+        if u32::from(length) % pbeats != 0 || bytes % (msize * mbeats) != 0 {
+            return Err(ConfigError::Length);
+        }
+
+        if !direct && mbeats != 1 {
+            let threshold = 4 * ((fcr & SFCR_FTH) + 1);
+            if threshold % (msize * mbeats) != 0 {
+                return Err(ConfigError::FifoThreshold);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `set_config` that first runs `check_config`
+    pub fn try_set_config(&self, src_address: u32, dst_address: u32, length: u16)
+        -> Result<(), ConfigError>
+    {
+        let dir = self.reg.scr(self.stream).read().dir();
+        let (peripheral, memory) = if dir.is_periph_to_memory() || dir.is_memory_to_memory() {
+            (src_address, dst_address)
+        } else {
+            (dst_address, src_address)
+        };
+        self.check_config(peripheral, memory, length)?;
+        self.set_config(src_address, dst_address, length);
+        Ok(())
+    }
+
     pub fn set_config(&self, src_address: u32, dst_address: u32, length: u16) {
         self.reg.sndtr(self.stream).write(|w| unsafe { w.ndt().bits(length) });
-        if self.reg.scr(self.stream).read().dir().is_periph_to_memory() {
+        let dir = self.reg.scr(self.stream).read().dir();
+        // NOTE in memory to memory mode the source is the peripheral port
+        if dir.is_periph_to_memory() || dir.is_memory_to_memory() {
             self.reg.spar(self.stream).write(|w| unsafe { w.bits(src_address) });
             self.reg.sm0ar(self.stream).write(|w| unsafe { w.bits(dst_address) });
         }
@@ -267,6 +616,343 @@ where
         }
     }
 
+    /// Starts raising the stream interrupt on `event`
+    ///
+    /// The interrupt line also has to be enabled in the NVIC, see
+    /// `interrupts::InterruptSource`: `listen_nvic` with a priority and
+    /// `interrupt` for the line (e.g. `DMA2_STREAM4`) of this stream.
+    pub fn listen(&self, event: Event) {
+        self.reg.scr(self.stream).modify(|r, w| unsafe { w.bits(r.bits() | event.bit()) });
+    }
+
+    /// Stops raising the stream interrupt on `event`
+    pub fn unlisten(&self, event: Event) {
+        self.reg.scr(self.stream).modify(|r, w| unsafe { w.bits(r.bits() & !event.bit()) });
+    }
+
+    /// Items left to transfer (NDTR)
+    ///
+    /// Safe to read while the stream runs, the value only goes down (and
+    /// reloads in circular mode)
+    pub fn remaining(&self) -> u16 {
+        self.reg.sndtr(self.stream).read().bits() as u16
+    }
+
+    /// Checks the transfer complete flag
+    pub fn is_transfer_complete(&self) -> bool {
+        self.flags() & TCIF != 0
+    }
+
+    /// Checks the transfer error flag
+    pub fn has_transfer_error(&self) -> bool {
+        self.flags() & TEIF != 0
+    }
+
+    /// Clears the transfer complete flag
+    pub fn clear_transfer_complete(&self) {
+        clear_stream_flags(self.reg, self.stream, TCIF);
+    }
+
+    /// Clears all the event flags of this stream
+    pub fn clear_flags(&self) {
+        clear_stream_flags(self.reg, self.stream, ALL_FLAGS);
+    }
+
+    /// Event flags of this stream, shifted down to bit 0
+    fn flags(&self) -> u32 {
+        stream_flags(self.reg, self.stream)
+    }
+
+    /// Starts a transfer between the peripheral register at `address` and
+    /// `buffer`
+    ///
+    /// The direction, channel and data sizes must already be configured.
+    /// The buffer is handed back by `Transfer::wait`.
+    pub fn transfer<W, B>(&'a self, address: u32, buffer: &'static mut B)
+        -> Result<Transfer<'a, U, B>, Error>
+        where B: Unsize<[W]>
+    {
+        if self.is_enabled() {
+            return Err(Error::InUse);
+        }
+
+        let len = {
+            let slice: &mut [W] = buffer;
+            let len = slice.len();
+            if len > 0xFFFF {
+                return Err(Error::Fault(fault::raise(Fault::TooLong)));
+            }
+            self.check_config(address, slice.as_mut_ptr() as u32, len as u16)?;
+
+            self.clear_flags();
+            self.reg.sndtr(self.stream).write(|w| unsafe { w.ndt().bits(len as u16) });
+            self.reg.spar(self.stream).write(|w| unsafe { w.bits(address) });
+            self.reg.sm0ar(self.stream).write(|w| unsafe {
+                w.bits(slice.as_mut_ptr() as u32)
+            });
+            len as u16
+        };
+        self.enable();
+
+        Ok(Transfer { dma: self, buffer: buffer, len: len })
+    }
+
+    /// Like `transfer` with a block of a `pool::Pool`
+    ///
+    /// The block goes back to the pool when the buffer returned by
+    /// `PoolTransfer::wait` is dropped, or when the transfer is, also on
+    /// error.
+    pub fn transfer_pooled<W, B>(&'a self, address: u32, buffer: PoolBuffer<B>)
+        -> Result<PoolTransfer<'a, U, B>, Error>
+        where B: Unsize<[W]>
+    {
+        let (block, slot) = buffer.into_parts();
+        match self.transfer::<W, B>(address, block) {
+            Ok(transfer) => Ok(PoolTransfer::new(transfer, slot)),
+            // NOTE dropping `slot` frees the block
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Starts sending `segments`, one after the other, to the peripheral
+    /// register at `address`
+    ///
+    /// The direction (memory to peripheral), channel, byte data sizes and
+    /// memory increment must already be configured. Empty segments are
+    /// skipped.
+    pub fn start_chain(&'a self, address: u32, segments: &'static [&'static [u8]])
+        -> Result<Chain<'a, U>, Error>
+    {
+        if self.is_enabled() {
+            return Err(Error::InUse);
+        }
+        if segments.iter().any(|segment| segment.len() > 0xFFFF) {
+            return Err(Error::Fault(fault::raise(Fault::TooLong)));
+        }
+        for segment in segments.iter().filter(|segment| !segment.is_empty()) {
+            self.check_config(address, segment.as_ptr() as u32, segment.len() as u16)?;
+        }
+
+        self.reg.spar(self.stream).write(|w| unsafe { w.bits(address) });
+        let chain = Chain { dma: self, segments: segments, next: Cell::new(0) };
+        chain.arm();
+        Ok(chain)
+    }
+}
+
+/// List of byte slices sent back-to-back by one stream, see
+/// `Dma::start_chain`
+///
+/// Call `on_interrupt` from the stream's interrupt (TCIE / TEIE enabled), or
+/// poll `wait`. Between two segments the peripheral idles for the
+/// interrupt latency plus a few register writes.
+pub struct Chain<'a, U>
+    where U: 'a + Any + DMA
+{
+    dma: &'a Dma<'a, U>,
+    segments: &'static [&'static [u8]],
+    next: Cell<usize>,
+}
+
+impl<'a, U> Chain<'a, U>
+    where U: Any + DMA
+{
+    /// Handles a stream event: starts the next segment
+    ///
+    /// Returns `Ok` once the last segment is sent, `WouldBlock` while
+    /// segments remain (or for unrelated events) and the error if a
+    /// transfer failed, which ends the chain.
+    pub fn on_interrupt(&self) -> nb::Result<(), Error> {
+        let dma = self.dma;
+        if dma.has_transfer_error() {
+            dma.disable();
+            while dma.is_enabled() {}
+            dma.clear_flags();
+            self.next.set(self.segments.len());
+            return Err(nb::Error::Other(Error::Transfer));
+        }
+
+        if dma.is_enabled() {
+            return Err(nb::Error::WouldBlock);
+        }
+        dma.clear_flags();
+
+        if self.arm() {
+            Err(nb::Error::WouldBlock)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks if the last segment was sent
+    pub fn is_done(&self) -> bool {
+        self.next.get() >= self.segments.len() && !self.dma.is_enabled()
+    }
+
+    /// Polls the chain until it's over
+    pub fn wait(self) -> Result<(), Error> {
+        block!(self.on_interrupt())
+    }
+
+    /// Starts the next non empty segment, returns `false` if none is left
+    fn arm(&self) -> bool {
+        let dma = self.dma;
+        let mut next = self.next.get();
+        while next < self.segments.len() {
+            let segment = self.segments[next];
+            next += 1;
+            // NOTE `start_chain` checked the lengths
+            if segment.is_empty() || segment.len() > 0xFFFF {
+                continue;
+            }
+
+            self.next.set(next);
+            dma.clear_flags();
+            dma.reg.sndtr(dma.stream).write(|w| unsafe { w.ndt().bits(segment.len() as u16) });
+            dma.reg.sm0ar(dma.stream).write(|w| unsafe { w.bits(segment.as_ptr() as u32) });
+            dma.enable();
+            return true;
+        }
+        self.next.set(next);
+        false
+    }
+}
+
+/// DMA transfer that owns its buffer
+pub struct Transfer<'a, U, B>
+    where U: 'a + Any + DMA,
+          B: 'static
+{
+    dma: &'a Dma<'a, U>,
+    buffer: &'static mut B,
+    len: u16,
+}
+
+impl<'a, U, B> Transfer<'a, U, B>
+    where U: Any + DMA
+{
+    /// Number of items of the transfer
+    pub fn len(&self) -> u16 {
+        self.len
+    }
+
+    /// Items not transferred yet
+    ///
+    /// Polling this gives the progress of long transfers; a value that
+    /// doesn't change across polls means the peripheral stalled.
+    pub fn remaining(&self) -> u16 {
+        // NOTE NDTR keeps the count where an error stopped the stream
+        if self.dma.is_transfer_complete() { 0 } else { self.dma.remaining() }
+    }
+
+    /// Transferred share, in percent
+    pub fn progress_percent(&self) -> u8 {
+        if self.len == 0 {
+            return 100;
+        }
+        let done = u32::from(self.len.saturating_sub(self.remaining()));
+        (done * 100 / u32::from(self.len)) as u8
+    }
+
+    /// Checks if the transfer is over, successfully or not
+    pub fn is_done(&self) -> bool {
+        self.dma.is_transfer_complete() || self.dma.has_transfer_error()
+    }
+
+    /// Waits for the end of the transfer and gives the buffer back
+    pub fn wait(self) -> (&'static mut B, Result<(), Error>) {
+        while !self.is_done() {}
+
+        let result = if self.dma.has_transfer_error() {
+            Err(Error::Transfer)
+        } else {
+            Ok(())
+        };
+
+        self.dma.disable();
+        while self.dma.is_enabled() {}
+        self.dma.clear_flags();
+
+        // NOTE the DMA wrote the buffer behind the compiler's back
+        atomic::compiler_fence(Ordering::SeqCst);
+
+        (self.buffer, result)
+    }
+
+    /// Stops the stream mid-transfer and gives the buffer back
+    pub(crate) fn abort(self) -> &'static mut B {
+        self.dma.disable();
+        while self.dma.is_enabled() {}
+        self.dma.clear_flags();
+
+        atomic::compiler_fence(Ordering::SeqCst);
+
+        self.buffer
+    }
+}
+
+/// NDTR value for `len` items, longer transfers go through the `fault`
+/// policy
+pub(crate) fn dma_len(len: usize) -> Result<u16, Error> {
+    if len > 0xFFFF {
+        Err(Error::Fault(fault::raise(Fault::TooLong)))
+    } else {
+        Ok(len as u16)
+    }
+}
+
+// NOTE(concurrency) The ISR / IFCR registers are shared by all the streams
+// of a controller, so stream flags must only be accessed through these two
+// functions. ISR is read-only. IFCR is write-1-to-clear and zeros are
+// ignored: a single `write` with only this stream's bits set touches nothing
+// else, so transfers on different streams can be polled from thread and
+// interrupt context without a critical section. A read-modify-write
+// (`modify`) on IFCR would not be sound.
+
+/// Event flags of `stream`, shifted down to bit 0
+fn stream_flags<D>(dma: &D, stream: DMAStream) -> u32
+    where D: DMA
+{
+    let isr = if stream.is_high() {
+        dma.hisr.read().bits()
+    } else {
+        dma.lisr.read().bits()
+    };
+    (isr >> stream.flag_offset()) & ALL_FLAGS
+}
+
+/// Clears the `mask` event flags of `stream`, `mask` is relative to bit 0
+fn clear_stream_flags<D>(dma: &D, stream: DMAStream, mask: u32)
+    where D: DMA
+{
+    let mask = (mask & ALL_FLAGS) << stream.flag_offset();
+    if stream.is_high() {
+        dma.hifcr.write(|w| unsafe { w.bits(mask) });
+    } else {
+        dma.lifcr.write(|w| unsafe { w.bits(mask) });
+    }
+}
+
+// Stream event flags, relative to the stream's flag offset
+const FEIF: u32 = 1 << 0;
+const DMEIF: u32 = 1 << 2;
+const TEIF: u32 = 1 << 3;
+const HTIF: u32 = 1 << 4;
+const TCIF: u32 = 1 << 5;
+const ALL_FLAGS: u32 = FEIF | DMEIF | TEIF | HTIF | TCIF;
+
+// SxFCR
+const SFCR_FTH: u32 = 0b11;
+const SFCR_DMDIS: u32 = 1 << 2;
+
+/// Beats of the (M/P)BURST field in the low bits of `bits`
+fn burst_beats(bits: u32) -> u32 {
+    match bits & 0b11 {
+        0b00 => 1,
+        0b01 => 4,
+        0b10 => 8,
+        _ => 16,
+    }
 }
 
 // DMA buffer definitions
@@ -391,6 +1077,10 @@ impl<T> Buffer<T> {
         self.flag.set(self.flag.get() + 1);
         self.state.set(State::Locked);
 
+        // NOTE the writes to the buffer must be done before the DMA is
+        // enabled
+        atomic::compiler_fence(Ordering::SeqCst);
+
         unsafe { &*self.data.get() }
     }
 
@@ -401,67 +1091,111 @@ impl<T> Buffer<T> {
         self.flag.set(WRITING);
         self.state.set(State::MutLocked);
 
+        atomic::compiler_fence(Ordering::SeqCst);
+
         unsafe { &mut *self.data.get() }
     }
 
-    unsafe fn unlock(&self, state: State) {
+    /// Like `lock`, but a locked or borrowed buffer goes through the
+    /// `fault` policy instead of panicking right away
+    pub(crate) fn try_lock(&self) -> Result<&T, Error> {
+        if self.state.get() != State::Unlocked || self.flag.get() == WRITING {
+            return Err(Error::Fault(fault::raise(Fault::BufferLocked)));
+        }
+        Ok(self.lock())
+    }
+
+    /// Like `lock_mut`, for the buffers the DMA writes into
+    pub(crate) fn try_lock_mut(&self) -> Result<&mut T, Error> {
+        if self.state.get() != State::Unlocked || self.flag.get() != UNUSED {
+            return Err(Error::Fault(fault::raise(Fault::BufferLocked)));
+        }
+        Ok(self.lock_mut())
+    }
+
+    /// Rejects a transfer on another stream than the buffer's, `release`
+    /// would wait on the wrong flags
+    pub(crate) fn check_stream(&self, stream: DMAStream) -> Result<(), Error> {
+        if self.stream != stream {
+            return Err(Error::Fault(fault::raise(Fault::WrongStream)));
+        }
+        Ok(())
+    }
+
+    /// Undoes a `lock` / `try_lock` when the transfer couldn't be started
+    pub(crate) fn cancel_lock(&self) {
+        let state = self.state.get();
+        self.unlock(state);
+    }
+
+    fn unlock(&self, state: State) {
         match state {
             State::Locked => self.flag.set(self.flag.get() - 1),
             State::MutLocked => self.flag.set(UNUSED),
-            _ => { /* unreachable!() */ }
+            State::Unlocked => {}
         }
 
         self.state.set(State::Unlocked);
     }
 
-    // FIXME these `release` methods probably want some of sort of barrier
     /// Waits until the DMA releases this buffer
-    pub fn release<D:DMA>(&self, dma: &D) -> nb::Result<(), Error> {
+    ///
+    /// The stream is disabled and the buffer unlocked when the transfer is
+    /// over, also when it failed. Can be called from the stream's interrupt
+    /// handler.
+    pub fn release<D: DMA>(&self, dma: &D) -> nb::Result<(), Error> {
         let state = self.state.get();
 
         if state == State::Unlocked {
             return Ok(());
         }
 
-        let dma_status = match self.stream {
-            DMAStream::Stream0 => (
-                dma.lisr.read().teif0().bit_is_set(),
-                dma.lisr.read().tcif0().bit_is_set(),
-            ),
-            DMAStream::Stream1 => (
-                dma.lisr.read().teif1().bit_is_set(),
-                dma.lisr.read().tcif1().bit_is_set(),
-            ),
-            DMAStream::Stream2 => (
-                dma.lisr.read().teif2().bit_is_set(),
-                dma.lisr.read().tcif2().bit_is_set(),
-            ),
-            DMAStream::Stream3 => (
-                dma.lisr.read().teif3().bit_is_set(),
-                dma.lisr.read().tcif3().bit_is_set(),
-            ),
-            DMAStream::Stream4 => (
-                dma.hisr.read().teif4().bit_is_set(),
-                dma.hisr.read().tcif4().bit_is_set(),
-            ),
-        };
+        let flags = stream_flags(dma, self.stream);
+        if flags & (TEIF | TCIF) == 0 {
+            return Err(nb::Error::WouldBlock);
+        }
 
-        if dma_status.0 {
-            return Err(nb::Error::Other(Error::Transfer));
-        } else if dma_status.1 {
-            unsafe { self.unlock(state) }
-            match self.stream {
-                DMAStream::Stream0 => dma.lifcr.write(|w| w.ctcif0().set_bit()),
-                DMAStream::Stream1 => dma.lifcr.write(|w| w.ctcif1().set_bit()),
-                DMAStream::Stream2 => dma.lifcr.write(|w| w.ctcif2().set_bit()),
-                DMAStream::Stream3 => dma.lifcr.write(|w| w.ctcif3().set_bit()),
-                DMAStream::Stream4 => dma.hifcr.write(|w| w.ctcif4().set_bit()),
-            }
+        dma.scr(self.stream).modify(|_, w| w.en().disable());
+        while dma.scr(self.stream).read().en().bit_is_set() {}
+        clear_stream_flags(dma, self.stream, TEIF | TCIF);
 
-            dma.scr(self.stream).modify(|_, w| w.en().disable());
-            Ok(())
+        // NOTE the DMA wrote the buffer behind the compiler's back
+        atomic::compiler_fence(Ordering::SeqCst);
+        self.unlock(state);
+
+        if flags & TEIF != 0 {
+            Err(nb::Error::Other(Error::Transfer))
         } else {
-            Err(nb::Error::WouldBlock)
+            Ok(())
         }
     }
 }
+
+/// Takes a `&'static mut` buffer of `$len` items for bursts of `$beats`
+///
+/// ``` ignore
+/// let buffer: &'static mut [u32; 64] = dma_buffer!([u32; 64] = [0; 64], burst: 4).unwrap();
+/// let transfer = dma.transfer(address, buffer)?;
+/// ```
+///
+/// A length that isn't a multiple of the burst doesn't compile (`expected
+/// an array with a fixed size of 0 elements`). Returns `None` when the
+/// expression already ran once, the buffer is a single `static`. Rust can't
+/// align a `static` beyond its item type, so the alignment to the burst
+/// size is left to `Dma::check_config`.
+#[macro_export]
+macro_rules! dma_buffer {
+    ([$ty:ty; $len:expr] = $init:expr, burst: $beats:expr) => {{
+        let _: [(); 0] = [(); $len % $beats];
+
+        static TAKEN: ::core::sync::atomic::AtomicBool =
+            ::core::sync::atomic::ATOMIC_BOOL_INIT;
+        static mut BUFFER: [$ty; $len] = $init;
+
+        if TAKEN.swap(true, ::core::sync::atomic::Ordering::Relaxed) {
+            None
+        } else {
+            Some(unsafe { &mut BUFFER })
+        }
+    }};
+}
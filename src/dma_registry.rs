@@ -0,0 +1,106 @@
+//! Compile time DMA stream assignment
+//!
+//! The drivers borrow their streams (`&'a Dma<'a, U>`), so nothing stops
+//! two of them from being handed the same one; the second transfer then
+//! reprograms the stream under the first, which shows up as corrupted
+//! data at run time, if at all. `dma_registry!` declares which driver owns
+//! which stream in one table, and refuses to compile when a stream is
+//! listed twice:
+//!
+//! ``` ignore
+//! dma_registry! {
+//!     /// DMA2 streams of the application
+//!     struct Dma2Streams: DMA2 {
+//!         spi1_rx: Stream2,
+//!         spi1_tx: Stream3,
+//!         tone: Stream5,
+//!         usart6_rx: Stream1,
+//!     }
+//! }
+//!
+//! let streams = Dma2Streams::new(dma2.split(rcc).unwrap());
+//! let spi = Spi::new(spi1, Role::MASTER, Some(&streams.spi1_rx), Some(&streams.spi1_tx));
+//! let tone = Tone::new(tim1, &streams.tone, Channel::_1)?;
+//! ```
+//!
+//! Adding `sniffer: Stream5,` to that table fails with
+//!
+//! ``` text
+//! error[E0119]: conflicting implementations of trait `Claimed` for type `Stream5`
+//! ```
+//!
+//! pointing at the `tone` and `sniffer` lines. `new` consumes the
+//! controller's `Streams` (`split` hands out a single set), so the table is
+//! the only source of `Dma` handles for that controller; the streams it
+//! doesn't list stay unused.
+//!
+//! That is all the table checks. The drivers still borrow their handle, so
+//! `&streams.tone` can be passed to a second driver as well and the streams
+//! collide as described above. Name each field after its one user and
+//! don't borrow it anywhere else; the table makes the assignment visible,
+//! it doesn't enforce it.
+//!
+//! The table only covers the stream numbers: the request channel is still
+//! picked by each driver, see the tables in the reference manual (RM0383,
+//! DMA request mapping).
+
+/// Declares the owners of the streams of a DMA controller, see the module
+/// documentation
+#[macro_export]
+macro_rules! dma_registry {
+    (
+        $(#[$attr:meta])*
+        struct $name:ident: $dma:ident {
+            $($owner:ident: $stream:ident,)+
+        }
+    ) => {
+        $(#[$attr])*
+        pub struct $name<'a> {
+            $(pub $owner: $crate::dma2::Dma<'a, $crate::stm32f411::$dma>,)+
+        }
+
+        impl<'a> $name<'a> {
+            /// Hands the listed streams to their owners
+            pub fn new(streams: $crate::dma2::Streams<'a, $crate::stm32f411::$dma>) -> Self {
+                // NOTE a stream listed twice implements `Claimed` twice,
+                // which is a coherence error naming that stream
+                #[allow(dead_code)]
+                struct Stream0;
+                #[allow(dead_code)]
+                struct Stream1;
+                #[allow(dead_code)]
+                struct Stream2;
+                #[allow(dead_code)]
+                struct Stream3;
+                #[allow(dead_code)]
+                struct Stream4;
+                #[allow(dead_code)]
+                struct Stream5;
+                #[allow(dead_code)]
+                struct Stream6;
+                #[allow(dead_code)]
+                struct Stream7;
+                trait Claimed {}
+                $(impl Claimed for $stream {})+
+
+                $name {
+                    $($owner: __dma_registry_stream!(streams, $stream),)+
+                }
+            }
+        }
+    };
+}
+
+/// Moves the handle of a stream out of a `Streams`, for `dma_registry!`
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __dma_registry_stream {
+    ($streams:ident, Stream0) => { $streams.s0 };
+    ($streams:ident, Stream1) => { $streams.s1 };
+    ($streams:ident, Stream2) => { $streams.s2 };
+    ($streams:ident, Stream3) => { $streams.s3 };
+    ($streams:ident, Stream4) => { $streams.s4 };
+    ($streams:ident, Stream5) => { $streams.s5 };
+    ($streams:ident, Stream6) => { $streams.s6 };
+    ($streams:ident, Stream7) => { $streams.s7 };
+}
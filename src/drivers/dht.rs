@@ -0,0 +1,205 @@
+//! DHT11 / DHT22 (AM2302) temperature and humidity sensors
+//!
+//! The single DATA line is driven open-drain (it needs a pull up, 4.7k -
+//! 10k, the internal one is enabled as a fallback) and the bit timing is
+//! measured with a `MicroTimer`. A frame takes ~5 ms during which
+//! interrupts are disabled, the 26 us / 70 us high pulses that encode the
+//! bits don't survive much jitter.
+//!
+//! ``` ignore
+//! let us = Timer::new(tim4).into_microsecond_base(&clocks)?;
+//! let pin = TypedPin::<GPIOA, N1, Input>::new().into_output(gpioa);
+//! let dht = Dht::new(&us, gpioa, pin, Model::Dht22);
+//!
+//! let reading = dht.read()?;
+//! // 23.4 C, 45.6 %
+//! assert_eq!((reading.temperature, reading.humidity), (234, 456));
+//! ```
+//!
+//! The sensors answer at most once per second (DHT11) or two (DHT22).
+
+use core::any::Any;
+use core::fmt;
+use core::ops::Deref;
+
+use cortex_m::interrupt;
+use stm32f411::gpioa;
+
+use gpio::{Io, Output, Pin, PinNumber, Pupd, TypedPin};
+use time::Microseconds;
+use timer::{MicroTimer, TIM, TIMBase};
+
+/// Attempts of `read` before giving up
+const RETRIES: u32 = 3;
+/// Wait between two attempts, the sensor ignores faster requests
+const BACKOFF_US: u32 = 2_000_000;
+/// Longest phase of the protocol (the 80 us response, with margin)
+const PHASE_TIMEOUT_US: u32 = 100;
+/// High phases longer than this are ones
+const ONE_THRESHOLD_US: u32 = 48;
+
+/// Sensor model
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Model {
+    /// 1 C / 1 % resolution, 0 - 50 C
+    Dht11,
+    /// DHT22 / AM2302, 0.1 C / 0.1 % resolution, -40 - 80 C
+    Dht22,
+}
+
+impl Model {
+    /// Length of the start signal
+    fn start_us(self) -> u32 {
+        match self {
+            Model::Dht11 => 18_000,
+            Model::Dht22 => 1_100,
+        }
+    }
+}
+
+/// Sensor reading
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Reading {
+    /// Temperature in tenths of degree Celsius
+    pub temperature: i16,
+    /// Relative humidity in tenths of percent
+    pub humidity: u16,
+}
+
+/// Read error
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The sensor didn't answer or stopped in the middle of a frame
+    Timeout,
+    /// The frame was corrupted
+    Checksum,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Error::Timeout => "DHT sensor timeout",
+            Error::Checksum => "DHT frame checksum mismatch",
+        })
+    }
+}
+
+/// DHT sensor on one GPIO pin
+pub struct Dht<'a, T, R, P>
+    where T: 'a + Any + TIM<R>,
+          R: 'a + TIMBase,
+          P: 'a + Deref<Target=gpioa::RegisterBlock>
+{
+    timer: &'a MicroTimer<'a, T, R>,
+    port: &'a P,
+    pin: Pin<P>,
+    model: Model,
+}
+
+impl<'a, T, R, P> Dht<'a, T, R, P>
+    where T: Any + TIM<R>,
+          R: TIMBase,
+          P: Deref<Target=gpioa::RegisterBlock>
+{
+    /// Makes `pin` open-drain with a pull up, released (high)
+    pub fn new<N>(timer: &'a MicroTimer<'a, T, R>, port: &'a P, pin: TypedPin<P, N, Output>,
+                  model: Model) -> Self
+        where N: PinNumber
+    {
+        let pin = pin.erase();
+        pin.set(port, Io::High);
+        pin.set_open_drain(port, true);
+        pin.set_pupd(port, Pupd::PullUp);
+
+        Dht { timer: timer, port: port, pin: pin, model: model }
+    }
+
+    /// Reads the sensor, retrying a few times on errors
+    ///
+    /// Waits 2 s between attempts, so a failing sensor blocks for up to 4 s.
+    pub fn read(&self) -> Result<Reading, Error> {
+        let mut attempt = 0;
+        loop {
+            match self.read_once() {
+                Ok(reading) => return Ok(reading),
+                Err(e) => {
+                    attempt += 1;
+                    if attempt >= RETRIES {
+                        return Err(e);
+                    }
+                    self.timer.delay_us(Microseconds(BACKOFF_US));
+                }
+            }
+        }
+    }
+
+    /// Single attempt, no retry
+    pub fn read_once(&self) -> Result<Reading, Error> {
+        let mut data = [0; 5];
+
+        // Start signal, with interrupts enabled: only its minimum length
+        // matters
+        self.pin.set(self.port, Io::Low);
+        self.timer.delay_us(Microseconds(self.model.start_us()));
+
+        interrupt::free(|_| -> Result<(), Error> {
+            // Release the line, the sensor answers with 80 us low, 80 us high
+            self.pin.set(self.port, Io::High);
+            self.level(Io::High)?;
+            self.level(Io::Low)?;
+            self.level(Io::High)?;
+
+            for byte in data.iter_mut() {
+                for _ in 0..8 {
+                    // 50 us low, then 26 - 28 us (0) or 70 us (1) high
+                    self.level(Io::Low)?;
+                    let high = self.level(Io::High)?;
+                    *byte = (*byte << 1) | if high > ONE_THRESHOLD_US { 1 } else { 0 };
+                }
+            }
+            Ok(())
+        })?;
+
+        let sum = data[..4].iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        if sum != data[4] {
+            return Err(Error::Checksum);
+        }
+
+        Ok(match self.model {
+            Model::Dht11 => Reading {
+                temperature: i16::from(data[2]) * 10 + i16::from(data[3] % 10),
+                humidity: u16::from(data[0]) * 10 + u16::from(data[1] % 10),
+            },
+            Model::Dht22 => {
+                let magnitude = ((u16::from(data[2]) & 0x7F) << 8 | u16::from(data[3])) as i16;
+                Reading {
+                    temperature: if data[2] & 0x80 != 0 { -magnitude } else { magnitude },
+                    humidity: u16::from(data[0]) << 8 | u16::from(data[1]),
+                }
+            }
+        })
+    }
+
+    /// Waits while the line is at `level`, returns how long it stayed there
+    fn level(&self, level: Io) -> Result<u32, Error> {
+        let high = match level {
+            Io::High => true,
+            Io::Low => false,
+        };
+
+        let start = self.timer.now();
+        loop {
+            let is_high = match self.pin.get(self.port) {
+                Io::High => true,
+                Io::Low => false,
+            };
+            let elapsed = self.timer.elapsed(start).0;
+            if is_high != high {
+                return Ok(elapsed);
+            }
+            if elapsed > PHASE_TIMEOUT_US {
+                return Err(Error::Timeout);
+            }
+        }
+    }
+}
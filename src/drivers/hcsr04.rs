@@ -0,0 +1,124 @@
+//! HC-SR04 ultrasonic range finder
+//!
+//! TRIG is a plain GPIO output. ECHO must be routed (alternate function) to
+//! a channel of the timer behind the `MicroTimer`; its pulse width, the
+//! round trip time of the sound, is measured with input capture on both
+//! edges, so interrupts can't skew the result.
+//!
+//! ``` ignore
+//! // TIM3 CH1 on PA6
+//! Pin::new(6).set_alternate(gpioa, AF2, Speed::Low);
+//! let us = Timer::new(tim3).into_microsecond_base(&clocks)?;
+//! let trig = TypedPin::<GPIOB, N0, Input>::new().into_output(gpiob);
+//! let sonar = Hcsr04::new(&us, Channel::_1, gpiob, trig);
+//!
+//! let mm = sonar.measure()?;
+//! ```
+//!
+//! Leave at least 60 ms between measurements, echoes of the previous ping
+//! may still be around.
+
+use core::any::Any;
+use core::fmt;
+use core::ops::Deref;
+
+use stm32f411::gpioa;
+
+use gpio::{Io, Output, Pin, PinNumber, TypedPin};
+use time::Microseconds;
+use timer::{Channel, MicroTimer, TIM, TIMBase};
+
+/// Trigger pulse width
+const TRIGGER_US: u32 = 10;
+/// The sensor starts the echo pulse ~0.5 ms after the trigger
+const ECHO_START_TIMEOUT_US: u32 = 2_000;
+/// Longest echo, the sensor gives up at ~38 ms when nothing is in range
+const ECHO_MAX_US: u32 = 30_000;
+/// Speed of sound at 20 C, in mm / ms
+const SOUND_MM_PER_MS: u32 = 343;
+
+/// Measurement error
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// No echo pulse started, the sensor is missing or not powered
+    Timeout,
+    /// Nothing in range (~5 m)
+    OutOfRange,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Error::Timeout => "HC-SR04 didn't answer",
+            Error::OutOfRange => "HC-SR04 out of range",
+        })
+    }
+}
+
+/// HC-SR04 sensor
+pub struct Hcsr04<'a, T, R, P>
+    where T: 'a + Any + TIM<R>,
+          R: 'a + TIMBase,
+          P: 'a + Deref<Target=gpioa::RegisterBlock>
+{
+    timer: &'a MicroTimer<'a, T, R>,
+    channel: Channel,
+    port: &'a P,
+    trig: Pin<P>,
+}
+
+impl<'a, T, R, P> Hcsr04<'a, T, R, P>
+    where T: Any + TIM<R>,
+          R: TIMBase,
+          P: Deref<Target=gpioa::RegisterBlock>
+{
+    /// Drives TRIG low and configures `channel` to capture ECHO
+    pub fn new<N>(timer: &'a MicroTimer<'a, T, R>, channel: Channel, port: &'a P,
+                  trig: TypedPin<P, N, Output>) -> Self
+        where N: PinNumber
+    {
+        let trig = trig.erase();
+        trig.set(port, Io::Low);
+        timer.enable_capture_both_edges(channel);
+
+        Hcsr04 { timer: timer, channel: channel, port: port, trig: trig }
+    }
+
+    /// Round trip time of the echo
+    pub fn echo(&self) -> Result<Microseconds, Error> {
+        let timer = self.timer;
+
+        // Drop a capture left by noise since the last measurement
+        let _ = timer.capture_us(self.channel);
+
+        self.trig.set(self.port, Io::High);
+        timer.delay_us(Microseconds(TRIGGER_US));
+        self.trig.set(self.port, Io::Low);
+
+        let start = self.wait_edge(timer.now(), ECHO_START_TIMEOUT_US)
+            .ok_or(Error::Timeout)?;
+        let end = self.wait_edge(start, ECHO_MAX_US).ok_or(Error::OutOfRange)?;
+
+        let period = u32::from(timer.0.arr_bits()) + 1;
+        Ok(Microseconds((end.0 + period - start.0) % period))
+    }
+
+    /// Distance to the nearest object, in mm
+    pub fn measure(&self) -> Result<u32, Error> {
+        let echo = self.echo()?;
+        // There and back
+        Ok(echo.0 * SOUND_MM_PER_MS / 2_000)
+    }
+
+    /// Waits for the next captured edge, at most `timeout_us` after `since`
+    fn wait_edge(&self, since: Microseconds, timeout_us: u32) -> Option<Microseconds> {
+        loop {
+            if let Ok(t) = self.timer.capture_us(self.channel) {
+                return Some(t);
+            }
+            if self.timer.elapsed(since).0 > timeout_us {
+                return None;
+            }
+        }
+    }
+}
@@ -0,0 +1,307 @@
+//! HD44780 character LCD driver
+//!
+//! The data lines must be wired to consecutive pins of a single port, a
+//! `PinGroup`, so a whole nibble / byte can be written with one BSRR access. The control lines
+//! (RS, EN and the optional RW) live on the same port, and the driver
+//! borrows the port's `PortAccess`.
+//!
+//! - 8-bit bus: D0..D7 = `data_pin`..`data_pin + 7`
+//! - 4-bit bus: D4..D7 = `data_pin`..`data_pin + 3`
+//!
+//! When RW is wired the busy flag is polled, otherwise the worst case
+//! execution time of each instruction is waited out using `delay`.
+
+use core::fmt;
+use core::ops::Deref;
+
+use stm32f411::{SYST, gpioa};
+
+use delay;
+use gpio::{Io, Mode, Pin, PinGroup, PortAccess};
+use time::{Microseconds, Milliseconds};
+
+const CLEAR_DISPLAY: u8 = 0x01;
+const RETURN_HOME: u8 = 0x02;
+const ENTRY_MODE: u8 = 0x04;
+const DISPLAY_CONTROL: u8 = 0x08;
+const FUNCTION_SET: u8 = 0x20;
+const SET_DDRAM_ADDR: u8 = 0x80;
+
+const ENTRY_INCREMENT: u8 = 0x02;
+const DISPLAY_ON: u8 = 0x04;
+const CURSOR_ON: u8 = 0x02;
+const BLINK_ON: u8 = 0x01;
+const EIGHT_BIT: u8 = 0x10;
+const TWO_LINES: u8 = 0x08;
+
+const BUSY_FLAG: u8 = 0x80;
+
+/// DDRAM offset of each row
+const ROW_OFFSETS: [u8; 4] = [0x00, 0x40, 0x14, 0x54];
+/// Columns of a DDRAM line, of a 1 or 2 line display
+const MAX_COLS: u8 = 40;
+/// Columns of a 4 line display, lines 3 and 4 continue lines 1 and 2
+const MAX_COLS_4_LINES: u8 = 20;
+
+/// Data bus width
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum Bus {
+    FourBit,
+    EightBit,
+}
+
+/// HD44780 character LCD
+pub struct Hd44780<'a, T>
+    where T: 'a + Deref<Target=gpioa::RegisterBlock>
+{
+    access: &'a PortAccess<'a, T>,
+    port: &'a T,
+    syst: &'a SYST,
+    rs: Pin<T>,
+    en: Pin<T>,
+    rw: Option<Pin<T>>,
+    data: PinGroup<T>,
+    bus: Bus,
+    cols: u8,
+    rows: u8,
+    row: u8,
+}
+
+impl<'a, T> Hd44780<'a, T>
+    where T: 'a + Deref<Target=gpioa::RegisterBlock>
+{
+    /// Display of `cols` x `rows` characters
+    ///
+    /// # Panics
+    ///
+    /// If `rows` isn't 1 to 4, or `cols` isn't 1 to 40 (1 to 20 with more
+    /// than 2 rows)
+    pub fn new(access: &'a PortAccess<'a, T>, syst: &'a SYST, rs: u8, en: u8, rw: Option<u8>,
+               data_pin: u8, bus: Bus, cols: u8, rows: u8) -> Self {
+        assert!(rows >= 1 && rows as usize <= ROW_OFFSETS.len());
+        let max_cols = if rows > 2 { MAX_COLS_4_LINES } else { MAX_COLS };
+        assert!(cols >= 1 && cols <= max_cols);
+
+        Hd44780 {
+            access: access,
+            port: access.port(),
+            syst: syst,
+            rs: Pin::new(rs),
+            en: Pin::new(en),
+            rw: rw.map(Pin::new),
+            data: PinGroup::consecutive(data_pin, width(bus)),
+            bus: bus,
+            cols: cols,
+            rows: rows,
+            row: 0,
+        }
+    }
+
+    /// Runs the power-on initialization sequence
+    ///
+    /// NOTE The GPIO port clock must be enabled before calling this
+    pub fn init(&mut self) {
+        self.rs.set_mode(self.port, Mode::Output);
+        self.en.set_mode(self.port, Mode::Output);
+        self.rs.set(self.port, Io::Low);
+        self.en.set(self.port, Io::Low);
+        if let Some(ref rw) = self.rw {
+            rw.set_mode(self.port, Mode::Output);
+            rw.set(self.port, Io::Low);
+        }
+        self.data_mode(Mode::Output);
+
+        // Wait for VCC to rise above 4.5V
+        delay::delay_ms(self.syst, Milliseconds(50));
+
+        // Initialization by instruction, see figure 23/24 of the datasheet
+        match self.bus {
+            Bus::EightBit => {
+                self.write_bus(0x30);
+                delay::delay_us(self.syst, Microseconds(4500));
+                self.write_bus(0x30);
+                delay::delay_us(self.syst, Microseconds(150));
+                self.write_bus(0x30);
+                delay::delay_us(self.syst, Microseconds(150));
+            }
+            Bus::FourBit => {
+                self.write_bus(0x03);
+                delay::delay_us(self.syst, Microseconds(4500));
+                self.write_bus(0x03);
+                delay::delay_us(self.syst, Microseconds(150));
+                self.write_bus(0x03);
+                delay::delay_us(self.syst, Microseconds(150));
+                self.write_bus(0x02);
+                delay::delay_us(self.syst, Microseconds(150));
+            }
+        }
+
+        let mut function = FUNCTION_SET;
+        if self.bus == Bus::EightBit {
+            function |= EIGHT_BIT;
+        }
+        if self.rows > 1 {
+            function |= TWO_LINES;
+        }
+        self.command(function);
+        self.display(true, false, false);
+        self.clear();
+        self.command(ENTRY_MODE | ENTRY_INCREMENT);
+    }
+
+    /// Clears the display and moves the cursor to the top left corner
+    pub fn clear(&mut self) {
+        self.command(CLEAR_DISPLAY);
+        self.row = 0;
+    }
+
+    /// Moves the cursor to the top left corner
+    pub fn home(&mut self) {
+        self.command(RETURN_HOME);
+        self.row = 0;
+    }
+
+    /// Turns the display, cursor and cursor blinking on / off
+    pub fn display(&mut self, on: bool, cursor: bool, blink: bool) {
+        let mut control = DISPLAY_CONTROL;
+        if on {
+            control |= DISPLAY_ON;
+        }
+        if cursor {
+            control |= CURSOR_ON;
+        }
+        if blink {
+            control |= BLINK_ON;
+        }
+        self.command(control);
+    }
+
+    /// Moves the cursor to `col` of `row`, both clamped to the display
+    pub fn set_cursor(&mut self, col: u8, row: u8) {
+        let col = if col < self.cols { col } else { self.cols - 1 };
+        let row = if row < self.rows { row } else { self.rows - 1 };
+        self.row = row;
+        self.command(SET_DDRAM_ADDR | (ROW_OFFSETS[row as usize] + col));
+    }
+
+    /// Writes a character at the current cursor position
+    pub fn write_char(&mut self, c: u8) {
+        self.rs.set(self.port, Io::High);
+        self.write_byte(c);
+        self.wait_ready(Microseconds(45));
+    }
+
+    /// Sends an instruction
+    pub fn command(&mut self, cmd: u8) {
+        self.rs.set(self.port, Io::Low);
+        self.write_byte(cmd);
+        let wait = if cmd == CLEAR_DISPLAY || cmd == RETURN_HOME {
+            Microseconds(1600)
+        } else {
+            Microseconds(45)
+        };
+        self.wait_ready(wait);
+    }
+
+    fn write_byte(&self, byte: u8) {
+        match self.bus {
+            Bus::EightBit => self.write_bus(byte),
+            Bus::FourBit => {
+                self.write_bus(byte >> 4);
+                self.write_bus(byte & 0x0F);
+            }
+        }
+    }
+
+    /// Puts `value` on the data lines and latches it with an enable pulse
+    fn write_bus(&self, value: u8) {
+        self.data.write(self.access, u16::from(value));
+        self.pulse_enable();
+    }
+
+    fn pulse_enable(&self) {
+        // tPW >= 450 ns, tcycE >= 1000 ns
+        self.en.set(self.port, Io::High);
+        delay::delay_us(self.syst, Microseconds(1));
+        self.en.set(self.port, Io::Low);
+        delay::delay_us(self.syst, Microseconds(1));
+    }
+
+    /// Waits until the controller can accept a new instruction
+    ///
+    /// Polls the busy flag when RW is wired, otherwise waits `max` out
+    fn wait_ready(&self, max: Microseconds) {
+        let rw = match self.rw {
+            Some(ref rw) => rw,
+            None => {
+                delay::delay_us(self.syst, max);
+                return;
+            }
+        };
+
+        self.data_mode(Mode::Input);
+        self.rs.set(self.port, Io::Low);
+        rw.set(self.port, Io::High);
+
+        loop {
+            let status = match self.bus {
+                Bus::EightBit => self.read_bus(),
+                Bus::FourBit => {
+                    let high = self.read_bus();
+                    let low = self.read_bus();
+                    (high << 4) | (low & 0x0F)
+                }
+            };
+
+            if status & BUSY_FLAG == 0 {
+                break;
+            }
+        }
+
+        rw.set(self.port, Io::Low);
+        self.data_mode(Mode::Output);
+    }
+
+    fn read_bus(&self) -> u8 {
+        self.en.set(self.port, Io::High);
+        // tDDR <= 360 ns
+        delay::delay_us(self.syst, Microseconds(1));
+        let value = self.data.read(self.access);
+        self.en.set(self.port, Io::Low);
+        delay::delay_us(self.syst, Microseconds(1));
+        value as u8
+    }
+
+    fn data_mode(&self, mode: Mode) {
+        self.data.set_mode(self.port, mode);
+    }
+}
+
+/// Number of data lines
+fn width(bus: Bus) -> u8 {
+    match bus {
+        Bus::FourBit => 4,
+        Bus::EightBit => 8,
+    }
+}
+
+impl<'a, T> fmt::Write for Hd44780<'a, T>
+    where T: 'a + Deref<Target=gpioa::RegisterBlock>
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            match byte {
+                b'\n' => {
+                    let row = (self.row + 1) % self.rows;
+                    self.set_cursor(0, row);
+                }
+                b'\r' => {
+                    let row = self.row;
+                    self.set_cursor(0, row);
+                }
+                _ => self.write_char(byte),
+            }
+        }
+        Ok(())
+    }
+}
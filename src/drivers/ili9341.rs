@@ -0,0 +1,524 @@
+//! ILI9341 / ST7735 RGB565 TFT driver
+//!
+//! Both controllers share the MIPI DCS command set: a window is selected
+//! with CASET / RASET and filled with RAMWR. Small drawing (pixels, filled
+//! rectangles) goes out byte by byte; full windows are sent from a pixel
+//! buffer by the TX DMA stream, in 16-bit frames, so the panel gets the
+//! big-endian RGB565 it expects without swapping bytes:
+//!
+//! ``` ignore
+//! static mut LINES: [u16; 240 * 40] = [0; 240 * 40];
+//!
+//! let dc = TypedPin::<GPIOB, N0, Input>::new().into_output(gpiob);
+//! let cs = TypedPin::<GPIOB, N1, Input>::new().into_output(gpiob);
+//! let reset = TypedPin::<GPIOB, N2, Input>::new().into_output(gpiob);
+//! let mut display = Ili9341::new(&spi, gpiob, dc, cs, reset, Panel::Ili9341);
+//! display.init(syst);
+//! display.fill_rect(0, 0, 240, 320, color::BLACK);
+//!
+//! // 40 lines at a time
+//! let flush = display.flush(0, y, 240, 40, unsafe { &mut LINES })?;
+//! let (lines, result) = flush.wait();
+//! ```
+//!
+//! A DMA stream moves at most 65535 items, a flush longer than that (e.g.
+//! a whole 240x320 frame) is sent in chunks, re-armed by `Flush::poll`.
+//! The SPI must be set up as master, mode 0, with a TX DMA stream
+//! configured for memory to peripheral transfers with memory increment
+//! (the data sizes follow the frames). The ILI9341 takes SCK up to 10 MHz
+//! for writes (more works on most panels), the ST7735 up to 15 MHz.
+
+use core::any::Any;
+use core::fmt;
+use core::ops::Deref;
+
+use hal;
+use nb;
+use stm32f411::{gpioa, SYST};
+
+use delay;
+use dma2::{self, DMA};
+use gpio::{Io, Output, OutputPin, Pin, PinNumber, TypedPin};
+use spi2::{SPI, Spi};
+use time::{Microseconds, Milliseconds};
+
+const SWRESET: u8 = 0x01;
+const SLPOUT: u8 = 0x11;
+const DISPON: u8 = 0x29;
+const CASET: u8 = 0x2A;
+const RASET: u8 = 0x2B;
+const RAMWR: u8 = 0x2C;
+const MADCTL: u8 = 0x36;
+const INVOFF: u8 = 0x20;
+const INVON: u8 = 0x21;
+
+/// Most items a DMA stream moves at once
+const CHUNK: usize = 0xFFFF;
+
+/// Flush error
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The window is empty or doesn't fit the display
+    Window,
+    /// The pixel buffer doesn't hold exactly the window
+    Length,
+    /// No TX stream, the stream is busy or the transfer failed
+    Dma(dma2::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Window => f.write_str("display window out of bounds"),
+            Error::Length => f.write_str("pixel buffer doesn't match the window"),
+            Error::Dma(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl From<dma2::Error> for Error {
+    fn from(e: dma2::Error) -> Self {
+        Error::Dma(e)
+    }
+}
+
+/// RGB565 colors
+pub mod color {
+    pub const BLACK: u16 = 0x0000;
+    pub const WHITE: u16 = 0xFFFF;
+    pub const RED: u16 = 0xF800;
+    pub const GREEN: u16 = 0x07E0;
+    pub const BLUE: u16 = 0x001F;
+
+    /// Packs 8-bit components
+    pub const fn rgb(r: u8, g: u8, b: u8) -> u16 {
+        ((r as u16 & 0xF8) << 8) | ((g as u16 & 0xFC) << 3) | (b as u16 >> 3)
+    }
+}
+
+/// Step of an init sequence
+struct Step {
+    command: u8,
+    args: &'static [u8],
+    delay_ms: u32,
+}
+
+macro_rules! steps {
+    ($($command:expr, [$($arg:expr),*], $delay:expr;)+) => {
+        &[$(Step { command: $command, args: &[$($arg),*], delay_ms: $delay }),+]
+    }
+}
+
+const ILI9341_INIT: &'static [Step] = steps! {
+    SWRESET, [], 150;
+    0xEF, [0x03, 0x80, 0x02], 0;
+    0xCF, [0x00, 0xC1, 0x30], 0;
+    0xED, [0x64, 0x03, 0x12, 0x81], 0;
+    0xE8, [0x85, 0x00, 0x78], 0;
+    0xCB, [0x39, 0x2C, 0x00, 0x34, 0x02], 0;
+    0xF7, [0x20], 0;
+    0xEA, [0x00, 0x00], 0;
+    // PWCTR1, PWCTR2, VMCTR1, VMCTR2
+    0xC0, [0x23], 0;
+    0xC1, [0x10], 0;
+    0xC5, [0x3E, 0x28], 0;
+    0xC7, [0x86], 0;
+    // VSCRSADD, COLMOD 16 bits, FRMCTR1, DFUNCTR
+    0x37, [0x00], 0;
+    0x3A, [0x55], 0;
+    0xB1, [0x00, 0x18], 0;
+    0xB6, [0x08, 0x82, 0x27], 0;
+    // Gamma
+    0xF2, [0x00], 0;
+    0x26, [0x01], 0;
+    0xE0, [0x0F, 0x31, 0x2B, 0x0C, 0x0E, 0x08, 0x4E, 0xF1,
+           0x37, 0x07, 0x10, 0x03, 0x0E, 0x09, 0x00], 0;
+    0xE1, [0x00, 0x0E, 0x14, 0x03, 0x11, 0x07, 0x31, 0xC1,
+           0x48, 0x08, 0x0F, 0x0C, 0x31, 0x36, 0x0F], 0;
+    SLPOUT, [], 120;
+    DISPON, [], 20;
+};
+
+const ST7735_INIT: &'static [Step] = steps! {
+    SWRESET, [], 150;
+    SLPOUT, [], 500;
+    // FRMCTR1 - 3, INVCTR
+    0xB1, [0x01, 0x2C, 0x2D], 0;
+    0xB2, [0x01, 0x2C, 0x2D], 0;
+    0xB3, [0x01, 0x2C, 0x2D, 0x01, 0x2C, 0x2D], 0;
+    0xB4, [0x07], 0;
+    // PWCTR1 - 5, VMCTR1
+    0xC0, [0xA2, 0x02, 0x84], 0;
+    0xC1, [0xC5], 0;
+    0xC2, [0x0A, 0x00], 0;
+    0xC3, [0x8A, 0x2A], 0;
+    0xC4, [0x8A, 0xEE], 0;
+    0xC5, [0x0E], 0;
+    INVOFF, [], 0;
+    // COLMOD 16 bits
+    0x3A, [0x05], 0;
+    // Gamma
+    0xE0, [0x02, 0x1C, 0x07, 0x12, 0x37, 0x32, 0x29, 0x2D,
+           0x29, 0x25, 0x2B, 0x39, 0x00, 0x01, 0x03, 0x10], 0;
+    0xE1, [0x03, 0x1D, 0x07, 0x06, 0x2E, 0x2C, 0x29, 0x2D,
+           0x2E, 0x2E, 0x37, 0x3F, 0x00, 0x00, 0x02, 0x10], 0;
+    // NORON
+    0x13, [], 10;
+    DISPON, [], 100;
+};
+
+/// Display controller, and panel size
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Panel {
+    /// ILI9341, 240x320
+    Ili9341,
+    /// ST7735R, 128x160
+    St7735,
+}
+
+impl Panel {
+    /// Width and height in portrait orientation
+    fn size(self) -> (u16, u16) {
+        match self {
+            Panel::Ili9341 => (240, 320),
+            Panel::St7735 => (128, 160),
+        }
+    }
+
+    fn init(self) -> &'static [Step] {
+        match self {
+            Panel::Ili9341 => ILI9341_INIT,
+            Panel::St7735 => ST7735_INIT,
+        }
+    }
+
+    /// MADCTL of each orientation, BGR panels
+    fn madctl(self, orientation: Orientation) -> u8 {
+        let table = match self {
+            Panel::Ili9341 => [0x48, 0x28, 0x88, 0xE8],
+            Panel::St7735 => [0xC8, 0xA8, 0x08, 0x68],
+        };
+        table[orientation as usize]
+    }
+}
+
+/// Display orientation, connector at the bottom in `Portrait`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Orientation {
+    Portrait = 0,
+    Landscape = 1,
+    PortraitFlipped = 2,
+    LandscapeFlipped = 3,
+}
+
+/// ILI9341 / ST7735 display on a 4-wire SPI bus
+pub struct Ili9341<'a, S, D, P>
+    where S: 'a + Any + SPI,
+          D: 'a + Any + DMA,
+          P: 'a + Deref<Target=gpioa::RegisterBlock>
+{
+    spi: &'a Spi<'a, S, D>,
+    port: &'a P,
+    dc: Pin<P>,
+    cs: Option<Pin<P>>,
+    reset: Option<Pin<P>>,
+    panel: Panel,
+    orientation: Orientation,
+    /// RAM offset of the visible area, ST7735 modules differ
+    offset: (u16, u16),
+}
+
+impl<'a, S, D, P> Ili9341<'a, S, D, P>
+    where S: Any + SPI,
+          D: Any + DMA,
+          P: Deref<Target=gpioa::RegisterBlock>
+{
+    /// `dc`, `cs` and `reset` are outputs of `port`, `cs` and `reset` can be
+    /// `NoPin`
+    ///
+    /// Without `cs` the panel's CS must be tied low and the bus can't be
+    /// shared; without `reset` `init` resets through the SWRESET command.
+    pub fn new<N, CS, RST>(
+        spi: &'a Spi<'a, S, D>,
+        port: &'a P,
+        dc: TypedPin<P, N, Output>,
+        cs: CS,
+        reset: RST,
+        panel: Panel,
+    ) -> Self
+        where N: PinNumber,
+              CS: OutputPin<P>,
+              RST: OutputPin<P>
+    {
+        let cs = cs.into_pin();
+        let reset = reset.into_pin();
+        if let Some(ref cs) = cs {
+            cs.set(port, Io::High);
+        }
+        if let Some(ref reset) = reset {
+            reset.set(port, Io::High);
+        }
+
+        Ili9341 {
+            spi: spi,
+            port: port,
+            dc: dc.erase(),
+            cs: cs,
+            reset: reset,
+            panel: panel,
+            orientation: Orientation::Portrait,
+            offset: (0, 0),
+        }
+    }
+
+    /// Offset of the visible area in the controller RAM
+    ///
+    /// ST7735 modules with a 128x128 or 80x160 glass, or the "green tab"
+    /// 128x160 ones, show a window of the 132x162 RAM, e.g. (2, 1).
+    pub fn offset(mut self, x: u16, y: u16) -> Self {
+        self.offset = (x, y);
+        self
+    }
+
+    /// Resets the controller, sends the panel's init sequence and turns
+    /// the display on, in portrait orientation
+    ///
+    /// Takes ~300 ms (ILI9341) to ~800 ms (ST7735), `syst` times the waits.
+    pub fn init(&mut self, syst: &SYST) {
+        if let Some(ref reset) = self.reset {
+            reset.set(self.port, Io::Low);
+            delay::delay_us(syst, Microseconds(20));
+            reset.set(self.port, Io::High);
+            delay::delay_ms(syst, Milliseconds(120));
+        }
+
+        for step in self.panel.init() {
+            self.command(step.command, step.args);
+            if step.delay_ms != 0 {
+                delay::delay_ms(syst, Milliseconds(step.delay_ms));
+            }
+        }
+        self.set_orientation(Orientation::Portrait);
+    }
+
+    /// Width in the current orientation
+    pub fn width(&self) -> u16 {
+        let (width, height) = self.panel.size();
+        match self.orientation {
+            Orientation::Portrait | Orientation::PortraitFlipped => width,
+            Orientation::Landscape | Orientation::LandscapeFlipped => height,
+        }
+    }
+
+    /// Height in the current orientation
+    pub fn height(&self) -> u16 {
+        let (width, height) = self.panel.size();
+        match self.orientation {
+            Orientation::Portrait | Orientation::PortraitFlipped => height,
+            Orientation::Landscape | Orientation::LandscapeFlipped => width,
+        }
+    }
+
+    pub fn set_orientation(&mut self, orientation: Orientation) {
+        self.orientation = orientation;
+        let madctl = self.panel.madctl(orientation);
+        self.command(MADCTL, &[madctl]);
+    }
+
+    /// Inverts the colors of the panel, doesn't touch the RAM
+    pub fn invert(&self, invert: bool) {
+        self.command(if invert { INVON } else { INVOFF }, &[]);
+    }
+
+    /// Draws one pixel, out of bounds pixels are ignored
+    pub fn set_pixel(&self, x: u16, y: u16, color: u16) {
+        if x < self.width() && y < self.height() {
+            self.window(x, y, 1, 1);
+            self.data_repeat(color, 1);
+        }
+    }
+
+    /// Fills a rectangle with `color`, clipped to the display
+    pub fn fill_rect(&self, x: u16, y: u16, width: u16, height: u16, color: u16) {
+        if x >= self.width() || y >= self.height() {
+            return;
+        }
+        let width = if width > self.width() - x { self.width() - x } else { width };
+        let height = if height > self.height() - y { self.height() - y } else { height };
+        if width == 0 || height == 0 {
+            return;
+        }
+
+        self.window(x, y, width, height);
+        self.data_repeat(color, u32::from(width) * u32::from(height));
+    }
+
+    /// Starts sending `pixels` to the `width` x `height` window at (`x`,
+    /// `y`), row by row
+    ///
+    /// The buffer is handed back by `Flush::wait`. Fails if the window is
+    /// empty or doesn't fit the display, if `pixels` doesn't hold exactly
+    /// `width * height` pixels or if the stream is busy; the buffer is
+    /// lost then.
+    pub fn flush(&self, x: u16, y: u16, width: u16, height: u16, pixels: &'static mut [u16])
+        -> Result<Flush<'a, S, D, P>, Error>
+    {
+        if width == 0 || height == 0 ||
+            u32::from(x) + u32::from(width) > u32::from(self.width()) ||
+            u32::from(y) + u32::from(height) > u32::from(self.height())
+        {
+            return Err(Error::Window);
+        }
+        if pixels.len() != usize::from(width) * usize::from(height) {
+            return Err(Error::Length);
+        }
+        self.spi.tx_stream()?;
+
+        self.window(x, y, width, height);
+        self.dc.set(self.port, Io::High);
+        self.select();
+
+        let mut flush = Flush {
+            spi: self.spi,
+            port: self.port,
+            cs: self.cs.as_ref().map(|cs| Pin::new(cs.number())),
+            pixels: pixels,
+            sent: 0,
+        };
+        if let Err(e) = flush.next_chunk() {
+            self.deselect();
+            return Err(e.into());
+        }
+        Ok(flush)
+    }
+
+    /// Selects the window and starts the RAM write, the window isn't empty
+    fn window(&self, x: u16, y: u16, width: u16, height: u16) {
+        let (x0, y0) = (x + self.offset.0, y + self.offset.1);
+        let (x1, y1) = (x0 + width - 1, y0 + height - 1);
+        self.command(CASET, &[(x0 >> 8) as u8, x0 as u8, (x1 >> 8) as u8, x1 as u8]);
+        self.command(RASET, &[(y0 >> 8) as u8, y0 as u8, (y1 >> 8) as u8, y1 as u8]);
+        self.command(RAMWR, &[]);
+    }
+
+    fn command(&self, command: u8, args: &[u8]) {
+        // NOTE a previous flush left 16-bit frames and an RX overrun
+        self.spi.set_frame::<u8>(&[]);
+        self.spi.clear_overrun();
+
+        self.dc.set(self.port, Io::Low);
+        self.select();
+        self.write(command);
+        self.dc.set(self.port, Io::High);
+        for arg in args {
+            self.write(*arg);
+        }
+        self.deselect();
+    }
+
+    /// Writes `color` `count` times, after a RAMWR
+    fn data_repeat(&self, color: u16, count: u32) {
+        self.dc.set(self.port, Io::High);
+        self.select();
+        for _ in 0..count {
+            self.write((color >> 8) as u8);
+            self.write(color as u8);
+        }
+        self.deselect();
+    }
+
+    fn write(&self, byte: u8) {
+        block!(hal::Spi::send(self.spi, byte)).ok();
+        block!(hal::Spi::read(self.spi)).ok();
+    }
+
+    fn select(&self) {
+        if let Some(ref cs) = self.cs {
+            cs.set(self.port, Io::Low);
+        }
+    }
+
+    fn deselect(&self) {
+        while self.spi.reg.sr.read().bsy().bit_is_set() {}
+        if let Some(ref cs) = self.cs {
+            cs.set(self.port, Io::High);
+        }
+    }
+}
+
+/// Window write in progress, see `Ili9341::flush`
+pub struct Flush<'a, S, D, P>
+    where S: 'a + Any + SPI,
+          D: 'a + Any + DMA,
+          P: 'a + Deref<Target=gpioa::RegisterBlock>
+{
+    spi: &'a Spi<'a, S, D>,
+    port: &'a P,
+    cs: Option<Pin<P>>,
+    pixels: &'static mut [u16],
+    /// Pixels handed to the DMA so far
+    sent: usize,
+}
+
+impl<'a, S, D, P> Flush<'a, S, D, P>
+    where S: Any + SPI,
+          D: Any + DMA,
+          P: Deref<Target=gpioa::RegisterBlock>
+{
+    /// Starts the next chunk when the current one is done
+    ///
+    /// Returns `Ok` once every pixel is out. Can be called from the TX
+    /// stream's interrupt (TCIE).
+    pub fn poll(&mut self) -> nb::Result<(), dma2::Error> {
+        let dma = self.spi.tx_stream()?;
+        if dma.has_transfer_error() {
+            dma.disable();
+            while dma.is_enabled() {}
+            dma.clear_flags();
+            self.sent = self.pixels.len();
+            self.deselect();
+            return Err(nb::Error::Other(dma2::Error::Transfer));
+        }
+        if !dma.is_transfer_complete() {
+            return Err(nb::Error::WouldBlock);
+        }
+        dma.clear_flags();
+
+        if self.sent < self.pixels.len() {
+            self.next_chunk().map_err(nb::Error::Other)?;
+            return Err(nb::Error::WouldBlock);
+        }
+        self.deselect();
+        Ok(())
+    }
+
+    /// Pixels not handed to the DMA yet
+    pub fn remaining(&self) -> usize {
+        self.pixels.len() - self.sent
+    }
+
+    /// Waits for the last chunk and gives the buffer back
+    pub fn wait(mut self) -> (&'static mut [u16], Result<(), dma2::Error>) {
+        let result = block!(self.poll());
+        (self.pixels, result)
+    }
+
+    fn next_chunk(&mut self) -> Result<(), dma2::Error> {
+        let end = if self.pixels.len() - self.sent > CHUNK {
+            self.sent + CHUNK
+        } else {
+            self.pixels.len()
+        };
+        // NOTE(start_tx) `self` keeps the buffer until the last chunk is out
+        self.spi.start_tx::<u16>(&self.pixels[self.sent..end])?;
+        self.sent = end;
+        Ok(())
+    }
+
+    fn deselect(&self) {
+        while self.spi.reg.sr.read().bsy().bit_is_set() {}
+        if let Some(ref cs) = self.cs {
+            cs.set(self.port, Io::High);
+        }
+    }
+}
@@ -0,0 +1,548 @@
+//! MPU-6050 family IMU driver (MPU-6000/6050/6500/9250, ICM-20602)
+//!
+//! The register interface is the same on all of these parts, only the way
+//! registers are reached differs. That is abstracted by the `Bus` trait;
+//! `SpiBus` implements it for the SPI capable parts (MPU-6000, MPU-6500,
+//! ICM-20602) and `I2cBus` for all of them, the I2C only MPU-6050 included:
+//!
+//! ``` ignore
+//! let i2c = I2c(i2c1);
+//! i2c.init(rcc, &timing);
+//! timer.set_timeout(Milliseconds(10));
+//!
+//! // FIFO bursts through DMA1 stream 0
+//! let bus = I2cBus::new(&i2c, &timer, ADDRESS).dma(&dma1.s0);
+//! let imu = Imu::new(&bus)?;
+//! ```
+//!
+//! The data ready output can be hooked to an EXTI line with
+//! `listen_data_ready`. For higher output data rates enable the FIFO and
+//! drain it in bursts with `read_fifo`.
+
+#[cfg(all(feature = "i2c", feature = "dma"))]
+use core::any::Any;
+use core::fmt;
+use core::ops::Deref;
+
+use hal;
+use stm32f411::{RCC, SYSCFG, gpioa};
+#[cfg(all(feature = "i2c", feature = "dma"))]
+use stm32f411::DMA1;
+
+#[cfg(all(feature = "i2c", feature = "dma"))]
+use dma2::Dma;
+use exti::{self, Edge, Exti};
+use gpio::{Io, Mode, Output, Pin, PinNumber, TypedPin};
+#[cfg(all(feature = "i2c", feature = "dma"))]
+use i2c::{self, I2c, I2C};
+use time::Hertz;
+
+/// I2C address with AD0 low
+pub const ADDRESS: u8 = 0x68;
+/// I2C address with AD0 high
+pub const ADDRESS_AD0: u8 = 0x69;
+
+const SMPLRT_DIV: u8 = 0x19;
+const CONFIG: u8 = 0x1A;
+const GYRO_CONFIG: u8 = 0x1B;
+const ACCEL_CONFIG: u8 = 0x1C;
+const FIFO_EN: u8 = 0x23;
+const INT_PIN_CFG: u8 = 0x37;
+const INT_ENABLE: u8 = 0x38;
+const INT_STATUS: u8 = 0x3A;
+const ACCEL_XOUT_H: u8 = 0x3B;
+const USER_CTRL: u8 = 0x6A;
+const PWR_MGMT_1: u8 = 0x6B;
+const FIFO_COUNTH: u8 = 0x72;
+const FIFO_R_W: u8 = 0x74;
+const WHO_AM_I: u8 = 0x75;
+
+// FIFO_EN: accel (XYZ) and gyro (XYZ)
+const FIFO_ACCEL_GYRO: u8 = 0b0111_1000;
+// USER_CTRL
+const USER_FIFO_EN: u8 = 1 << 6;
+const USER_FIFO_RESET: u8 = 1 << 2;
+// PWR_MGMT_1
+const DEVICE_RESET: u8 = 1 << 7;
+const CLKSEL_PLL: u8 = 0x01;
+// INT_ENABLE / INT_STATUS
+const DATA_RDY: u8 = 1 << 0;
+const FIFO_OFLOW: u8 = 1 << 4;
+
+/// Internal sample clock with the digital low pass filter enabled
+const GYRO_OUTPUT_RATE: u32 = 1_000;
+
+/// Size of one accel + gyro record in the FIFO
+const FIFO_RECORD: usize = 12;
+
+/// `PWR_MGMT_1` reads before `new` gives up on the reset, the reset takes
+/// about 100 ms and one read at least 20 us on either bus
+const RESET_POLLS: u32 = 10_000;
+
+/// Register access to the IMU
+pub trait Bus {
+    type Error;
+
+    fn write_register(&self, register: u8, value: u8) -> Result<(), Self::Error>;
+
+    /// Reads `buffer.len()` consecutive registers starting at `register`
+    ///
+    /// Reading `FIFO_R_W` repeatedly drains the FIFO, so implementations
+    /// should use a single burst transaction (DMA if available)
+    fn read_registers(&self, register: u8, buffer: &mut [u8]) -> Result<(), Self::Error>;
+}
+
+/// SPI bus with a GPIO chip select
+pub struct SpiBus<'a, S, T>
+    where S: 'a + hal::Spi<u8>,
+          T: 'a + Deref<Target=gpioa::RegisterBlock>
+{
+    spi: &'a S,
+    port: &'a T,
+    cs: Pin<T>,
+}
+
+impl<'a, S, T> SpiBus<'a, S, T>
+    where S: 'a + hal::Spi<u8>,
+          T: 'a + Deref<Target=gpioa::RegisterBlock>
+{
+    /// `spi` must be in mode 3, at most 1 MHz for register writes
+    pub fn new<N>(spi: &'a S, port: &'a T, cs: TypedPin<T, N, Output>) -> Self
+        where N: PinNumber
+    {
+        let cs = cs.erase();
+        cs.set(port, Io::High);
+        SpiBus { spi: spi, port: port, cs: cs }
+    }
+
+    fn exchange(&self, byte: u8) -> Result<u8, S::Error> {
+        block!(self.spi.send(byte))?;
+        block!(self.spi.read())
+    }
+}
+
+impl<'a, S, T> Bus for SpiBus<'a, S, T>
+    where S: 'a + hal::Spi<u8>,
+          T: 'a + Deref<Target=gpioa::RegisterBlock>
+{
+    type Error = S::Error;
+
+    fn write_register(&self, register: u8, value: u8) -> Result<(), S::Error> {
+        self.cs.set(self.port, Io::Low);
+        let result = self.exchange(register & 0x7F).and_then(|_| self.exchange(value));
+        self.cs.set(self.port, Io::High);
+        result.map(|_| ())
+    }
+
+    fn read_registers(&self, register: u8, buffer: &mut [u8]) -> Result<(), S::Error> {
+        self.cs.set(self.port, Io::Low);
+        let mut result = self.exchange(register | 0x80).map(|_| ());
+        for byte in buffer.iter_mut() {
+            if result.is_err() {
+                break;
+            }
+            result = self.exchange(0).map(|b| *byte = b);
+        }
+        self.cs.set(self.port, Io::High);
+        result
+    }
+}
+
+/// I2C bus, for every part of the family
+///
+/// Each register access is a transaction with a `timer` timeout, set it up
+/// with `set_timeout` beforehand. With a DMA stream, FIFO bursts are read
+/// through `I2c::write_read_dma`.
+#[cfg(all(feature = "i2c", feature = "dma"))]
+pub struct I2cBus<'a, I, T>
+    where I: 'a + Any + I2C,
+          T: 'a + hal::Timer
+{
+    i2c: &'a I2c<'a, I>,
+    timer: &'a T,
+    address: u8,
+    dma: Option<&'a Dma<'a, DMA1>>,
+}
+
+#[cfg(all(feature = "i2c", feature = "dma"))]
+impl<'a, I, T> I2cBus<'a, I, T>
+    where I: 'a + Any + I2C,
+          T: 'a + hal::Timer
+{
+    /// Device at `address` (`ADDRESS` or `ADDRESS_AD0`) on an `init`ialized
+    /// `i2c`
+    pub fn new(i2c: &'a I2c<'a, I>, timer: &'a T, address: u8) -> Self {
+        I2cBus {
+            i2c: i2c,
+            timer: timer,
+            address: address,
+            dma: None,
+        }
+    }
+
+    /// Reads bursts (2 bytes or more) through `dma`, a DMA1 stream wired to
+    /// the I2C RX request; see `I2c::write_read_dma`
+    pub fn dma(mut self, dma: &'a Dma<'a, DMA1>) -> Self {
+        self.dma = Some(dma);
+        self
+    }
+}
+
+#[cfg(all(feature = "i2c", feature = "dma"))]
+impl<'a, I, T> Bus for I2cBus<'a, I, T>
+    where I: 'a + Any + I2C,
+          T: 'a + hal::Timer
+{
+    type Error = i2c::Error;
+
+    fn write_register(&self, register: u8, value: u8) -> Result<(), i2c::Error> {
+        self.i2c.write(self.timer, self.address, &[register, value])
+    }
+
+    fn read_registers(&self, register: u8, buffer: &mut [u8]) -> Result<(), i2c::Error> {
+        match self.dma {
+            Some(dma) => self.i2c.write_read_dma(self.timer, dma, self.address, &[register],
+                                                 buffer),
+            None => self.i2c.write_read(self.timer, self.address, &[register], buffer),
+        }
+    }
+}
+
+/// IMU error
+#[derive(Debug)]
+pub enum Error<E> {
+    /// Bus error
+    Bus(E),
+    /// `WHO_AM_I` returned an unknown identifier
+    UnknownDevice(u8),
+    /// The device didn't come out of reset
+    ResetTimeout,
+    /// The FIFO overflowed, it has been reset
+    FifoOverflow,
+}
+
+impl<E> fmt::Display for Error<E>
+    where E: fmt::Debug
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Bus(ref e) => write!(f, "bus error: {:?}", e),
+            Error::UnknownDevice(id) => write!(f, "unknown IMU, WHO_AM_I = {:#04x}", id),
+            Error::ResetTimeout => f.write_str("IMU reset timeout"),
+            Error::FifoOverflow => f.write_str("IMU FIFO overflow"),
+        }
+    }
+}
+
+/// Detected part
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Model {
+    Mpu6050,
+    Mpu6500,
+    Mpu9250,
+    Icm20602,
+}
+
+/// Accelerometer full scale range
+#[derive(Clone, Copy, Debug)]
+pub enum AccelRange {
+    G2 = 0,
+    G4 = 1,
+    G8 = 2,
+    G16 = 3,
+}
+
+impl AccelRange {
+    /// LSB per g
+    fn sensitivity(&self) -> u32 {
+        16_384 >> (*self as u32)
+    }
+}
+
+/// Gyroscope full scale range
+#[derive(Clone, Copy, Debug)]
+pub enum GyroRange {
+    Dps250 = 0,
+    Dps500 = 1,
+    Dps1000 = 2,
+    Dps2000 = 3,
+}
+
+impl GyroRange {
+    /// LSB per 10 dps
+    fn sensitivity(&self) -> u32 {
+        1_310 >> (*self as u32)
+    }
+}
+
+/// Raw measurement
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RawSample {
+    pub accel: [i16; 3],
+    pub gyro: [i16; 3],
+}
+
+/// Measurement in g and degrees per second
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sample {
+    pub accel: [f32; 3],
+    pub gyro: [f32; 3],
+}
+
+/// Measurement in milli-g and milli-degrees per second
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FixedSample {
+    pub accel: [i32; 3],
+    pub gyro: [i32; 3],
+}
+
+/// MPU-6050 family IMU
+pub struct Imu<'a, B>
+    where B: 'a + Bus
+{
+    bus: &'a B,
+    model: Model,
+    accel_range: AccelRange,
+    gyro_range: GyroRange,
+}
+
+impl<'a, B> Imu<'a, B>
+    where B: 'a + Bus
+{
+    /// Checks `WHO_AM_I`, resets the device and wakes it up with the gyro PLL
+    /// as clock source
+    ///
+    /// Defaults to +-2 g, +-250 dps, 1 kHz output data rate. Fails with
+    /// `ResetTimeout` if the reset bit doesn't clear.
+    pub fn new(bus: &'a B) -> Result<Self, Error<B::Error>> {
+        let mut whoami = [0];
+        bus.read_registers(WHO_AM_I, &mut whoami).map_err(Error::Bus)?;
+        let model = match whoami[0] {
+            0x68 => Model::Mpu6050,
+            0x70 => Model::Mpu6500,
+            0x71 => Model::Mpu9250,
+            0x12 => Model::Icm20602,
+            id => return Err(Error::UnknownDevice(id)),
+        };
+
+        bus.write_register(PWR_MGMT_1, DEVICE_RESET).map_err(Error::Bus)?;
+        // The reset bit clears itself once the reset is complete
+        let mut polls = 0;
+        loop {
+            let mut pwr = [0];
+            bus.read_registers(PWR_MGMT_1, &mut pwr).map_err(Error::Bus)?;
+            if pwr[0] & DEVICE_RESET == 0 {
+                break;
+            }
+            polls += 1;
+            if polls == RESET_POLLS {
+                return Err(Error::ResetTimeout);
+            }
+        }
+        bus.write_register(PWR_MGMT_1, CLKSEL_PLL).map_err(Error::Bus)?;
+
+        let imu = Imu {
+            bus: bus,
+            model: model,
+            accel_range: AccelRange::G2,
+            gyro_range: GyroRange::Dps250,
+        };
+
+        // DLPF at ~188 Hz keeps the internal sample rate at 1 kHz
+        imu.write(CONFIG, 0x01)?;
+        imu.write(SMPLRT_DIV, 0)?;
+        imu.write(ACCEL_CONFIG, 0)?;
+        imu.write(GYRO_CONFIG, 0)?;
+
+        Ok(imu)
+    }
+
+    /// Part detected by `new`
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    /// Sets the output data rate, between ~4 Hz and 1 kHz
+    ///
+    /// Returns the rate that was actually programmed
+    pub fn set_sample_rate(&self, rate: Hertz) -> Result<Hertz, Error<B::Error>> {
+        let rate = if rate.0 == 0 { 1 } else { rate.0 };
+        let div = (GYRO_OUTPUT_RATE / rate).saturating_sub(1);
+        let div = if div > 0xFF { 0xFF } else { div };
+        self.write(SMPLRT_DIV, div as u8)?;
+        Ok(Hertz(GYRO_OUTPUT_RATE / (div + 1)))
+    }
+
+    pub fn set_accel_range(&mut self, range: AccelRange) -> Result<(), Error<B::Error>> {
+        self.write(ACCEL_CONFIG, (range as u8) << 3)?;
+        self.accel_range = range;
+        Ok(())
+    }
+
+    pub fn set_gyro_range(&mut self, range: GyroRange) -> Result<(), Error<B::Error>> {
+        self.write(GYRO_CONFIG, (range as u8) << 3)?;
+        self.gyro_range = range;
+        Ok(())
+    }
+
+    /// Reads the latest measurement
+    pub fn read_raw(&self) -> Result<RawSample, Error<B::Error>> {
+        // accel XYZ, temperature, gyro XYZ
+        let mut buffer = [0; 14];
+        self.bus.read_registers(ACCEL_XOUT_H, &mut buffer).map_err(Error::Bus)?;
+
+        let mut sample = RawSample::default();
+        for i in 0..3 {
+            sample.accel[i] = be16(&buffer[2 * i..]);
+            sample.gyro[i] = be16(&buffer[8 + 2 * i..]);
+        }
+        Ok(sample)
+    }
+
+    /// Reads the latest measurement in g and dps
+    pub fn read(&self) -> Result<Sample, Error<B::Error>> {
+        self.read_raw().map(|raw| self.scale(&raw))
+    }
+
+    /// Reads the latest measurement in milli-g and milli-dps
+    pub fn read_fixed(&self) -> Result<FixedSample, Error<B::Error>> {
+        self.read_raw().map(|raw| self.scale_fixed(&raw))
+    }
+
+    /// Reads the die temperature in hundredths of a degree Celsius
+    pub fn read_temperature(&self) -> Result<i32, Error<B::Error>> {
+        let mut buffer = [0; 2];
+        self.bus.read_registers(ACCEL_XOUT_H + 6, &mut buffer).map_err(Error::Bus)?;
+        let raw = be16(&buffer) as i32;
+        Ok(match self.model {
+            Model::Mpu6050 => raw * 100 / 340 + 3653,
+            Model::Icm20602 => raw * 1000 / 3268 + 2500,
+            Model::Mpu6500 | Model::Mpu9250 => raw * 1000 / 3338 + 2100,
+        })
+    }
+
+    /// Converts a raw measurement to g and dps
+    pub fn scale(&self, raw: &RawSample) -> Sample {
+        let accel = self.accel_range.sensitivity() as f32;
+        let gyro = self.gyro_range.sensitivity() as f32 / 10.;
+        let mut sample = Sample::default();
+        for i in 0..3 {
+            sample.accel[i] = raw.accel[i] as f32 / accel;
+            sample.gyro[i] = raw.gyro[i] as f32 / gyro;
+        }
+        sample
+    }
+
+    /// Converts a raw measurement to milli-g and milli-dps
+    pub fn scale_fixed(&self, raw: &RawSample) -> FixedSample {
+        let accel = self.accel_range.sensitivity() as i32;
+        let gyro = self.gyro_range.sensitivity() as i32;
+        let mut sample = FixedSample::default();
+        for i in 0..3 {
+            sample.accel[i] = raw.accel[i] as i32 * 1_000 / accel;
+            sample.gyro[i] = raw.gyro[i] as i32 * 10_000 / gyro;
+        }
+        sample
+    }
+
+    /// Enables the data ready interrupt and routes the INT pin (`line` of
+    /// `port`) to its EXTI line
+    ///
+    /// The INT pin is push-pull active high; reading `INT_STATUS` (done by
+    /// `data_ready`) clears it
+    pub fn listen_data_ready<T>(&self, int: Pin<T>, gpio: &T, line: u8, port: exti::Port,
+                                exti: &Exti, syscfg: &SYSCFG, rcc: &RCC)
+        -> Result<(), Error<B::Error>>
+        where T: Deref<Target=gpioa::RegisterBlock>
+    {
+        int.set_mode(gpio, Mode::Input);
+        // LATCH_INT_EN | INT_RD_CLEAR
+        self.write(INT_PIN_CFG, 0x30)?;
+        self.write(INT_ENABLE, DATA_RDY)?;
+        exti.init(line, port, Edge::Rising, syscfg, rcc);
+        exti.listen(line);
+        Ok(())
+    }
+
+    /// Disables the data ready interrupt
+    pub fn unlisten_data_ready(&self) -> Result<(), Error<B::Error>> {
+        self.write(INT_ENABLE, 0)
+    }
+
+    /// Checks, and clears, the data ready flag
+    pub fn data_ready(&self) -> Result<bool, Error<B::Error>> {
+        let mut status = [0];
+        self.bus.read_registers(INT_STATUS, &mut status).map_err(Error::Bus)?;
+        Ok(status[0] & DATA_RDY != 0)
+    }
+
+    /// Starts storing accel and gyro samples in the FIFO
+    ///
+    /// The FIFO is emptied first. The other `USER_CTRL` bits (I2C master,
+    /// I2C interface disable on the SPI parts) are kept.
+    pub fn enable_fifo(&self) -> Result<(), Error<B::Error>> {
+        self.modify(USER_CTRL, USER_FIFO_EN, USER_FIFO_RESET)?;
+        self.write(FIFO_EN, FIFO_ACCEL_GYRO)?;
+        self.modify(USER_CTRL, USER_FIFO_RESET, USER_FIFO_EN)
+    }
+
+    pub fn disable_fifo(&self) -> Result<(), Error<B::Error>> {
+        self.write(FIFO_EN, 0)?;
+        self.modify(USER_CTRL, USER_FIFO_EN, 0)
+    }
+
+    /// Number of complete samples stored in the FIFO
+    pub fn fifo_len(&self) -> Result<usize, Error<B::Error>> {
+        let mut count = [0; 2];
+        self.bus.read_registers(FIFO_COUNTH, &mut count).map_err(Error::Bus)?;
+        Ok((((count[0] as usize) << 8) | count[1] as usize) / FIFO_RECORD)
+    }
+
+    /// Drains up to `samples.len()` samples from the FIFO
+    ///
+    /// `scratch` must hold at least `FIFO_RECORD * samples.len()` bytes, the
+    /// whole batch is read with a single burst. Returns the number of samples
+    /// read.
+    pub fn read_fifo(&self, samples: &mut [RawSample], scratch: &mut [u8])
+        -> Result<usize, Error<B::Error>>
+    {
+        let mut status = [0];
+        self.bus.read_registers(INT_STATUS, &mut status).map_err(Error::Bus)?;
+        if status[0] & FIFO_OFLOW != 0 {
+            self.enable_fifo()?;
+            return Err(Error::FifoOverflow);
+        }
+
+        let available = self.fifo_len()?;
+        let mut n = if available < samples.len() { available } else { samples.len() };
+        if scratch.len() / FIFO_RECORD < n {
+            n = scratch.len() / FIFO_RECORD;
+        }
+
+        let bytes = &mut scratch[..n * FIFO_RECORD];
+        self.bus.read_registers(FIFO_R_W, bytes).map_err(Error::Bus)?;
+
+        for (sample, record) in samples.iter_mut().zip(bytes.chunks(FIFO_RECORD)) {
+            for i in 0..3 {
+                sample.accel[i] = be16(&record[2 * i..]);
+                sample.gyro[i] = be16(&record[6 + 2 * i..]);
+            }
+        }
+        Ok(n)
+    }
+
+    fn write(&self, register: u8, value: u8) -> Result<(), Error<B::Error>> {
+        self.bus.write_register(register, value).map_err(Error::Bus)
+    }
+
+    /// Read-modify-write of `register`
+    fn modify(&self, register: u8, clear: u8, set: u8) -> Result<(), Error<B::Error>> {
+        let mut value = [0];
+        self.bus.read_registers(register, &mut value).map_err(Error::Bus)?;
+        self.write(register, (value[0] & !clear) | set)
+    }
+}
+
+fn be16(bytes: &[u8]) -> i16 {
+    (((bytes[0] as u16) << 8) | bytes[1] as u16) as i16
+}
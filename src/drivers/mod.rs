@@ -0,0 +1,14 @@
+//! Drivers for external devices commonly wired to the STM32F411
+
+pub mod hd44780;
+pub mod font;
+#[cfg(feature = "spi")]
+pub mod ssd1306;
+#[cfg(feature = "spi")]
+pub mod ili9341;
+pub mod nrf24;
+pub mod imu;
+pub mod sdspi;
+pub mod hcsr04;
+pub mod dht;
+pub mod onewire;
@@ -0,0 +1,425 @@
+//! nRF24L01(+) 2.4 GHz transceiver driver
+//!
+//! The radio is driven through any `hal::Spi<u8>` (mode 0, <= 8 MHz), a CSN
+//! and a CE GPIO. The active low IRQ line can be hooked to an EXTI line with
+//! `listen_irq`; the EXTI handler then calls `interrupts` to find out (and
+//! acknowledge) what happened.
+//!
+//! Payloads are at most 32 bytes. With dynamic payloads enabled the length of
+//! each received packet is read back from the radio, otherwise every pipe uses
+//! the static width given to `open_rx_pipe`.
+
+use core::fmt;
+use core::ops::Deref;
+
+use hal;
+use nb;
+use stm32f411::{RCC, SYSCFG, gpioa};
+
+use exti::{self, Edge, Exti};
+use gpio::{Io, Mode, Output, Pin, PinNumber, TypedPin};
+
+/// Maximum payload size
+pub const MAX_PAYLOAD: usize = 32;
+/// Highest RX pipe number
+const MAX_PIPE: u8 = 5;
+
+// Registers
+const CONFIG: u8 = 0x00;
+const EN_AA: u8 = 0x01;
+const EN_RXADDR: u8 = 0x02;
+const SETUP_AW: u8 = 0x03;
+const SETUP_RETR: u8 = 0x04;
+const RF_CH: u8 = 0x05;
+const RF_SETUP: u8 = 0x06;
+const STATUS: u8 = 0x07;
+const RX_ADDR_P0: u8 = 0x0A;
+const TX_ADDR: u8 = 0x10;
+const RX_PW_P0: u8 = 0x11;
+const FIFO_STATUS: u8 = 0x17;
+const DYNPD: u8 = 0x1C;
+const FEATURE: u8 = 0x1D;
+
+// Commands
+const R_REGISTER: u8 = 0x00;
+const W_REGISTER: u8 = 0x20;
+const R_RX_PL_WID: u8 = 0x60;
+const R_RX_PAYLOAD: u8 = 0x61;
+const W_TX_PAYLOAD: u8 = 0xA0;
+const W_TX_PAYLOAD_NOACK: u8 = 0xB0;
+const FLUSH_TX: u8 = 0xE1;
+const FLUSH_RX: u8 = 0xE2;
+const NOP: u8 = 0xFF;
+
+// CONFIG bits
+const EN_CRC: u8 = 1 << 3;
+const CRCO: u8 = 1 << 2;
+const PWR_UP: u8 = 1 << 1;
+const PRIM_RX: u8 = 1 << 0;
+
+// STATUS bits
+const RX_DR: u8 = 1 << 6;
+const TX_DS: u8 = 1 << 5;
+const MAX_RT: u8 = 1 << 4;
+
+// FEATURE bits
+const EN_DPL: u8 = 1 << 2;
+const EN_DYN_ACK: u8 = 1 << 0;
+
+// FIFO_STATUS bits
+const RX_EMPTY: u8 = 1 << 0;
+
+/// Radio error
+#[derive(Debug)]
+pub enum Error<E> {
+    /// SPI bus error
+    Spi(E),
+    /// No acknowledgement received after all retransmissions
+    MaxRetries,
+    /// Payload longer than 32 bytes or pipe number out of range
+    InvalidArgument,
+}
+
+impl<E> fmt::Display for Error<E>
+    where E: fmt::Debug
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Spi(ref e) => write!(f, "SPI error: {:?}", e),
+            Error::MaxRetries => f.write_str("nRF24 no ACK after max retries"),
+            Error::InvalidArgument => f.write_str("nRF24 invalid payload length or pipe"),
+        }
+    }
+}
+
+/// Air data rate
+#[derive(Clone, Copy, Debug)]
+pub enum DataRate {
+    R250Kbps,
+    R1Mbps,
+    R2Mbps,
+}
+
+/// TX output power
+#[derive(Clone, Copy, Debug)]
+pub enum Power {
+    /// -18 dBm
+    Min = 0b00,
+    /// -12 dBm
+    Low = 0b01,
+    /// -6 dBm
+    High = 0b10,
+    /// 0 dBm
+    Max = 0b11,
+}
+
+/// Pending interrupt sources, returned by `interrupts`
+#[derive(Clone, Copy, Debug)]
+pub struct Interrupts {
+    /// A packet arrived in the RX FIFO
+    pub rx_ready: bool,
+    /// A packet was sent (and acknowledged, if auto-ack is on)
+    pub tx_done: bool,
+    /// Retransmission limit reached
+    pub max_retries: bool,
+}
+
+/// nRF24L01 radio
+pub struct Nrf24<'a, S, T>
+    where S: 'a + hal::Spi<u8>,
+          T: 'a + Deref<Target=gpioa::RegisterBlock>
+{
+    spi: &'a S,
+    port: &'a T,
+    csn: Pin<T>,
+    ce: Pin<T>,
+    dynamic_payloads: bool,
+}
+
+impl<'a, S, T> Nrf24<'a, S, T>
+    where S: 'a + hal::Spi<u8>,
+          T: 'a + Deref<Target=gpioa::RegisterBlock>
+{
+    pub fn new<CSN, CE>(spi: &'a S, port: &'a T, csn: TypedPin<T, CSN, Output>,
+                        ce: TypedPin<T, CE, Output>) -> Self
+        where CSN: PinNumber,
+              CE: PinNumber
+    {
+        Nrf24 {
+            spi: spi,
+            port: port,
+            csn: csn.erase(),
+            ce: ce.erase(),
+            dynamic_payloads: false,
+        }
+    }
+
+    /// Configures the radio and leaves it powered up in standby
+    ///
+    /// 2 byte CRC, 5 byte addresses, up to 15 retransmissions 1.5 ms apart
+    ///
+    /// NOTE The radio needs 100 ms after power on before it accepts commands
+    pub fn init(&mut self, channel: u8, rate: DataRate, power: Power) -> Result<(), Error<S::Error>> {
+        self.csn.set(self.port, Io::High);
+        self.ce.set(self.port, Io::Low);
+
+        self.write_register(SETUP_AW, 0b11)?;
+        self.write_register(SETUP_RETR, (5 << 4) | 15)?;
+        self.set_channel(channel)?;
+        self.set_rf(rate, power)?;
+        self.write_register(FEATURE, 0)?;
+        self.write_register(DYNPD, 0)?;
+        self.write_register(STATUS, RX_DR | TX_DS | MAX_RT)?;
+        self.command(FLUSH_RX)?;
+        self.command(FLUSH_TX)?;
+        self.write_register(CONFIG, EN_CRC | CRCO | PWR_UP)
+    }
+
+    /// Selects the RF channel, `2400 + channel` MHz
+    pub fn set_channel(&self, channel: u8) -> Result<(), Error<S::Error>> {
+        self.write_register(RF_CH, channel & 0x7F)
+    }
+
+    /// Sets the air data rate and output power
+    pub fn set_rf(&self, rate: DataRate, power: Power) -> Result<(), Error<S::Error>> {
+        let rate = match rate {
+            DataRate::R250Kbps => 1 << 5,
+            DataRate::R1Mbps => 0,
+            DataRate::R2Mbps => 1 << 3,
+        };
+        self.write_register(RF_SETUP, rate | ((power as u8) << 1))
+    }
+
+    /// Enables dynamic payload length on every pipe
+    ///
+    /// Also allows `send_no_ack`
+    pub fn enable_dynamic_payloads(&mut self) -> Result<(), Error<S::Error>> {
+        self.write_register(FEATURE, EN_DPL | EN_DYN_ACK)?;
+        self.write_register(DYNPD, 0b11_1111)?;
+        self.dynamic_payloads = true;
+        Ok(())
+    }
+
+    /// Sets the destination address
+    ///
+    /// Pipe 0 receives on the same address so the auto-ack reply comes back
+    pub fn set_tx_address(&self, address: &[u8; 5]) -> Result<(), Error<S::Error>> {
+        self.write_registers(TX_ADDR, address)?;
+        self.write_registers(RX_ADDR_P0, address)
+    }
+
+    /// Opens RX `pipe` (0 to 5) on `address`
+    ///
+    /// Pipes 2 to 5 share bytes 1 to 4 of pipe 1's address, only `address[0]`
+    /// is used for them. `width` is the static payload width, ignored when
+    /// dynamic payloads are enabled.
+    pub fn open_rx_pipe(&self, pipe: u8, address: &[u8; 5], width: u8, auto_ack: bool)
+        -> Result<(), Error<S::Error>>
+    {
+        if pipe > MAX_PIPE || width as usize > MAX_PAYLOAD {
+            return Err(Error::InvalidArgument);
+        }
+
+        if pipe < 2 {
+            self.write_registers(RX_ADDR_P0 + pipe, address)?;
+        } else {
+            self.write_register(RX_ADDR_P0 + pipe, address[0])?;
+        }
+        self.write_register(RX_PW_P0 + pipe, width)?;
+
+        let en_aa = self.read_register(EN_AA)?;
+        let en_aa = if auto_ack { en_aa | (1 << pipe) } else { en_aa & !(1 << pipe) };
+        self.write_register(EN_AA, en_aa)?;
+
+        let en_rxaddr = self.read_register(EN_RXADDR)?;
+        self.write_register(EN_RXADDR, en_rxaddr | (1 << pipe))
+    }
+
+    /// Closes RX `pipe` (0 to 5)
+    pub fn close_rx_pipe(&self, pipe: u8) -> Result<(), Error<S::Error>> {
+        if pipe > MAX_PIPE {
+            return Err(Error::InvalidArgument);
+        }
+
+        let en_rxaddr = self.read_register(EN_RXADDR)?;
+        self.write_register(EN_RXADDR, en_rxaddr & !(1 << pipe))
+    }
+
+    /// Enters RX mode and starts listening
+    pub fn rx_mode(&self) -> Result<(), Error<S::Error>> {
+        self.ce.set(self.port, Io::Low);
+        let config = self.read_register(CONFIG)?;
+        self.write_register(CONFIG, config | PWR_UP | PRIM_RX)?;
+        self.write_register(STATUS, RX_DR | TX_DS | MAX_RT)?;
+        self.ce.set(self.port, Io::High);
+        Ok(())
+    }
+
+    /// Enters TX mode, packets are sent as soon as they are queued
+    pub fn tx_mode(&self) -> Result<(), Error<S::Error>> {
+        self.ce.set(self.port, Io::Low);
+        let config = self.read_register(CONFIG)?;
+        self.write_register(CONFIG, (config | PWR_UP) & !PRIM_RX)?;
+        self.ce.set(self.port, Io::High);
+        Ok(())
+    }
+
+    /// Goes back to standby I, stops listening / transmitting
+    pub fn standby(&self) {
+        self.ce.set(self.port, Io::Low);
+    }
+
+    /// Powers the radio down
+    pub fn power_down(&self) -> Result<(), Error<S::Error>> {
+        self.ce.set(self.port, Io::Low);
+        let config = self.read_register(CONFIG)?;
+        self.write_register(CONFIG, config & !PWR_UP)
+    }
+
+    /// Queues a packet for transmission, must be in TX mode
+    pub fn send(&self, payload: &[u8]) -> Result<(), Error<S::Error>> {
+        if payload.len() > MAX_PAYLOAD {
+            return Err(Error::InvalidArgument);
+        }
+        self.transaction(W_TX_PAYLOAD, payload, &mut [])?;
+        Ok(())
+    }
+
+    /// Queues a packet that won't be acknowledged by the receiver
+    ///
+    /// NOTE Requires `enable_dynamic_payloads`
+    pub fn send_no_ack(&self, payload: &[u8]) -> Result<(), Error<S::Error>> {
+        if payload.len() > MAX_PAYLOAD {
+            return Err(Error::InvalidArgument);
+        }
+        self.transaction(W_TX_PAYLOAD_NOACK, payload, &mut [])?;
+        Ok(())
+    }
+
+    /// Checks the outcome of the last `send`
+    ///
+    /// On `MaxRetries` the TX FIFO is flushed
+    pub fn poll_send(&self) -> nb::Result<(), Error<S::Error>> {
+        let status = self.status().map_err(nb::Error::Other)?;
+
+        if status & MAX_RT != 0 {
+            self.write_register(STATUS, MAX_RT).map_err(nb::Error::Other)?;
+            self.command(FLUSH_TX).map_err(nb::Error::Other)?;
+            Err(nb::Error::Other(Error::MaxRetries))
+        } else if status & TX_DS != 0 {
+            self.write_register(STATUS, TX_DS).map_err(nb::Error::Other)?;
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
+    /// Reads the next packet from the RX FIFO into `buffer`
+    ///
+    /// Returns the pipe it arrived on and its length
+    pub fn read(&self, buffer: &mut [u8; MAX_PAYLOAD]) -> nb::Result<(u8, usize), Error<S::Error>> {
+        let fifo = self.read_register(FIFO_STATUS).map_err(nb::Error::Other)?;
+        if fifo & RX_EMPTY != 0 {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        let status = self.status().map_err(nb::Error::Other)?;
+        let pipe = (status >> 1) & 0b111;
+        if pipe > MAX_PIPE {
+            // RX_P_NO reads 0b111 once the FIFO emptied since FIFO_STATUS
+            return Err(nb::Error::WouldBlock);
+        }
+
+        let len = if self.dynamic_payloads {
+            let mut width = [0];
+            self.transaction(R_RX_PL_WID, &[], &mut width).map_err(nb::Error::Other)?;
+            if width[0] as usize > MAX_PAYLOAD {
+                // Corrupted packet, see the datasheet
+                self.command(FLUSH_RX).map_err(nb::Error::Other)?;
+                return Err(nb::Error::WouldBlock);
+            }
+            width[0] as usize
+        } else {
+            self.read_register(RX_PW_P0 + pipe).map_err(nb::Error::Other)? as usize
+        };
+
+        self.transaction(R_RX_PAYLOAD, &[], &mut buffer[..len]).map_err(nb::Error::Other)?;
+        self.write_register(STATUS, RX_DR).map_err(nb::Error::Other)?;
+
+        Ok((pipe, len))
+    }
+
+    /// Reads and acknowledges the pending interrupt sources
+    ///
+    /// Call this from the EXTI handler of the IRQ line. `RX_DR` is left set
+    /// when the RX FIFO still holds packets; drain it with `read`.
+    pub fn interrupts(&self) -> Result<Interrupts, Error<S::Error>> {
+        let status = self.status()?;
+        self.write_register(STATUS, status & (TX_DS | MAX_RT))?;
+
+        Ok(Interrupts {
+            rx_ready: status & RX_DR != 0,
+            tx_done: status & TX_DS != 0,
+            max_retries: status & MAX_RT != 0,
+        })
+    }
+
+    /// Routes the IRQ pin (`line` of `port`) to its EXTI line and unmasks it
+    ///
+    /// The IRQ output is active low so the line triggers on the falling edge
+    pub fn listen_irq(&self, irq: Pin<T>, line: u8, port: exti::Port, exti: &Exti,
+                      syscfg: &SYSCFG, rcc: &RCC) {
+        irq.set_mode(self.port, Mode::Input);
+        exti.init(line, port, Edge::Falling, syscfg, rcc);
+        exti.listen(line);
+    }
+
+    /// Reads the STATUS register
+    pub fn status(&self) -> Result<u8, Error<S::Error>> {
+        self.command(NOP)
+    }
+
+    pub fn read_register(&self, register: u8) -> Result<u8, Error<S::Error>> {
+        let mut value = [0];
+        self.transaction(R_REGISTER | register, &[], &mut value)?;
+        Ok(value[0])
+    }
+
+    pub fn write_register(&self, register: u8, value: u8) -> Result<(), Error<S::Error>> {
+        self.write_registers(register, &[value])
+    }
+
+    fn write_registers(&self, register: u8, values: &[u8]) -> Result<(), Error<S::Error>> {
+        self.transaction(W_REGISTER | register, values, &mut [])?;
+        Ok(())
+    }
+
+    /// Sends a single byte command, returns STATUS
+    fn command(&self, cmd: u8) -> Result<u8, Error<S::Error>> {
+        self.transaction(cmd, &[], &mut [])
+    }
+
+    /// Sends `cmd` followed by `tx`, then clocks in `rx`. Returns STATUS
+    fn transaction(&self, cmd: u8, tx: &[u8], rx: &mut [u8]) -> Result<u8, Error<S::Error>> {
+        self.csn.set(self.port, Io::Low);
+
+        let result = (|| -> Result<u8, Error<S::Error>> {
+            let status = self.exchange(cmd)?;
+            for byte in tx {
+                self.exchange(*byte)?;
+            }
+            for byte in rx.iter_mut() {
+                *byte = self.exchange(NOP)?;
+            }
+            Ok(status)
+        })();
+
+        self.csn.set(self.port, Io::High);
+        result
+    }
+
+    fn exchange(&self, byte: u8) -> Result<u8, Error<S::Error>> {
+        block!(self.spi.send(byte)).map_err(Error::Spi)?;
+        block!(self.spi.read()).map_err(Error::Spi)
+    }
+}
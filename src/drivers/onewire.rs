@@ -0,0 +1,339 @@
+//! 1-Wire bus master and DS18B20 thermometer
+//!
+//! The bus is a single open-drain GPIO with a 4.7k pull up. Bit slots are
+//! timed with `delay::CyclesToTime` busy-waits and run with interrupts
+//! disabled, the longest critical section is a 70 us slot (the reset pulse
+//! itself is interruptible).
+//!
+//! ``` ignore
+//! let pin = TypedPin::<GPIOB, N7, Input>::new().into_output(gpiob);
+//! let bus = OneWire::new(gpiob, pin, &clocks);
+//!
+//! let mut search = Search::new();
+//! while let Some(rom) = bus.search(&mut search)? {
+//!     if rom.family() == ds18b20::FAMILY {
+//!         let sensor = ds18b20::Ds18b20(Some(rom));
+//!         sensor.start_conversion(&bus)?;
+//!         // 750 ms at 12-bit resolution
+//!         while !bus.read_bit() {}
+//!         let millicelsius = sensor.read_temperature(&bus)?;
+//!     }
+//! }
+//! ```
+
+use core::fmt;
+use core::ops::Deref;
+
+use cortex_m::interrupt;
+use stm32f411::gpioa;
+
+use clock_switch::ClockListener;
+use delay::CyclesToTime;
+use gpio::{Io, Output, Pin, PinNumber, TypedPin};
+use rcc::Clocks;
+use time::Microseconds;
+
+const SEARCH_ROM: u8 = 0xF0;
+const MATCH_ROM: u8 = 0x55;
+const SKIP_ROM: u8 = 0xCC;
+
+/// Bus error
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// No device answered the reset pulse
+    NoPresence,
+    /// CRC mismatch on a ROM code or a scratchpad
+    Crc,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Error::NoPresence => "no 1-Wire device present",
+            Error::Crc => "1-Wire CRC mismatch",
+        })
+    }
+}
+
+/// 64-bit ROM code: family code, serial number, CRC
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Rom(pub [u8; 8]);
+
+impl Rom {
+    /// Family code, identifies the device type
+    pub fn family(&self) -> u8 {
+        self.0[0]
+    }
+}
+
+/// State of a ROM search, see `OneWire::search`
+#[derive(Clone, Copy, Debug)]
+pub struct Search {
+    rom: [u8; 8],
+    last_discrepancy: u8,
+    done: bool,
+}
+
+impl Search {
+    pub fn new() -> Self {
+        Search { rom: [0; 8], last_discrepancy: 0, done: false }
+    }
+}
+
+/// 1-Wire bus master on one GPIO pin
+pub struct OneWire<'a, P>
+    where P: 'a + Deref<Target=gpioa::RegisterBlock>
+{
+    port: &'a P,
+    pin: Pin<P>,
+    delay: CyclesToTime,
+}
+
+impl<'a, P> OneWire<'a, P>
+    where P: Deref<Target=gpioa::RegisterBlock>
+{
+    /// Makes `pin` open-drain, released (high)
+    ///
+    /// `clocks` calibrates the bit timing
+    pub fn new<N>(port: &'a P, pin: TypedPin<P, N, Output>, clocks: &Clocks) -> Self
+        where N: PinNumber
+    {
+        let pin = pin.erase();
+        pin.set(port, Io::High);
+        pin.set_open_drain(port, true);
+
+        OneWire { port: port, pin: pin, delay: CyclesToTime::new(clocks) }
+    }
+
+    /// Reset pulse, returns whether a device answered with a presence pulse
+    pub fn reset(&self) -> bool {
+        self.pin.set(self.port, Io::Low);
+        self.delay.delay_us(Microseconds(480));
+
+        let present = interrupt::free(|_| {
+            self.pin.set(self.port, Io::High);
+            self.delay.delay_us(Microseconds(70));
+            !self.is_high()
+        });
+
+        // End of the presence pulse and recovery
+        self.delay.delay_us(Microseconds(410));
+        present
+    }
+
+    /// Write slot
+    pub fn write_bit(&self, bit: bool) {
+        interrupt::free(|_| {
+            self.pin.set(self.port, Io::Low);
+            if bit {
+                self.delay.delay_us(Microseconds(6));
+                self.pin.set(self.port, Io::High);
+                self.delay.delay_us(Microseconds(64));
+            } else {
+                self.delay.delay_us(Microseconds(60));
+                self.pin.set(self.port, Io::High);
+                self.delay.delay_us(Microseconds(10));
+            }
+        })
+    }
+
+    /// Read slot
+    pub fn read_bit(&self) -> bool {
+        interrupt::free(|_| {
+            self.pin.set(self.port, Io::Low);
+            self.delay.delay_us(Microseconds(6));
+            self.pin.set(self.port, Io::High);
+            self.delay.delay_us(Microseconds(9));
+            let bit = self.is_high();
+            self.delay.delay_us(Microseconds(55));
+            bit
+        })
+    }
+
+    /// Writes a byte, LSB first
+    pub fn write_byte(&self, byte: u8) {
+        for i in 0..8 {
+            self.write_bit(byte & (1 << i) != 0);
+        }
+    }
+
+    /// Reads a byte, LSB first
+    pub fn read_byte(&self) -> u8 {
+        let mut byte = 0;
+        for i in 0..8 {
+            if self.read_bit() {
+                byte |= 1 << i;
+            }
+        }
+        byte
+    }
+
+    /// Resets the bus and addresses `rom`, or every device with `None`
+    pub fn select(&self, rom: Option<&Rom>) -> Result<(), Error> {
+        if !self.reset() {
+            return Err(Error::NoPresence);
+        }
+
+        match rom {
+            Some(rom) => {
+                self.write_byte(MATCH_ROM);
+                for byte in rom.0.iter() {
+                    self.write_byte(*byte);
+                }
+            }
+            None => self.write_byte(SKIP_ROM),
+        }
+        Ok(())
+    }
+
+    /// Finds the next device on the bus, `None` once all were found
+    ///
+    /// Implements the search algorithm of Maxim AN187: each call walks the
+    /// ROM tree taking the other branch at the deepest discrepancy of the
+    /// previous call.
+    pub fn search(&self, search: &mut Search) -> Result<Option<Rom>, Error> {
+        if search.done {
+            return Ok(None);
+        }
+        if !self.reset() {
+            *search = Search::new();
+            return Err(Error::NoPresence);
+        }
+
+        self.write_byte(SEARCH_ROM);
+
+        let mut last_zero = 0;
+        for n in 1..65u8 {
+            let byte = usize::from((n - 1) / 8);
+            let mask = 1 << ((n - 1) % 8);
+
+            let bit = self.read_bit();
+            let complement = self.read_bit();
+            let direction = match (bit, complement) {
+                // Every device left the search
+                (true, true) => {
+                    *search = Search::new();
+                    return Err(Error::NoPresence);
+                }
+                (false, false) => {
+                    let direction = if n < search.last_discrepancy {
+                        search.rom[byte] & mask != 0
+                    } else {
+                        n == search.last_discrepancy
+                    };
+                    if !direction {
+                        last_zero = n;
+                    }
+                    direction
+                }
+                // All remaining devices agree on this bit
+                (bit, _) => bit,
+            };
+
+            if direction {
+                search.rom[byte] |= mask;
+            } else {
+                search.rom[byte] &= !mask;
+            }
+            self.write_bit(direction);
+        }
+
+        search.last_discrepancy = last_zero;
+        search.done = last_zero == 0;
+
+        if crc8(&search.rom[..7]) != search.rom[7] {
+            *search = Search::new();
+            return Err(Error::Crc);
+        }
+        Ok(Some(Rom(search.rom)))
+    }
+
+    fn is_high(&self) -> bool {
+        match self.pin.get(self.port) {
+            Io::High => true,
+            Io::Low => false,
+        }
+    }
+}
+
+impl<'a, P> ClockListener for OneWire<'a, P>
+    where P: Deref<Target=gpioa::RegisterBlock>
+{
+    /// Keeps the slot timing
+    fn clocks_changed(&self, old: &Clocks, new: &Clocks) {
+        self.delay.clocks_changed(old, new);
+    }
+}
+
+/// Dallas / Maxim CRC-8 (x^8 + x^5 + x^4 + 1), used by ROM codes and
+/// scratchpads
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in data {
+        let mut byte = *byte;
+        for _ in 0..8 {
+            let mix = (crc ^ byte) & 1;
+            crc >>= 1;
+            if mix != 0 {
+                crc ^= 0x8C;
+            }
+            byte >>= 1;
+        }
+    }
+    crc
+}
+
+/// DS18B20 thermometer
+pub mod ds18b20 {
+    use core::ops::Deref;
+
+    use stm32f411::gpioa;
+
+    use super::{crc8, Error, OneWire, Rom};
+
+    /// Family code of the DS18B20
+    pub const FAMILY: u8 = 0x28;
+
+    const CONVERT_T: u8 = 0x44;
+    const READ_SCRATCHPAD: u8 = 0xBE;
+
+    /// DS18B20 at `rom`, or the only device on the bus with `None`
+    #[derive(Clone, Copy, Debug)]
+    pub struct Ds18b20(pub Option<Rom>);
+
+    impl Ds18b20 {
+        /// Starts a temperature conversion
+        ///
+        /// Takes up to 750 ms at the default 12-bit resolution; the device
+        /// answers read slots with 0 until it's done. Parasite powered
+        /// devices need a strong pull up meanwhile, not supported here.
+        pub fn start_conversion<P>(&self, bus: &OneWire<P>) -> Result<(), Error>
+            where P: Deref<Target=gpioa::RegisterBlock>
+        {
+            bus.select(self.0.as_ref())?;
+            bus.write_byte(CONVERT_T);
+            Ok(())
+        }
+
+        /// Reads the result of the last conversion, in thousandths of degree
+        /// Celsius
+        pub fn read_temperature<P>(&self, bus: &OneWire<P>) -> Result<i32, Error>
+            where P: Deref<Target=gpioa::RegisterBlock>
+        {
+            bus.select(self.0.as_ref())?;
+            bus.write_byte(READ_SCRATCHPAD);
+
+            let mut scratchpad = [0; 9];
+            for byte in scratchpad.iter_mut() {
+                *byte = bus.read_byte();
+            }
+            if crc8(&scratchpad[..8]) != scratchpad[8] {
+                return Err(Error::Crc);
+            }
+
+            // Sixteenths of degree
+            let raw = (u16::from(scratchpad[1]) << 8 | u16::from(scratchpad[0])) as i16;
+            Ok(i32::from(raw) * 125 / 2)
+        }
+    }
+}
@@ -0,0 +1,466 @@
+//! SD / MMC card driver, SPI mode
+//!
+//! For boards without the SDIO pins routed. Supports SDv1, SDv2 standard
+//! capacity and SDHC/SDXC cards with 512 byte blocks. CRC checking is turned
+//! on during initialization: commands carry a CRC7 and data blocks a CRC16
+//! in both directions.
+//!
+//! The SPI bus must run at 400 kHz or less until `init` returns, afterwards
+//! it can be raised to 25 MHz.
+
+use core::cell::Cell;
+use core::fmt;
+use core::ops::Deref;
+
+use hal;
+use stm32f411::gpioa;
+
+use gpio::{Io, Output, Pin, PinNumber, TypedPin};
+
+/// Block size
+pub const BLOCK_SIZE: usize = 512;
+
+const CMD0: u8 = 0;
+const CMD8: u8 = 8;
+const CMD9: u8 = 9;
+const CMD12: u8 = 12;
+const CMD16: u8 = 16;
+const CMD17: u8 = 17;
+const CMD18: u8 = 18;
+const CMD24: u8 = 24;
+const CMD25: u8 = 25;
+const CMD55: u8 = 55;
+const CMD58: u8 = 58;
+const CMD59: u8 = 59;
+const ACMD41: u8 = 41;
+
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+
+const DATA_START_BLOCK: u8 = 0xFE;
+const WRITE_MULTIPLE_TOKEN: u8 = 0xFC;
+const STOP_TRAN_TOKEN: u8 = 0xFD;
+const DATA_RES_MASK: u8 = 0x1F;
+const DATA_RES_ACCEPTED: u8 = 0x05;
+
+/// Busy-poll iterations before giving up
+const TIMEOUT: u32 = 100_000;
+
+/// Card error
+#[derive(Debug)]
+pub enum Error<E> {
+    /// SPI bus error
+    Spi(E),
+    /// The card didn't answer in time
+    Timeout,
+    /// CRC mismatch on a received data block
+    Crc,
+    /// The card answered a command with an error R1 status
+    Command(u8, u8),
+    /// The card rejected a data block, contains the data response token
+    WriteRejected(u8),
+    /// The card doesn't support the 2.7-3.6V range or isn't an SD card
+    UnsupportedCard,
+    /// `init` hasn't completed successfully
+    NotInitialized,
+}
+
+impl<E> fmt::Display for Error<E>
+    where E: fmt::Debug
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Spi(ref e) => write!(f, "SPI error: {:?}", e),
+            Error::Timeout => f.write_str("SD card timeout"),
+            Error::Crc => f.write_str("SD card data CRC mismatch"),
+            Error::Command(cmd, r1) => write!(f, "SD card CMD{} failed, R1 = {:#04x}", cmd, r1),
+            Error::WriteRejected(token) => {
+                write!(f, "SD card rejected write, token = {:#04x}", token)
+            }
+            Error::UnsupportedCard => f.write_str("unsupported SD card"),
+            Error::NotInitialized => f.write_str("SD card not initialized"),
+        }
+    }
+}
+
+/// Detected card type
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CardType {
+    SdV1,
+    SdV2,
+    /// High / extended capacity, block addressed
+    SdHc,
+}
+
+/// SD card on a SPI bus
+pub struct SdSpi<'a, S, T>
+    where S: 'a + hal::Spi<u8>,
+          T: 'a + Deref<Target=gpioa::RegisterBlock>
+{
+    spi: &'a S,
+    port: &'a T,
+    cs: Pin<T>,
+    card: Cell<Option<CardType>>,
+}
+
+impl<'a, S, T> SdSpi<'a, S, T>
+    where S: 'a + hal::Spi<u8>,
+          T: 'a + Deref<Target=gpioa::RegisterBlock>
+{
+    pub fn new<N>(spi: &'a S, port: &'a T, cs: TypedPin<T, N, Output>) -> Self
+        where N: PinNumber
+    {
+        let cs = cs.erase();
+        cs.set(port, Io::High);
+        SdSpi { spi: spi, port: port, cs: cs, card: Cell::new(None) }
+    }
+
+    /// Puts the card in SPI mode and runs the initialization handshake
+    pub fn init(&self) -> Result<CardType, Error<S::Error>> {
+        self.card.set(None);
+
+        // >= 74 clocks with CS high
+        self.cs.set(self.port, Io::High);
+        for _ in 0..10 {
+            self.exchange(0xFF)?;
+        }
+
+        let result = self.handshake();
+        self.deselect()?;
+        let card = result?;
+        self.card.set(Some(card));
+        Ok(card)
+    }
+
+    fn handshake(&self) -> Result<CardType, Error<S::Error>> {
+        self.select()?;
+
+        let mut tries = 0;
+        loop {
+            if self.command(CMD0, 0)? == R1_IDLE {
+                break;
+            }
+            tries += 1;
+            if tries == 10 {
+                return Err(Error::Timeout);
+            }
+        }
+
+        // Turn on CRC checking
+        let r1 = self.command(CMD59, 1)?;
+        if r1 != R1_IDLE {
+            return Err(Error::Command(CMD59, r1));
+        }
+
+        let mut card = CardType::SdV2;
+        let r1 = self.command(CMD8, 0x1AA)?;
+        if r1 & R1_ILLEGAL_COMMAND != 0 {
+            card = CardType::SdV1;
+        } else {
+            let mut r7 = [0; 4];
+            self.read_bytes(&mut r7)?;
+            if r7[2] & 0x0F != 0x01 || r7[3] != 0xAA {
+                return Err(Error::UnsupportedCard);
+            }
+        }
+
+        let arg = if card == CardType::SdV1 { 0 } else { 0x4000_0000 };
+        let mut tries = 0;
+        loop {
+            let r1 = self.app_command(ACMD41, arg)?;
+            if r1 == 0 {
+                break;
+            } else if r1 != R1_IDLE {
+                return Err(Error::UnsupportedCard);
+            }
+            tries += 1;
+            if tries == TIMEOUT {
+                return Err(Error::Timeout);
+            }
+        }
+
+        if card == CardType::SdV2 {
+            let r1 = self.command(CMD58, 0)?;
+            if r1 != 0 {
+                return Err(Error::Command(CMD58, r1));
+            }
+            let mut ocr = [0; 4];
+            self.read_bytes(&mut ocr)?;
+            if ocr[0] & 0x40 != 0 {
+                card = CardType::SdHc;
+            }
+        }
+
+        if card != CardType::SdHc {
+            let r1 = self.command(CMD16, BLOCK_SIZE as u32)?;
+            if r1 != 0 {
+                return Err(Error::Command(CMD16, r1));
+            }
+        }
+
+        Ok(card)
+    }
+
+    /// Card type detected by `init`
+    pub fn card_type(&self) -> Option<CardType> {
+        self.card.get()
+    }
+
+    /// Card capacity in blocks, read from the CSD register
+    pub fn num_blocks(&self) -> Result<u32, Error<S::Error>> {
+        self.check_init()?;
+
+        let mut csd = [0; 16];
+        self.select()?;
+        let result = self.command(CMD9, 0).and_then(|r1| {
+            if r1 != 0 {
+                Err(Error::Command(CMD9, r1))
+            } else {
+                self.read_data(&mut csd)
+            }
+        });
+        self.deselect()?;
+        result?;
+
+        match csd[0] >> 6 {
+            // CSD version 2.0: (C_SIZE + 1) * 512 KiB
+            1 => {
+                let c_size = (((csd[7] & 0x3F) as u32) << 16) | ((csd[8] as u32) << 8) | csd[9] as u32;
+                Ok((c_size + 1) * 1024)
+            }
+            // CSD version 1.0
+            _ => {
+                let read_bl_len = (csd[5] & 0x0F) as u32;
+                let c_size = (((csd[6] & 0x03) as u32) << 10) | ((csd[7] as u32) << 2)
+                    | ((csd[8] >> 6) as u32);
+                let c_size_mult = (((csd[9] & 0x03) << 1) | (csd[10] >> 7)) as u32;
+                let bytes = (c_size + 1) << (c_size_mult + 2 + read_bl_len);
+                Ok(bytes / BLOCK_SIZE as u32)
+            }
+        }
+    }
+
+    /// Reads consecutive blocks starting at `block`, nothing for an empty
+    /// `blocks`
+    pub fn read_blocks(&self, block: u32, blocks: &mut [[u8; BLOCK_SIZE]]) -> Result<(), Error<S::Error>> {
+        let address = self.address(block)?;
+        if blocks.is_empty() {
+            return Ok(());
+        }
+
+        self.select()?;
+        let result = if blocks.len() == 1 {
+            self.command(CMD17, address).and_then(|r1| {
+                if r1 != 0 {
+                    Err(Error::Command(CMD17, r1))
+                } else {
+                    self.read_data(&mut blocks[0])
+                }
+            })
+        } else {
+            self.command(CMD18, address).and_then(|r1| {
+                if r1 != 0 {
+                    return Err(Error::Command(CMD18, r1));
+                }
+                let mut result = Ok(());
+                for block in blocks.iter_mut() {
+                    result = self.read_data(block);
+                    if result.is_err() {
+                        break;
+                    }
+                }
+                // Stop even on error to get the card out of the read state
+                self.command(CMD12, 0)?;
+                result
+            })
+        };
+        self.deselect()?;
+        result
+    }
+
+    /// Writes consecutive blocks starting at `block`, nothing for an empty
+    /// `blocks`
+    pub fn write_blocks(&self, block: u32, blocks: &[[u8; BLOCK_SIZE]]) -> Result<(), Error<S::Error>> {
+        let address = self.address(block)?;
+        if blocks.is_empty() {
+            return Ok(());
+        }
+
+        self.select()?;
+        let result = if blocks.len() == 1 {
+            self.command(CMD24, address).and_then(|r1| {
+                if r1 != 0 {
+                    Err(Error::Command(CMD24, r1))
+                } else {
+                    self.write_data(DATA_START_BLOCK, &blocks[0])
+                }
+            })
+        } else {
+            self.command(CMD25, address).and_then(|r1| {
+                if r1 != 0 {
+                    return Err(Error::Command(CMD25, r1));
+                }
+                for block in blocks {
+                    self.write_data(WRITE_MULTIPLE_TOKEN, block)?;
+                }
+                self.exchange(STOP_TRAN_TOKEN)?;
+                self.exchange(0xFF)?;
+                self.wait_not_busy()
+            })
+        };
+        self.deselect()?;
+        result
+    }
+
+    fn check_init(&self) -> Result<CardType, Error<S::Error>> {
+        self.card.get().ok_or(Error::NotInitialized)
+    }
+
+    /// SDSC cards are byte addressed, SDHC cards block addressed
+    fn address(&self, block: u32) -> Result<u32, Error<S::Error>> {
+        match self.check_init()? {
+            CardType::SdHc => Ok(block),
+            _ => Ok(block * BLOCK_SIZE as u32),
+        }
+    }
+
+    fn select(&self) -> Result<(), Error<S::Error>> {
+        self.cs.set(self.port, Io::Low);
+        self.exchange(0xFF).map(|_| ())
+    }
+
+    fn deselect(&self) -> Result<(), Error<S::Error>> {
+        self.cs.set(self.port, Io::High);
+        // Release DO
+        self.exchange(0xFF).map(|_| ())
+    }
+
+    fn app_command(&self, cmd: u8, arg: u32) -> Result<u8, Error<S::Error>> {
+        self.command(CMD55, 0)?;
+        self.command(cmd, arg)
+    }
+
+    /// Sends a command and returns its R1 response
+    fn command(&self, cmd: u8, arg: u32) -> Result<u8, Error<S::Error>> {
+        if cmd != CMD0 && cmd != CMD12 {
+            self.wait_not_busy()?;
+        }
+
+        let frame = [
+            0x40 | cmd,
+            (arg >> 24) as u8,
+            (arg >> 16) as u8,
+            (arg >> 8) as u8,
+            arg as u8,
+        ];
+        for byte in frame.iter() {
+            self.exchange(*byte)?;
+        }
+        self.exchange(crc7(&frame))?;
+
+        if cmd == CMD12 {
+            // Discard the stuff byte
+            self.exchange(0xFF)?;
+        }
+
+        for _ in 0..10 {
+            let r1 = self.exchange(0xFF)?;
+            if r1 & 0x80 == 0 {
+                return Ok(r1);
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    fn read_data(&self, buffer: &mut [u8]) -> Result<(), Error<S::Error>> {
+        let mut tries = 0;
+        loop {
+            match self.exchange(0xFF)? {
+                0xFF => {}
+                DATA_START_BLOCK => break,
+                token => return Err(Error::Command(0, token)),
+            }
+            tries += 1;
+            if tries == TIMEOUT {
+                return Err(Error::Timeout);
+            }
+        }
+
+        self.read_bytes(buffer)?;
+        let crc = ((self.exchange(0xFF)? as u16) << 8) | self.exchange(0xFF)? as u16;
+        if crc != crc16(buffer) {
+            return Err(Error::Crc);
+        }
+        Ok(())
+    }
+
+    fn write_data(&self, token: u8, buffer: &[u8]) -> Result<(), Error<S::Error>> {
+        self.wait_not_busy()?;
+        self.exchange(token)?;
+        for byte in buffer {
+            self.exchange(*byte)?;
+        }
+        let crc = crc16(buffer);
+        self.exchange((crc >> 8) as u8)?;
+        self.exchange(crc as u8)?;
+
+        let response = self.exchange(0xFF)?;
+        if response & DATA_RES_MASK != DATA_RES_ACCEPTED {
+            return Err(Error::WriteRejected(response));
+        }
+        self.wait_not_busy()
+    }
+
+    fn wait_not_busy(&self) -> Result<(), Error<S::Error>> {
+        for _ in 0..TIMEOUT {
+            if self.exchange(0xFF)? == 0xFF {
+                return Ok(());
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    fn read_bytes(&self, buffer: &mut [u8]) -> Result<(), Error<S::Error>> {
+        for byte in buffer.iter_mut() {
+            *byte = self.exchange(0xFF)?;
+        }
+        Ok(())
+    }
+
+    fn exchange(&self, byte: u8) -> Result<u8, Error<S::Error>> {
+        block!(self.spi.send(byte)).map_err(Error::Spi)?;
+        block!(self.spi.read()).map_err(Error::Spi)
+    }
+}
+
+/// CRC7 of a command frame, returned with the end bit set
+fn crc7(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in data {
+        let mut byte = *byte;
+        for _ in 0..8 {
+            crc <<= 1;
+            if (byte ^ crc) & 0x80 != 0 {
+                crc ^= 0x09;
+            }
+            byte <<= 1;
+        }
+    }
+    (crc << 1) | 1
+}
+
+/// CRC16-CCITT (XMODEM) of a data block
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
@@ -0,0 +1,354 @@
+//! SSD1306 128x64 monochrome OLED driver
+//!
+//! Drawing happens in a RAM framebuffer which is pushed to the panel with a
+//! single DMA transfer by `flush`. The framebuffer is a DMA `Buffer` so it
+//! can't be drawn on while a flush is in progress; call `wait` first.
+//!
+//! The bus is abstracted by the `Interface` trait. `SpiInterface` implements
+//! it for the 4-wire SPI mode (SCK, MOSI, D/C and an optional CS) and
+//! `I2cInterface` for the I2C modules (SCL, SDA). Over I2C `flush` blocks
+//! until the framebuffer is out, about 25 ms at 400 kHz.
+
+use core::any::Any;
+use core::ops::Deref;
+use core::ptr;
+
+use hal;
+use static_ref::Static;
+use stm32f411::gpioa;
+
+use dma2::{self, DMA, Buffer};
+use drivers::font;
+use gpio::{Io, Output, OutputPin, Pin, PinNumber, TypedPin};
+#[cfg(feature = "i2c")]
+use i2c::{self, I2c, I2C};
+use spi2::{SPI, Spi};
+
+/// Panel width in pixels
+pub const WIDTH: u8 = 128;
+
+/// Panel height in pixels
+pub const HEIGHT: u8 = 64;
+
+/// Framebuffer size in bytes
+pub const BUFFER_SIZE: usize = (WIDTH as usize) * (HEIGHT as usize) / 8;
+
+/// Framebuffer backing a display
+pub type FrameBuffer = Buffer<[u8; BUFFER_SIZE]>;
+
+/// I2C address with SA0 low
+pub const ADDRESS: u8 = 0x3C;
+/// I2C address with SA0 high
+pub const ADDRESS_SA0: u8 = 0x3D;
+
+// I2C control bytes: a stream of commands / of display data
+const CONTROL_COMMANDS: u8 = 0x00;
+const CONTROL_DATA: u8 = 0x40;
+
+const SET_CONTRAST: u8 = 0x81;
+const DISPLAY_ALL_ON_RESUME: u8 = 0xA4;
+const NORMAL_DISPLAY: u8 = 0xA6;
+const INVERT_DISPLAY: u8 = 0xA7;
+const DISPLAY_OFF: u8 = 0xAE;
+const DISPLAY_ON: u8 = 0xAF;
+const SET_DISPLAY_OFFSET: u8 = 0xD3;
+const SET_COM_PINS: u8 = 0xDA;
+const SET_VCOM_DETECT: u8 = 0xDB;
+const SET_DISPLAY_CLOCK_DIV: u8 = 0xD5;
+const SET_PRECHARGE: u8 = 0xD9;
+const SET_MULTIPLEX: u8 = 0xA8;
+const SET_START_LINE: u8 = 0x40;
+const MEMORY_MODE: u8 = 0x20;
+const COLUMN_ADDR: u8 = 0x21;
+const PAGE_ADDR: u8 = 0x22;
+const COM_SCAN_DEC: u8 = 0xC8;
+const SEG_REMAP: u8 = 0xA1;
+const CHARGE_PUMP: u8 = 0x8D;
+
+/// Bus used to talk to the display controller
+pub trait Interface {
+    type Error;
+
+    /// Sends command bytes, blocking until they are out
+    fn commands(&self, cmds: &[u8]) -> Result<(), Self::Error>;
+    /// Starts sending the framebuffer as display data
+    fn data(&self, buffer: &Static<FrameBuffer>) -> Result<(), Self::Error>;
+    /// Waits until the display data started by `data` has been sent
+    fn wait(&self, buffer: &Static<FrameBuffer>) -> Result<(), Self::Error>;
+}
+
+/// 4-wire SPI interface
+pub struct SpiInterface<'a, S, D, T>
+    where S: 'a + Any + SPI,
+          D: 'a + Any + DMA,
+          T: 'a + Deref<Target=gpioa::RegisterBlock>
+{
+    spi: &'a Spi<'a, S, D>,
+    port: &'a T,
+    dc: Pin<T>,
+    cs: Option<Pin<T>>,
+}
+
+impl<'a, S, D, T> SpiInterface<'a, S, D, T>
+    where S: 'a + Any + SPI,
+          D: 'a + Any + DMA,
+          T: 'a + Deref<Target=gpioa::RegisterBlock>
+{
+    /// `spi` must have a TX DMA stream configured for memory to peripheral
+    /// transfers with memory increment enabled. `cs` is `NoPin` when the
+    /// display's CS is tied low.
+    pub fn new<N, CS>(spi: &'a Spi<'a, S, D>, port: &'a T, dc: TypedPin<T, N, Output>, cs: CS)
+        -> Self
+        where N: PinNumber,
+              CS: OutputPin<T>
+    {
+        SpiInterface { spi: spi, port: port, dc: dc.erase(), cs: cs.into_pin() }
+    }
+
+    fn select(&self) {
+        if let Some(ref cs) = self.cs {
+            cs.set(self.port, Io::Low);
+        }
+    }
+
+    fn deselect(&self) {
+        while self.spi.reg.sr.read().bsy().bit_is_set() {}
+        if let Some(ref cs) = self.cs {
+            cs.set(self.port, Io::High);
+        }
+    }
+
+    /// Clears the overrun left behind by a TX only DMA transfer
+    fn clear_overrun(&self) {
+        let spi = self.spi.reg;
+        unsafe { ptr::read_volatile(&spi.dr as *const _ as *const u8) };
+        spi.sr.read();
+    }
+}
+
+impl<'a, S, D, T> Interface for SpiInterface<'a, S, D, T>
+    where S: 'a + Any + SPI,
+          D: 'a + Any + DMA,
+          T: 'a + Deref<Target=gpioa::RegisterBlock>
+{
+    type Error = dma2::Error;
+
+    fn commands(&self, cmds: &[u8]) -> Result<(), dma2::Error> {
+        self.clear_overrun();
+        self.dc.set(self.port, Io::Low);
+        self.select();
+        for cmd in cmds {
+            block!(hal::Spi::send(self.spi, *cmd)).ok();
+            block!(hal::Spi::read(self.spi)).ok();
+        }
+        self.deselect();
+        Ok(())
+    }
+
+    fn data(&self, buffer: &Static<FrameBuffer>) -> Result<(), dma2::Error> {
+        self.dc.set(self.port, Io::High);
+        self.select();
+        let result = self.spi.send_dma(buffer);
+        if result.is_err() {
+            self.deselect();
+        }
+        result
+    }
+
+    fn wait(&self, buffer: &Static<FrameBuffer>) -> Result<(), dma2::Error> {
+        let result = match self.spi.tx_stream() {
+            Ok(dma) => block!(buffer.release(dma.reg)),
+            Err(e) => Err(e),
+        };
+        self.deselect();
+        result
+    }
+}
+
+/// I2C interface
+///
+/// Each call is a transaction with a `timer` timeout, set it up with
+/// `set_timeout` beforehand; a timeout long enough for the whole
+/// framebuffer is needed by `data`.
+#[cfg(feature = "i2c")]
+pub struct I2cInterface<'a, I, T>
+    where I: 'a + Any + I2C,
+          T: 'a + hal::Timer
+{
+    i2c: &'a I2c<'a, I>,
+    timer: &'a T,
+    address: u8,
+}
+
+#[cfg(feature = "i2c")]
+impl<'a, I, T> I2cInterface<'a, I, T>
+    where I: 'a + Any + I2C,
+          T: 'a + hal::Timer
+{
+    /// Display at `address` (`ADDRESS` or `ADDRESS_SA0`) on an `init`ialized
+    /// `i2c`
+    pub fn new(i2c: &'a I2c<'a, I>, timer: &'a T, address: u8) -> Self {
+        I2cInterface { i2c: i2c, timer: timer, address: address }
+    }
+}
+
+#[cfg(feature = "i2c")]
+impl<'a, I, T> Interface for I2cInterface<'a, I, T>
+    where I: 'a + Any + I2C,
+          T: 'a + hal::Timer
+{
+    type Error = i2c::Error;
+
+    fn commands(&self, cmds: &[u8]) -> Result<(), i2c::Error> {
+        self.i2c.write_chunks(self.timer, self.address, &[&[CONTROL_COMMANDS], cmds])
+    }
+
+    /// Sends the whole framebuffer before returning
+    fn data(&self, buffer: &Static<FrameBuffer>) -> Result<(), i2c::Error> {
+        let buffer = buffer.borrow();
+        self.i2c.write_chunks(self.timer, self.address, &[&[CONTROL_DATA], &buffer[..]])
+    }
+
+    fn wait(&self, _buffer: &Static<FrameBuffer>) -> Result<(), i2c::Error> {
+        Ok(())
+    }
+}
+
+/// SSD1306 display
+pub struct Ssd1306<'a, I>
+    where I: 'a + Interface
+{
+    interface: &'a I,
+    buffer: &'a Static<FrameBuffer>,
+}
+
+impl<'a, I> Ssd1306<'a, I>
+    where I: 'a + Interface
+{
+    pub fn new(interface: &'a I, buffer: &'a Static<FrameBuffer>) -> Self {
+        Ssd1306 { interface: interface, buffer: buffer }
+    }
+
+    /// Sends the power-on configuration and turns the panel on
+    ///
+    /// Configures the internal charge pump and horizontal addressing mode
+    pub fn init(&self) -> Result<(), I::Error> {
+        self.interface.commands(&[
+            DISPLAY_OFF,
+            SET_DISPLAY_CLOCK_DIV, 0x80,
+            SET_MULTIPLEX, HEIGHT - 1,
+            SET_DISPLAY_OFFSET, 0x00,
+            SET_START_LINE | 0x00,
+            CHARGE_PUMP, 0x14,
+            MEMORY_MODE, 0x00,
+            SEG_REMAP,
+            COM_SCAN_DEC,
+            SET_COM_PINS, 0x12,
+            SET_CONTRAST, 0xCF,
+            SET_PRECHARGE, 0xF1,
+            SET_VCOM_DETECT, 0x40,
+            DISPLAY_ALL_ON_RESUME,
+            NORMAL_DISPLAY,
+            DISPLAY_ON,
+        ])
+    }
+
+    /// Sets the panel contrast
+    pub fn set_contrast(&self, contrast: u8) -> Result<(), I::Error> {
+        self.interface.commands(&[SET_CONTRAST, contrast])
+    }
+
+    /// Inverts the panel output, doesn't touch the framebuffer
+    pub fn invert(&self, invert: bool) -> Result<(), I::Error> {
+        self.interface.commands(&[if invert { INVERT_DISPLAY } else { NORMAL_DISPLAY }])
+    }
+
+    /// Starts pushing the framebuffer to the panel
+    pub fn flush(&self) -> Result<(), I::Error> {
+        self.interface.commands(&[
+            COLUMN_ADDR, 0, WIDTH - 1,
+            PAGE_ADDR, 0, HEIGHT / 8 - 1,
+        ])?;
+        self.interface.data(self.buffer)
+    }
+
+    /// Waits until the framebuffer has been sent
+    pub fn wait(&self) -> Result<(), I::Error> {
+        self.interface.wait(self.buffer)
+    }
+
+    /// Fills the whole framebuffer
+    pub fn clear(&self, on: bool) {
+        let fill = if on { 0xFF } else { 0x00 };
+        for byte in self.buffer.borrow_mut().iter_mut() {
+            *byte = fill;
+        }
+    }
+
+    /// Sets a single pixel, out of bounds coordinates are ignored
+    pub fn set_pixel(&self, x: u8, y: u8, on: bool) {
+        set_pixel(&mut *self.buffer.borrow_mut(), x as u16, y as u16, on);
+    }
+
+    /// Fills a rectangle, clipped to the panel
+    pub fn fill_rect(&self, x: u8, y: u8, width: u8, height: u8, on: bool) {
+        let buffer = &mut *self.buffer.borrow_mut();
+        for row in y as u16..y as u16 + height as u16 {
+            for col in x as u16..x as u16 + width as u16 {
+                set_pixel(buffer, col, row, on);
+            }
+        }
+    }
+
+    /// Draws the outline of a rectangle, clipped to the panel
+    pub fn draw_rect(&self, x: u8, y: u8, width: u8, height: u8, on: bool) {
+        if width == 0 || height == 0 {
+            return;
+        }
+
+        let buffer = &mut *self.buffer.borrow_mut();
+        let right = x as u16 + width as u16 - 1;
+        let bottom = y as u16 + height as u16 - 1;
+        for col in x as u16..right + 1 {
+            set_pixel(buffer, col, y as u16, on);
+            set_pixel(buffer, col, bottom, on);
+        }
+        for row in y as u16..bottom + 1 {
+            set_pixel(buffer, x as u16, row, on);
+            set_pixel(buffer, right, row, on);
+        }
+    }
+
+    /// Draws `text` with its top left corner at (`x`, `y`)
+    ///
+    /// Glyphs are 5x7 pixels with one column of spacing. Returns the x
+    /// coordinate following the last glyph.
+    pub fn draw_text(&self, x: u8, y: u8, text: &str) -> u8 {
+        let buffer = &mut *self.buffer.borrow_mut();
+        let mut x = x;
+        for c in text.bytes() {
+            for (col, bits) in font::glyph(c).iter().enumerate() {
+                for row in 0..font::HEIGHT {
+                    let on = bits & (1 << row) != 0;
+                    set_pixel(buffer, x as u16 + col as u16, y as u16 + row as u16, on);
+                }
+            }
+            x = x.saturating_add(font::WIDTH + 1);
+        }
+        x
+    }
+}
+
+fn set_pixel(buffer: &mut [u8; BUFFER_SIZE], x: u16, y: u16, on: bool) {
+    if x >= WIDTH as u16 || y >= HEIGHT as u16 {
+        return;
+    }
+
+    let index = (x as usize) + (y as usize / 8) * (WIDTH as usize);
+    let mask = 1 << (y % 8);
+    if on {
+        buffer[index] |= mask;
+    } else {
+        buffer[index] &= !mask;
+    }
+}
@@ -0,0 +1,347 @@
+//! EEPROM emulation in two flash sectors
+//!
+//! A small key / value store (`u16` id -> `u32` value) for configuration and
+//! calibration data, without an external EEPROM. Writes are appended to the
+//! active sector as 8-byte records, so a value can be rewritten thousands of
+//! times before the sector is full; then the latest value of every id is
+//! copied to the other sector and the full one is erased. Each sector is
+//! only erased once per fill, which is what wears the flash (10 k cycles).
+//!
+//! The sectors are picked at compile time and must be left out of the
+//! application image, e.g. the last two 128 KB sectors:
+//!
+//! ``` text
+//! /* memory.x */
+//! FLASH : ORIGIN = 0x08000000, LENGTH = 256K
+//! ```
+//!
+//! ``` ignore
+//! static EEPROM: Eeprom = Eeprom::new(6, 7);
+//!
+//! EEPROM.init(flash)?;
+//! let gain = EEPROM.read(ID_GAIN).unwrap_or(DEFAULT_GAIN);
+//! EEPROM.write(flash, ID_GAIN, gain + 1)?;
+//! ```
+//!
+//! # Power failure
+//!
+//! A record is its value followed by its id, programmed in that order: a
+//! record interrupted before the id is written doesn't count and the
+//! previous value of the id stays in place. A compaction marks the new
+//! sector as complete before it erases the old one: `init` rolls back a
+//! compaction interrupted before that point, and finishes one interrupted
+//! after it whatever state the half erased old sector is in. A reset at any
+//! point loses at most the write in progress.
+//!
+//! Erasing a sector stalls the CPU for up to 2 s (128 KB sectors) or
+//! 0.5 s (16 KB sectors), see `flash`.
+
+use core::{fmt, ptr};
+
+use stm32f411::FLASH;
+
+use flash::{self, SECTORS};
+
+/// Erased word
+const ERASED: u32 = 0xFFFF_FFFF;
+/// Compaction target, being filled
+const RECEIVING: u32 = 0xEEEE_EEEE;
+/// Sector holding the current values
+const VALID: u32 = 0x0000_0000;
+/// Second header word of a compaction target that holds every value
+const COPIED: u32 = 0x0000_0000;
+
+/// Header (state + copy marker) and record size
+const RECORD: u32 = 8;
+
+/// Store error
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// Erase or program error
+    Flash(flash::Error),
+    /// There's no room for another id, even after a compaction
+    Full,
+    /// `init` wasn't called
+    NotInitialized,
+    #[doc(hidden)]
+    _Extensible,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Flash(ref e) => e.fmt(f),
+            Error::Full => f.write_str("emulated EEPROM full"),
+            Error::NotInitialized => f.write_str("emulated EEPROM not initialized"),
+            Error::_Extensible => f.write_str("unknown error"),
+        }
+    }
+}
+
+impl From<flash::Error> for Error {
+    fn from(e: flash::Error) -> Self {
+        Error::Flash(e)
+    }
+}
+
+/// Key / value store in two flash sectors
+pub struct Eeprom {
+    sectors: [u8; 2],
+}
+
+impl Eeprom {
+    /// Store in `first` and `second`, which should have the same size
+    ///
+    /// Holds up to `sector_size / 8 - 2` ids.
+    pub const fn new(first: u8, second: u8) -> Self {
+        Eeprom { sectors: [first, second] }
+    }
+
+    /// Checks the sectors, repairing the damage of a power failure
+    ///
+    /// Formats the sectors if they hold anything else than a store, e.g. on
+    /// the first boot. Call it once before `read` / `write`.
+    pub fn init(&self, flash: &FLASH) -> Result<(), Error> {
+        assert!(self.sectors[0] < SECTORS && self.sectors[1] < SECTORS);
+        assert!(self.sectors[0] != self.sectors[1]);
+
+        // A compaction was interrupted after the copy was complete, the old
+        // sector may be half erased: finish it
+        if let Some(target) = self.complete_copy() {
+            return self.finish_compaction(flash, target);
+        }
+
+        let states = (self.state(0), self.state(1));
+        match states {
+            (VALID, ERASED) | (ERASED, VALID) => Ok(()),
+            // .. or before, the old sector is untouched: start over
+            (VALID, RECEIVING) => self.erase(flash, 1),
+            (RECEIVING, VALID) => self.erase(flash, 0),
+            // An interrupted erase leaves anything in the header
+            (VALID, _) if self.is_erased(1) => Ok(()),
+            (VALID, _) => self.erase(flash, 1),
+            (_, VALID) if self.is_erased(0) => Ok(()),
+            (_, VALID) => self.erase(flash, 0),
+            _ => self.format(flash),
+        }
+    }
+
+    /// Erases every value
+    pub fn format(&self, flash: &FLASH) -> Result<(), Error> {
+        self.erase(flash, 0)?;
+        self.erase(flash, 1)?;
+        self.set_state(flash, 0, VALID)
+    }
+
+    /// Latest value written to `id`, `None` if it never was
+    pub fn read(&self, id: u16) -> Option<u32> {
+        let active = match self.active() {
+            Some(active) => active,
+            None => return None,
+        };
+        self.find(active, id)
+    }
+
+    /// Writes `value` to `id`
+    ///
+    /// Compacts the store when the active sector is full. Id `0xFFFF` is
+    /// reserved.
+    pub fn write(&self, flash: &FLASH, id: u16, value: u32) -> Result<(), Error> {
+        assert!(id != 0xFFFF);
+
+        let active = match self.active() {
+            Some(active) => active,
+            None => return Err(Error::NotInitialized),
+        };
+        if self.find(active, id) == Some(value) {
+            return Ok(());
+        }
+
+        match self.free_slot(active) {
+            Some(address) => self.append(flash, address, id, value),
+            None => self.compact(flash, active, id, value),
+        }
+    }
+
+    /// Copies the latest value of every id, and the new record, to the
+    /// other sector, then erases `active`
+    fn compact(&self, flash: &FLASH, active: usize, id: u16, value: u32)
+        -> Result<(), Error>
+    {
+        let target = 1 - active;
+
+        if !self.is_erased(target) {
+            self.erase(flash, target)?;
+        }
+        self.set_state(flash, target, RECEIVING)?;
+
+        let mut next = self.start(target) + RECORD;
+        self.append(flash, next, id, value)?;
+        next += RECORD;
+
+        let mut address = self.start(active) + RECORD;
+        while address < self.end(active) {
+            let record = address;
+            address += RECORD;
+
+            let other = match record_id(record) {
+                Some(other) => other,
+                None => continue,
+            };
+
+            if other == id || self.find(target, other).is_some() {
+                continue;
+            }
+            if next >= self.end(target) {
+                return Err(Error::Full);
+            }
+            // Always `Some`, `other` has a record
+            if let Some(latest) = self.find(active, other) {
+                self.append(flash, next, other, latest)?;
+                next += RECORD;
+            }
+        }
+
+        flash::program_word(flash, self.start(target) + 4, COPIED)?;
+        self.finish_compaction(flash, target)
+    }
+
+    /// Erases the old sector and makes the complete copy in `target` the
+    /// active one
+    fn finish_compaction(&self, flash: &FLASH, target: usize) -> Result<(), Error> {
+        if !self.is_erased(1 - target) {
+            self.erase(flash, 1 - target)?;
+        }
+        self.set_state(flash, target, VALID)
+    }
+
+    /// Sector holding a complete compaction copy that isn't active yet
+    fn complete_copy(&self) -> Option<usize> {
+        (0..2).find(|&index| {
+            if read_word(self.start(index) + 4) != COPIED {
+                return false;
+            }
+            match self.state(index) {
+                RECEIVING => true,
+                ERASED | VALID => false,
+                // `set_state(VALID)` was interrupted, the old sector is
+                // erased already
+                _ => self.state(1 - index) == ERASED,
+            }
+        })
+    }
+
+    /// Programs a record: value first, the id makes it count
+    fn append(&self, flash: &FLASH, address: u32, id: u16, value: u32)
+        -> Result<(), Error>
+    {
+        flash::program_word(flash, address, value)?;
+        flash::program_word(flash, address + 4, encode(id))?;
+        Ok(())
+    }
+
+    /// Latest value of `id` in sector `index`
+    fn find(&self, index: usize, id: u16) -> Option<u32> {
+        let mut latest = None;
+        let mut address = self.start(index) + RECORD;
+        while address < self.end(index) {
+            match record_id(address) {
+                Some(other) if other == id => latest = Some(read_word(address)),
+                Some(_) => {}
+                // Interrupted record, or the end of the records
+                None => {
+                    if is_free(address) {
+                        break;
+                    }
+                }
+            }
+            address += RECORD;
+        }
+        latest
+    }
+
+    /// First unused record slot of sector `index`
+    fn free_slot(&self, index: usize) -> Option<u32> {
+        let mut address = self.end(index);
+        // Walk back over the erased tail, an interrupted record is skipped
+        while address > self.start(index) + RECORD && is_free(address - RECORD) {
+            address -= RECORD;
+        }
+
+        if address < self.end(index) {
+            Some(address)
+        } else {
+            None
+        }
+    }
+
+    /// Index of the sector holding the current values
+    fn active(&self) -> Option<usize> {
+        if self.state(0) == VALID {
+            Some(0)
+        } else if self.state(1) == VALID {
+            Some(1)
+        } else {
+            None
+        }
+    }
+
+    fn state(&self, index: usize) -> u32 {
+        read_word(self.start(index))
+    }
+
+    fn set_state(&self, flash: &FLASH, index: usize, state: u32) -> Result<(), Error> {
+        flash::program_word(flash, self.start(index), state)?;
+        Ok(())
+    }
+
+    fn erase(&self, flash: &FLASH, index: usize) -> Result<(), Error> {
+        flash::erase_sector(flash, self.sectors[index])?;
+        Ok(())
+    }
+
+    fn is_erased(&self, index: usize) -> bool {
+        let mut address = self.start(index);
+        while address < self.end(index) {
+            if read_word(address) != ERASED {
+                return false;
+            }
+            address += 4;
+        }
+        true
+    }
+
+    fn start(&self, index: usize) -> u32 {
+        flash::sector_address(self.sectors[index])
+    }
+
+    fn end(&self, index: usize) -> u32 {
+        self.start(index) + flash::sector_size(self.sectors[index])
+    }
+}
+
+/// The id word holds the id and its complement, a partially programmed
+/// word doesn't decode
+fn encode(id: u16) -> u32 {
+    u32::from(!id) << 16 | u32::from(id)
+}
+
+/// Id of the record at `address`, `None` if it has none
+fn record_id(address: u32) -> Option<u16> {
+    let word = read_word(address + 4);
+    let id = word as u16;
+    if (word >> 16) as u16 == !id && id != 0xFFFF {
+        Some(id)
+    } else {
+        None
+    }
+}
+
+/// Whether the record slot at `address` was never programmed
+fn is_free(address: u32) -> bool {
+    read_word(address) == ERASED && read_word(address + 4) == ERASED
+}
+
+fn read_word(address: u32) -> u32 {
+    unsafe { ptr::read_volatile(address as *const u32) }
+}
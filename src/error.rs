@@ -0,0 +1,216 @@
+//! Crate level error
+//!
+//! Every module keeps its own `Error` type, this wraps the peripheral ones so
+//! applications that use several drivers can propagate them with `?`:
+//!
+//! ``` ignore
+//! fn setup(..) -> Result<(), bsp::Error> {
+//!     let clocks = Cfgr::new().sysclk(100_000_000.hz()).try_freeze(rcc, flash, pwr)?;
+//!     spi.set_frequency(&clocks, 8_000_000.hz())?;
+//!     ..
+//! }
+//! ```
+//!
+//! All the error types implement `Debug` and `Display`.
+
+use core::fmt;
+
+#[cfg(feature = "dma")]
+use {bitbang, circular, dma2};
+#[cfg(feature = "pwm")]
+use fan;
+#[cfg(feature = "i2c")]
+use i2c;
+#[cfg(feature = "usart")]
+use serial;
+#[cfg(feature = "spi")]
+use spi2;
+#[cfg(all(feature = "pwm", feature = "dma"))]
+use tone;
+#[cfg(feature = "usb")]
+use usb_host;
+use {eeprom_emul, flash};
+use protocols::{framed, sbus};
+#[cfg(all(feature = "usart", feature = "dma"))]
+use protocols::dmx512;
+use rcc::ClockError;
+
+/// Any peripheral error
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// Parallel output error
+    #[cfg(feature = "dma")]
+    Bitbang(bitbang::Error),
+    /// Clock configuration error
+    Clock(ClockError),
+    /// Circular DMA reception error
+    #[cfg(feature = "dma")]
+    Circular(circular::Error),
+    /// DMA error
+    #[cfg(feature = "dma")]
+    Dma(dma2::Error),
+    /// DMX512 transmitter error
+    #[cfg(all(feature = "usart", feature = "dma"))]
+    Dmx512(dmx512::Error),
+    /// Emulated EEPROM error
+    Eeprom(eeprom_emul::Error),
+    /// Fan error
+    #[cfg(feature = "pwm")]
+    Fan(fan::Error),
+    /// Flash programming error
+    Flash(flash::Error),
+    /// I2C error
+    #[cfg(feature = "i2c")]
+    I2c(i2c::Error),
+    /// Serial framing error
+    Framed(framed::Error),
+    /// SBUS reception error
+    Sbus(sbus::Error),
+    /// Serial error
+    #[cfg(feature = "usart")]
+    Serial(serial::Error),
+    /// SPI error
+    #[cfg(feature = "spi")]
+    Spi(spi2::Error),
+    /// Tone generator error
+    #[cfg(all(feature = "pwm", feature = "dma"))]
+    Tone(tone::Error),
+    /// USB host error
+    #[cfg(feature = "usb")]
+    UsbHost(usb_host::Error),
+    #[doc(hidden)]
+    _Extensible,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            #[cfg(feature = "dma")]
+            Error::Bitbang(ref e) => e.fmt(f),
+            Error::Clock(ref e) => e.fmt(f),
+            #[cfg(feature = "dma")]
+            Error::Circular(ref e) => e.fmt(f),
+            #[cfg(feature = "dma")]
+            Error::Dma(ref e) => e.fmt(f),
+            #[cfg(all(feature = "usart", feature = "dma"))]
+            Error::Dmx512(ref e) => e.fmt(f),
+            Error::Eeprom(ref e) => e.fmt(f),
+            #[cfg(feature = "pwm")]
+            Error::Fan(ref e) => e.fmt(f),
+            Error::Flash(ref e) => e.fmt(f),
+            #[cfg(feature = "i2c")]
+            Error::I2c(ref e) => e.fmt(f),
+            Error::Framed(ref e) => e.fmt(f),
+            Error::Sbus(ref e) => e.fmt(f),
+            #[cfg(feature = "usart")]
+            Error::Serial(ref e) => e.fmt(f),
+            #[cfg(feature = "spi")]
+            Error::Spi(ref e) => e.fmt(f),
+            #[cfg(all(feature = "pwm", feature = "dma"))]
+            Error::Tone(ref e) => e.fmt(f),
+            #[cfg(feature = "usb")]
+            Error::UsbHost(ref e) => e.fmt(f),
+            Error::_Extensible => f.write_str("unknown error"),
+        }
+    }
+}
+
+#[cfg(feature = "dma")]
+impl From<bitbang::Error> for Error {
+    fn from(e: bitbang::Error) -> Self {
+        Error::Bitbang(e)
+    }
+}
+
+impl From<ClockError> for Error {
+    fn from(e: ClockError) -> Self {
+        Error::Clock(e)
+    }
+}
+
+#[cfg(feature = "dma")]
+impl From<circular::Error> for Error {
+    fn from(e: circular::Error) -> Self {
+        Error::Circular(e)
+    }
+}
+
+#[cfg(feature = "dma")]
+impl From<dma2::Error> for Error {
+    fn from(e: dma2::Error) -> Self {
+        Error::Dma(e)
+    }
+}
+
+#[cfg(all(feature = "usart", feature = "dma"))]
+impl From<dmx512::Error> for Error {
+    fn from(e: dmx512::Error) -> Self {
+        Error::Dmx512(e)
+    }
+}
+
+impl From<eeprom_emul::Error> for Error {
+    fn from(e: eeprom_emul::Error) -> Self {
+        Error::Eeprom(e)
+    }
+}
+
+#[cfg(feature = "pwm")]
+impl From<fan::Error> for Error {
+    fn from(e: fan::Error) -> Self {
+        Error::Fan(e)
+    }
+}
+
+impl From<flash::Error> for Error {
+    fn from(e: flash::Error) -> Self {
+        Error::Flash(e)
+    }
+}
+
+#[cfg(feature = "i2c")]
+impl From<i2c::Error> for Error {
+    fn from(e: i2c::Error) -> Self {
+        Error::I2c(e)
+    }
+}
+
+impl From<framed::Error> for Error {
+    fn from(e: framed::Error) -> Self {
+        Error::Framed(e)
+    }
+}
+
+impl From<sbus::Error> for Error {
+    fn from(e: sbus::Error) -> Self {
+        Error::Sbus(e)
+    }
+}
+
+#[cfg(feature = "usart")]
+impl From<serial::Error> for Error {
+    fn from(e: serial::Error) -> Self {
+        Error::Serial(e)
+    }
+}
+
+#[cfg(feature = "spi")]
+impl From<spi2::Error> for Error {
+    fn from(e: spi2::Error) -> Self {
+        Error::Spi(e)
+    }
+}
+
+#[cfg(all(feature = "pwm", feature = "dma"))]
+impl From<tone::Error> for Error {
+    fn from(e: tone::Error) -> Self {
+        Error::Tone(e)
+    }
+}
+
+#[cfg(feature = "usb")]
+impl From<usb_host::Error> for Error {
+    fn from(e: usb_host::Error) -> Self {
+        Error::UsbHost(e)
+    }
+}
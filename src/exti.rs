@@ -0,0 +1,111 @@
+//! External interrupt / event controller (EXTI)
+//!
+//! Lines 0 to 15 are connected to the GPIO pin with the same number of one
+//! port, selected through SYSCFG.
+
+use stm32f411::{EXTI, RCC, SYSCFG};
+
+/// GPIO port driving an EXTI line
+#[derive(Clone, Copy, Debug)]
+pub enum Port {
+    A = 0,
+    B = 1,
+    C = 2,
+    D = 3,
+    E = 4,
+    H = 7,
+}
+
+/// Trigger edge
+#[derive(Clone, Copy, Debug)]
+pub enum Edge {
+    Rising,
+    Falling,
+    RisingFalling,
+}
+
+/// External interrupt controller
+pub struct Exti<'a>(pub &'a EXTI);
+
+impl<'a> Exti<'a> {
+    /// Connects pin `line` of `port` to EXTI line `line` and selects the
+    /// trigger edge
+    ///
+    /// NOTE The line is left masked, use `listen` to unmask it
+    pub fn init(&self, line: u8, port: Port, edge: Edge, syscfg: &SYSCFG, rcc: &RCC) {
+        assert!(line < 16);
+
+        rcc.apb2enr.modify(|_, w| w.syscfgen().set_bit());
+
+        let shift = (line % 4) * 4;
+        let mask = !(0b1111 << shift);
+        let value = (port as u32) << shift;
+        unsafe {
+            match line / 4 {
+                0 => syscfg.exticr1.modify(|r, w| w.bits((r.bits() & mask) | value)),
+                1 => syscfg.exticr2.modify(|r, w| w.bits((r.bits() & mask) | value)),
+                2 => syscfg.exticr3.modify(|r, w| w.bits((r.bits() & mask) | value)),
+                _ => syscfg.exticr4.modify(|r, w| w.bits((r.bits() & mask) | value)),
+            }
+        }
+
+        self.set_edge(line, edge);
+    }
+
+    /// Changes the trigger edge of `line`
+    pub fn set_edge(&self, line: u8, edge: Edge) {
+        let bit = 1 << line;
+        let (rising, falling) = match edge {
+            Edge::Rising => (true, false),
+            Edge::Falling => (false, true),
+            Edge::RisingFalling => (true, true),
+        };
+
+        let exti = self.0;
+        unsafe {
+            exti.rtsr.modify(|r, w| {
+                w.bits(if rising { r.bits() | bit } else { r.bits() & !bit })
+            });
+            exti.ftsr.modify(|r, w| {
+                w.bits(if falling { r.bits() | bit } else { r.bits() & !bit })
+            });
+        }
+    }
+
+    /// Unmasks the interrupt request of `line`
+    pub fn listen(&self, line: u8) {
+        self.0.imr.modify(|r, w| unsafe { w.bits(r.bits() | (1 << line)) });
+    }
+
+    /// Masks the interrupt request of `line`
+    pub fn unlisten(&self, line: u8) {
+        self.0.imr.modify(|r, w| unsafe { w.bits(r.bits() & !(1 << line)) });
+    }
+
+    /// Unmasks the event request of `line`, which ends `WFE` (and Stop
+    /// mode entered with it) without running a handler
+    pub fn listen_event(&self, line: u8) {
+        self.0.emr.modify(|r, w| unsafe { w.bits(r.bits() | (1 << line)) });
+    }
+
+    /// Masks the event request of `line`
+    pub fn unlisten_event(&self, line: u8) {
+        self.0.emr.modify(|r, w| unsafe { w.bits(r.bits() & !(1 << line)) });
+    }
+
+    /// Checks if `line` has a pending trigger
+    pub fn is_pending(&self, line: u8) -> bool {
+        self.0.pr.read().bits() & (1 << line) != 0
+    }
+
+    /// Clears the pending trigger of `line`
+    pub fn clear_pending(&self, line: u8) {
+        // NOTE(write) PR is write 1 to clear, other lines are not affected
+        self.0.pr.write(|w| unsafe { w.bits(1 << line) });
+    }
+
+    /// Triggers `line` from software
+    pub fn trigger(&self, line: u8) {
+        self.0.swier.modify(|r, w| unsafe { w.bits(r.bits() | (1 << line)) });
+    }
+}
@@ -0,0 +1,224 @@
+//! PWM fan with tachometer feedback
+//!
+//! The speed is set through a TIM1 PWM channel and read back from the fan's
+//! tachometer output, which pulls low a fixed number of times per
+//! revolution (2 for most 4-wire PC fans). The tach line is an open-drain
+//! output: give its pin a pull-up and an EXTI line on the falling edge,
+//! whose handler counts the pulses:
+//!
+//! ``` ignore
+//! static TACH: Tachometer = Tachometer::new();
+//!
+//! let mut fan = FanController::new(Pwm(tim1), Channel::_1, &TACH, 3000);
+//! fan.set_rpm(1800);
+//!
+//! // EXTI handler
+//! TACH.on_edge();
+//! exti.clear_pending(line);
+//!
+//! // every 500 ms
+//! match fan.update(500.ms()) {
+//!     Ok(rpm) => {}
+//!     Err(fan::Error::Stall) => { /* raise an alarm, the fan is at full duty */ }
+//! }
+//! ```
+//!
+//! `set_rpm` closes the loop with a `control::Pid`, `set_duty` runs the fan
+//! open loop. A fan that produces no pulses for `stall_timeout` updates
+//! while driven is reported as stalled and driven at full duty, in case it
+//! only needs a kick to start again: failing towards maximum cooling is the
+//! safe way for a fan. Update periods of 250 ms - 1 s give a usable
+//! resolution at a few pulses per update.
+
+use core::fmt;
+use core::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+
+use hal;
+use stm32f411::TIM1;
+
+use control::{self, Pid};
+use pwm2::Pwm;
+use time::Milliseconds;
+use timer::Channel;
+
+/// Fan error
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// No tachometer pulses while driven, the fan is at full duty now
+    Stall,
+    #[doc(hidden)]
+    _Extensible,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Error::Stall => "fan stalled",
+            Error::_Extensible => "unknown error",
+        })
+    }
+}
+
+/// Tachometer pulse counter, shared with the EXTI handler
+pub struct Tachometer {
+    pulses: AtomicUsize,
+}
+
+impl Tachometer {
+    pub const fn new() -> Self {
+        Tachometer { pulses: ATOMIC_USIZE_INIT }
+    }
+
+    /// Counts a pulse, call this from the tach pin's EXTI handler
+    pub fn on_edge(&self) {
+        self.pulses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Pulses since the last call
+    fn take(&self) -> u32 {
+        self.pulses.swap(0, Ordering::Relaxed) as u32
+    }
+}
+
+/// Fan speed control
+pub struct FanController<'a> {
+    pwm: Pwm<'a, TIM1>,
+    channel: Channel,
+    tach: &'a Tachometer,
+    max_rpm: u32,
+    pulses_per_revolution: u32,
+    stall_timeout: u8,
+    pid: Pid,
+    /// Closed loop setpoint
+    target: Option<u32>,
+    /// Duty cycle, Q15
+    duty: i16,
+    rpm: u32,
+    idle_updates: u8,
+}
+
+impl<'a> FanController<'a> {
+    /// Fan on `channel` of an initialized `pwm`, `max_rpm` being its speed
+    /// at full duty
+    ///
+    /// Starts stopped, 2 pulses per revolution, stalled after 3 updates
+    /// without pulses.
+    pub fn new(pwm: Pwm<'a, TIM1>, channel: Channel, tach: &'a Tachometer, max_rpm: u32)
+        -> Self
+    {
+        assert!(max_rpm != 0);
+
+        let fan = FanController {
+            pwm: pwm,
+            channel: channel,
+            tach: tach,
+            max_rpm: max_rpm,
+            pulses_per_revolution: 2,
+            stall_timeout: 3,
+            pid: Pid::new(control::q15(1, 4), control::q15(1, 8), 0).output_limits(0, 32767),
+            target: None,
+            duty: 0,
+            rpm: 0,
+            idle_updates: 0,
+        };
+        fan.write_duty();
+        hal::Pwm::enable(&fan.pwm, channel);
+        fan
+    }
+
+    /// Tachometer pulses per revolution
+    pub fn pulses_per_revolution(mut self, pulses: u32) -> Self {
+        assert!(pulses != 0);
+        self.pulses_per_revolution = pulses;
+        self
+    }
+
+    /// Updates without a pulse before a driven fan counts as stalled
+    pub fn stall_timeout(mut self, updates: u8) -> Self {
+        assert!(updates != 0);
+        self.stall_timeout = updates;
+        self
+    }
+
+    /// Closed loop controller, signals are speeds in Q15 of `max_rpm`
+    ///
+    /// The output limits set the duty cycle range, e.g. a minimum below
+    /// which the fan doesn't start.
+    pub fn pid(mut self, pid: Pid) -> Self {
+        self.pid = pid;
+        self
+    }
+
+    /// Runs the fan open loop at `duty` (Q15, 32767 is full speed)
+    pub fn set_duty(&mut self, duty: i16) {
+        self.target = None;
+        self.duty = if duty < 0 { 0 } else { duty };
+        self.write_duty();
+    }
+
+    /// Regulates the fan to `rpm` from the next `update` on
+    pub fn set_rpm(&mut self, rpm: u32) {
+        if self.target.is_none() {
+            self.pid.reset();
+        }
+        self.target = Some(rpm);
+    }
+
+    /// Speed measured by the last `update`
+    pub fn rpm(&self) -> u32 {
+        self.rpm
+    }
+
+    /// Current duty cycle, Q15
+    pub fn duty(&self) -> i16 {
+        self.duty
+    }
+
+    /// Checks if the last `update` found the fan stalled
+    pub fn is_stalled(&self) -> bool {
+        self.idle_updates >= self.stall_timeout
+    }
+
+    /// Measures the speed over the `period` since the previous call and,
+    /// in closed loop, adjusts the duty cycle
+    ///
+    /// Call this at a steady rate. Returns the speed in RPM.
+    pub fn update(&mut self, period: Milliseconds) -> Result<u32, Error> {
+        assert!(period.0 != 0);
+
+        let pulses = self.tach.take();
+        self.rpm = pulses * 60_000 / (self.pulses_per_revolution * period.0);
+
+        if pulses == 0 && self.duty > 0 {
+            self.idle_updates = self.idle_updates.saturating_add(1);
+        } else {
+            self.idle_updates = 0;
+        }
+
+        if self.is_stalled() {
+            self.pid.reset();
+            self.duty = 32767;
+            self.write_duty();
+            return Err(Error::Stall);
+        }
+
+        if let Some(target) = self.target {
+            let setpoint = self.q15(target);
+            let measurement = self.q15(self.rpm);
+            self.duty = self.pid.update(setpoint, measurement);
+            self.write_duty();
+        }
+
+        Ok(self.rpm)
+    }
+
+    /// `rpm` as a Q15 share of `max_rpm`, saturated
+    fn q15(&self, rpm: u32) -> i16 {
+        let q15 = u64::from(rpm) * 32768 / u64::from(self.max_rpm);
+        if q15 > 32767 { 32767 } else { q15 as i16 }
+    }
+
+    fn write_duty(&self) {
+        self.pwm.set_duty_fraction(self.channel, self.duty as u16, 32767);
+    }
+}
@@ -0,0 +1,127 @@
+//! Driver misuse handling
+//!
+//! Some DMA / SPI calls can be made in ways the hardware can't honor: a
+//! buffer locked twice, a transfer longer than NDTR can count, a DMA method
+//! called on an `Spi` built without that stream, or with a stream that isn't
+//! wired to the peripheral's request. These are bugs, and by
+//! default they panic like an `assert!`. Those calls run in interrupt
+//! handlers though, where a panic halts the whole system; with
+//! `FaultPolicy::Report` they fail with `dma2::Error::Fault` instead and
+//! are counted, so a production build can keep going and report the
+//! anomalies later:
+//!
+//! ``` ignore
+//! fault::set_policy(FaultPolicy::Report);
+//!
+//! // telemetry task
+//! if fault::count() != 0 {
+//!     log!("{} driver faults, last: {:?}", fault::count(), fault::last());
+//! }
+//! ```
+//!
+//! The DMA paths of `dma2`, `spi2`, `serial`, `i2c` and `bitbang` go through
+//! the policy, `Spi::prepare` and `Spi::circular_rx` included. The stream
+//! checks done once by constructors (`ParallelOut::new`, `Tone::new`,
+//! `Dmx512::new`) always return their module's `WrongStream` error; the one
+//! in `CircularSampler::start` still panics.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT,
+                         ATOMIC_USIZE_INIT};
+
+static REPORT: AtomicBool = ATOMIC_BOOL_INIT;
+static COUNT: AtomicUsize = ATOMIC_USIZE_INIT;
+/// `Fault::code` of the last fault, 0 if none
+static LAST: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// What happens on a driver misuse
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FaultPolicy {
+    /// Panic, the default
+    Panic,
+    /// Count the fault and return `dma2::Error::Fault`
+    Report,
+}
+
+/// Driver misuse
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Fault {
+    /// A `dma2::Buffer` was handed to the DMA while locked or borrowed
+    BufferLocked,
+    /// A transfer longer than 65535 items
+    TooLong,
+    /// A DMA method of `Spi` without the DMA stream it needs
+    NoStream,
+    /// A DMA stream / channel that doesn't serve the peripheral's request
+    WrongStream,
+    /// Transmit and receive buffers of different lengths
+    LengthMismatch,
+}
+
+impl Fault {
+    fn code(self) -> usize {
+        match self {
+            Fault::BufferLocked => 1,
+            Fault::TooLong => 2,
+            Fault::NoStream => 3,
+            Fault::WrongStream => 4,
+            Fault::LengthMismatch => 5,
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Fault::BufferLocked => "DMA buffer already locked",
+            Fault::TooLong => "DMA transfer too long",
+            Fault::NoStream => "SPI has no DMA stream",
+            Fault::WrongStream => "DMA stream not wired to the peripheral",
+            Fault::LengthMismatch => "DMA buffers of different lengths",
+        })
+    }
+}
+
+/// Sets the policy for all the drivers
+pub fn set_policy(policy: FaultPolicy) {
+    REPORT.store(policy == FaultPolicy::Report, Ordering::Relaxed);
+}
+
+/// Current policy
+pub fn policy() -> FaultPolicy {
+    if REPORT.load(Ordering::Relaxed) {
+        FaultPolicy::Report
+    } else {
+        FaultPolicy::Panic
+    }
+}
+
+/// Number of faults reported so far
+pub fn count() -> usize {
+    COUNT.load(Ordering::Relaxed)
+}
+
+/// Last reported fault
+pub fn last() -> Option<Fault> {
+    match LAST.load(Ordering::Relaxed) {
+        1 => Some(Fault::BufferLocked),
+        2 => Some(Fault::TooLong),
+        3 => Some(Fault::NoStream),
+        4 => Some(Fault::WrongStream),
+        5 => Some(Fault::LengthMismatch),
+        _ => None,
+    }
+}
+
+/// Applies the policy to `fault`; returns it when the caller must bail out
+/// with an error
+pub(crate) fn raise(fault: Fault) -> Fault {
+    match policy() {
+        FaultPolicy::Panic => panic!("{}", fault),
+        FaultPolicy::Report => {
+            COUNT.fetch_add(1, Ordering::Relaxed);
+            LAST.store(fault.code(), Ordering::Relaxed);
+            fault
+        }
+    }
+}
@@ -0,0 +1,237 @@
+//! Crash reports that survive a reset (`fault_handler` feature)
+//!
+//! `trampoline` is an exception handler for HardFault, MemManage, BusFault
+//! and UsageFault. It saves the registers the core stacked on exception
+//! entry plus the fault status registers (CFSR, HFSR and the faulting
+//! address) to the RTC backup registers 7 - 19, then stops at a breakpoint
+//! if a debugger is attached and resets the chip otherwise. The next boot
+//! reads the report back:
+//!
+//! ``` ignore
+//! #[link_section = ".vector_table.exceptions"]
+//! #[no_mangle]
+//! pub static EXCEPTIONS: exception::Handlers = exception::Handlers {
+//!     hard_fault: fault_handler::trampoline,
+//!     mem_manage: fault_handler::trampoline,
+//!     bus_fault: fault_handler::trampoline,
+//!     usage_fault: fault_handler::trampoline,
+//!     ..exception::DEFAULT_HANDLERS
+//! };
+//!
+//! // init
+//! fault_handler::enable(scb);
+//! if let Some(report) = fault_handler::last_fault(rtc) {
+//!     log!("{}", report);
+//!     fault_handler::clear(pwr, rcc, rtc);
+//! }
+//! ```
+//!
+//! The backup registers keep the report across resets and, with a VBAT
+//! supply, power loss; `boot` uses backup register 0, the others are free.
+//! The PC of the report is the instruction that faulted (or the next one for
+//! imprecise bus faults), look it up with `addr2line`.
+
+use core::fmt;
+use core::ptr;
+
+use stm32f411::{PWR, RCC, RTC, SCB};
+
+/// First backup register of the report
+const FIRST: isize = 7;
+/// Number of backup registers of the report
+const WORDS: usize = 13;
+/// "FALT", marks a valid report
+const MAGIC: u32 = 0x4641_4C54;
+
+// NOTE(address) the handler runs without the peripherals, it accesses the
+// registers it needs directly
+const RCC_APB1ENR: usize = 0x4002_3840;
+const PWR_CR: usize = 0x4000_7000;
+const RTC_BKP0R: usize = 0x4000_2850;
+const SCB_ICSR: usize = 0xE000_ED04;
+const SCB_AIRCR: usize = 0xE000_ED0C;
+const SCB_CFSR: usize = 0xE000_ED28;
+const SCB_HFSR: usize = 0xE000_ED2C;
+const SCB_MMFAR: usize = 0xE000_ED34;
+const SCB_BFAR: usize = 0xE000_ED38;
+const DHCSR: usize = 0xE000_EDF0;
+
+// CFSR
+const MMARVALID: u32 = 1 << 7;
+const BFARVALID: u32 = 1 << 15;
+
+/// Exception that reported the fault
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Exception {
+    HardFault,
+    MemManage,
+    BusFault,
+    UsageFault,
+}
+
+/// Registers stacked by the core on exception entry
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct StackedRegisters {
+    pub r0: u32,
+    pub r1: u32,
+    pub r2: u32,
+    pub r3: u32,
+    pub r12: u32,
+    pub lr: u32,
+    pub pc: u32,
+    pub xpsr: u32,
+}
+
+/// Fault saved by `trampoline`
+#[derive(Clone, Copy, Debug)]
+pub struct FaultReport {
+    pub exception: Exception,
+    pub registers: StackedRegisters,
+    /// Configurable fault status register (MMFSR, BFSR and UFSR)
+    pub cfsr: u32,
+    /// HardFault status register
+    pub hfsr: u32,
+    /// Faulting data address, from BFAR or MMFAR, if valid
+    pub address: Option<u32>,
+}
+
+impl fmt::Display for FaultReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let r = &self.registers;
+        write!(
+            f,
+            "{:?} PC={:#010x} LR={:#010x} xPSR={:#010x} CFSR={:#010x} HFSR={:#010x}",
+            self.exception, r.pc, r.lr, r.xpsr, self.cfsr, self.hfsr
+        )?;
+        if let Some(address) = self.address {
+            write!(f, " address={:#010x}", address)?;
+        }
+        write!(
+            f,
+            " R0={:#010x} R1={:#010x} R2={:#010x} R3={:#010x} R12={:#010x}",
+            r.r0, r.r1, r.r2, r.r3, r.r12
+        )
+    }
+}
+
+/// Enables the MemManage, BusFault and UsageFault exceptions
+///
+/// Without this the three escalate to HardFault; the report is the same,
+/// only `exception` tells them apart. Integer division by zero faults too.
+pub fn enable(scb: &SCB) {
+    unsafe {
+        // MEMFAULTENA, BUSFAULTENA, USGFAULTENA
+        scb.shcrs.modify(|r| r | (0b111 << 16));
+        // DIV_0_TRP
+        scb.ccr.modify(|r| r | (1 << 4));
+    }
+}
+
+/// The report saved before the last reset, if any
+pub fn last_fault(rtc: &RTC) -> Option<FaultReport> {
+    let base = &rtc.bkp0r as *const _ as *const u32;
+    let mut words = [0; WORDS];
+    for (i, word) in words.iter_mut().enumerate() {
+        *word = unsafe { ptr::read_volatile(base.offset(FIRST + i as isize)) };
+    }
+
+    if words[0] != MAGIC {
+        return None;
+    }
+
+    let exception = match words[1] & 0xFF {
+        4 => Exception::MemManage,
+        5 => Exception::BusFault,
+        6 => Exception::UsageFault,
+        _ => Exception::HardFault,
+    };
+    let valid = words[1] & (1 << 8) != 0;
+
+    Some(FaultReport {
+        exception: exception,
+        registers: StackedRegisters {
+            r0: words[5],
+            r1: words[6],
+            r2: words[7],
+            r3: words[8],
+            r12: words[9],
+            lr: words[10],
+            pc: words[11],
+            xpsr: words[12],
+        },
+        cfsr: words[2],
+        hfsr: words[3],
+        address: if valid { Some(words[4]) } else { None },
+    })
+}
+
+/// Erases the report
+pub fn clear(pwr: &PWR, rcc: &RCC, rtc: &RTC) {
+    rcc.apb1enr.modify(|_, w| w.pwren().set_bit());
+    pwr.cr.modify(|_, w| w.dbp().set_bit());
+    let base = &rtc.bkp0r as *const _ as *mut u32;
+    unsafe { ptr::write_volatile(base.offset(FIRST), 0) };
+    pwr.cr.modify(|_, w| w.dbp().clear_bit());
+}
+
+/// HardFault / MemManage / BusFault / UsageFault handler
+///
+/// Picks the stack the core used (MSP or PSP) and hands the stacked
+/// registers to `bsp_fault_record`.
+#[naked]
+pub extern "C" fn trampoline() {
+    unsafe {
+        asm!("tst lr, #4
+              ite eq
+              mrseq r0, msp
+              mrsne r0, psp
+              b bsp_fault_record"
+             :
+             :
+             :
+             : "volatile");
+    }
+}
+
+#[doc(hidden)]
+#[no_mangle]
+pub unsafe extern "C" fn bsp_fault_record(registers: &StackedRegisters) -> ! {
+    let read = |address: usize| ptr::read_volatile(address as *const u32);
+    let write = |address: usize, value: u32| ptr::write_volatile(address as *mut u32, value);
+
+    let cfsr = read(SCB_CFSR);
+    let address = if cfsr & BFARVALID != 0 {
+        Some(read(SCB_BFAR))
+    } else if cfsr & MMARVALID != 0 {
+        Some(read(SCB_MMFAR))
+    } else {
+        None
+    };
+    // VECTACTIVE, plus a flag for a valid address
+    let vector = read(SCB_ICSR) & 0x1FF;
+    let info = vector | if address.is_some() { 1 << 8 } else { 0 };
+    let address = address.unwrap_or(0);
+
+    // Backup domain write access: PWREN, DBP
+    write(RCC_APB1ENR, read(RCC_APB1ENR) | (1 << 28));
+    write(PWR_CR, read(PWR_CR) | (1 << 8));
+
+    let r = registers;
+    let words: [u32; WORDS] = [
+        MAGIC, info, cfsr, read(SCB_HFSR), address,
+        r.r0, r.r1, r.r2, r.r3, r.r12, r.lr, r.pc, r.xpsr,
+    ];
+    for (i, word) in words.iter().enumerate() {
+        write(RTC_BKP0R + 4 * (FIRST as usize + i), *word);
+    }
+
+    // C_DEBUGEN: let the debugger look at the crash
+    if read(DHCSR) & 1 != 0 {
+        asm!("bkpt" : : : : "volatile");
+    }
+
+    // VECTKEY | SYSRESETREQ
+    write(SCB_AIRCR, (0x05FA << 16) | (read(SCB_AIRCR) & 0x0700) | (1 << 2));
+    loop {}
+}
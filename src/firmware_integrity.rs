@@ -0,0 +1,112 @@
+//! Firmware image self-check
+//!
+//! `check` computes the CRC (see `crc`) of the application image and
+//! compares it against a footer word stored right after it. The linker
+//! script of the application must provide the image bounds:
+//!
+//! ``` text
+//! /* memory.x */
+//! __image_start = ORIGIN(FLASH);
+//! __image_footer = ALIGN(LOADADDR(.data) + SIZEOF(.data), 4);
+//! ```
+//!
+//! and the footer is filled in after linking: pad the `objcopy -O binary`
+//! output to a multiple of 4 bytes and append the CRC of the padded image,
+//! little endian. An image flashed without the footer reports `Missing`.
+//!
+//! ``` ignore
+//! match firmware_integrity::check(rcc, crc) {
+//!     Ok(()) => {}
+//!     Err(e) => { /* stay in the bootloader, request an update, .. */ }
+//! }
+//! ```
+//!
+//! `check_region` checks an arbitrary image, e.g. the application from a
+//! bootloader.
+
+use core::{fmt, slice};
+
+use stm32f411::{CRC, RCC};
+
+use crc::Crc;
+use flash::{FLASH_SIZE, FLASH_START};
+
+/// Footer value of an image without CRC (erased flash)
+const ERASED: u32 = 0xFFFF_FFFF;
+
+extern "C" {
+    static __image_start: u32;
+    static __image_footer: u32;
+}
+
+/// Image integrity error
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The footer is erased, no CRC was stored
+    Missing,
+    /// The image doesn't match the stored CRC
+    Mismatch {
+        /// CRC stored in the footer
+        expected: u32,
+        /// CRC of the image in flash
+        computed: u32,
+    },
+    /// The image bounds are misaligned or not in flash
+    InvalidRegion,
+    #[doc(hidden)]
+    _Extensible,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Error::Missing => "image has no CRC footer",
+            Error::Mismatch { .. } => "image CRC mismatch",
+            Error::InvalidRegion => "invalid image region",
+            Error::_Extensible => "unknown error",
+        })
+    }
+}
+
+/// Checks the running image against its footer
+///
+/// Takes about 1 ms per 100 KB at 100 MHz. Enables the CRC clock.
+pub fn check(rcc: &RCC, crc: &CRC) -> Result<(), Error> {
+    let (start, footer) = unsafe {
+        (
+            &__image_start as *const u32 as u32,
+            &__image_footer as *const u32 as u32,
+        )
+    };
+    check_region(rcc, crc, start, footer)
+}
+
+/// Checks the image in `start .. footer` against the word at `footer`
+pub fn check_region(rcc: &RCC, crc: &CRC, start: u32, footer: u32) -> Result<(), Error> {
+    if start % 4 != 0 || footer % 4 != 0 || start < FLASH_START || footer < start ||
+        footer + 4 > FLASH_START + FLASH_SIZE
+    {
+        return Err(Error::InvalidRegion);
+    }
+
+    let expected = unsafe { *(footer as *const u32) };
+    if expected == ERASED {
+        return Err(Error::Missing);
+    }
+
+    let image = unsafe {
+        slice::from_raw_parts(start as *const u32, ((footer - start) / 4) as usize)
+    };
+    let crc = Crc(crc);
+    crc.init(rcc);
+    let computed = crc.checksum(image);
+
+    if computed == expected {
+        Ok(())
+    } else {
+        Err(Error::Mismatch {
+            expected: expected,
+            computed: computed,
+        })
+    }
+}
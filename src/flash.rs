@@ -0,0 +1,394 @@
+//! Embedded flash interface
+//!
+//! `AcrConfig` sets the wait states and the ART accelerator (prefetch buffer,
+//! instruction and data caches). `Cfgr::freeze` applies `AcrConfig::for_hclk`
+//! on its own; use this directly to change the caches afterwards, e.g. to
+//! turn the data cache off while benchmarking:
+//!
+//! ``` ignore
+//! AcrConfig::for_hclk(clocks.hclk().0).dcache(false).apply(flash);
+//! ```
+//!
+//! NOTE Lowering the wait states below what `hclk` needs (see `for_hclk`)
+//! makes the core fetch garbage.
+//!
+//! `erase_sector` and `program_word` write the flash with 32-bit
+//! parallelism, which needs a 2.7 - 3.6 V supply. Code keeps running from
+//! flash meanwhile, but every flash access stalls until the operation is
+//! done: up to 2 s for the erase of a 128 KB sector.
+//!
+//! `Storage` puts the same operations behind the read / write / erase
+//! interface of a NOR flash device, with offsets instead of addresses, for
+//! file systems and update code that don't care about the sector layout.
+//! It is not an `embedded-storage` `NorFlash` implementation: that crate
+//! needs a newer compiler than this crate builds with, so libraries written
+//! against those traits can't use `Storage` directly.
+
+use core::{fmt, ptr};
+
+use stm32f411::FLASH;
+
+/// Start of the main flash memory
+pub const FLASH_START: u32 = 0x0800_0000;
+
+/// Size of the main flash memory
+pub const FLASH_SIZE: u32 = 512 * 1024;
+
+const PRFTEN: u32 = 1 << 8;
+const ICEN: u32 = 1 << 9;
+const DCEN: u32 = 1 << 10;
+const ICRST: u32 = 1 << 11;
+const DCRST: u32 = 1 << 12;
+
+// KEYR
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+
+// CR
+const PG: u32 = 1 << 0;
+const SER: u32 = 1 << 1;
+const PSIZE_X32: u32 = 0b10 << 8;
+const STRT: u32 = 1 << 16;
+const LOCK: u32 = 1 << 31;
+
+// SR
+const EOP: u32 = 1 << 0;
+const OPERR: u32 = 1 << 1;
+const WRPERR: u32 = 1 << 4;
+const PGAERR: u32 = 1 << 5;
+const PGPERR: u32 = 1 << 6;
+const PGSERR: u32 = 1 << 7;
+const BSY: u32 = 1 << 16;
+
+/// Number of sectors of the main flash memory
+pub const SECTORS: u8 = 8;
+
+/// Erase / program error
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The sector is write protected (option bytes)
+    WriteProtection,
+    /// Misaligned address, or not in the main flash memory
+    Alignment,
+    /// Wrong parallelism for the supply voltage, or programming sequence
+    /// error
+    Programming,
+    /// The range doesn't fit in the flash, or an erase range doesn't start
+    /// and end on sector boundaries
+    OutOfBounds,
+    #[doc(hidden)]
+    _Extensible,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Error::WriteProtection => "flash sector is write protected",
+            Error::Alignment => "misaligned flash address",
+            Error::Programming => "flash programming error",
+            Error::OutOfBounds => "flash range out of bounds",
+            Error::_Extensible => "unknown error",
+        })
+    }
+}
+
+/// Flash access control configuration
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AcrConfig {
+    latency: u8,
+    prefetch: bool,
+    icache: bool,
+    dcache: bool,
+}
+
+impl AcrConfig {
+    /// Wait states `hclk` needs at 2.7 - 3.6 V, with prefetch and both
+    /// caches enabled
+    pub fn for_hclk(hclk: u32) -> Self {
+        let latency = match hclk {
+            0...30_000_000 => 0,
+            30_000_001...64_000_000 => 1,
+            64_000_001...90_000_000 => 2,
+            _ => 3,
+        };
+
+        AcrConfig {
+            latency: latency,
+            prefetch: true,
+            icache: true,
+            dcache: true,
+        }
+    }
+
+    /// Current configuration
+    pub fn read(flash: &FLASH) -> Self {
+        let acr = flash.acr.read().bits();
+        AcrConfig {
+            latency: (acr & 0xF) as u8,
+            prefetch: acr & PRFTEN != 0,
+            icache: acr & ICEN != 0,
+            dcache: acr & DCEN != 0,
+        }
+    }
+
+    /// Number of wait states (0 - 15)
+    pub fn latency(mut self, latency: u8) -> Self {
+        assert!(latency < 16);
+        self.latency = latency;
+        self
+    }
+
+    /// Enables the prefetch buffer
+    pub fn prefetch(mut self, enable: bool) -> Self {
+        self.prefetch = enable;
+        self
+    }
+
+    /// Enables the instruction cache
+    pub fn icache(mut self, enable: bool) -> Self {
+        self.icache = enable;
+        self
+    }
+
+    /// Enables the data cache
+    pub fn dcache(mut self, enable: bool) -> Self {
+        self.dcache = enable;
+        self
+    }
+
+    /// Number of wait states
+    pub fn wait_states(&self) -> u8 {
+        self.latency
+    }
+
+    /// Writes the configuration to the ACR register
+    ///
+    /// A cache that goes from disabled to enabled is flushed first, it may
+    /// hold lines from before the flash was last written.
+    pub fn apply(self, flash: &FLASH) {
+        let current = AcrConfig::read(flash);
+
+        let mut reset = 0;
+        if self.icache && !current.icache {
+            reset |= ICRST;
+        }
+        if self.dcache && !current.dcache {
+            reset |= DCRST;
+        }
+        if reset != 0 {
+            flash.acr.modify(|r, w| unsafe { w.bits(r.bits() | reset) });
+            flash.acr.modify(|r, w| unsafe { w.bits(r.bits() & !reset) });
+        }
+
+        let mut acr = u32::from(self.latency);
+        if self.prefetch {
+            acr |= PRFTEN;
+        }
+        if self.icache {
+            acr |= ICEN;
+        }
+        if self.dcache {
+            acr |= DCEN;
+        }
+        flash.acr.modify(|r, w| unsafe {
+            w.bits((r.bits() & !(0xF | PRFTEN | ICEN | DCEN)) | acr)
+        });
+        while flash.acr.read().bits() & 0xF != u32::from(self.latency) {}
+    }
+}
+
+/// Flushes the instruction and data caches
+///
+/// Needed after writing to flash, the caches don't see the new contents.
+/// The caches are disabled while they are reset and restored afterwards.
+pub fn flush_caches(flash: &FLASH) {
+    let acr = flash.acr.read().bits();
+
+    // The caches can only be reset while disabled
+    flash.acr.write(|w| unsafe { w.bits(acr & !(ICEN | DCEN)) });
+    flash.acr.write(|w| unsafe { w.bits((acr & !(ICEN | DCEN)) | ICRST | DCRST) });
+    flash.acr.write(|w| unsafe { w.bits(acr & !(ICEN | DCEN)) });
+    flash.acr.write(|w| unsafe { w.bits(acr) });
+}
+
+/// Start address of `sector`
+///
+/// Sectors 0 - 3 are 16 KB, 4 is 64 KB and 5 - 7 are 128 KB.
+pub fn sector_address(sector: u8) -> u32 {
+    assert!(sector < SECTORS);
+    match sector {
+        0...4 => FLASH_START + u32::from(sector) * 16 * 1024,
+        _ => FLASH_START + u32::from(sector - 4) * 128 * 1024,
+    }
+}
+
+/// Size of `sector`, in bytes
+pub fn sector_size(sector: u8) -> u32 {
+    assert!(sector < SECTORS);
+    match sector {
+        0...3 => 16 * 1024,
+        4 => 64 * 1024,
+        _ => 128 * 1024,
+    }
+}
+
+/// Erases `sector` (to all ones)
+///
+/// Flushes the caches afterwards. Erasing the sector the code runs from
+/// ends badly.
+pub fn erase_sector(flash: &FLASH, sector: u8) -> Result<(), Error> {
+    assert!(sector < SECTORS);
+
+    let result = unlocked(flash, || {
+        flash.cr.write(|w| unsafe {
+            w.bits(PSIZE_X32 | SER | (u32::from(sector) << 3))
+        });
+        flash.cr.modify(|r, w| unsafe { w.bits(r.bits() | STRT) });
+        wait(flash)
+    });
+    flush_caches(flash);
+    result
+}
+
+/// Programs the word at `address`
+///
+/// Bits can only go from 1 to 0, the word should be erased, or `value`
+/// have no ones where the word has zeros.
+pub fn program_word(flash: &FLASH, address: u32, value: u32) -> Result<(), Error> {
+    if address % 4 != 0 || address < FLASH_START || address + 4 > FLASH_START + FLASH_SIZE {
+        return Err(Error::Alignment);
+    }
+
+    unlocked(flash, || {
+        flash.cr.write(|w| unsafe { w.bits(PSIZE_X32 | PG) });
+        unsafe { ::core::ptr::write_volatile(address as *mut u32, value) }
+        wait(flash)
+    })
+}
+
+/// Main flash as a NOR flash device, offsets count from `FLASH_START`
+///
+/// Reads have byte granularity, writes word granularity (offset and length
+/// multiples of `WRITE_SIZE`) and erases sector granularity: 16 KB, 64 KB
+/// or 128 KB depending on the offset, see `sector_size`. As with any NOR
+/// flash, writes can only clear bits.
+pub struct Storage<'a>(pub &'a FLASH);
+
+impl<'a> Storage<'a> {
+    /// Smallest read unit, in bytes
+    pub const READ_SIZE: u32 = 1;
+    /// Smallest write unit, in bytes
+    pub const WRITE_SIZE: u32 = 4;
+
+    /// Size of the device, in bytes
+    pub fn capacity(&self) -> u32 {
+        FLASH_SIZE
+    }
+
+    /// Reads `bytes.len()` bytes at `offset`
+    pub fn read(&self, offset: u32, bytes: &mut [u8]) -> Result<(), Error> {
+        check_range(offset, bytes.len())?;
+
+        let base = FLASH_START + offset;
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = unsafe { ptr::read_volatile((base + i as u32) as *const u8) };
+        }
+        Ok(())
+    }
+
+    /// Programs `bytes` at `offset`, which must have been erased
+    pub fn write(&self, offset: u32, bytes: &[u8]) -> Result<(), Error> {
+        check_range(offset, bytes.len())?;
+        if offset % Self::WRITE_SIZE != 0 || bytes.len() as u32 % Self::WRITE_SIZE != 0 {
+            return Err(Error::Alignment);
+        }
+
+        let mut address = FLASH_START + offset;
+        for word in bytes.chunks(4) {
+            let value = u32::from(word[0]) | u32::from(word[1]) << 8 |
+                u32::from(word[2]) << 16 | u32::from(word[3]) << 24;
+            program_word(self.0, address, value)?;
+            address += 4;
+        }
+        Ok(())
+    }
+
+    /// Erases `from .. to`, both on sector boundaries
+    pub fn erase(&self, from: u32, to: u32) -> Result<(), Error> {
+        if from > to || to > FLASH_SIZE {
+            return Err(Error::OutOfBounds);
+        }
+
+        let (first, last) = match (sector_at(from), sector_at(to)) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Err(Error::OutOfBounds),
+        };
+        for sector in first..last {
+            erase_sector(self.0, sector)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks that `len` bytes at `offset` are in the flash
+fn check_range(offset: u32, len: usize) -> Result<(), Error> {
+    if offset > FLASH_SIZE || len as u32 > FLASH_SIZE - offset {
+        Err(Error::OutOfBounds)
+    } else {
+        Ok(())
+    }
+}
+
+/// Sector that starts at `offset`; `SECTORS` for the end of the flash
+fn sector_at(offset: u32) -> Option<u8> {
+    if offset == FLASH_SIZE {
+        return Some(SECTORS);
+    }
+    (0..SECTORS).find(|sector| sector_address(*sector) - FLASH_START == offset)
+}
+
+/// Runs `f` with the control register unlocked, locks it again afterwards
+fn unlocked<F>(flash: &FLASH, f: F) -> Result<(), Error>
+    where F: FnOnce() -> Result<(), Error>
+{
+    while flash.sr.read().bits() & BSY != 0 {}
+    clear_errors(flash);
+
+    if flash.cr.read().bits() & LOCK != 0 {
+        flash.keyr.write(|w| unsafe { w.bits(KEY1) });
+        flash.keyr.write(|w| unsafe { w.bits(KEY2) });
+    }
+
+    let result = f();
+
+    flash.cr.write(|w| unsafe { w.bits(LOCK) });
+    result
+}
+
+/// Clears the flags of the previous operation, its errors would block the
+/// next one
+pub(crate) fn clear_errors(flash: &FLASH) {
+    flash.sr.write(|w| unsafe {
+        w.bits(EOP | OPERR | WRPERR | PGAERR | PGPERR | PGSERR)
+    });
+}
+
+/// Waits for the end of the current operation
+pub(crate) fn wait(flash: &FLASH) -> Result<(), Error> {
+    let sr = loop {
+        let sr = flash.sr.read().bits();
+        if sr & BSY == 0 {
+            break sr;
+        }
+    };
+
+    if sr & WRPERR != 0 {
+        Err(Error::WriteProtection)
+    } else if sr & PGAERR != 0 {
+        Err(Error::Alignment)
+    } else if sr & (OPERR | PGPERR | PGSERR) != 0 {
+        Err(Error::Programming)
+    } else {
+        Ok(())
+    }
+}
@@ -1,15 +1,93 @@
-use stm32f411::{GPIOB, RCC};
+//! General Purpose I/O
+//!
+//! A `TypedPin` carries its port, pin number and mode in its type, `N0` ..
+//! `N15` and `Input` / `Output` / `Analog` / `Alternate<AF>`, so a driver
+//! can ask for e.g. an output pin in its signature, and the mode changes
+//! consume the pin. It is the way to drive and read a single pin:
+//!
+//! ``` ignore
+//! let led: TypedPin<GPIOD, N12, Output> = TypedPin::new().into_output(gpiod);
+//! led.set(gpiod, Io::High);
+//! ```
+//!
+//! A `Pin` is typed by its port only, the pin number is a value, so pins of
+//! one port can be stored in arrays and configured in loops. It only
+//! configures the pin; `erase` turns a `TypedPin` into one:
+//!
+//! ``` ignore
+//! const LEDS: [Pin<GPIOD>; 4] = [Pin::new(12), Pin::new(13), Pin::new(14), Pin::new(15)];
+//!
+//! for led in LEDS.iter() {
+//!     led.set_mode(gpiod, Mode::Output);
+//! }
+//! ```
+//!
+//! Pins grant no access by themselves: every method takes the port's
+//! register block, so the port (an RTFM resource) is the access token and
+//! the resource ceilings decide who can touch it. The `TypedPin` I/O
+//! methods are single BSRR writes / IDR reads; the configuration methods
+//! are read-modify-write and must not be used on a port shared between
+//! priorities without a lock.
+//!
+//! Whole-port access (any mask of pins at once) goes through `PortAccess`,
+//! and so does the I/O of a `PinGroup`. Only one exists per port at a time,
+//! so the code holding it is the only user of the port-level operations:
+//!
+//! ``` ignore
+//! let bus = PortAccess::claim(gpiod).unwrap();
+//! bus.write(0x00FF, byte as u16);
+//! let inputs = bus.read();
+//! ```
+//!
+//! Alternate functions are given as `AF0` .. `AF15` markers rather than
+//! numbers. Drivers with several possible pins per signal (`spi2::route`,
+//! `serial::route`) take the typed pins at the end of this module instead,
+//! with a per-peripheral table, so a pin / peripheral combination that
+//! doesn't exist doesn't compile and the AF number can't be wrong.
+//!
+//! PA13 and PA14 are the SWD pins. Changing their mode, alternate
+//! function, pull, speed or output type panics until `DebugPins` was
+//! consumed, see there.
+
+use stm32f411::{GPIOA, GPIOB, GPIOC, GPIOD, GPIOE, RCC};
 use stm32f411::gpioa;
 use core::ops::Deref;
 use core::marker::PhantomData;
+use core::sync::atomic::{ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT, AtomicBool, AtomicUsize,
+                         Ordering};
+
+/// GPIOA base address
+const GPIOA_BASE: usize = 0x4002_0000;
+/// Distance between two ports
+const PORT_STRIDE: usize = 0x400;
+/// PA13 (SWDIO) and PA14 (SWCLK)
+const SWD_PINS: u16 = (1 << 13) | (1 << 14);
+
+static SWD_RELEASED: AtomicBool = ATOMIC_BOOL_INIT;
+/// Set once the `DebugPins` token was handed out
+static DEBUG_PINS_TAKEN: AtomicBool = ATOMIC_BOOL_INIT;
+/// Ports with a live `PortAccess`, bit n for GPIOA + n * `PORT_STRIDE`
+static PORTS_CLAIMED: AtomicUsize = ATOMIC_USIZE_INIT;
 
 pub struct Pin<T>
     where T: Deref<Target=gpioa::RegisterBlock>
 {
-    phantom: PhantomData<*const T>,
+    // NOTE(fn) only names the port, a `Pin` holds no reference to it and is
+    // `Send` / `Sync`
+    phantom: PhantomData<fn() -> T>,
     pin: u8,
 }
 
+impl<T> Clone for Pin<T>
+    where T: Deref<Target=gpioa::RegisterBlock>
+{
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Pin<T> where T: Deref<Target=gpioa::RegisterBlock> {}
+
 #[derive(Copy, Clone)]
 pub enum Io {
     Low,
@@ -39,6 +117,45 @@ pub enum Pupd {
     PullDown,
 }
 
+/// Alternate function, implemented by the `AF0` .. `AF15` markers
+pub unsafe trait AltFn: Copy {
+    /// Value of the AFRL / AFRH field
+    const NUMBER: u8;
+}
+
+macro_rules! alternate_functions {
+    ($($AF:ident: $n:expr,)+) => {
+        $(
+            /// Alternate function marker
+            #[derive(Clone, Copy, Debug)]
+            pub struct $AF;
+
+            unsafe impl AltFn for $AF {
+                const NUMBER: u8 = $n;
+            }
+        )+
+    }
+}
+
+alternate_functions! {
+    AF0: 0,
+    AF1: 1,
+    AF2: 2,
+    AF3: 3,
+    AF4: 4,
+    AF5: 5,
+    AF6: 6,
+    AF7: 7,
+    AF8: 8,
+    AF9: 9,
+    AF10: 10,
+    AF11: 11,
+    AF12: 12,
+    AF13: 13,
+    AF14: 14,
+    AF15: 15,
+}
+
 impl<T> Pin<T>
     where T: Deref<Target=gpioa::RegisterBlock>
 {
@@ -46,7 +163,12 @@ impl<T> Pin<T>
         Pin {pin, phantom: PhantomData}
     }
 
-    pub fn set(&self, port: &T, data: Io) {
+    /// Pin number within the port
+    pub fn number(&self) -> u8 {
+        self.pin
+    }
+
+    pub(crate) fn set(&self, port: &T, data: Io) {
         let value: u32 = match data {
             Io::High => 1 << self.pin,
             Io::Low => 1 << (16 + self.pin),
@@ -54,7 +176,15 @@ impl<T> Pin<T>
         port.bsrr.write(|w| unsafe { w.bits(value) });
     }
 
-    pub fn get(&self, port: &T) -> Io {
+    /// Inverts the output level
+    ///
+    /// Reads ODR but writes BSRR, so concurrent changes to other pins of the
+    /// port are never undone, see `PortAccess::toggle`
+    pub(crate) fn toggle(&self, port: &T) {
+        toggle_many(port, 1 << self.pin);
+    }
+
+    pub(crate) fn get(&self, port: &T) -> Io {
         let value: bool = ((port.idr.read().bits()) & (1 << self.pin)) != 0;
         if value {
             Io::High
@@ -63,7 +193,9 @@ impl<T> Pin<T>
         }
     }
 
-    pub fn alternate_function(&self, port:&T, mode: u8) {
+    /// Raw AFR write, `set_alternate` takes the typed `AltFn` instead
+    pub(crate) fn alternate_function(&self, port:&T, mode: u8) {
+        check_swd(port, 1 << self.pin);
         if self.pin < 8 {
             let value = (mode as u32) << (self.pin * 4);
             let mask = !((0b1111 as u32) << (self.pin * 4));
@@ -75,23 +207,653 @@ impl<T> Pin<T>
         }
     }
 
+    /// Sets the pin mode
+    ///
+    /// # Panics
+    ///
+    /// On PA13 / PA14 until `DebugPins` released them
     pub fn set_mode(&self, port:&T, mode: Mode) {
+        check_swd(port, 1 << self.pin);
         let value: u32 = (mode as u32) << (self.pin * 2);
         let mask = !((0b11 as u32) << (self.pin * 2));
         port.moder.modify(|r, w| unsafe { w.bits((r.bits() & mask) | value) })
     }
 
+    /// Sets the output speed
+    ///
+    /// # Panics
+    ///
+    /// On PA13 / PA14 until `DebugPins` released them
     pub fn set_speed(&self, port: &T, speed: Speed) {
+        check_swd(port, 1 << self.pin);
         let value: u32 = (speed as u32) << (self.pin * 2);
         let mask = !((0b11 as u32) << (self.pin * 2));
         port.ospeedr.modify(|r, w| unsafe { w.bits((r.bits() & mask) | value) })
     }
 
+    /// Sets the pull up / down
+    ///
+    /// # Panics
+    ///
+    /// On PA13 / PA14 until `DebugPins` released them
     pub fn set_pupd(&self, port: &T, pupd: Pupd) {
+        check_swd(port, 1 << self.pin);
         let value: u32 = (pupd as u32) << (self.pin * 2);
         let mask = !((0b11 as u32) << (self.pin * 2));
         port.pupdr.modify(|r, w| unsafe { w.bits((r.bits() & mask) | value) })
     }
+
+    /// Hands the pin over to the peripheral behind alternate function `af`
+    ///
+    /// # Panics
+    ///
+    /// On PA13 / PA14 until `DebugPins` released them
+    pub fn set_alternate<A>(&self, port: &T, _af: A, speed: Speed)
+        where A: AltFn
+    {
+        self.alternate_function(port, A::NUMBER);
+        self.set_speed(port, speed);
+        self.set_mode(port, Mode::AlternateFunction);
+    }
+
+    /// Selects open-drain (`true`) or push-pull output
+    ///
+    /// # Panics
+    ///
+    /// On PA13 / PA14 until `DebugPins` released them
+    pub fn set_open_drain(&self, port: &T, open_drain: bool) {
+        check_swd(port, 1 << self.pin);
+        let mask = 1 << self.pin;
+        port.otyper.modify(|r, w| unsafe {
+            if open_drain {
+                w.bits(r.bits() | mask)
+            } else {
+                w.bits(r.bits() & !mask)
+            }
+        })
+    }
+}
+
+/// Drives every pin selected by `mask` to the matching bit of `value`
+///
+/// All pins change state with a single BSRR write
+pub(crate) fn write_port<T>(port: &T, mask: u16, value: u16)
+    where T: Deref<Target=gpioa::RegisterBlock>
+{
+    let set = (value & mask) as u32;
+    let reset = (!value & mask) as u32;
+    port.bsrr.write(|w| unsafe { w.bits((reset << 16) | set) });
+}
+
+/// Drives every pin selected by `mask` high with a single BSRR write
+pub(crate) fn set_many<T>(port: &T, mask: u16)
+    where T: Deref<Target=gpioa::RegisterBlock>
+{
+    port.bsrr.write(|w| unsafe { w.bits(mask as u32) });
+}
+
+/// Drives every pin selected by `mask` low with a single BSRR write
+pub(crate) fn clear_many<T>(port: &T, mask: u16)
+    where T: Deref<Target=gpioa::RegisterBlock>
+{
+    port.bsrr.write(|w| unsafe { w.bits((mask as u32) << 16) });
+}
+
+/// Inverts every pin selected by `mask`, see `PortAccess::toggle`
+pub(crate) fn toggle_many<T>(port: &T, mask: u16)
+    where T: Deref<Target=gpioa::RegisterBlock>
+{
+    let odr = port.odr.read().bits() as u16;
+    write_port(port, mask, !odr);
+}
+
+/// Reads the input state of the whole port
+pub(crate) fn read_port<T>(port: &T) -> u16
+    where T: Deref<Target=gpioa::RegisterBlock>
+{
+    port.idr.read().bits() as u16
+}
+
+/// Exclusive whole-port access, see the module documentation
+///
+/// Released when dropped.
+pub struct PortAccess<'a, T>
+    where T: 'a + Deref<Target=gpioa::RegisterBlock>
+{
+    port: &'a T,
+    bit: usize,
+}
+
+// NOTE(unsafe) the claim makes this the only port-level handle, and every
+// access is a single IDR / ODR read or a single BSRR write
+unsafe impl<'a, T> Send for PortAccess<'a, T>
+    where T: 'a + Deref<Target=gpioa::RegisterBlock>
+{}
+
+impl<'a, T> PortAccess<'a, T>
+    where T: Deref<Target=gpioa::RegisterBlock>
+{
+    /// Takes the port-level access to `port`, `None` if it is already held
+    pub fn claim(port: &'a T) -> Option<Self> {
+        let base = &**port as *const gpioa::RegisterBlock as usize;
+        let bit = 1 << ((base - GPIOA_BASE) / PORT_STRIDE);
+        if PORTS_CLAIMED.fetch_or(bit, Ordering::Acquire) & bit != 0 {
+            return None;
+        }
+        Some(PortAccess { port: port, bit: bit })
+    }
+
+    /// The port, for the pin configuration methods
+    pub fn port(&self) -> &'a T {
+        self.port
+    }
+
+    /// Drives every pin selected by `mask` to the matching bit of `value`
+    ///
+    /// All pins change state with a single BSRR write
+    pub fn write(&self, mask: u16, value: u16) {
+        write_port(self.port, mask, value);
+    }
+
+    /// Drives every pin selected by `mask` high with a single BSRR write
+    pub fn set(&self, mask: u16) {
+        set_many(self.port, mask);
+    }
+
+    /// Drives every pin selected by `mask` low with a single BSRR write
+    pub fn clear(&self, mask: u16) {
+        clear_many(self.port, mask);
+    }
+
+    /// Inverts every pin selected by `mask` with a single BSRR write
+    ///
+    /// The new levels are computed from ODR, but only the selected pins are
+    /// written: an interrupt that changes other pins of the port between
+    /// the read and the write is not undone, unlike with a read-modify-write
+    /// of ODR. The selected pins themselves must not be changed
+    /// concurrently.
+    pub fn toggle(&self, mask: u16) {
+        toggle_many(self.port, mask);
+    }
+
+    /// Reads the input state of the whole port
+    pub fn read(&self) -> u16 {
+        read_port(self.port)
+    }
+}
+
+impl<'a, T> Drop for PortAccess<'a, T>
+    where T: 'a + Deref<Target=gpioa::RegisterBlock>
+{
+    fn drop(&mut self) {
+        PORTS_CLAIMED.fetch_and(!self.bit, Ordering::Release);
+    }
+}
+
+/// Up to 16 pins of one port, driven and read together
+///
+/// `write` is a single BSRR write and `read` a single IDR read; the mask
+/// and shift are computed once, by the `const fn` constructors, so a group
+/// can be a `const`. The I/O methods take the port's `PortAccess`:
+///
+/// ``` ignore
+/// // 8-bit data bus on PD0 - PD7
+/// const BUS: PinGroup<GPIOD> = PinGroup::consecutive(0, 8);
+///
+/// BUS.set_mode(gpiod, Mode::Output);
+/// let access = PortAccess::claim(gpiod).unwrap();
+/// BUS.write(&access, 0xA5);
+/// ```
+pub struct PinGroup<T>
+    where T: Deref<Target=gpioa::RegisterBlock>
+{
+    phantom: PhantomData<fn() -> T>,
+    mask: u16,
+    shift: u8,
+}
+
+impl<T> Clone for PinGroup<T>
+    where T: Deref<Target=gpioa::RegisterBlock>
+{
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for PinGroup<T> where T: Deref<Target=gpioa::RegisterBlock> {}
+
+impl<T> PinGroup<T>
+    where T: Deref<Target=gpioa::RegisterBlock>
+{
+    /// `width` consecutive pins from `first`; bit 0 of the values is pin
+    /// `first`
+    pub const fn consecutive(first: u8, width: u8) -> Self {
+        PinGroup {
+            phantom: PhantomData,
+            mask: (((1u32 << width) - 1) << first) as u16,
+            shift: first,
+        }
+    }
+
+    /// The pins set in `mask`; the values keep the port bit positions
+    pub const fn from_mask(mask: u16) -> Self {
+        PinGroup { phantom: PhantomData, mask: mask, shift: 0 }
+    }
+
+    /// Port bits of the pins
+    pub fn mask(&self) -> u16 {
+        self.mask
+    }
+
+    /// BSRR word that drives the pins to `value`, e.g. for a DMA transfer
+    /// to BSRR
+    pub fn bsrr(&self, value: u16) -> u32 {
+        let value = value << self.shift;
+        let set = u32::from(value & self.mask);
+        let reset = u32::from(!value & self.mask);
+        (reset << 16) | set
+    }
+
+    /// Drives the pins to `value`, other pins of the port are untouched
+    pub fn write(&self, access: &PortAccess<T>, value: u16) {
+        access.port.bsrr.write(|w| unsafe { w.bits(self.bsrr(value)) });
+    }
+
+    /// Drives all the pins high
+    pub fn set(&self, access: &PortAccess<T>) {
+        access.set(self.mask);
+    }
+
+    /// Drives all the pins low
+    pub fn clear(&self, access: &PortAccess<T>) {
+        access.clear(self.mask);
+    }
+
+    /// Input state of the pins
+    pub fn read(&self, access: &PortAccess<T>) -> u16 {
+        (access.read() & self.mask) >> self.shift
+    }
+
+    /// Sets the mode of all the pins with a single MODER read-modify-write
+    ///
+    /// # Panics
+    ///
+    /// If the group covers PA13 / PA14 before `DebugPins` released them
+    pub fn set_mode(&self, port: &T, mode: Mode) {
+        check_swd(port, self.mask);
+        let (mask, value) = self.fields(2, mode as u32);
+        port.moder.modify(|r, w| unsafe { w.bits((r.bits() & !mask) | value) })
+    }
+
+    /// Sets the pull up / down of all the pins with a single PUPDR
+    /// read-modify-write
+    ///
+    /// # Panics
+    ///
+    /// If the group covers PA13 / PA14 before `DebugPins` released them
+    pub fn set_pupd(&self, port: &T, pupd: Pupd) {
+        check_swd(port, self.mask);
+        let (mask, value) = self.fields(2, pupd as u32);
+        port.pupdr.modify(|r, w| unsafe { w.bits((r.bits() & !mask) | value) })
+    }
+
+    /// Selects open-drain (`true`) or push-pull outputs for all the pins
+    ///
+    /// # Panics
+    ///
+    /// If the group covers PA13 / PA14 before `DebugPins` released them
+    pub fn set_open_drain(&self, port: &T, open_drain: bool) {
+        check_swd(port, self.mask);
+        let mask = u32::from(self.mask);
+        port.otyper.modify(|r, w| unsafe {
+            if open_drain {
+                w.bits(r.bits() | mask)
+            } else {
+                w.bits(r.bits() & !mask)
+            }
+        })
+    }
+
+    /// Mask and value of the `width`-bit fields of the pins in a
+    /// configuration register
+    fn fields(&self, width: u32, field: u32) -> (u32, u32) {
+        let mut mask = 0;
+        let mut value = 0;
+        for pin in 0..16 {
+            if self.mask & (1 << pin) != 0 {
+                mask |= ((1 << width) - 1) << (pin * width);
+                value |= field << (pin * width);
+            }
+        }
+        (mask, value)
+    }
+}
+
+/// Pin number as a type, implemented by the `N0` .. `N15` markers
+pub unsafe trait PinNumber {
+    /// Pin number within the port
+    const NUMBER: u8;
+}
+
+macro_rules! pin_numbers {
+    ($($N:ident: $n:expr,)+) => {
+        $(
+            /// Pin number marker
+            #[derive(Clone, Copy, Debug)]
+            pub struct $N;
+
+            unsafe impl PinNumber for $N {
+                const NUMBER: u8 = $n;
+            }
+        )+
+    }
+}
+
+pin_numbers! {
+    N0: 0,
+    N1: 1,
+    N2: 2,
+    N3: 3,
+    N4: 4,
+    N5: 5,
+    N6: 6,
+    N7: 7,
+    N8: 8,
+    N9: 9,
+    N10: 10,
+    N11: 11,
+    N12: 12,
+    N13: 13,
+    N14: 14,
+    N15: 15,
+}
+
+/// Input mode marker
+pub struct Input;
+/// Output mode marker
+pub struct Output;
+/// Analog mode marker
+pub struct Analog;
+/// Alternate function mode marker
+pub struct Alternate<A>(PhantomData<A>);
+
+/// Pin typed by port, number and mode, see the module documentation
+pub struct TypedPin<T, N, MODE>
+    where T: Deref<Target=gpioa::RegisterBlock>,
+          N: PinNumber
+{
+    // NOTE(fn) names the types only, like `Pin`
+    phantom: PhantomData<fn() -> (T, N, MODE)>,
+}
+
+impl<T, N> TypedPin<T, N, Input>
+    where T: Deref<Target=gpioa::RegisterBlock>,
+          N: PinNumber
+{
+    /// The pin in its reset state, input
+    ///
+    /// Doesn't touch the port, and doesn't hold for the debug pins (PA13,
+    /// PA14, PA15, PB3, PB4) which leave reset in their alternate function.
+    pub const fn new() -> Self {
+        TypedPin { phantom: PhantomData }
+    }
+}
+
+impl<T, N, MODE> TypedPin<T, N, MODE>
+    where T: Deref<Target=gpioa::RegisterBlock>,
+          N: PinNumber
+{
+    /// Pin number within the port
+    pub fn number(&self) -> u8 {
+        N::NUMBER
+    }
+
+    /// Forgets the number and mode, for arrays of pins and loops
+    pub const fn erase(self) -> Pin<T> {
+        Pin::new(N::NUMBER)
+    }
+
+    /// Configures the pin as an input
+    ///
+    /// # Panics
+    ///
+    /// On PA13 / PA14 until `DebugPins` released them
+    pub fn into_input(self, port: &T) -> TypedPin<T, N, Input> {
+        Pin::<T>::new(N::NUMBER).set_mode(port, Mode::Input);
+        TypedPin { phantom: PhantomData }
+    }
+
+    /// Configures the pin as a push-pull output
+    ///
+    /// # Panics
+    ///
+    /// On PA13 / PA14 until `DebugPins` released them
+    pub fn into_output(self, port: &T) -> TypedPin<T, N, Output> {
+        let pin = Pin::<T>::new(N::NUMBER);
+        pin.set_open_drain(port, false);
+        pin.set_mode(port, Mode::Output);
+        TypedPin { phantom: PhantomData }
+    }
+
+    /// Configures the pin as an analog input, for the ADC
+    ///
+    /// # Panics
+    ///
+    /// On PA13 / PA14 until `DebugPins` released them
+    pub fn into_analog(self, port: &T) -> TypedPin<T, N, Analog> {
+        Pin::<T>::new(N::NUMBER).set_mode(port, Mode::Analog);
+        TypedPin { phantom: PhantomData }
+    }
+
+    /// Hands the pin over to the peripheral behind alternate function `af`
+    ///
+    /// # Panics
+    ///
+    /// On PA13 / PA14 until `DebugPins` released them
+    pub fn into_alternate<A>(self, port: &T, af: A, speed: Speed)
+        -> TypedPin<T, N, Alternate<A>>
+        where A: AltFn
+    {
+        Pin::<T>::new(N::NUMBER).set_alternate(port, af, speed);
+        TypedPin { phantom: PhantomData }
+    }
+}
+
+impl<T, N> TypedPin<T, N, Input>
+    where T: Deref<Target=gpioa::RegisterBlock>,
+          N: PinNumber
+{
+    pub fn get(&self, port: &T) -> Io {
+        Pin::<T>::new(N::NUMBER).get(port)
+    }
+
+    /// Sets the pull up / down
+    ///
+    /// # Panics
+    ///
+    /// On PA13 / PA14 until `DebugPins` released them
+    pub fn set_pupd(&self, port: &T, pupd: Pupd) {
+        Pin::<T>::new(N::NUMBER).set_pupd(port, pupd);
+    }
+}
+
+impl<T, N> TypedPin<T, N, Output>
+    where T: Deref<Target=gpioa::RegisterBlock>,
+          N: PinNumber
+{
+    pub fn set(&self, port: &T, data: Io) {
+        Pin::<T>::new(N::NUMBER).set(port, data);
+    }
+
+    /// Inverts the output level, see `Pin::toggle`
+    pub fn toggle(&self, port: &T) {
+        Pin::<T>::new(N::NUMBER).toggle(port);
+    }
+
+    /// Reads back the level on the pin
+    pub fn get(&self, port: &T) -> Io {
+        Pin::<T>::new(N::NUMBER).get(port)
+    }
+
+    /// Sets the output speed
+    ///
+    /// # Panics
+    ///
+    /// On PA13 / PA14 until `DebugPins` released them
+    pub fn set_speed(&self, port: &T, speed: Speed) {
+        Pin::<T>::new(N::NUMBER).set_speed(port, speed);
+    }
+
+    /// Selects open-drain (`true`) or push-pull output
+    ///
+    /// # Panics
+    ///
+    /// On PA13 / PA14 until `DebugPins` released them
+    pub fn set_open_drain(&self, port: &T, open_drain: bool) {
+        Pin::<T>::new(N::NUMBER).set_open_drain(port, open_drain);
+    }
+}
+
+impl<T, N, A> TypedPin<T, N, Alternate<A>>
+    where T: Deref<Target=gpioa::RegisterBlock>,
+          N: PinNumber
+{
+    /// Reads the level on the pin, the input stage stays on in alternate
+    /// function mode
+    pub fn get(&self, port: &T) -> Io {
+        Pin::<T>::new(N::NUMBER).get(port)
+    }
+}
+
+/// The SWD pins, PA13 (SWDIO) and PA14 (SWCLK)
+///
+/// Turning them into GPIOs cuts the debugger off, and firmware that does
+/// it right after reset can't be reflashed over SWD any more: the probe
+/// must then connect under reset, or the chip boot the system bootloader
+/// (BOOT0 high). Until `disconnect_debugger_and_repurpose_swd_pins` is
+/// called, configuring these pins panics.
+///
+/// Repurposing them a few seconds after boot, or only when a jumper is
+/// fitted, leaves a window to attach the probe.
+pub struct DebugPins<'a> {
+    // NOTE only the ownership token, the pins are released through
+    // `SWD_RELEASED`
+    _gpioa: &'a GPIOA,
+}
+
+impl<'a> DebugPins<'a> {
+    /// Takes the token, `None` if it was already taken
+    pub fn new(gpioa: &'a GPIOA) -> Option<Self> {
+        if DEBUG_PINS_TAKEN.swap(true, Ordering::Relaxed) {
+            return None;
+        }
+        Some(DebugPins { _gpioa: gpioa })
+    }
+
+    /// Releases PA13 and PA14 for other uses, the debug session ends here
+    ///
+    /// Returns the pins, still in their SWD configuration until changed.
+    pub fn disconnect_debugger_and_repurpose_swd_pins(self) -> (Pin<GPIOA>, Pin<GPIOA>) {
+        SWD_RELEASED.store(true, Ordering::Relaxed);
+        (Pin::new(13), Pin::new(14))
+    }
+}
+
+/// Panics if `mask` covers an SWD pin of GPIOA that wasn't released
+fn check_swd<T>(port: &T, mask: u16)
+    where T: Deref<Target=gpioa::RegisterBlock>
+{
+    let base = &**port as *const gpioa::RegisterBlock as usize;
+    if base == GPIOA_BASE && mask & SWD_PINS != 0 && !SWD_RELEASED.load(Ordering::Relaxed) {
+        panic!("PA13 / PA14 are the SWD pins, see `gpio::DebugPins`");
+    }
+}
+
+/// Unused signal, e.g. MISO of a transmit only SPI bus
+pub struct NoPin;
+
+/// Optional output of a driver on port `T`: an output `TypedPin`, or
+/// `NoPin` when the signal isn't wired
+pub unsafe trait OutputPin<T>
+    where T: Deref<Target=gpioa::RegisterBlock>
+{
+    #[doc(hidden)]
+    fn into_pin(self) -> Option<Pin<T>>;
+}
+
+unsafe impl<T, N> OutputPin<T> for TypedPin<T, N, Output>
+    where T: Deref<Target=gpioa::RegisterBlock>,
+          N: PinNumber
+{
+    fn into_pin(self) -> Option<Pin<T>> {
+        Some(self.erase())
+    }
+}
+
+unsafe impl<T> OutputPin<T> for NoPin
+    where T: Deref<Target=gpioa::RegisterBlock>
+{
+    fn into_pin(self) -> Option<Pin<T>> {
+        None
+    }
+}
+
+macro_rules! pins {
+    ($($PXi:ident: ($GPIOX:ident, $i:expr),)+) => {
+        $(
+            /// Pin of a port, to be passed to a driver's `route`
+            pub struct $PXi<'a>(pub &'a $GPIOX);
+
+            impl<'a> $PXi<'a> {
+                pub(crate) fn set_alternate<A>(&self, af: A)
+                    where A: AltFn
+                {
+                    Pin::<$GPIOX>::new($i).set_alternate(self.0, af, Speed::High);
+                }
+            }
+        )+
+    }
+}
+
+pins! {
+    PA2: (GPIOA, 2),
+    PA3: (GPIOA, 3),
+    PA5: (GPIOA, 5),
+    PA6: (GPIOA, 6),
+    PA7: (GPIOA, 7),
+    PA9: (GPIOA, 9),
+    PA10: (GPIOA, 10),
+    PA11: (GPIOA, 11),
+    PA12: (GPIOA, 12),
+    PA15: (GPIOA, 15),
+    PB0: (GPIOB, 0),
+    PB3: (GPIOB, 3),
+    PB4: (GPIOB, 4),
+    PB5: (GPIOB, 5),
+    PB6: (GPIOB, 6),
+    PB7: (GPIOB, 7),
+    PB8: (GPIOB, 8),
+    PB10: (GPIOB, 10),
+    PB12: (GPIOB, 12),
+    PB13: (GPIOB, 13),
+    PB14: (GPIOB, 14),
+    PB15: (GPIOB, 15),
+    PC2: (GPIOC, 2),
+    PC3: (GPIOC, 3),
+    PC6: (GPIOC, 6),
+    PC7: (GPIOC, 7),
+    PC10: (GPIOC, 10),
+    PC11: (GPIOC, 11),
+    PC12: (GPIOC, 12),
+    PD3: (GPIOD, 3),
+    PD5: (GPIOD, 5),
+    PD6: (GPIOD, 6),
+    PE2: (GPIOE, 2),
+    PE5: (GPIOE, 5),
+    PE6: (GPIOE, 6),
+    PE12: (GPIOE, 12),
+    PE13: (GPIOE, 13),
+    PE14: (GPIOE, 14),
 }
 
 // macro_rules! pin {
@@ -0,0 +1,835 @@
+//! Inter-Integrated Circuit (I2C) master
+//!
+//! The bus timing (CCR / TRISE) is computed at runtime from the frozen
+//! `Clocks`, the same way `Serial::try_set_baud_rate` does for the baud
+//! rate. Combinations that would produce an out-of-spec SCL are rejected:
+//!
+//! ``` ignore
+//! let timing = Timing::new(&clocks, 400_000.hz(), Mode::Fast(Duty::Ratio16to9))?;
+//! let i2c = I2c(i2c1);
+//! i2c.init(rcc, &timing);
+//!
+//! timer.set_timeout(Milliseconds(10));
+//! i2c.write_read(&timer, ADDRESS, &[WHO_AM_I], &mut id)?;
+//! ```
+//!
+//! The blocking transactions take a `hal::Timer` so a stuck bus (no pull
+//! ups, a slave holding SDA low) ends in `Error::Timeout` instead of a hang,
+//! see `timeout`. `write_read_dma` does the read phase through a DMA1
+//! stream, for bursts such as a sensor FIFO.
+//!
+//! `Engine` runs the same transactions from the event and error interrupts,
+//! so sensors can be polled without tying up the main loop:
+//!
+//! ``` ignore
+//! static mut REG: [u8; 1] = [ACCEL_OUT];
+//! static mut SAMPLE: [u8; 6] = [0; 6];
+//!
+//! let mut engine = Engine::new(I2c(i2c1)).on_complete(sample_ready);
+//! engine.submit(Transaction::write_read(ADDRESS, unsafe { &REG }, unsafe { &mut SAMPLE }))
+//!     .ok();
+//!
+//! // I2C1_EV interrupt
+//! engine.on_event();
+//! // I2C1_ER interrupt
+//! engine.on_error();
+//!
+//! // later, or from `sample_ready`
+//! if let Some((transaction, Ok(()))) = engine.take() {
+//!     let sample = transaction.buffer();
+//! }
+//! ```
+
+use core::any::Any;
+#[cfg(feature = "dma")]
+use core::any::TypeId;
+use core::fmt;
+use core::ops::Deref;
+#[cfg(feature = "dma")]
+use core::sync::atomic::{self, Ordering};
+
+use hal;
+use nb;
+use stm32f411::{i2c3, I2C1, I2C2, I2C3, RCC};
+#[cfg(feature = "dma")]
+use stm32f411::DMA1;
+
+#[cfg(feature = "dma")]
+use dma2::{self, DMAStream, Dma};
+#[cfg(feature = "dma")]
+use fault::{self, Fault};
+use rcc::{ClockError, Clocks};
+use time::Hertz;
+use timeout::{self, with_timeout};
+
+// CR1
+const PE: u32 = 1 << 0;
+const START: u32 = 1 << 8;
+const STOP: u32 = 1 << 9;
+const ACK: u32 = 1 << 10;
+const SWRST: u32 = 1 << 15;
+
+// CR2
+const ITERREN: u32 = 1 << 8;
+const ITEVTEN: u32 = 1 << 9;
+const ITBUFEN: u32 = 1 << 10;
+const DMAEN: u32 = 1 << 11;
+const LAST: u32 = 1 << 12;
+
+// SR1
+const SB: u32 = 1 << 0;
+const ADDR: u32 = 1 << 1;
+const BTF: u32 = 1 << 2;
+const RXNE: u32 = 1 << 6;
+const TXE: u32 = 1 << 7;
+const BERR: u32 = 1 << 8;
+const ARLO: u32 = 1 << 9;
+const AF: u32 = 1 << 10;
+const OVR: u32 = 1 << 11;
+
+// CCR
+const CCR_FS: u32 = 1 << 15;
+const CCR_DUTY: u32 = 1 << 14;
+
+/// Slowest APB1 clock for standard mode
+const PCLK1_MIN_SM: u32 = 2_000_000;
+/// Slowest APB1 clock for fast mode
+const PCLK1_MIN_FM: u32 = 4_000_000;
+/// Fastest APB1 clock the peripheral accepts (FREQ field)
+const PCLK1_MAX: u32 = 50_000_000;
+
+/// IMPLEMENTATION DETAIL
+pub unsafe trait I2C: Deref<Target = i2c3::RegisterBlock> {
+    /// IMPLEMENTATION DETAIL
+    fn enable_clock(rcc: &RCC);
+}
+
+unsafe impl I2C for I2C1 {
+    fn enable_clock(rcc: &RCC) {
+        rcc.apb1enr.modify(|_, w| w.i2c1en().set_bit());
+    }
+}
+
+unsafe impl I2C for I2C2 {
+    fn enable_clock(rcc: &RCC) {
+        rcc.apb1enr.modify(|_, w| w.i2c2en().set_bit());
+    }
+}
+
+unsafe impl I2C for I2C3 {
+    fn enable_clock(rcc: &RCC) {
+        rcc.apb1enr.modify(|_, w| w.i2c3en().set_bit());
+    }
+}
+
+/// Fast mode SCL low / high ratio
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Duty {
+    /// t_low = 2 * t_high
+    Ratio2to1,
+    /// t_low = 16 / 9 * t_high, reaches 400 kHz with APB1 clocks that are a
+    /// multiple of 10 MHz
+    Ratio16to9,
+}
+
+/// Bus mode
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mode {
+    /// Up to 100 kHz
+    Standard,
+    /// Up to 400 kHz
+    Fast(Duty),
+}
+
+/// Register values for a bus speed
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Timing {
+    freq: u32,
+    ccr: u32,
+    trise: u32,
+    scl: Hertz,
+}
+
+impl Timing {
+    /// Computes the timing for an SCL of at most `speed`
+    ///
+    /// The divider is rounded so the bus is never faster than requested;
+    /// `scl` returns the achieved frequency. Errors:
+    ///
+    /// - `BusClockOutOfRange` if APB1 runs below 2 MHz (standard mode),
+    ///   below 4 MHz (fast mode) or above 50 MHz
+    /// - `FrequencyOutOfRange` if `speed` is 0 or above the mode limit, or
+    ///   so low the divider overflows
+    pub fn new(clocks: &Clocks, speed: Hertz, mode: Mode) -> Result<Self, ClockError> {
+        let pclk1 = clocks.pclk1().0;
+        let (min_pclk1, max_speed) = match mode {
+            Mode::Standard => (PCLK1_MIN_SM, 100_000),
+            Mode::Fast(_) => (PCLK1_MIN_FM, 400_000),
+        };
+        if pclk1 < min_pclk1 || pclk1 > PCLK1_MAX {
+            return Err(ClockError::BusClockOutOfRange);
+        }
+        if speed.0 == 0 || speed.0 > max_speed {
+            return Err(ClockError::FrequencyOutOfRange);
+        }
+
+        // SCL period in APB1 cycles per CCR unit
+        let (cycles, min_ccr, flags) = match mode {
+            Mode::Standard => (2, 4, 0),
+            Mode::Fast(Duty::Ratio2to1) => (3, 1, CCR_FS),
+            Mode::Fast(Duty::Ratio16to9) => (25, 1, CCR_FS | CCR_DUTY),
+        };
+        let divisor = speed.0 * cycles;
+        let ccr = (pclk1 + divisor - 1) / divisor;
+        let ccr = if ccr < min_ccr { min_ccr } else { ccr };
+        if ccr > 0xFFF {
+            return Err(ClockError::FrequencyOutOfRange);
+        }
+
+        let freq = pclk1 / 1_000_000;
+        // Maximum rise time, 1000 ns / 300 ns, in APB1 cycles plus one
+        let trise = match mode {
+            Mode::Standard => freq + 1,
+            Mode::Fast(_) => freq * 300 / 1_000 + 1,
+        };
+
+        Ok(Timing {
+            freq: freq,
+            ccr: ccr | flags,
+            trise: trise,
+            scl: Hertz(pclk1 / (ccr * cycles)),
+        })
+    }
+
+    /// Achieved SCL frequency, ignoring the rise time
+    pub fn scl(&self) -> Hertz {
+        self.scl
+    }
+}
+
+/// An error
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The slave didn't acknowledge its address or a byte
+    Nack,
+    /// Another master won the bus
+    ArbitrationLost,
+    /// Misplaced START / STOP condition
+    Bus,
+    /// Data lost, only with clock stretching disabled
+    Overrun,
+    /// The bus didn't progress before the timer expired
+    Timeout,
+    /// The DMA stream of `write_read_dma` failed or can't be used
+    #[cfg(feature = "dma")]
+    Dma(dma2::Error),
+    #[doc(hidden)]
+    _Extensible,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Nack => f.write_str("I2C NACK"),
+            Error::ArbitrationLost => f.write_str("I2C arbitration lost"),
+            Error::Bus => f.write_str("I2C bus error"),
+            Error::Overrun => f.write_str("I2C overrun"),
+            Error::Timeout => f.write_str("I2C timeout"),
+            #[cfg(feature = "dma")]
+            Error::Dma(ref e) => e.fmt(f),
+            Error::_Extensible => f.write_str("unknown error"),
+        }
+    }
+}
+
+#[cfg(feature = "dma")]
+impl From<dma2::Error> for Error {
+    fn from(e: dma2::Error) -> Self {
+        Error::Dma(e)
+    }
+}
+
+impl From<timeout::Error<Error>> for Error {
+    fn from(e: timeout::Error<Error>) -> Self {
+        match e {
+            timeout::Error::Timeout => Error::Timeout,
+            timeout::Error::Other(e) => e,
+        }
+    }
+}
+
+/// I2C master
+///
+/// Addresses are 7-bit, unshifted.
+pub struct I2c<'a, I>(pub &'a I) where I: 'a + Any + I2C;
+
+impl<'a, I> I2c<'a, I>
+    where I: Any + I2C
+{
+    /// Resets the peripheral and enables it with `timing`
+    pub fn init(&self, rcc: &RCC, timing: &Timing) {
+        I::enable_clock(rcc);
+
+        let i2c = self.0;
+        unsafe {
+            // Also releases a peripheral stuck with BUSY set
+            i2c.cr1.write(|w| w.bits(SWRST));
+            i2c.cr1.write(|w| w.bits(0));
+        }
+        self.set_timing(timing);
+        i2c.cr1.write(|w| unsafe { w.bits(PE) });
+    }
+
+    /// Changes the bus speed, no transaction must be in progress
+    pub fn set_timing(&self, timing: &Timing) {
+        let i2c = self.0;
+        let pe = i2c.cr1.read().bits() & PE;
+        unsafe {
+            i2c.cr1.modify(|r, w| w.bits(r.bits() & !PE));
+            i2c.cr2.modify(|r, w| w.bits((r.bits() & !0x3F) | timing.freq));
+            i2c.ccr.write(|w| w.bits(timing.ccr));
+            i2c.trise.write(|w| w.bits(timing.trise));
+            i2c.cr1.modify(|r, w| w.bits(r.bits() | pe));
+        }
+    }
+
+    /// Writes `bytes` to `address`
+    pub fn write<T>(&self, timer: &T, address: u8, bytes: &[u8]) -> Result<(), Error>
+        where T: hal::Timer
+    {
+        self.transaction(timer, |i2c| {
+            i2c.start(timer, address, false)?;
+            i2c.send(timer, bytes)?;
+            i2c.stop();
+            Ok(())
+        })
+    }
+
+    /// Writes `chunks`, back to back, to `address` in a single transaction
+    ///
+    /// For a header in front of a buffer (e.g. a control byte and display
+    /// data) without copying them together.
+    pub fn write_chunks<T>(&self, timer: &T, address: u8, chunks: &[&[u8]]) -> Result<(), Error>
+        where T: hal::Timer
+    {
+        self.transaction(timer, |i2c| {
+            i2c.start(timer, address, false)?;
+            for chunk in chunks {
+                i2c.send(timer, chunk)?;
+            }
+            i2c.stop();
+            Ok(())
+        })
+    }
+
+    /// Reads `buffer.len()` bytes from `address`
+    pub fn read<T>(&self, timer: &T, address: u8, buffer: &mut [u8]) -> Result<(), Error>
+        where T: hal::Timer
+    {
+        self.transaction(timer, |i2c| {
+            i2c.start(timer, address, true)?;
+            i2c.receive(timer, buffer)
+        })
+    }
+
+    /// Writes `bytes` then, after a repeated START, reads `buffer.len()`
+    /// bytes; the usual register read
+    pub fn write_read<T>(&self, timer: &T, address: u8, bytes: &[u8], buffer: &mut [u8])
+        -> Result<(), Error>
+        where T: hal::Timer
+    {
+        self.transaction(timer, |i2c| {
+            i2c.start(timer, address, false)?;
+            i2c.send(timer, bytes)?;
+            i2c.start(timer, address, true)?;
+            i2c.receive(timer, buffer)
+        })
+    }
+
+    /// `write_read` with the read phase done by DMA
+    ///
+    /// `dma` must be a DMA1 stream wired to the RX request of this
+    /// peripheral (I2C1: stream 0 or 5, I2C2: stream 2 or 3, I2C3: stream 1
+    /// or 2); its configuration is overwritten. The DMA1 clock must be
+    /// enabled. Reads of less than 2 bytes go through `write_read`, the
+    /// peripheral can only NACK the last byte of a DMA read of 2 or more.
+    #[cfg(feature = "dma")]
+    pub fn write_read_dma<T>(&self, timer: &T, dma: &Dma<DMA1>, address: u8, bytes: &[u8],
+                             buffer: &mut [u8])
+        -> Result<(), Error>
+        where T: hal::Timer
+    {
+        if buffer.len() < 2 {
+            return self.write_read(timer, address, bytes, buffer);
+        }
+        let channel = match rx_channel(self.0, dma.stream()) {
+            Some(channel) => channel,
+            None => return Err(dma2::Error::Fault(fault::raise(Fault::WrongStream)).into()),
+        };
+        if buffer.len() > 0xFFFF {
+            return Err(dma2::Error::Fault(fault::raise(Fault::TooLong)).into());
+        }
+        if dma.is_enabled() {
+            return Err(dma2::Error::InUse.into());
+        }
+
+        // CHSEL, byte sized, MINC, peripheral to memory
+        dma.reg.scr(dma.stream()).write(|w| unsafe { w.bits((channel << 25) | (1 << 10)) });
+        dma.clear_flags();
+        dma.set_config(
+            &self.0.dr as *const _ as u32,
+            buffer.as_mut_ptr() as u32,
+            buffer.len() as u16,
+        );
+
+        let result = self.transaction(timer, |i2c| {
+            i2c.start(timer, address, false)?;
+            i2c.send(timer, bytes)?;
+            i2c.start(timer, address, true)?;
+
+            // LAST makes the peripheral NACK the byte that ends the DMA
+            // transfer; both are set up before ADDR is cleared
+            i2c.0.cr2.modify(|r, w| unsafe { w.bits(r.bits() | DMAEN | LAST) });
+            dma.enable();
+            i2c.0.sr2.read();
+
+            with_timeout(timer, || if dma.has_transfer_error() {
+                Err(nb::Error::Other(Error::Dma(dma2::Error::Transfer)))
+            } else if dma.is_transfer_complete() {
+                Ok(())
+            } else {
+                match i2c.take_error() {
+                    Some(e) => Err(nb::Error::Other(e)),
+                    None => Err(nb::Error::WouldBlock),
+                }
+            })?;
+            i2c.stop();
+            Ok(())
+        });
+
+        // Also after an error: the stream must not write to `buffer` once
+        // it's given back
+        dma.disable();
+        while dma.is_enabled() {}
+        dma.clear_flags();
+        self.0.cr2.modify(|r, w| unsafe { w.bits(r.bits() & !(DMAEN | LAST)) });
+        // The DMA wrote `buffer` behind the compiler's back
+        atomic::compiler_fence(Ordering::SeqCst);
+
+        result
+    }
+
+    /// Runs `f` with `timer` restarted; on error the bus is released
+    fn transaction<T, F>(&self, timer: &T, f: F) -> Result<(), Error>
+        where T: hal::Timer,
+              F: FnOnce(&Self) -> Result<(), Error>
+    {
+        timer.restart();
+        timer.resume();
+        let result = f(self);
+        timer.pause();
+
+        if result.is_err() {
+            self.stop();
+        }
+        result
+    }
+
+    /// Sends a (repeated) START and the address, `read` sets the R/W bit
+    fn start<T>(&self, timer: &T, address: u8, read: bool) -> Result<(), Error>
+        where T: hal::Timer
+    {
+        let i2c = self.0;
+        i2c.cr1.modify(|r, w| unsafe { w.bits(r.bits() | START | ACK) });
+        with_timeout(timer, || self.poll(SB))?;
+
+        let rw = if read { 1 } else { 0 };
+        i2c.dr.write(|w| unsafe { w.bits((u32::from(address) << 1) | rw) });
+        with_timeout(timer, || self.poll(ADDR))?;
+
+        // NOTE ADDR is left set, it stretches SCL until `send` / `receive`
+        // clear it by reading SR2; a single byte read must NACK before that
+        Ok(())
+    }
+
+    fn send<T>(&self, timer: &T, bytes: &[u8]) -> Result<(), Error>
+        where T: hal::Timer
+    {
+        let i2c = self.0;
+        i2c.sr2.read();
+        for byte in bytes {
+            with_timeout(timer, || self.poll(TXE))?;
+            i2c.dr.write(|w| unsafe { w.bits(u32::from(*byte)) });
+        }
+        with_timeout(timer, || self.poll(BTF))?;
+        Ok(())
+    }
+
+    fn receive<T>(&self, timer: &T, buffer: &mut [u8]) -> Result<(), Error>
+        where T: hal::Timer
+    {
+        let i2c = self.0;
+        if buffer.len() == 1 {
+            // NACK the only byte
+            i2c.cr1.modify(|r, w| unsafe { w.bits(r.bits() & !ACK) });
+        }
+        i2c.sr2.read();
+
+        if let Some((last, rest)) = buffer.split_last_mut() {
+            for byte in rest.iter_mut() {
+                *byte = with_timeout(timer, || self.poll(RXNE))
+                    .map(|_| i2c.dr.read().bits() as u8)?;
+            }
+            i2c.cr1.modify(|r, w| unsafe { w.bits((r.bits() & !ACK) | STOP) });
+            *last = with_timeout(timer, || self.poll(RXNE))
+                .map(|_| i2c.dr.read().bits() as u8)?;
+        } else {
+            self.stop();
+        }
+        Ok(())
+    }
+
+    fn stop(&self) {
+        self.0.cr1.modify(|r, w| unsafe { w.bits(r.bits() | STOP) });
+    }
+
+    /// Waits for `flag` in SR1, fails on the error flags (and clears them)
+    fn poll(&self, flag: u32) -> nb::Result<(), Error> {
+        if let Some(e) = self.take_error() {
+            Err(nb::Error::Other(e))
+        } else if self.0.sr1.read().bits() & flag != 0 {
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
+    /// Clears the error flags of SR1, returns the error they flagged
+    fn take_error(&self) -> Option<Error> {
+        let i2c = self.0;
+        let sr1 = i2c.sr1.read().bits();
+
+        let error = if sr1 & AF != 0 {
+            Some(Error::Nack)
+        } else if sr1 & ARLO != 0 {
+            Some(Error::ArbitrationLost)
+        } else if sr1 & BERR != 0 {
+            Some(Error::Bus)
+        } else if sr1 & OVR != 0 {
+            Some(Error::Overrun)
+        } else {
+            None
+        };
+
+        if error.is_some() {
+            // NOTE the error flags are cleared by writing 0
+            i2c.sr1.write(|w| unsafe { w.bits(sr1 & !(AF | ARLO | BERR | OVR)) });
+        }
+        error
+    }
+}
+
+/// DMA1 channel that serves the RX request of `i2c` on `stream`
+#[cfg(feature = "dma")]
+fn rx_channel<I>(i2c: &I, stream: DMAStream) -> Option<u32>
+    where I: Any
+{
+    let id = i2c.get_type_id();
+    match stream {
+        DMAStream::Stream0 | DMAStream::Stream5 if id == TypeId::of::<I2C1>() => Some(1),
+        DMAStream::Stream2 | DMAStream::Stream3 if id == TypeId::of::<I2C2>() => Some(7),
+        DMAStream::Stream1 if id == TypeId::of::<I2C3>() => Some(1),
+        DMAStream::Stream2 if id == TypeId::of::<I2C3>() => Some(3),
+        _ => None,
+    }
+}
+
+/// Transaction descriptor for `Engine`
+///
+/// The buffers are `'static` as the transaction outlives the call that
+/// submits it; `take` gives them back.
+pub struct Transaction {
+    address: u8,
+    bytes: &'static [u8],
+    buffer: Option<&'static mut [u8]>,
+}
+
+impl Transaction {
+    /// Writes `bytes` to `address`
+    pub fn write(address: u8, bytes: &'static [u8]) -> Self {
+        Transaction { address: address, bytes: bytes, buffer: None }
+    }
+
+    /// Reads `buffer.len()` bytes from `address`
+    pub fn read(address: u8, buffer: &'static mut [u8]) -> Self {
+        Transaction { address: address, bytes: &[], buffer: Some(buffer) }
+    }
+
+    /// Writes `bytes` then, after a repeated START, reads `buffer.len()`
+    /// bytes
+    pub fn write_read(address: u8, bytes: &'static [u8], buffer: &'static mut [u8]) -> Self {
+        Transaction { address: address, bytes: bytes, buffer: Some(buffer) }
+    }
+
+    /// Slave address
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    /// Bytes read, empty for a write
+    pub fn buffer(&self) -> &[u8] {
+        match self.buffer {
+            Some(ref buffer) => &buffer[..],
+            None => &[],
+        }
+    }
+
+    /// Releases the buffers
+    pub fn free(self) -> (&'static [u8], Option<&'static mut [u8]>) {
+        (self.bytes, self.buffer)
+    }
+
+    fn reads(&self) -> bool {
+        match self.buffer {
+            Some(ref buffer) => !buffer.is_empty(),
+            None => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum State {
+    Idle,
+    /// Waiting for SB, `true` for the read phase
+    Start(bool),
+    /// Waiting for ADDR
+    Address(bool),
+    Write,
+    Read,
+    Done,
+}
+
+/// Interrupt driven I2C master
+///
+/// Runs one `Transaction` at a time. Call `on_event` from the event
+/// interrupt and `on_error` from the error interrupt; give both the same
+/// priority, the engine isn't reentrant. Completion is signaled by `is_done`
+/// and by the `on_complete` callback, which runs in the interrupt.
+///
+/// There is no timeout: a stuck bus leaves the transaction pending, `abort`
+/// it from a timer or the main loop.
+pub struct Engine<'a, I>
+    where I: 'a + Any + I2C
+{
+    i2c: I2c<'a, I>,
+    transaction: Option<Transaction>,
+    state: State,
+    /// Next byte to send or receive
+    index: usize,
+    result: Result<(), Error>,
+    callback: Option<fn(Result<(), Error>)>,
+}
+
+impl<'a, I> Engine<'a, I>
+    where I: Any + I2C
+{
+    /// Engine on an `init`ialized `i2c`
+    pub fn new(i2c: I2c<'a, I>) -> Self {
+        Engine {
+            i2c: i2c,
+            transaction: None,
+            state: State::Idle,
+            index: 0,
+            result: Ok(()),
+            callback: None,
+        }
+    }
+
+    /// Calls `f` with the result when a transaction ends
+    pub fn on_complete(mut self, f: fn(Result<(), Error>)) -> Self {
+        self.callback = Some(f);
+        self
+    }
+
+    /// Starts `transaction`
+    ///
+    /// Gives the transaction back if the previous one wasn't `take`n. The
+    /// event and error interrupts must be enabled in the NVIC.
+    pub fn submit(&mut self, transaction: Transaction) -> Result<(), Transaction> {
+        if self.state != State::Idle {
+            return Err(transaction);
+        }
+
+        let i2c = self.i2c.0;
+        let read = transaction.bytes.is_empty() && transaction.reads();
+        self.transaction = Some(transaction);
+        self.index = 0;
+        self.result = Ok(());
+        self.state = State::Start(read);
+
+        i2c.cr2.modify(|r, w| unsafe { w.bits(r.bits() | ITERREN | ITEVTEN | ITBUFEN) });
+        i2c.cr1.modify(|r, w| unsafe { w.bits(r.bits() | START | ACK) });
+        Ok(())
+    }
+
+    /// Checks if a transaction is in progress
+    pub fn is_busy(&self) -> bool {
+        match self.state {
+            State::Idle | State::Done => false,
+            _ => true,
+        }
+    }
+
+    /// Checks if a transaction ended and waits to be `take`n
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// Returns the ended transaction and its result, the engine is then
+    /// ready for the next one
+    pub fn take(&mut self) -> Option<(Transaction, Result<(), Error>)> {
+        if self.state != State::Done {
+            return None;
+        }
+
+        self.state = State::Idle;
+        self.transaction.take().map(|transaction| (transaction, self.result))
+    }
+
+    /// Ends the transaction in progress with `Error::Timeout`, releasing
+    /// the bus
+    pub fn abort(&mut self) {
+        if self.is_busy() {
+            self.i2c.stop();
+            self.finish(Err(Error::Timeout));
+        }
+    }
+
+    /// Advances the transaction, call this from the event interrupt
+    ///
+    /// Returns the result when the transaction ends, `WouldBlock` before.
+    pub fn on_event(&mut self) -> nb::Result<(), Error> {
+        let i2c = self.i2c.0;
+        let sr1 = i2c.sr1.read().bits();
+
+        let end = {
+            let transaction = match self.transaction {
+                Some(ref mut transaction) => transaction,
+                None => return Err(nb::Error::WouldBlock),
+            };
+
+            match self.state {
+                State::Start(read) if sr1 & SB != 0 => {
+                    let rw = if read { 1 } else { 0 };
+                    i2c.dr.write(|w| unsafe {
+                        w.bits((u32::from(transaction.address) << 1) | rw)
+                    });
+                    self.state = State::Address(read);
+                    false
+                }
+                State::Address(false) if sr1 & ADDR != 0 => {
+                    i2c.sr2.read();
+                    if transaction.bytes.is_empty() {
+                        i2c.cr1.modify(|r, w| unsafe { w.bits(r.bits() | STOP) });
+                        true
+                    } else {
+                        self.state = State::Write;
+                        false
+                    }
+                }
+                State::Address(true) if sr1 & ADDR != 0 => {
+                    self.index = 0;
+                    self.state = State::Read;
+                    if transaction.buffer().len() == 1 {
+                        // NACK the only byte, the STOP follows it
+                        i2c.cr1.modify(|r, w| unsafe { w.bits(r.bits() & !ACK) });
+                        i2c.sr2.read();
+                        i2c.cr1.modify(|r, w| unsafe { w.bits(r.bits() | STOP) });
+                    } else {
+                        i2c.sr2.read();
+                    }
+                    false
+                }
+                State::Write if self.index < transaction.bytes.len() && sr1 & TXE != 0 => {
+                    let byte = transaction.bytes[self.index];
+                    i2c.dr.write(|w| unsafe { w.bits(u32::from(byte)) });
+                    self.index += 1;
+                    if self.index == transaction.bytes.len() {
+                        // TXE stays set from here on, wait for BTF
+                        i2c.cr2.modify(|r, w| unsafe { w.bits(r.bits() & !ITBUFEN) });
+                    }
+                    false
+                }
+                State::Write if self.index == transaction.bytes.len() && sr1 & BTF != 0 => {
+                    if transaction.reads() {
+                        i2c.cr2.modify(|r, w| unsafe { w.bits(r.bits() | ITBUFEN) });
+                        i2c.cr1.modify(|r, w| unsafe { w.bits(r.bits() | START | ACK) });
+                        self.state = State::Start(true);
+                        false
+                    } else {
+                        i2c.cr1.modify(|r, w| unsafe { w.bits(r.bits() | STOP) });
+                        true
+                    }
+                }
+                State::Read if sr1 & RXNE != 0 => {
+                    let buffer = match transaction.buffer {
+                        Some(ref mut buffer) => buffer,
+                        None => unreachable!(),
+                    };
+                    buffer[self.index] = i2c.dr.read().bits() as u8;
+                    self.index += 1;
+                    if self.index + 1 == buffer.len() {
+                        // NACK the last byte
+                        i2c.cr1.modify(|r, w| unsafe { w.bits((r.bits() & !ACK) | STOP) });
+                    }
+                    self.index == buffer.len()
+                }
+                _ => false,
+            }
+        };
+
+        if end {
+            self.finish(Ok(()));
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
+    /// Handles a bus error, call this from the error interrupt
+    ///
+    /// Ends the transaction in progress with the error and releases the
+    /// bus. Returns `WouldBlock` if there was no error.
+    pub fn on_error(&mut self) -> nb::Result<(), Error> {
+        let e = match self.i2c.take_error() {
+            Some(e) => e,
+            None => return Err(nb::Error::WouldBlock),
+        };
+
+        if self.is_busy() {
+            // NOTE after a lost arbitration the peripheral is a slave
+            // already, the STOP request is ignored
+            self.i2c.stop();
+            self.finish(Err(e));
+        }
+        Err(nb::Error::Other(e))
+    }
+
+    /// Releases the peripheral, disabling its interrupts
+    pub fn free(self) -> I2c<'a, I> {
+        let i2c = self.i2c.0;
+        i2c.cr2.modify(|r, w| unsafe { w.bits(r.bits() & !(ITERREN | ITEVTEN | ITBUFEN)) });
+        self.i2c
+    }
+
+    fn finish(&mut self, result: Result<(), Error>) {
+        let i2c = self.i2c.0;
+        i2c.cr2.modify(|r, w| unsafe { w.bits(r.bits() & !(ITERREN | ITEVTEN | ITBUFEN)) });
+
+        self.result = result;
+        self.state = State::Done;
+        if let Some(f) = self.callback {
+            f(result);
+        }
+    }
+}
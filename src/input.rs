@@ -0,0 +1,145 @@
+//! Debounced digital inputs
+//!
+//! `DebouncedInput` samples a GPIO pin on a periodic tick (e.g. a timer update
+//! interrupt) and filters contact bounce with an integrator: every sample
+//! moves a counter towards the sampled level and the debounced state only
+//! changes once the counter saturates.
+//!
+//! ``` ignore
+//! // User button on PA0, active high, 5 ms tick
+//! let mut button = DebouncedInput::new(TypedPin::<GPIOA, N0, Input>::new(), Active::High, 4, 200);
+//! button.init(gpioa);
+//!
+//! // every tick
+//! match button.update(gpioa) {
+//!     Some(Event::Pressed) => { .. }
+//!     Some(Event::Held(ticks)) => { .. }
+//!     Some(Event::Released) => { .. }
+//!     None => {}
+//! }
+//! ```
+//!
+//! `new` is a `const fn`, so the input can also be the initial value of an
+//! RTFM resource.
+
+use core::ops::Deref;
+
+use stm32f411::gpioa;
+
+use gpio::{Input, Io, Mode, Pin, PinNumber, Pupd, TypedPin};
+
+/// Logic level that corresponds to the "pressed" state
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Active {
+    /// Pressed when the pin reads low (button to ground, pull-up)
+    Low,
+    /// Pressed when the pin reads high (button to VDD, pull-down)
+    High,
+}
+
+/// Debounced input event
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Event {
+    /// The input became active
+    Pressed,
+    /// The input became inactive
+    Released,
+    /// The input has been active for this many ticks
+    ///
+    /// Reported every `hold_period` ticks while the input stays active
+    Held(u32),
+}
+
+/// Debounced digital input
+pub struct DebouncedInput<T>
+    where T: Deref<Target=gpioa::RegisterBlock>
+{
+    pin: Pin<T>,
+    active: Active,
+    integrator: u8,
+    threshold: u8,
+    pressed: bool,
+    held: u32,
+    hold_period: u32,
+}
+
+impl<T> DebouncedInput<T>
+    where T: Deref<Target=gpioa::RegisterBlock>
+{
+    /// Creates a new debounced input
+    ///
+    /// `threshold` is the number of consistent samples needed to change
+    /// state, a `threshold` of `0` is taken as `1`. `hold_period` is the
+    /// number of ticks between `Held` events, `0` disables them.
+    pub const fn new<N>(pin: TypedPin<T, N, Input>, active: Active, threshold: u8,
+                        hold_period: u32) -> Self
+        where N: PinNumber
+    {
+        DebouncedInput {
+            pin: pin.erase(),
+            active: active,
+            integrator: 0,
+            // NOTE with 0 the input would read pressed and released on
+            // alternate ticks
+            threshold: threshold + (threshold == 0) as u8,
+            pressed: false,
+            held: 0,
+            hold_period: hold_period,
+        }
+    }
+
+    /// Configures the pin as an input with the pull resistor matching the
+    /// active level
+    pub fn init(&self, port: &T) {
+        self.pin.set_mode(port, Mode::Input);
+        match self.active {
+            Active::Low => self.pin.set_pupd(port, Pupd::PullUp),
+            Active::High => self.pin.set_pupd(port, Pupd::PullDown),
+        }
+    }
+
+    /// Samples the pin, must be called once per tick
+    pub fn update(&mut self, port: &T) -> Option<Event> {
+        let active = match (self.pin.get(port), self.active) {
+            (Io::Low, Active::Low) | (Io::High, Active::High) => true,
+            _ => false,
+        };
+
+        if active {
+            if self.integrator < self.threshold {
+                self.integrator += 1;
+            }
+        } else if self.integrator > 0 {
+            self.integrator -= 1;
+        }
+
+        if !self.pressed && self.integrator >= self.threshold {
+            self.pressed = true;
+            self.held = 0;
+            Some(Event::Pressed)
+        } else if self.pressed && self.integrator == 0 {
+            self.pressed = false;
+            self.held = 0;
+            Some(Event::Released)
+        } else if self.pressed {
+            self.held = self.held.saturating_add(1);
+            if self.hold_period != 0 && self.held % self.hold_period == 0 {
+                Some(Event::Held(self.held))
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    }
+
+    /// Returns the debounced state
+    pub fn is_pressed(&self) -> bool {
+        self.pressed
+    }
+
+    /// Number of ticks the input has been held active, `0` if released
+    pub fn held_for(&self) -> u32 {
+        self.held
+    }
+}
@@ -0,0 +1,252 @@
+//! NVIC helpers
+//!
+//! Associates each peripheral (and each DMA stream) with its interrupt line
+//! so drivers can enable their own interrupt:
+//!
+//! ``` ignore
+//! serial.listen(Event::Rxne);
+//! serial.listen_nvic(nvic, 2);
+//! ```
+//!
+//! DMA streams work the same way, each `Dma` knows its own line so the
+//! `DMA2_STREAM4`-style mapping doesn't have to be looked up:
+//!
+//! ``` ignore
+//! let streams = dma2.split(rcc).unwrap();
+//! streams.s4.listen(dma2::Event::TransferComplete);
+//! streams.s4.listen(dma2::Event::TransferError);
+//! streams.s4.listen_nvic(nvic, 1);
+//! assert_eq!(streams.s4.interrupt(), Interrupt::DMA2_STREAM4);
+//! ```
+//!
+//! Priorities are *logical*: `0` (highest) to `15` (lowest), the F411
+//! implements the 4 most significant bits of each priority register.
+
+use core::any::Any;
+
+#[cfg(feature = "adc")]
+use stm32f411::ADC1;
+#[cfg(feature = "dma")]
+use stm32f411::{DMA1, DMA2};
+use stm32f411::{NVIC, SCB, SPI1, SPI2, SPI3, SPI4, SPI5, TIM1, TIM3, TIM4,
+                USART1, USART2, USART6};
+use stm32f411::interrupt::Interrupt;
+
+#[cfg(feature = "adc")]
+use comparator::SoftComparator;
+#[cfg(feature = "dma")]
+use dma2::{DMA, DMAStream, Dma};
+#[cfg(feature = "usart")]
+use serial::{Serial, Usart};
+#[cfg(feature = "spi")]
+use spi2::{SPI, Spi};
+use timer::{TIM, TIMBase, Timer};
+
+/// Number of priority bits implemented by the STM32F411
+pub const PRIORITY_BITS: u8 = 4;
+
+/// Split of the priority bits between preemption priority and sub-priority
+#[derive(Clone, Copy, Debug)]
+pub enum PriorityGrouping {
+    /// 16 preemption levels, no sub-priority (reset value)
+    Preempt4Sub0 = 3,
+    /// 8 preemption levels, 2 sub-priorities
+    Preempt3Sub1 = 4,
+    /// 4 preemption levels, 4 sub-priorities
+    Preempt2Sub2 = 5,
+    /// 2 preemption levels, 8 sub-priorities
+    Preempt1Sub3 = 6,
+    /// No preemption, 16 sub-priorities
+    Preempt0Sub4 = 7,
+}
+
+/// Sets the priority grouping (AIRCR.PRIGROUP)
+pub fn set_priority_grouping(scb: &SCB, grouping: PriorityGrouping) {
+    unsafe {
+        // VECTKEY must be written along with PRIGROUP
+        scb.aircr.modify(|r| {
+            (0x05FA << 16) | (r & !(0xFFFF_0000 | 0x0700)) | ((grouping as u32) << 8)
+        });
+    }
+}
+
+/// Converts a logical priority into the value of the priority register
+pub fn hw_priority(priority: u8) -> u8 {
+    assert!(priority < (1 << PRIORITY_BITS));
+    priority << (8 - PRIORITY_BITS)
+}
+
+/// `NVIC` extension trait
+pub trait NvicExt {
+    /// Sets the logical priority of `irq` and enables it
+    fn enable_with_priority(&self, irq: Interrupt, priority: u8);
+}
+
+impl NvicExt for NVIC {
+    fn enable_with_priority(&self, irq: Interrupt, priority: u8) {
+        self.set_priority(irq, hw_priority(priority));
+        self.clear_pending(irq);
+        self.enable(irq);
+    }
+}
+
+/// Peripheral instance with a single interrupt line
+pub unsafe trait HasInterrupt {
+    const INTERRUPT: Interrupt;
+}
+
+unsafe impl HasInterrupt for USART1 {
+    const INTERRUPT: Interrupt = Interrupt::USART1;
+}
+
+unsafe impl HasInterrupt for USART2 {
+    const INTERRUPT: Interrupt = Interrupt::USART2;
+}
+
+unsafe impl HasInterrupt for USART6 {
+    const INTERRUPT: Interrupt = Interrupt::USART6;
+}
+
+unsafe impl HasInterrupt for SPI1 {
+    const INTERRUPT: Interrupt = Interrupt::SPI1;
+}
+
+unsafe impl HasInterrupt for SPI2 {
+    const INTERRUPT: Interrupt = Interrupt::SPI2;
+}
+
+unsafe impl HasInterrupt for SPI3 {
+    const INTERRUPT: Interrupt = Interrupt::SPI3;
+}
+
+unsafe impl HasInterrupt for SPI4 {
+    const INTERRUPT: Interrupt = Interrupt::SPI4;
+}
+
+unsafe impl HasInterrupt for SPI5 {
+    const INTERRUPT: Interrupt = Interrupt::SPI5;
+}
+
+#[cfg(feature = "adc")]
+unsafe impl HasInterrupt for ADC1 {
+    const INTERRUPT: Interrupt = Interrupt::ADC;
+}
+
+unsafe impl HasInterrupt for TIM1 {
+    // Only the update interrupt, capture / compare events go to TIM1_CC
+    const INTERRUPT: Interrupt = Interrupt::TIM1_UP_TIM10;
+}
+
+unsafe impl HasInterrupt for TIM3 {
+    const INTERRUPT: Interrupt = Interrupt::TIM3;
+}
+
+unsafe impl HasInterrupt for TIM4 {
+    const INTERRUPT: Interrupt = Interrupt::TIM4;
+}
+
+/// DMA controller with one interrupt line per stream
+#[cfg(feature = "dma")]
+pub unsafe trait DmaInterrupts {
+    fn stream_interrupt(stream: DMAStream) -> Interrupt;
+}
+
+#[cfg(feature = "dma")]
+unsafe impl DmaInterrupts for DMA1 {
+    fn stream_interrupt(stream: DMAStream) -> Interrupt {
+        match stream {
+            DMAStream::Stream0 => Interrupt::DMA1_STREAM0,
+            DMAStream::Stream1 => Interrupt::DMA1_STREAM1,
+            DMAStream::Stream2 => Interrupt::DMA1_STREAM2,
+            DMAStream::Stream3 => Interrupt::DMA1_STREAM3,
+            DMAStream::Stream4 => Interrupt::DMA1_STREAM4,
+            DMAStream::Stream5 => Interrupt::DMA1_STREAM5,
+            DMAStream::Stream6 => Interrupt::DMA1_STREAM6,
+            DMAStream::Stream7 => Interrupt::DMA1_STREAM7,
+        }
+    }
+}
+
+#[cfg(feature = "dma")]
+unsafe impl DmaInterrupts for DMA2 {
+    fn stream_interrupt(stream: DMAStream) -> Interrupt {
+        match stream {
+            DMAStream::Stream0 => Interrupt::DMA2_STREAM0,
+            DMAStream::Stream1 => Interrupt::DMA2_STREAM1,
+            DMAStream::Stream2 => Interrupt::DMA2_STREAM2,
+            DMAStream::Stream3 => Interrupt::DMA2_STREAM3,
+            DMAStream::Stream4 => Interrupt::DMA2_STREAM4,
+            DMAStream::Stream5 => Interrupt::DMA2_STREAM5,
+            DMAStream::Stream6 => Interrupt::DMA2_STREAM6,
+            DMAStream::Stream7 => Interrupt::DMA2_STREAM7,
+        }
+    }
+}
+
+/// Driver that can manage its own NVIC interrupt line
+pub trait InterruptSource {
+    /// Interrupt line used by this driver
+    fn interrupt(&self) -> Interrupt;
+
+    /// Enables the interrupt line in the NVIC with the given logical priority
+    ///
+    /// NOTE This doesn't enable any interrupt event in the peripheral
+    fn listen_nvic(&self, nvic: &NVIC, priority: u8) {
+        nvic.enable_with_priority(self.interrupt(), priority);
+    }
+
+    /// Disables the interrupt line in the NVIC
+    fn unlisten_nvic(&self, nvic: &NVIC) {
+        nvic.disable(self.interrupt());
+    }
+
+    /// Sets the interrupt line pending, the handler will run as soon as its
+    /// priority allows it
+    fn pend_nvic(&self, nvic: &NVIC) {
+        nvic.set_pending(self.interrupt());
+    }
+}
+
+#[cfg(feature = "usart")]
+impl<'a, U> InterruptSource for Serial<'a, U>
+    where U: Any + Usart + HasInterrupt
+{
+    fn interrupt(&self) -> Interrupt {
+        U::INTERRUPT
+    }
+}
+
+#[cfg(feature = "spi")]
+impl<'a, S, D> InterruptSource for Spi<'a, S, D>
+    where S: Any + SPI + HasInterrupt,
+          D: Any + DMA
+{
+    fn interrupt(&self) -> Interrupt {
+        S::INTERRUPT
+    }
+}
+
+impl<'a, T, R> InterruptSource for Timer<'a, T, R>
+    where R: TIMBase,
+          T: Any + TIM<R> + HasInterrupt
+{
+    fn interrupt(&self) -> Interrupt {
+        T::INTERRUPT
+    }
+}
+
+#[cfg(feature = "adc")]
+impl<'a> InterruptSource for SoftComparator<'a> {
+    fn interrupt(&self) -> Interrupt {
+        ADC1::INTERRUPT
+    }
+}
+
+#[cfg(feature = "dma")]
+impl<'a, U> InterruptSource for Dma<'a, U>
+    where U: Any + DMA + DmaInterrupts
+{
+    fn interrupt(&self) -> Interrupt {
+        U::stream_interrupt(self.stream())
+    }
+}
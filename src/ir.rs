@@ -0,0 +1,411 @@
+//! Infrared remote control: NEC and RC5
+//!
+//! Frames are handled as lists of mark / space durations in microseconds,
+//! marks (carrier on) at even indexes and spaces at odd ones.
+//!
+//! Transmission: a TIM1 PWM channel generates the carrier and `IrTx` gates
+//! it. `send` and `step` return how long the current symbol lasts; program a
+//! timer with it and call `step` from its update interrupt:
+//!
+//! ``` ignore
+//! let n = ir::nec_encode(0x04, 0x08, &mut symbols);
+//! tx.init(&clocks, ir::NEC_CARRIER)?;
+//! if let Some(us) = tx.send(&symbols[..n]) {
+//!     timer.set_timeout(us);
+//!     timer.resume();
+//! }
+//!
+//! // TIM3 update interrupt
+//! match tx.step() {
+//!     Some(us) => timer.set_timeout(us),
+//!     None => timer.pause(),
+//! }
+//! ```
+//!
+//! Reception: feed the duration of every level seen at the output of a
+//! demodulating receiver (TSOP style) to a decoder. The durations can come
+//! from input capture or from EXTI edges timestamped with a free running
+//! timer. Receivers are usually active low: a low output is a mark.
+
+use rcc::{ClockError, Clocks};
+use pwm2::Pwm;
+use stm32f411::TIM1;
+use time::{Hertz, Microseconds};
+use timer::Channel;
+
+/// Carrier frequency of NEC remotes
+pub const NEC_CARRIER: Hertz = Hertz(38_000);
+/// Carrier frequency of RC5 remotes
+pub const RC5_CARRIER: Hertz = Hertz(36_000);
+
+/// Durations in an NEC frame
+pub const NEC_LEN: usize = 67;
+/// Durations in an NEC repeat code
+pub const NEC_REPEAT_LEN: usize = 3;
+/// Maximum number of durations in an RC5 frame
+pub const RC5_MAX_LEN: usize = 28;
+
+const NEC_LEADER_MARK: u16 = 9000;
+const NEC_LEADER_SPACE: u16 = 4500;
+const NEC_REPEAT_SPACE: u16 = 2250;
+const NEC_BIT_MARK: u16 = 562;
+const NEC_ZERO_SPACE: u16 = 562;
+const NEC_ONE_SPACE: u16 = 1687;
+
+/// RC5 half bit
+const RC5_HALF: u16 = 889;
+
+/// Accepted deviation from the nominal durations, in percent
+const TOLERANCE: u32 = 25;
+
+/// Encodes an NEC frame: address, inverted address, command, inverted
+/// command, LSB first. Returns the number of durations written
+///
+/// # Panics
+///
+/// If `out` is shorter than `NEC_LEN`
+pub fn nec_encode(address: u8, command: u8, out: &mut [u16]) -> usize {
+    assert!(out.len() >= NEC_LEN);
+
+    let bits = u32::from(address) | u32::from(!address) << 8 |
+        u32::from(command) << 16 | u32::from(!command) << 24;
+
+    out[0] = NEC_LEADER_MARK;
+    out[1] = NEC_LEADER_SPACE;
+    for i in 0..32 {
+        out[2 + 2 * i] = NEC_BIT_MARK;
+        out[3 + 2 * i] = if bits & (1 << i) != 0 { NEC_ONE_SPACE } else { NEC_ZERO_SPACE };
+    }
+    out[NEC_LEN - 1] = NEC_BIT_MARK;
+    NEC_LEN
+}
+
+/// Encodes an NEC repeat code, sent every 108 ms while a key is held.
+/// Returns the number of durations written
+///
+/// # Panics
+///
+/// If `out` is shorter than `NEC_REPEAT_LEN`
+pub fn nec_repeat(out: &mut [u16]) -> usize {
+    out[..NEC_REPEAT_LEN].copy_from_slice(&[NEC_LEADER_MARK, NEC_REPEAT_SPACE, NEC_BIT_MARK]);
+    NEC_REPEAT_LEN
+}
+
+/// Encodes an RC5 frame; `toggle` must change on every key press.
+/// Returns the number of durations written
+///
+/// Commands above 63 use the second field bit (RC5X).
+///
+/// # Panics
+///
+/// If `address` or `command` is out of range, or if `out` is shorter than
+/// `RC5_MAX_LEN`
+pub fn rc5_encode(toggle: bool, address: u8, command: u8, out: &mut [u16]) -> usize {
+    assert!(address < 32 && command < 128);
+    assert!(out.len() >= RC5_MAX_LEN);
+
+    let field = command & 0x40 == 0;
+    let bits = 1 << 13 | (field as u16) << 12 | (toggle as u16) << 11 |
+        u16::from(address) << 6 | u16::from(command & 0x3F);
+
+    // Manchester: a `1` is a space then a mark, a `0` a mark then a space.
+    // The first half of the start bit is the idle line and isn't sent
+    let mut n = 0;
+    let mut mark = true;
+    let mut run = 0;
+    for i in (0..14).rev() {
+        let one = bits & (1 << i) != 0;
+        for &half in [!one, one].iter() {
+            if n == 0 && run == 0 && !half {
+                continue;
+            }
+            if half == mark {
+                run += 1;
+            } else {
+                out[n] = run * RC5_HALF;
+                n += 1;
+                mark = half;
+                run = 1;
+            }
+        }
+    }
+    // A trailing space merges with the idle line
+    if mark {
+        out[n] = run * RC5_HALF;
+        n += 1;
+    }
+    n
+}
+
+/// NEC / RC5 transmitter gating a TIM1 PWM carrier
+pub struct IrTx<'a> {
+    tim: &'a TIM1,
+    channel: Channel,
+    symbols: &'a [u16],
+    index: usize,
+}
+
+impl<'a> IrTx<'a> {
+    /// `channel` must be routed to the IR LED driver
+    pub fn new(tim: &'a TIM1, channel: Channel) -> Self {
+        IrTx { tim: tim, channel: channel, symbols: &[], index: 0 }
+    }
+
+    /// Sets the carrier to `carrier` with a 1/3 duty cycle, output off
+    ///
+    /// The PWM must have been `init`ialized. Returns the achieved carrier
+    /// frequency.
+    pub fn init(&self, clocks: &Clocks, carrier: Hertz) -> Result<Hertz, ClockError> {
+        let pwm = Pwm(self.tim);
+        let freq = pwm.try_set_frequency(clocks, carrier)?;
+        pwm.set_duty_fraction(self.channel, 1, 3);
+        ::hal::Pwm::disable(&pwm, self.channel);
+        Ok(freq)
+    }
+
+    /// Starts sending `symbols`, returns the duration of the first mark or
+    /// `None` if there's nothing to send
+    pub fn send(&mut self, symbols: &'a [u16]) -> Option<Microseconds> {
+        self.symbols = symbols;
+        self.index = 0;
+        self.apply()
+    }
+
+    /// Moves to the next symbol, returns its duration or `None` (carrier
+    /// off) when the frame is over
+    pub fn step(&mut self) -> Option<Microseconds> {
+        self.index += 1;
+        self.apply()
+    }
+
+    /// Checks if a frame is being sent
+    pub fn is_busy(&self) -> bool {
+        self.index < self.symbols.len()
+    }
+
+    fn apply(&self) -> Option<Microseconds> {
+        let pwm = Pwm(self.tim);
+        match self.symbols.get(self.index) {
+            Some(&us) => {
+                if self.index % 2 == 0 {
+                    ::hal::Pwm::enable(&pwm, self.channel);
+                } else {
+                    ::hal::Pwm::disable(&pwm, self.channel);
+                }
+                Some(Microseconds(u32::from(us)))
+            }
+            None => {
+                ::hal::Pwm::disable(&pwm, self.channel);
+                None
+            }
+        }
+    }
+}
+
+/// Level at the output of the receiver
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Level {
+    /// Carrier present
+    Mark,
+    /// No carrier
+    Space,
+}
+
+/// Decoded NEC event
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NecEvent {
+    /// Key press; `address` holds 16 bits for extended NEC remotes, which
+    /// don't send the inverted address
+    Command { address: u16, command: u8 },
+    /// The last key is still held
+    Repeat,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum NecState {
+    Idle,
+    LeaderSpace,
+    RepeatMark,
+    BitMark,
+    BitSpace,
+}
+
+/// NEC decoder
+pub struct NecDecoder {
+    state: NecState,
+    bits: u32,
+    count: u8,
+}
+
+impl NecDecoder {
+    pub const fn new() -> Self {
+        NecDecoder { state: NecState::Idle, bits: 0, count: 0 }
+    }
+
+    /// Feeds the duration of a level that just ended
+    pub fn feed(&mut self, level: Level, duration: Microseconds) -> Option<NecEvent> {
+        let us = duration.0;
+        let mark = level == Level::Mark;
+
+        let (state, event) = match self.state {
+            NecState::Idle if mark && within(us, NEC_LEADER_MARK) => {
+                (NecState::LeaderSpace, None)
+            }
+            NecState::LeaderSpace if !mark && within(us, NEC_LEADER_SPACE) => {
+                self.bits = 0;
+                self.count = 0;
+                (NecState::BitMark, None)
+            }
+            NecState::LeaderSpace if !mark && within(us, NEC_REPEAT_SPACE) => {
+                (NecState::RepeatMark, None)
+            }
+            NecState::RepeatMark if mark && within(us, NEC_BIT_MARK) => {
+                (NecState::Idle, Some(NecEvent::Repeat))
+            }
+            NecState::BitMark if mark && within(us, NEC_BIT_MARK) => {
+                if self.count == 32 {
+                    (NecState::Idle, self.frame())
+                } else {
+                    (NecState::BitSpace, None)
+                }
+            }
+            NecState::BitSpace if !mark && within(us, NEC_ZERO_SPACE) => {
+                self.count += 1;
+                (NecState::BitMark, None)
+            }
+            NecState::BitSpace if !mark && within(us, NEC_ONE_SPACE) => {
+                self.bits |= 1 << self.count;
+                self.count += 1;
+                (NecState::BitMark, None)
+            }
+            // A leader mark always starts over
+            _ if mark && within(us, NEC_LEADER_MARK) => (NecState::LeaderSpace, None),
+            _ => (NecState::Idle, None),
+        };
+
+        self.state = state;
+        event
+    }
+
+    fn frame(&self) -> Option<NecEvent> {
+        let bits = self.bits;
+        let command = (bits >> 16) as u8;
+        if command != !(bits >> 24) as u8 {
+            return None;
+        }
+
+        let address = bits as u8;
+        let address = if address == !(bits >> 8) as u8 {
+            u16::from(address)
+        } else {
+            bits as u16
+        };
+
+        Some(NecEvent::Command { address: address, command: command })
+    }
+}
+
+/// Decoded RC5 frame
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Rc5Event {
+    /// Changes on every key press, stays the same while a key is held
+    pub toggle: bool,
+    pub address: u8,
+    /// 0 to 127, RC5X commands included
+    pub command: u8,
+}
+
+/// RC5 decoder
+pub struct Rc5Decoder {
+    /// Half bit levels received so far, 1 = mark, oldest in the MSB
+    halves: u32,
+    count: u8,
+}
+
+impl Rc5Decoder {
+    pub const fn new() -> Self {
+        Rc5Decoder { halves: 0, count: 0 }
+    }
+
+    /// Feeds the duration of a level that just ended
+    pub fn feed(&mut self, level: Level, duration: Microseconds) -> Option<Rc5Event> {
+        let us = duration.0;
+        let mark = level == Level::Mark;
+
+        let n = if within(us, RC5_HALF) {
+            1
+        } else if within(us, 2 * RC5_HALF) {
+            2
+        } else {
+            0
+        };
+
+        if self.count == 0 {
+            if !mark || n == 0 {
+                return None;
+            }
+            // The first half of the start bit is the idle line
+            self.push(false);
+        }
+
+        if n == 0 {
+            // Only the space that ends a frame can be longer; it completes
+            // a trailing `0`
+            let event = if !mark && self.count == 27 {
+                self.push(false);
+                self.frame()
+            } else {
+                None
+            };
+            self.reset();
+            return event;
+        }
+
+        for _ in 0..n {
+            self.push(mark);
+            if self.count == 28 {
+                let event = self.frame();
+                self.reset();
+                return event;
+            }
+        }
+        None
+    }
+
+    fn push(&mut self, mark: bool) {
+        self.halves = self.halves << 1 | mark as u32;
+        self.count += 1;
+    }
+
+    fn reset(&mut self) {
+        self.halves = 0;
+        self.count = 0;
+    }
+
+    fn frame(&self) -> Option<Rc5Event> {
+        let mut bits = 0u16;
+        for i in (0..14).rev() {
+            bits <<= 1;
+            match (self.halves >> (2 * i)) & 0b11 {
+                0b01 => bits |= 1,
+                0b10 => {}
+                _ => return None,
+            }
+        }
+
+        let field = bits & (1 << 12) != 0;
+        let command = (bits & 0x3F) as u8 | if field { 0 } else { 0x40 };
+        Some(Rc5Event {
+            toggle: bits & (1 << 11) != 0,
+            address: ((bits >> 6) & 0x1F) as u8,
+            command: command,
+        })
+    }
+}
+
+/// Checks if `us` is within `TOLERANCE` of `nominal`
+fn within(us: u32, nominal: u16) -> bool {
+    let nominal = u32::from(nominal);
+    let delta = nominal * TOLERANCE / 100;
+    us >= nominal - delta && us <= nominal + delta
+}
@@ -0,0 +1,227 @@
+//! Key matrix scanner
+//!
+//! `Keypad` scans an up to 8 x 8 key matrix on every timer tick: the rows
+//! are open-drain outputs driven low one at a time, the columns inputs with
+//! pull ups, read as a `PinGroup` with one IDR access per row. Each key is debounced like a
+//! `DebouncedInput` and the debounced presses and releases are queued.
+//!
+//! ``` ignore
+//! // Rows on PB12 - PB15, columns on PC0 - PC3, 1 ms tick
+//! static mut KEYPAD: Keypad<GPIOB, GPIOC, [Event; 8]> = Keypad::new(0xF000, 0x000F, 5);
+//!
+//! let rows = PortAccess::claim(gpiob).unwrap();
+//! let columns = PortAccess::claim(gpioc).unwrap();
+//! KEYPAD.init(&rows, &columns);
+//!
+//! // timer interrupt
+//! KEYPAD.scan(&rows, &columns);
+//!
+//! // idle loop
+//! while let Some(Event::Pressed(key)) = KEYPAD.poll() { .. }
+//! ```
+//!
+//! # Ghosting
+//!
+//! Without a diode per key, three pressed keys on the corners of a
+//! rectangle make the fourth corner read as pressed too. A scan where two
+//! rows share two or more pressed columns is ambiguous and is dropped: the
+//! keys keep their debounced state until the pattern goes away, see
+//! `is_ghosting`.
+//!
+//! As with `PortScanner`, the queue isn't shared safely between contexts on
+//! its own.
+
+use core::marker::Unsize;
+use core::ops::Deref;
+
+use heapless::RingBuffer;
+use stm32f411::gpioa;
+
+use gpio::{Mode, PinGroup, PortAccess, Pupd};
+
+/// Largest number of rows, and of columns
+pub const MAX_LINES: u32 = 8;
+
+/// Position of a key in the matrix
+///
+/// `row` and `column` count the pins of the masks from the lowest one.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Key {
+    pub row: u8,
+    pub column: u8,
+}
+
+/// Debounced key event
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Event {
+    Pressed(Key),
+    Released(Key),
+}
+
+/// Scans a key matrix and queues key events
+pub struct Keypad<R, C, A>
+    where R: Deref<Target=gpioa::RegisterBlock>,
+          C: Deref<Target=gpioa::RegisterBlock>,
+          A: Unsize<[Event]>
+{
+    rows: PinGroup<R>,
+    columns: PinGroup<C>,
+    threshold: u8,
+    integrators: [u8; 64],
+    /// Debounced state, bit `row * 8 + column`
+    pressed: u64,
+    ghosting: bool,
+    dropped: u32,
+    queue: RingBuffer<Event, A>,
+}
+
+impl<R, C, A> Keypad<R, C, A>
+    where R: Deref<Target=gpioa::RegisterBlock>,
+          C: Deref<Target=gpioa::RegisterBlock>,
+          A: Unsize<[Event]>
+{
+    /// Creates a scanner for the row pins in `rows` and the column pins in
+    /// `columns`, at most 8 of each
+    ///
+    /// `threshold` is the number of consistent scans needed to change the
+    /// state of a key.
+    pub const fn new(rows: u16, columns: u16, threshold: u8) -> Self {
+        Keypad {
+            rows: PinGroup::from_mask(rows),
+            columns: PinGroup::from_mask(columns),
+            threshold: threshold,
+            integrators: [0; 64],
+            pressed: 0,
+            ghosting: false,
+            dropped: 0,
+            queue: RingBuffer::new(),
+        }
+    }
+
+    /// Configures the rows as open-drain outputs, released, and the columns
+    /// as inputs with pull ups
+    pub fn init(&self, row_port: &PortAccess<R>, column_port: &PortAccess<C>) {
+        assert!(self.rows.mask().count_ones() <= MAX_LINES);
+        assert!(self.columns.mask().count_ones() <= MAX_LINES);
+
+        self.rows.set(row_port);
+        self.rows.set_open_drain(row_port.port(), true);
+        self.rows.set_mode(row_port.port(), Mode::Output);
+        self.columns.set_pupd(column_port.port(), Pupd::PullUp);
+        self.columns.set_mode(column_port.port(), Mode::Input);
+    }
+
+    /// Scans the matrix, must be called once per tick
+    pub fn scan(&mut self, row_port: &PortAccess<R>, column_port: &PortAccess<C>) {
+        let mut raw = [0u8; 8];
+        for (row, pin) in pins(self.rows.mask()).enumerate() {
+            row_port.clear(1 << pin);
+            // Let the column lines settle through the pull ups
+            let _ = self.columns.read(column_port);
+            let columns = !self.columns.read(column_port) & self.columns.mask();
+            row_port.set(1 << pin);
+
+            for (column, pin) in pins(self.columns.mask()).enumerate() {
+                if columns & (1 << pin) != 0 {
+                    raw[row] |= 1 << column;
+                }
+            }
+        }
+
+        self.ghosting = is_ambiguous(&raw);
+        if self.ghosting {
+            return;
+        }
+
+        let rows = self.rows.mask().count_ones() as usize;
+        let columns = self.columns.mask().count_ones() as usize;
+        for row in 0..rows {
+            for column in 0..columns {
+                let index = row * 8 + column;
+                let active = raw[row] & (1 << column) != 0;
+                let key = Key { row: row as u8, column: column as u8 };
+                self.debounce(index, active, key);
+            }
+        }
+    }
+
+    /// Takes the oldest event from the queue
+    pub fn poll(&mut self) -> Option<Event> {
+        self.queue.dequeue()
+    }
+
+    /// Debounced state of `key`
+    pub fn is_pressed(&self, key: Key) -> bool {
+        self.pressed & (1 << (key.row * 8 + key.column)) != 0
+    }
+
+    /// Whether the last scan was dropped because of a possible ghost key
+    pub fn is_ghosting(&self) -> bool {
+        self.ghosting
+    }
+
+    /// Number of events lost because the queue was full
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    fn debounce(&mut self, index: usize, active: bool, key: Key) {
+        let integrator = &mut self.integrators[index];
+        if active {
+            if *integrator < self.threshold {
+                *integrator += 1;
+            }
+        } else if *integrator > 0 {
+            *integrator -= 1;
+        }
+
+        let mask = 1u64 << index;
+        let event = if self.pressed & mask == 0 && *integrator >= self.threshold {
+            self.pressed |= mask;
+            Event::Pressed(key)
+        } else if self.pressed & mask != 0 && *integrator == 0 {
+            self.pressed &= !mask;
+            Event::Released(key)
+        } else {
+            return;
+        };
+
+        if self.queue.enqueue(event).is_err() {
+            self.dropped = self.dropped.wrapping_add(1);
+        }
+    }
+}
+
+/// Whether two rows share two pressed columns, the pattern a ghost key
+/// produces
+fn is_ambiguous(raw: &[u8; 8]) -> bool {
+    for (i, a) in raw.iter().enumerate() {
+        for b in raw[i + 1..].iter() {
+            if (a & b).count_ones() >= 2 {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// Pin numbers of the set bits of `mask`, lowest first
+fn pins(mask: u16) -> Pins {
+    Pins(mask)
+}
+
+struct Pins(u16);
+
+impl Iterator for Pins {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if self.0 == 0 {
+            None
+        } else {
+            let pin = self.0.trailing_zeros() as u8;
+            self.0 &= self.0 - 1;
+            Some(pin)
+        }
+    }
+}
@@ -9,9 +9,92 @@
 //! configuration of the quickstart crate.
 //!
 //! [i]: https://docs.rs/cortex-m-quickstart/0.1.8/cortex_m_quickstart/
+//!
+//! # Features
+//!
+//! Peripheral drivers can be left out to save flash, e.g. in a bootloader:
+//! with `default-features = false` only the GPIO, clock, timer and NVIC
+//! support is built. All of these are on by default:
+//!
+//! - `dma`: `dma2`, `circular`, `bitbang`, `tlc5955`
+//! - `spi`: `spi2` and the SPI based drivers, implies `dma`
+//! - `usart`: `serial`; the DMA methods and `protocols::dmx512` also need
+//!   `dma`
+//! - `adc`: `adc2`, `sampling` and `comparator`, implies `dma`
+//! - `pwm`: `pwm2` and `ir`
+//! - `i2c`: `i2c`
+//!
+//! `selftest` needs both `spi` and `usart`.
+//!
+//! Off by default:
+//!
+//! - `usb`: `usb_host`, and makes `Cfgr::freeze` insist on an exact 48 MHz
+//!   USB clock
+//! - `async`: `waker`, interrupt driven wake ups for executors on the DMA
+//!   streams and the USARTs; implies `dma` and `usart`
+//! - `fault_handler`: `fault_handler`, HardFault / BusFault / UsageFault
+//!   reports kept across a reset; needs the `naked_functions` feature gate
+//!
+//! # Concurrency
+//!
+//! Drivers (`Serial`, `Spi`, `Timer`, `Dma`, `Pwm`, ...) are thin wrappers
+//! around a `&'a` reference to a peripheral and cost nothing to build, so
+//! the intended pattern with RTFM is to keep the *peripheral* as the task
+//! resource and wrap it in each task:
+//!
+//! ``` ignore
+//! fn rx(_t: &mut Threshold, r: USART1::Resources) {
+//!     let serial = Serial::new(&**r.USART1);
+//!     ..
+//! }
+//! ```
+//!
+//! The drivers are neither `Send` nor `Sync`: the register blocks aren't,
+//! and a driver can be built again from the same peripheral, so moving one
+//! to another context wouldn't stop the original context from using the
+//! registers. RTFM's resource ceilings, not the driver types, serialize the
+//! access.
+//!
+//! Where a task needs to *own* a peripheral (a thread-like executor, a
+//! resource moved into a task), `owned::Owned` is a take-once handle that is
+//! `Send`; the task builds its drivers from it.
+//!
+//! Audit of the shareable types:
+//!
+//! - `owned::Owned<P>`: `Send`, not `Sync`. Taken once until dropped.
+//! - `serial::Tx` / `serial::Rx` (from `Serial::split`): `Send`, each half
+//!   only touches its own direction's bits. Move them to the tasks that
+//!   produce and consume the data.
+//! - `gpio::Pin`, `gpio::TypedPin`, `gpio::PinGroup`: `Send` + `Sync`, they
+//!   only store pin numbers. Setting pins through BSRR is atomic, but mode /
+//!   pull / speed changes are read-modify-write and need the port resource.
+//! - `gpio::PortAccess`: `Send`, not `Sync`. Claimed once per port, every
+//!   access is a single register read or BSRR write.
+//! - `Serial`, `Spi`, `Timer`, `Pwm`, `Dma`, `I2c`: neither. `Dma` streams
+//!   of one controller can still be used from different priorities, the
+//!   shared flag registers are only accessed with single-write operations,
+//!   see `dma2`. `split` hands out one set of streams per controller, until
+//!   `join`.
+//! - `rcc::Clocks`: `Send` + `Sync`, it is `Copy` and can also be read
+//!   anywhere with `Clocks::get()`.
+//! - `dma2::Buffer`: neither, it uses `Cell`s for its borrow state and must
+//!   stay in a single priority (or behind a resource).
+//!
+//! # Register access
+//!
+//! Features the crate doesn't cover can be reached without `unsafe` pointer
+//! casts through the drivers' scoped accessors: `Serial::with_raw`,
+//! `Spi::with_raw`, `Timer::with_raw`, `Dma::with_raw` and
+//! `pwm2::Pwm::with_tim`, each documenting the registers it is safe to
+//! change. As a rule, configuration registers that a driver doesn't write
+//! after `init` (e.g. SPI CRC polynomial, USART guard time, timer dead time)
+//! can be changed freely. Don't touch the enable bits or the status / data
+//! registers of a peripheral with a transfer in flight, the driver tracks
+//! those.
 
 #![allow(missing_docs)]
 // #![deny(warnings)]
+#![feature(asm)]
 #![feature(const_unsafe_cell_new)]
 #![feature(const_cell_new)]
 #![feature(const_fn)]
@@ -19,6 +102,7 @@
 #![feature(never_type)]
 #![feature(unsize)]
 #![feature(fixed_size_array)]
+#![cfg_attr(feature = "fault_handler", feature(naked_functions))]
 #![no_std]
 
 extern crate cast;
@@ -33,16 +117,85 @@ extern crate cortex_m_semihosting as semihosting;
 
 pub extern crate stm32f411;
 
+#[cfg(feature = "spi")]
 pub mod spi2;
+#[cfg(feature = "dma")]
 pub mod dma2;
+#[cfg(feature = "dma")]
+pub mod dma_registry;
+pub mod error;
+pub mod fault;
+#[cfg(feature = "fault_handler")]
+pub mod fault_handler;
+#[cfg(feature = "dma")]
+pub mod circular;
+#[cfg(feature = "dma")]
+pub mod pool;
+#[cfg(feature = "dma")]
+pub mod sniffer;
+#[cfg(feature = "pwm")]
 pub mod pwm2;
+#[cfg(feature = "pwm")]
+pub mod ir;
+#[cfg(feature = "pwm")]
+pub mod fan;
+#[cfg(all(feature = "pwm", feature = "dma"))]
+pub mod tone;
 pub mod time;
 pub mod timer;
+pub mod timeout;
 pub mod delay;
+pub mod calibration;
 pub mod gpio;
+#[cfg(feature = "dma")]
 pub mod tlc5955;
+#[cfg(feature = "usart")]
 pub mod serial;
+#[cfg(feature = "i2c")]
+pub mod i2c;
+pub mod soft_i2c;
+pub mod soft_uart;
+pub mod input;
+pub mod keypad;
+pub mod drivers;
+pub mod protocols;
+pub mod exti;
+pub mod control;
+pub mod crc;
+pub mod debug;
+pub mod firmware_integrity;
+pub mod flash;
+pub mod eeprom_emul;
+pub mod option_bytes;
+pub mod boot;
+pub mod interrupts;
+pub mod owned;
+pub mod mpu;
+#[cfg(all(feature = "spi", feature = "usart"))]
+pub mod selftest;
+pub mod pwr;
+pub mod power_profile;
+pub mod rcc;
+pub mod clock_switch;
+pub mod rtc;
+#[cfg(feature = "adc")]
+pub mod adc2;
+#[cfg(feature = "adc")]
+pub mod sampling;
+#[cfg(feature = "adc")]
+pub mod comparator;
+#[cfg(feature = "dma")]
+pub mod bitbang;
+pub mod scanner;
+pub mod watchdog;
+#[cfg(feature = "usb")]
+pub mod usb_host;
+#[cfg(feature = "async")]
+pub mod waker;
+#[cfg(feature = "disco")]
+pub mod disco;
 pub use hal::prelude;
+pub use error::Error;
 
 pub use timer::{Timer};
 /*pub mod led;
@@ -127,20 +280,27 @@ macro_rules! frequency {
 }
 
 /// Advance High-performance Bus (AHB)
+///
+/// `Ticks` at the 16 MHz reset clock, see `clock_switch`
 pub mod ahb {
     frequency!(16_000_000);
 }
 
 /// Advance Peripheral Bus 1 (APB1)
+///
+/// `Ticks` at the 16 MHz reset clock, see `clock_switch`
 pub mod apb1 {
     frequency!(16_000_000);
 }
 
 /// Advance Peripheral Bus 2 (APB2)
+///
+/// `Ticks` at the 16 MHz reset clock, see `clock_switch`
 pub mod apb2 {
     frequency!(16_000_000);
 }
 
+/// SysTick clock, HCLK / 8 at the 16 MHz reset clock, see `clock_switch`
 pub mod sysclk {
     frequency!(::ahb::FREQUENCY / 8);
 }
\ No newline at end of file
@@ -0,0 +1,253 @@
+//! Memory Protection Unit regions
+//!
+//! `Region` builds the RBAR / RASR pair of one of the 8 MPU regions:
+//!
+//! ``` ignore
+//! // Stack overflow guard: the bottom 32 bytes of the stack trap
+//! mpu::stack_guard(mpu, 0, stack_bottom);
+//!
+//! // DMA buffers: not cacheable, shareable, not executable
+//! Region::new(1, 0x2001_C000, 16 * 1024)
+//!     .memory(Memory::NonCacheable)
+//!     .shareable(true)
+//!     .execute_never(true)
+//!     .apply(mpu);
+//!
+//! mpu::enable(mpu, true);
+//! ```
+//!
+//! # DMA buffers
+//!
+//! The Cortex-M4 has no data cache, so on the F411 the memory attributes of
+//! DMA buffers make no difference; marking them non-cacheable and
+//! shareable now keeps code that moves to a Cortex-M7 part correct, where a
+//! cached buffer and the DMA see different data. A region must be a power
+//! of two in size and aligned to its size, so gather the `dma2::Buffer`
+//! statics (and the `&'static mut` buffers of `Transfer`s) in a dedicated
+//! section:
+//!
+//! ``` ignore
+//! #[link_section = ".dma_buffers"]
+//! static BUFFER: Buffer<[u8; 64]> = Buffer::new([0; 64], DMAStream::Stream4);
+//! ```
+//!
+//! ``` text
+//! /* memory.x, after RAM is shrunk by 16K */
+//! DMA_RAM : ORIGIN = 0x2001C000, LENGTH = 16K
+//! SECTIONS { .dma_buffers (NOLOAD) : { *(.dma_buffers) } > DMA_RAM }
+//! ```
+//!
+//! NOLOAD sections aren't initialized by the runtime: write the buffers
+//! before the first transfer.
+//!
+//! Accesses that violate a region raise MemManage, which escalates to
+//! HardFault unless it's enabled in `SCB.SHCSR`.
+
+use stm32f411::MPU;
+
+/// Number of regions of the Cortex-M4 MPU
+pub const REGIONS: u8 = 8;
+
+// CTRL
+const ENABLE: u32 = 1 << 0;
+const PRIVDEFENA: u32 = 1 << 2;
+
+// RBAR
+const VALID: u32 = 1 << 4;
+
+// RASR
+const XN: u32 = 1 << 28;
+const S: u32 = 1 << 18;
+
+/// Access permissions
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Access {
+    /// Any access faults, e.g. a stack guard
+    NoAccess,
+    /// Read / write, privileged code only
+    Privileged,
+    /// Read only, privileged code only
+    PrivilegedReadOnly,
+    /// Read / write for everyone
+    ReadWrite,
+    /// Read only for everyone
+    ReadOnly,
+}
+
+impl Access {
+    /// AP field
+    fn bits(self) -> u32 {
+        match self {
+            Access::NoAccess => 0b000,
+            Access::Privileged => 0b001,
+            Access::PrivilegedReadOnly => 0b101,
+            Access::ReadWrite => 0b011,
+            Access::ReadOnly => 0b110,
+        }
+    }
+}
+
+/// Memory type and cache policy
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Memory {
+    /// Normal memory, write-back cacheable (the default map of SRAM)
+    WriteBack,
+    /// Normal memory, write-through cacheable
+    WriteThrough,
+    /// Normal memory, not cacheable; DMA buffers
+    NonCacheable,
+    /// Device memory (peripherals), accesses aren't merged or reordered
+    Device,
+    /// Strongly ordered, every access completes before the next one
+    StronglyOrdered,
+}
+
+impl Memory {
+    /// TEX, C, B fields
+    fn bits(self) -> u32 {
+        let (tex, c, b) = match self {
+            Memory::WriteBack => (0b000, 1, 1),
+            Memory::WriteThrough => (0b000, 1, 0),
+            Memory::NonCacheable => (0b001, 0, 0),
+            Memory::Device => (0b000, 0, 1),
+            Memory::StronglyOrdered => (0b000, 0, 0),
+        };
+        (tex << 19) | (c << 17) | (b << 16)
+    }
+}
+
+/// MPU region configuration
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    number: u8,
+    base: u32,
+    size: u32,
+    access: Access,
+    memory: Memory,
+    shareable: bool,
+    execute_never: bool,
+    disabled_subregions: u8,
+}
+
+impl Region {
+    /// Region `number` (0 - 7) covering `size` bytes from `base`
+    ///
+    /// `size` is a power of two, at least 32, and `base` is aligned to it.
+    /// Starts out read / write, write-back cacheable, executable.
+    pub fn new(number: u8, base: u32, size: u32) -> Self {
+        assert!(number < REGIONS);
+        assert!(size >= 32 && size.is_power_of_two());
+        assert!(base & (size - 1) == 0);
+
+        Region {
+            number: number,
+            base: base,
+            size: size,
+            access: Access::ReadWrite,
+            memory: Memory::WriteBack,
+            shareable: false,
+            execute_never: false,
+            disabled_subregions: 0,
+        }
+    }
+
+    /// Access permissions
+    pub fn access(mut self, access: Access) -> Self {
+        self.access = access;
+        self
+    }
+
+    /// Memory type
+    pub fn memory(mut self, memory: Memory) -> Self {
+        self.memory = memory;
+        self
+    }
+
+    /// Shared with other bus masters (DMA)
+    pub fn shareable(mut self, shareable: bool) -> Self {
+        self.shareable = shareable;
+        self
+    }
+
+    /// Faults on instruction fetches
+    pub fn execute_never(mut self, execute_never: bool) -> Self {
+        self.execute_never = execute_never;
+        self
+    }
+
+    /// Leaves out the eighths of the region set in `mask`, bit 0 being the
+    /// lowest; only regions of 256 bytes or more have subregions
+    pub fn disable_subregions(mut self, mask: u8) -> Self {
+        assert!(mask == 0 || self.size >= 256);
+        self.disabled_subregions = mask;
+        self
+    }
+
+    /// Programs and enables the region
+    ///
+    /// Regions with a higher number take precedence where they overlap.
+    pub fn apply(&self, mpu: &MPU) {
+        let size = self.size.trailing_zeros() - 1;
+
+        let mut rasr = (self.access.bits() << 24) | self.memory.bits() |
+            (u32::from(self.disabled_subregions) << 8) | (size << 1) | ENABLE;
+        if self.shareable {
+            rasr |= S;
+        }
+        if self.execute_never {
+            rasr |= XN;
+        }
+
+        unsafe {
+            mpu.rbar.write(self.base | VALID | u32::from(self.number));
+            mpu.rasr.write(rasr);
+        }
+    }
+}
+
+/// Disables region `number`
+pub fn clear(mpu: &MPU, number: u8) {
+    assert!(number < REGIONS);
+    unsafe {
+        mpu.rnr.write(u32::from(number));
+        mpu.rasr.write(0);
+    }
+}
+
+/// Turns a 32-byte region at `stack_bottom` into a no-access guard, a
+/// stack overflow faults instead of corrupting the statics below it
+///
+/// `stack_bottom` is 32-byte aligned; the stack loses those 32 bytes.
+pub fn stack_guard(mpu: &MPU, number: u8, stack_bottom: u32) {
+    Region::new(number, stack_bottom, 32)
+        .access(Access::NoAccess)
+        .execute_never(true)
+        .apply(mpu);
+}
+
+/// Enables the MPU
+///
+/// With `default_map` privileged code can still access the memory no
+/// region covers, with the default attributes; without it only the regions
+/// are accessible.
+pub fn enable(mpu: &MPU, default_map: bool) {
+    let ctrl = if default_map { ENABLE | PRIVDEFENA } else { ENABLE };
+    unsafe {
+        mpu.ctrl.write(ctrl);
+        // The new map must apply to the next access and instruction fetch
+        asm!("dsb
+              isb"
+             :
+             :
+             : "memory"
+             : "volatile");
+    }
+}
+
+/// Disables the MPU, the default memory map applies again
+pub fn disable(mpu: &MPU) {
+    unsafe {
+        asm!("dmb" : : : "memory" : "volatile");
+        mpu.ctrl.write(0);
+    }
+}
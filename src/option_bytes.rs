@@ -0,0 +1,224 @@
+//! Option bytes
+//!
+//! `read` decodes the user option bytes: brownout reset level, read
+//! protection, watchdog and low-power reset settings, write protection.
+//! Field firmware can check them at boot and fix the ones it's allowed to
+//! change:
+//!
+//! ``` ignore
+//! let options = option_bytes::read(flash);
+//! if options.bor_level != BorLevel::Level3 {
+//!     option_bytes::unlock(flash)
+//!         .bor_level(BorLevel::Level3)
+//!         .watchdog(Watchdog::Hardware)
+//!         .program()?;
+//!     // the new values apply after a reset
+//!     boot::reset(scb);
+//! }
+//! ```
+//!
+//! Programming goes through `Unlocked`, which only exists between `unlock`
+//! and `program` (or its drop, which locks the option bytes again), so no
+//! stray OPTCR write can change them. The read protection can only be
+//! queried: going back from level 1 to 0 mass erases the flash, and level 2
+//! permanently disables the debug port and the option bytes themselves.
+
+use stm32f411::FLASH;
+
+use flash::{self, Error};
+
+const OPTKEY1: u32 = 0x0819_2A3B;
+const OPTKEY2: u32 = 0x4C5D_6E7F;
+
+// OPTCR
+const OPTLOCK: u32 = 1 << 0;
+const OPTSTRT: u32 = 1 << 1;
+const BOR_LEV: u32 = 0b11 << 2;
+const WDG_SW: u32 = 1 << 5;
+const NRST_STOP: u32 = 1 << 6;
+const NRST_STDBY: u32 = 1 << 7;
+const RDP: u32 = 0xFF << 8;
+const NWRP: u32 = 0xFF << 16;
+
+// RDP byte
+const RDP_LEVEL0: u32 = 0xAA;
+const RDP_LEVEL2: u32 = 0xCC;
+
+/// Brownout reset threshold, the chip is held in reset below it
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BorLevel {
+    /// Only the power-on / power-down reset, ~1.8 V
+    Off,
+    /// 2.1 V
+    Level1,
+    /// 2.4 V
+    Level2,
+    /// 2.7 V, the lowest supply flash programming works at with 32-bit
+    /// parallelism
+    Level3,
+}
+
+impl BorLevel {
+    fn bits(self) -> u32 {
+        match self {
+            BorLevel::Level3 => 0b00,
+            BorLevel::Level2 => 0b01,
+            BorLevel::Level1 => 0b10,
+            BorLevel::Off => 0b11,
+        }
+    }
+
+    fn from_bits(bits: u32) -> Self {
+        match bits & 0b11 {
+            0b00 => BorLevel::Level3,
+            0b01 => BorLevel::Level2,
+            0b10 => BorLevel::Level1,
+            _ => BorLevel::Off,
+        }
+    }
+}
+
+/// Read protection level
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReadProtection {
+    /// No protection
+    Level0,
+    /// The flash can't be read through the debug port or from RAM / the
+    /// system memory
+    Level1,
+    /// Level 1 for good: debug port off, option bytes frozen
+    Level2,
+}
+
+/// Watchdog start
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Watchdog {
+    /// The IWDG starts when the firmware enables it
+    Software,
+    /// The IWDG runs from reset, the firmware can't stop it
+    Hardware,
+}
+
+/// User option bytes
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct OptionBytes {
+    pub bor_level: BorLevel,
+    pub read_protection: ReadProtection,
+    pub watchdog: Watchdog,
+    /// Entering Stop mode resets the chip
+    pub reset_on_stop: bool,
+    /// Entering Standby mode resets the chip
+    pub reset_on_standby: bool,
+    /// Write protected sectors, bit `n` for sector `n`
+    pub write_protected: u8,
+}
+
+/// Reads the option bytes
+///
+/// These are the values loaded at the last reset, or programmed since.
+pub fn read(flash: &FLASH) -> OptionBytes {
+    let optcr = flash.optcr.read().bits();
+
+    OptionBytes {
+        bor_level: BorLevel::from_bits(optcr >> 2),
+        read_protection: read_protection(flash),
+        watchdog: if optcr & WDG_SW != 0 { Watchdog::Software } else { Watchdog::Hardware },
+        reset_on_stop: optcr & NRST_STOP == 0,
+        reset_on_standby: optcr & NRST_STDBY == 0,
+        write_protected: !(optcr >> 16) as u8,
+    }
+}
+
+/// Read protection level
+pub fn read_protection(flash: &FLASH) -> ReadProtection {
+    match (flash.optcr.read().bits() & RDP) >> 8 {
+        RDP_LEVEL0 => ReadProtection::Level0,
+        RDP_LEVEL2 => ReadProtection::Level2,
+        _ => ReadProtection::Level1,
+    }
+}
+
+/// Unlocks the option bytes for programming
+///
+/// # Panics
+///
+/// At read protection level 2, where the option bytes can't be changed
+pub fn unlock(flash: &FLASH) -> Unlocked {
+    assert!(read_protection(flash) != ReadProtection::Level2);
+
+    if flash.optcr.read().bits() & OPTLOCK != 0 {
+        flash.optkeyr.write(|w| unsafe { w.bits(OPTKEY1) });
+        flash.optkeyr.write(|w| unsafe { w.bits(OPTKEY2) });
+    }
+
+    Unlocked { flash: flash, optcr: flash.optcr.read().bits() }
+}
+
+/// Unlocked option bytes, changes are staged until `program`
+///
+/// Dropping it discards the changes and locks the option bytes.
+pub struct Unlocked<'a> {
+    flash: &'a FLASH,
+    optcr: u32,
+}
+
+impl<'a> Unlocked<'a> {
+    /// Brownout reset threshold
+    pub fn bor_level(mut self, level: BorLevel) -> Self {
+        self.optcr = (self.optcr & !BOR_LEV) | (level.bits() << 2);
+        self
+    }
+
+    /// Watchdog start
+    pub fn watchdog(mut self, watchdog: Watchdog) -> Self {
+        self.optcr = match watchdog {
+            Watchdog::Software => self.optcr | WDG_SW,
+            Watchdog::Hardware => self.optcr & !WDG_SW,
+        };
+        self
+    }
+
+    /// Whether entering Stop / Standby mode resets the chip
+    pub fn reset_on_low_power(mut self, stop: bool, standby: bool) -> Self {
+        self.optcr |= NRST_STOP | NRST_STDBY;
+        if stop {
+            self.optcr &= !NRST_STOP;
+        }
+        if standby {
+            self.optcr &= !NRST_STDBY;
+        }
+        self
+    }
+
+    /// Write protects the sectors set in `sectors`, bit `n` for sector `n`,
+    /// and unprotects the others
+    pub fn write_protect(mut self, sectors: u8) -> Self {
+        self.optcr = (self.optcr & !NWRP) | (u32::from(!sectors) << 16);
+        self
+    }
+
+    /// Programs the staged option bytes and locks them again
+    ///
+    /// Flash accesses stall meanwhile. Most settings apply at the next
+    /// reset. The read protection byte is written back as it was read.
+    pub fn program(self) -> Result<(), Error> {
+        let flash = self.flash;
+        // Never let a corrupted value slip into RDP: level 2 is forever
+        assert!((self.optcr & RDP) >> 8 != RDP_LEVEL2);
+
+        // Errors of an earlier operation don't matter here
+        let _ = flash::wait(flash);
+        flash::clear_errors(flash);
+        let optcr = self.optcr & !(OPTLOCK | OPTSTRT);
+        flash.optcr.write(|w| unsafe { w.bits(optcr) });
+        flash.optcr.write(|w| unsafe { w.bits(optcr | OPTSTRT) });
+        flash::wait(flash)
+    }
+}
+
+impl<'a> Drop for Unlocked<'a> {
+    fn drop(&mut self) {
+        let flash = self.flash;
+        flash.optcr.modify(|r, w| unsafe { w.bits(r.bits() | OPTLOCK) });
+    }
+}
@@ -0,0 +1,102 @@
+//! Owned peripherals
+//!
+//! The drivers borrow their peripheral and can be built any number of
+//! times, so they can't be `Send`: two tasks could drive the same registers.
+//! `Owned` is the exclusive handle instead, a peripheral taken once and
+//! `Send`, that moves into the task (or the executor future) that uses it.
+//! The task builds its drivers from the handle:
+//!
+//! ``` ignore
+//! // init
+//! let usart2 = Owned::take(USART2.borrow(cs)).unwrap();
+//! // `None` while the first handle lives
+//! assert!(Owned::take(USART2.borrow(cs)).is_none());
+//!
+//! // moved into the task
+//! let serial = Serial::new(&*usart2);
+//! serial.write_all(b"hi")?;
+//! ```
+//!
+//! The peripheral is released when the handle is dropped. Only `Owned`
+//! handles exclude each other: code that borrows the peripheral directly
+//! (`Peripheral::borrow`, RTFM resources) isn't stopped, the application
+//! must pick one model per peripheral.
+//!
+//! See the crate level "Concurrency" documentation for which driver types
+//! are `Send` / `Sync`.
+
+use core::ops::Deref;
+use core::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+
+/// First peripheral address, TIM2 on APB1
+const PERIPH_BASE: usize = 0x4000_0000;
+/// Peripherals are 1 KB apart on APB1, APB2 and AHB1
+const PERIPH_STRIDE: usize = 0x400;
+/// Bits per word of the claim map
+const BITS: usize = 32;
+/// Covers APB1, APB2 and AHB1, up to DMA2 at `0x4002_6400`
+const WORDS: usize = 5;
+
+/// Taken peripherals, bit n for `PERIPH_BASE + n * PERIPH_STRIDE`
+static CLAIMED: [AtomicUsize; WORDS] = [
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT,
+];
+
+/// Exclusive, `Send` handle to a peripheral, see the module documentation
+pub struct Owned<P>
+    where P: 'static
+{
+    periph: &'static P,
+    word: usize,
+    bit: usize,
+}
+
+// NOTE(unsafe) the claim map makes this the only `Owned` handle of the
+// peripheral, so moving it to another context is moving the peripheral, like
+// a `&'static mut P`
+unsafe impl<P> Send for Owned<P> where P: 'static + Send {}
+
+impl<P> Owned<P>
+    where P: 'static
+{
+    /// Takes `periph`, `None` if it is already owned
+    ///
+    /// Also `None` for peripherals outside APB1, APB2 and AHB1 (USB OTG,
+    /// the Cortex-M core peripherals).
+    pub fn take(periph: &'static P) -> Option<Self> {
+        let address = periph as *const P as usize;
+        if address < PERIPH_BASE {
+            return None;
+        }
+        let index = (address - PERIPH_BASE) / PERIPH_STRIDE;
+        let word = index / BITS;
+        if word >= WORDS {
+            return None;
+        }
+        let bit = 1 << (index % BITS);
+
+        if CLAIMED[word].fetch_or(bit, Ordering::Acquire) & bit != 0 {
+            return None;
+        }
+        Some(Owned { periph: periph, word: word, bit: bit })
+    }
+}
+
+impl<P> Deref for Owned<P>
+    where P: 'static
+{
+    type Target = P;
+
+    fn deref(&self) -> &P {
+        self.periph
+    }
+}
+
+impl<P> Drop for Owned<P>
+    where P: 'static
+{
+    fn drop(&mut self) {
+        CLAIMED[self.word].fetch_and(!self.bit, Ordering::Release);
+    }
+}
@@ -0,0 +1,197 @@
+//! Pool of fixed size DMA buffers
+//!
+//! `Transfer` owns a `&'static mut` buffer, which suits a handful of
+//! buffers known at compile time. Packet traffic (a USB to USART bridge, a
+//! protocol with a queue of frames in flight) needs buffers taken and given
+//! back at run time; a `Pool` hands out blocks of a static array as
+//! `PoolBuffer`s, and a block goes back to the pool when its `PoolBuffer`
+//! is dropped:
+//!
+//! ``` ignore
+//! static POOL: Pool<[u8; 64], [[u8; 64]; 8]> = Pool::new([[0; 64]; 8]);
+//!
+//! let mut buffer = POOL.alloc().ok_or(Error::NoBuffer)?;
+//! buffer[..frame.len()].copy_from_slice(&frame);
+//! let transfer = spi.send_pooled(buffer)?;
+//!
+//! // the block is back in the pool once the buffer is dropped
+//! let (buffer, result) = transfer.wait();
+//! ```
+//!
+//! Dropping a `PoolTransfer` that is still running stops the stream before
+//! the block is freed. The pool bookkeeping runs in short critical sections,
+//! so `alloc` and the drops work from any interrupt priority.
+
+use core::any::Any;
+use core::cell::{Cell, UnsafeCell};
+use core::marker::{PhantomData, Unsize};
+use core::ops;
+
+use cortex_m::interrupt;
+
+use dma2::{DMA, Error, Transfer};
+
+/// Most blocks a pool can hold
+pub const MAX_BLOCKS: usize = 32;
+
+/// Static array of `B` blocks, `A` is `[B; N]`
+pub struct Pool<B, A> {
+    blocks: UnsafeCell<A>,
+    /// Bit `n` is set while block `n` is handed out
+    used: Cell<u32>,
+    _block: PhantomData<B>,
+}
+
+// NOTE(Sync) `used` is only accessed in critical sections and every block is
+// handed out once at a time
+unsafe impl<B, A> Sync for Pool<B, A>
+    where B: Send,
+          A: Send
+{
+}
+
+impl<B, A> Pool<B, A> {
+    /// Creates a pool of the blocks of `blocks`, at most `MAX_BLOCKS`
+    pub const fn new(blocks: A) -> Self {
+        Pool {
+            blocks: UnsafeCell::new(blocks),
+            used: Cell::new(0),
+            _block: PhantomData,
+        }
+    }
+}
+
+impl<B, A> Pool<B, A>
+    where A: Unsize<[B]>
+{
+    /// Takes a free block, `None` if all are in use
+    ///
+    /// The block keeps the contents of its last use.
+    ///
+    /// # Panics
+    ///
+    /// If the pool holds more than `MAX_BLOCKS` blocks
+    pub fn alloc(&'static self) -> Option<PoolBuffer<B>> {
+        let blocks: *mut [B] = self.blocks.get();
+        let len = unsafe { (*blocks).len() };
+        assert!(len <= MAX_BLOCKS);
+
+        let index = interrupt::free(|_| {
+            let used = self.used.get();
+            let index = (!used).trailing_zeros() as usize;
+            if index < len {
+                self.used.set(used | (1 << index));
+                Some(index)
+            } else {
+                None
+            }
+        });
+
+        match index {
+            Some(index) => Some(PoolBuffer {
+                block: unsafe { &mut *(blocks as *mut B).offset(index as isize) },
+                slot: Slot { used: &self.used, index: index as u8 },
+            }),
+            None => None,
+        }
+    }
+
+    /// Number of free blocks
+    pub fn available(&self) -> usize {
+        let blocks: *mut [B] = self.blocks.get();
+        let len = unsafe { (*blocks).len() };
+        len - self.used.get().count_ones() as usize
+    }
+}
+
+/// Pool block in use, given back to its pool on drop
+pub struct PoolBuffer<B>
+    where B: 'static
+{
+    block: &'static mut B,
+    slot: Slot,
+}
+
+impl<B> PoolBuffer<B> {
+    pub(crate) fn into_parts(self) -> (&'static mut B, Slot) {
+        (self.block, self.slot)
+    }
+
+    pub(crate) fn from_parts(block: &'static mut B, slot: Slot) -> Self {
+        PoolBuffer { block: block, slot: slot }
+    }
+}
+
+impl<B> ops::Deref for PoolBuffer<B> {
+    type Target = B;
+
+    fn deref(&self) -> &B {
+        self.block
+    }
+}
+
+impl<B> ops::DerefMut for PoolBuffer<B> {
+    fn deref_mut(&mut self) -> &mut B {
+        self.block
+    }
+}
+
+/// Marks a block as used until dropped
+pub(crate) struct Slot {
+    used: &'static Cell<u32>,
+    index: u8,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let used = self.used;
+        let mask = 1 << self.index;
+        interrupt::free(|_| used.set(used.get() & !mask));
+    }
+}
+
+/// DMA transfer of a pool block, see `Dma::transfer_pooled`
+pub struct PoolTransfer<'a, U, B>
+    where U: 'a + Any + DMA,
+          B: 'static
+{
+    // NOTE both are only `None` after `wait`
+    transfer: Option<Transfer<'a, U, B>>,
+    slot: Option<Slot>,
+}
+
+impl<'a, U, B> PoolTransfer<'a, U, B>
+    where U: Any + DMA
+{
+    pub(crate) fn new(transfer: Transfer<'a, U, B>, slot: Slot) -> Self {
+        PoolTransfer { transfer: Some(transfer), slot: Some(slot) }
+    }
+
+    /// Items not transferred yet
+    pub fn remaining(&self) -> u16 {
+        self.transfer.as_ref().map(|t| t.remaining()).unwrap_or(0)
+    }
+
+    /// Checks if the transfer is over, successfully or not
+    pub fn is_done(&self) -> bool {
+        self.transfer.as_ref().map(|t| t.is_done()).unwrap_or(true)
+    }
+
+    /// Waits for the end of the transfer and gives the buffer back
+    pub fn wait(mut self) -> (PoolBuffer<B>, Result<(), Error>) {
+        let (block, result) = self.transfer.take().unwrap().wait();
+        (PoolBuffer::from_parts(block, self.slot.take().unwrap()), result)
+    }
+}
+
+impl<'a, U, B> Drop for PoolTransfer<'a, U, B>
+    where U: 'a + Any + DMA,
+          B: 'static
+{
+    fn drop(&mut self) {
+        // The stream must let go of the block before it's freed
+        if let Some(transfer) = self.transfer.take() {
+            transfer.abort();
+        }
+    }
+}
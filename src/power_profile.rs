@@ -0,0 +1,191 @@
+//! Peripheral clock audit
+//!
+//! A peripheral whose clock is enabled draws current even when it does
+//! nothing, a few µA per MHz of its bus clock; at 100 MHz a forgotten timer
+//! costs about as much as the core in Stop mode. `power_report` lists the
+//! enabled clocks with an estimate of their draw, `disable_unused` gates
+//! every clock the application didn't declare as used:
+//!
+//! ``` ignore
+//! power_profile::power_report(&mut Itm(&itm.stim[0]), rcc, &clocks)?;
+//! // GPIOA  AHB1   155 uA
+//! // DMA2   AHB1  1255 uA
+//! // TIM2   APB1   565 uA
+//! // total        1975 uA
+//!
+//! power_profile::disable_unused(rcc, &[power_profile::GPIOA, power_profile::DMA2]);
+//! ```
+//!
+//! The figures are the typical dynamic currents of the datasheet (DS10314,
+//! peripheral current consumption), rounded; the analog part of the ADC
+//! and the USB transceiver come on top when they're powered up. Treat the
+//! total as an order of magnitude, measure the board for anything else.
+//!
+//! A gated peripheral keeps its register contents but ignores writes, and
+//! GPIO pins keep driving their last level while their port clock is off.
+
+use core::fmt::{self, Write};
+
+use stm32f411::RCC;
+
+use rcc::Clocks;
+
+/// Bus of a peripheral, and the enable register of its clock
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Bus {
+    Ahb1,
+    Ahb2,
+    Apb1,
+    Apb2,
+}
+
+impl Bus {
+    fn name(self) -> &'static str {
+        match self {
+            Bus::Ahb1 => "AHB1",
+            Bus::Ahb2 => "AHB2",
+            Bus::Apb1 => "APB1",
+            Bus::Apb2 => "APB2",
+        }
+    }
+
+    fn enr(self, rcc: &RCC) -> u32 {
+        match self {
+            Bus::Ahb1 => rcc.ahb1enr.read().bits(),
+            Bus::Ahb2 => rcc.ahb2enr.read().bits(),
+            Bus::Apb1 => rcc.apb1enr.read().bits(),
+            Bus::Apb2 => rcc.apb2enr.read().bits(),
+        }
+    }
+
+    /// Clears the `mask` bits of the enable register
+    fn gate(self, rcc: &RCC, mask: u32) {
+        match self {
+            Bus::Ahb1 => rcc.ahb1enr.modify(|r, w| unsafe { w.bits(r.bits() & !mask) }),
+            Bus::Ahb2 => rcc.ahb2enr.modify(|r, w| unsafe { w.bits(r.bits() & !mask) }),
+            Bus::Apb1 => rcc.apb1enr.modify(|r, w| unsafe { w.bits(r.bits() & !mask) }),
+            Bus::Apb2 => rcc.apb2enr.modify(|r, w| unsafe { w.bits(r.bits() & !mask) }),
+        }
+    }
+
+    fn clock(self, clocks: &Clocks) -> u32 {
+        match self {
+            Bus::Ahb1 | Bus::Ahb2 => clocks.hclk().0,
+            Bus::Apb1 => clocks.pclk1().0,
+            Bus::Apb2 => clocks.pclk2().0,
+        }
+    }
+}
+
+/// Clock gate of a peripheral
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Gate {
+    pub name: &'static str,
+    pub bus: Bus,
+    /// Enable bit
+    pub bit: u8,
+    /// Typical draw, 1/100 µA per MHz of the bus clock
+    pub centi_ua_per_mhz: u16,
+}
+
+impl Gate {
+    /// Checks if the clock is enabled
+    pub fn is_enabled(&self, rcc: &RCC) -> bool {
+        self.bus.enr(rcc) & (1 << self.bit) != 0
+    }
+
+    /// Gates the clock
+    pub fn disable(&self, rcc: &RCC) {
+        self.bus.gate(rcc, 1 << self.bit);
+    }
+
+    /// Estimated draw with the clock enabled, in µA
+    pub fn current_ua(&self, clocks: &Clocks) -> u32 {
+        self.bus.clock(clocks) / 1_000_000 * u32::from(self.centi_ua_per_mhz) / 100
+    }
+}
+
+macro_rules! gates {
+    ($($name:ident: $bus:ident, $bit:expr, $current:expr;)+) => {
+        $(
+            pub const $name: Gate = Gate {
+                name: stringify!($name),
+                bus: Bus::$bus,
+                bit: $bit,
+                centi_ua_per_mhz: $current,
+            };
+        )+
+
+        /// Every peripheral clock `power_report` and `disable_unused` know
+        pub const GATES: &'static [Gate] = &[$($name),+];
+    }
+}
+
+gates! {
+    GPIOA: Ahb1, 0, 155;
+    GPIOB: Ahb1, 1, 155;
+    GPIOC: Ahb1, 2, 155;
+    GPIOD: Ahb1, 3, 155;
+    GPIOE: Ahb1, 4, 155;
+    GPIOH: Ahb1, 7, 155;
+    CRC: Ahb1, 12, 35;
+    DMA1: Ahb1, 21, 1255;
+    DMA2: Ahb1, 22, 1255;
+    OTG_FS: Ahb2, 7, 2316;
+    TIM2: Apb1, 0, 1131;
+    TIM3: Apb1, 1, 881;
+    TIM4: Apb1, 2, 881;
+    TIM5: Apb1, 3, 1131;
+    WWDG: Apb1, 11, 63;
+    SPI2: Apb1, 14, 188;
+    SPI3: Apb1, 15, 188;
+    USART2: Apb1, 17, 258;
+    I2C1: Apb1, 21, 236;
+    I2C2: Apb1, 22, 236;
+    I2C3: Apb1, 23, 236;
+    PWR: Apb1, 28, 43;
+    TIM1: Apb2, 0, 1235;
+    USART1: Apb2, 4, 313;
+    USART6: Apb2, 5, 313;
+    ADC1: Apb2, 8, 341;
+    SDIO: Apb2, 11, 390;
+    SPI1: Apb2, 12, 138;
+    SPI4: Apb2, 13, 138;
+    SYSCFG: Apb2, 14, 75;
+    TIM9: Apb2, 16, 525;
+    TIM10: Apb2, 17, 341;
+    TIM11: Apb2, 18, 341;
+    SPI5: Apb2, 20, 138;
+}
+
+/// Writes the enabled peripheral clocks with their estimated draw
+///
+/// Returns the estimated total, in µA.
+pub fn power_report<W>(w: &mut W, rcc: &RCC, clocks: &Clocks) -> Result<u32, fmt::Error>
+    where W: Write
+{
+    let mut total = 0;
+    for gate in GATES.iter().filter(|gate| gate.is_enabled(rcc)) {
+        let current = gate.current_ua(clocks);
+        total += current;
+        writeln!(w, "{:<6} {} {:>5} uA", gate.name, gate.bus.name(), current)?;
+    }
+    writeln!(w, "total       {:>5} uA", total)?;
+    Ok(total)
+}
+
+/// Gates the clock of every peripheral of `GATES` that isn't in `used`
+///
+/// PWR stays on: the backup domain and the voltage regulator settings go
+/// through it. Returns the number of clocks that were gated.
+pub fn disable_unused(rcc: &RCC, used: &[Gate]) -> usize {
+    let mut count = 0;
+    for gate in GATES.iter() {
+        if *gate == PWR || used.contains(gate) || !gate.is_enabled(rcc) {
+            continue;
+        }
+        gate.disable(rcc);
+        count += 1;
+    }
+    count
+}
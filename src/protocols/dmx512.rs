@@ -0,0 +1,316 @@
+//! DMX512 transmitter
+//!
+//! Sends a universe (start code 0 and 512 slots) over and over on USART6 at
+//! 250 kbaud, 8N2, through an RS-485 transceiver. The break and the mark
+//! after break are timed by a general purpose timer that drives the TX pin
+//! as a GPIO in between frames; the slots go out through DMA2 stream 6:
+//!
+//! ``` ignore
+//! static mut FRAME: [u8; dmx512::FRAME] = [0; dmx512::FRAME];
+//!
+//! let tx = TypedPin::<GPIOC, N6, Input>::new().into_alternate(gpioc, AF8, Speed::High);
+//! let mut dmx = Dmx512::new(
+//!     Serial::new(usart6), &streams.s6, gpioc, tx, Timer::new(tim3), &clocks,
+//!     unsafe { &mut FRAME },
+//! )?;
+//! dmx.start();
+//!
+//! // TIM3 interrupt
+//! dmx.on_interrupt();
+//!
+//! // anywhere, e.g. a fader task
+//! dmx.set_channel(0, 255);
+//! ```
+//!
+//! `set_channel` writes a staging copy of the universe; the changes are
+//! copied into the frame buffer during the next break, so the DMA never
+//! sends a half updated frame. With the default 176 µs break and 16 µs mark
+//! after break a frame takes ~22.8 ms, ~44 frames per second.
+//!
+//! The timer ticks at 1 MHz, its clock must be a whole number of MHz. The
+//! transceiver's driver enable can be tied high, the port only transmits.
+
+use core::any::Any;
+use core::fmt;
+use core::ops::Deref;
+
+use stm32f411::{gpioa, DMA2, USART6};
+
+use dma2::{DMA, DMAStream, Dma};
+use gpio::{AF8, Alternate, Io, Mode, Pin, PinNumber, TypedPin};
+use rcc::{ClockError, Clocks};
+use serial::{self, Serial};
+use time::{Microseconds, U32Ext};
+use timer::{Event, TIM, TIMBase, Timer};
+
+/// Slots of a universe
+pub const SLOTS: usize = 512;
+/// Length of a frame: the start code and the slots
+pub const FRAME: usize = SLOTS + 1;
+
+/// Bit rate fixed by the standard
+const BAUD_RATE: u32 = 250_000;
+/// Duration of a slot, 11 bits at 250 kbaud
+const SLOT_US: u16 = 44;
+/// Shortest break and mark after break a receiver has to accept
+const BREAK_MIN_US: u32 = 88;
+const MAB_MIN_US: u32 = 8;
+
+/// DMX512 transmitter error
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The DMA handle isn't DMA2 stream 6, the one USART6_TX is wired to
+    WrongStream,
+    /// 250 kbaud or a 1 MHz timer tick can't be derived from the clocks
+    Clock(ClockError),
+    #[doc(hidden)]
+    _Extensible,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::WrongStream => f.write_str("USART6_TX requests are routed to DMA2 stream 6"),
+            Error::Clock(ref e) => e.fmt(f),
+            Error::_Extensible => f.write_str("unknown error"),
+        }
+    }
+}
+
+impl From<ClockError> for Error {
+    fn from(e: ClockError) -> Self {
+        Error::Clock(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Phase {
+    Stopped,
+    Break,
+    MarkAfterBreak,
+    Data,
+}
+
+/// DMX512 transmitter, see the module documentation
+pub struct Dmx512<'a, P, T, R>
+    where P: 'a + Deref<Target=gpioa::RegisterBlock>,
+          T: 'a + Any + TIM<R>,
+          R: TIMBase
+{
+    serial: Serial<'a, USART6>,
+    dma: &'a Dma<'a, DMA2>,
+    port: &'a P,
+    pin: Pin<P>,
+    timer: Timer<'a, T, R>,
+    /// Prescaler for a 1 MHz timer clock
+    psc: u16,
+    frame: &'static mut [u8; FRAME],
+    staging: [u8; FRAME],
+    dirty: bool,
+    phase: Phase,
+    break_us: u16,
+    mab_us: u16,
+    frames: u32,
+}
+
+impl<'a, P, T, R> Dmx512<'a, P, T, R>
+    where P: Deref<Target=gpioa::RegisterBlock>,
+          T: Any + TIM<R>,
+          R: TIMBase
+{
+    /// Sets up USART6, DMA2 stream 6 and `timer` for DMX512
+    ///
+    /// `pin` is the USART6 TX pin of `port` (PA11 or PC6), routed to USART6.
+    /// The line idles high (mark) until `start`.
+    /// Fails if `dma` isn't DMA2 stream 6, or if 250 kbaud or a 1 MHz timer
+    /// tick can't be derived from the clocks.
+    pub fn new<N>(
+        serial: Serial<'a, USART6>,
+        dma: &'a Dma<'a, DMA2>,
+        port: &'a P,
+        pin: TypedPin<P, N, Alternate<AF8>>,
+        timer: Timer<'a, T, R>,
+        clocks: &Clocks,
+        frame: &'static mut [u8; FRAME],
+    ) -> Result<Self, Error>
+        where N: PinNumber
+    {
+        match dma.stream() {
+            DMAStream::Stream6 => {}
+            _ => return Err(Error::WrongStream),
+        }
+
+        let timclk = T::timclk(clocks).0;
+        if timclk < 1_000_000 || timclk % 1_000_000 != 0 || timclk / 1_000_000 > 0x1_0000 {
+            return Err(Error::Clock(ClockError::FrequencyOutOfRange));
+        }
+        let psc = (timclk / 1_000_000 - 1) as u16;
+
+        serial.try_set_baud_rate(clocks, BAUD_RATE.hz())?;
+        let usart = serial.0;
+        // 2 stop bits
+        usart.cr2.modify(|r, w| unsafe { w.bits((r.bits() & !(0b11 << 12)) | (0b10 << 12)) });
+        // UE, TE, 8 data bits, no parity
+        usart.cr1.write(|w| unsafe { w.bits((1 << 13) | (1 << 3)) });
+        // DMAT
+        usart.cr3.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 7)) });
+
+        // CHSEL = 5, byte sized, MINC, memory to peripheral
+        dma.reg.scr(dma.stream()).write(|w| unsafe {
+            w.bits((serial::USART6_DMA_CHANNEL << 25) | (1 << 10) | (0b01 << 6))
+        });
+
+        let pin = pin.erase();
+        pin.set(port, Io::High);
+        pin.set_open_drain(port, false);
+
+        frame[0] = 0;
+        Ok(Dmx512 {
+            serial: serial,
+            dma: dma,
+            port: port,
+            pin: pin,
+            timer: timer,
+            psc: psc,
+            staging: *frame,
+            frame: frame,
+            dirty: false,
+            phase: Phase::Stopped,
+            break_us: 176,
+            mab_us: 16,
+            frames: 0,
+        })
+    }
+
+    /// Length of the break, at least 88 µs
+    pub fn break_time(mut self, time: Microseconds) -> Self {
+        assert!(time.0 >= BREAK_MIN_US && time.0 <= 0xFFFF);
+        self.break_us = time.0 as u16;
+        self
+    }
+
+    /// Length of the mark after break, at least 8 µs
+    ///
+    /// Some older fixtures miss the start code after a short mark, 16 µs or
+    /// more is safe.
+    pub fn mark_after_break(mut self, time: Microseconds) -> Self {
+        assert!(time.0 >= MAB_MIN_US && time.0 <= 0xFFFF);
+        self.mab_us = time.0 as u16;
+        self
+    }
+
+    /// Starts sending frames, the first one begins with a break right away
+    ///
+    /// The timer interrupt must be enabled in the NVIC and call
+    /// `on_interrupt`.
+    pub fn start(&mut self) {
+        if self.phase != Phase::Stopped {
+            return;
+        }
+        self.timer.listen(Event::Update);
+        self.begin_break();
+    }
+
+    /// Stops after the frame in progress, the line then idles high
+    pub fn stop(&mut self) {
+        while self.phase != Phase::Stopped && self.phase != Phase::Break {
+            self.on_interrupt();
+        }
+        self.timer.unlisten(Event::Update);
+        self.pin.set(self.port, Io::High);
+        self.phase = Phase::Stopped;
+    }
+
+    /// Sets slot `index` (0 is DMX channel 1) from the next frame on
+    pub fn set_channel(&mut self, index: usize, value: u8) {
+        assert!(index < SLOTS);
+        self.staging[index + 1] = value;
+        self.dirty = true;
+    }
+
+    /// Sets the slots from `index` on to `values`, all in the same frame
+    pub fn set_channels(&mut self, index: usize, values: &[u8]) {
+        assert!(index + values.len() <= SLOTS);
+        self.staging[index + 1..index + 1 + values.len()].copy_from_slice(values);
+        self.dirty = true;
+    }
+
+    /// Value of slot `index` as of the latest `set_channel`
+    pub fn channel(&self, index: usize) -> u8 {
+        self.staging[index + 1]
+    }
+
+    /// Frames sent since `new`
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// Advances the frame, call this from the timer interrupt
+    pub fn on_interrupt(&mut self) {
+        if !self.timer.is_pending(Event::Update) {
+            return;
+        }
+        self.timer.clear_interrupt(Event::Update);
+
+        match self.phase {
+            Phase::Stopped => {}
+            Phase::Break => {
+                self.pin.set(self.port, Io::High);
+                self.phase = Phase::MarkAfterBreak;
+                self.arm(self.mab_us);
+            }
+            Phase::MarkAfterBreak => {
+                self.pin.set_mode(self.port, Mode::AlternateFunction);
+                self.send_frame();
+                self.phase = Phase::Data;
+                self.arm(FRAME as u16 * SLOT_US);
+            }
+            Phase::Data => {
+                let usart = self.serial.0;
+                // TC: the stop bits of the last slot are out
+                let sent = self.dma.is_transfer_complete() && usart.sr.read().bits() & (1 << 6) != 0;
+                if sent || self.dma.has_transfer_error() {
+                    self.dma.disable();
+                    while self.dma.is_enabled() {}
+                    self.dma.clear_flags();
+                    self.frames = self.frames.wrapping_add(1);
+                    self.begin_break();
+                } else {
+                    self.arm(SLOT_US);
+                }
+            }
+        }
+    }
+
+    /// Pulls the line low and publishes the staged slots
+    fn begin_break(&mut self) {
+        self.pin.set(self.port, Io::Low);
+        self.pin.set_mode(self.port, Mode::Output);
+        self.phase = Phase::Break;
+        self.arm(self.break_us);
+
+        if self.dirty {
+            self.frame.copy_from_slice(&self.staging);
+            self.dirty = false;
+        }
+    }
+
+    fn send_frame(&self) {
+        let usart = self.serial.0;
+        // TC is rc_w0, the other flags ignore the write
+        usart.sr.write(|w| unsafe { w.bits(!(1 << 6)) });
+
+        self.dma.clear_flags();
+        self.dma.set_config(
+            self.frame.as_ptr() as u32,
+            &usart.dr as *const _ as u32,
+            FRAME as u16,
+        );
+        self.dma.enable();
+    }
+
+    /// Raises the next update event `us` from now
+    fn arm(&self, us: u16) {
+        self.timer.0.start_with(self.psc, us - 1);
+    }
+}
@@ -0,0 +1,274 @@
+//! Framed transport over a serial link
+//!
+//! The F411 has no CAN or Ethernet, a UART is usually the link to a PC or
+//! another MCU. This layer turns the byte stream into packets: each payload
+//! gets a CRC-16 appended and is COBS encoded, so a frame never contains a
+//! zero byte and a single `0x00` ends it. A receiver that starts mid-stream
+//! or loses bytes resynchronizes at the next delimiter, and corrupted frames
+//! are caught by the CRC.
+//!
+//! ``` ignore
+//! let (tx, rx) = Serial::new(usart2).split();
+//!
+//! framed::send_frame(&tx, b"ping")?;
+//!
+//! // payloads up to 64 bytes, and their CRC
+//! static mut RX: [u8; 66] = [0; 66];
+//! let mut decoder = Decoder::new(unsafe { &mut RX });
+//! loop {
+//!     let byte = block!(hal::serial::Read::read(&rx))?;
+//!     match decoder.push(byte) {
+//!         Some(Ok(payload)) => handle(payload),
+//!         Some(Err(e)) => { /* the frame is dropped */ }
+//!         None => {}
+//!     }
+//! }
+//! ```
+//!
+//! `send_frame` works with anything that implements the `embedded-hal`
+//! serial `Write`; to send through DMA instead (e.g. `write_dma`), `encode`
+//! the frame into a buffer first.
+//!
+//! The CRC is CRC-16/CCITT-FALSE (polynomial `0x1021`, initial value
+//! `0xFFFF`) of the payload, sent MSB first. On the PC side the frame is
+//! `cobs.encode(payload + crc.to_bytes(2, 'big')) + b'\0'`.
+
+use core::fmt;
+
+use hal;
+
+/// Ends every frame, never appears inside one
+pub const DELIMITER: u8 = 0x00;
+
+/// Length of the CRC that trails the payload
+const CRC_LEN: usize = 2;
+/// Longest run of non-zero bytes a COBS code covers
+const RUN: usize = 254;
+
+/// Framing error, the frame is dropped
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The frame doesn't fit the buffer
+    TooLong,
+    /// The frame is shorter than its CRC
+    TooShort,
+    /// A COBS code points past the end of the frame
+    Encoding,
+    /// CRC mismatch
+    Crc,
+    #[doc(hidden)]
+    _Extensible,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Error::TooLong => "frame too long",
+            Error::TooShort => "frame too short",
+            Error::Encoding => "invalid COBS encoding",
+            Error::Crc => "frame CRC mismatch",
+            Error::_Extensible => "unknown error",
+        })
+    }
+}
+
+/// Longest encoded frame, delimiter included, for a `len` byte payload
+pub const fn max_encoded_len(len: usize) -> usize {
+    // One COBS code per started run of 254 bytes
+    len + CRC_LEN + (len + CRC_LEN) / RUN + 1 + 1
+}
+
+/// CRC-16/CCITT-FALSE of `data`
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for byte in data {
+        crc ^= u16::from(*byte) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+/// Encodes `payload` into `buffer`, returns the length of the frame
+///
+/// The frame ends with the delimiter. `buffer` needs
+/// `max_encoded_len(payload.len())` bytes at worst.
+pub fn encode(payload: &[u8], buffer: &mut [u8]) -> Result<usize, Error> {
+    let mut len = 0;
+    encode_with(payload, |byte| {
+        if len == buffer.len() {
+            return Err(Error::TooLong);
+        }
+        buffer[len] = byte;
+        len += 1;
+        Ok(())
+    })?;
+    Ok(len)
+}
+
+/// Sends `payload` as a frame, blocking until the last byte is written
+///
+/// The frame is encoded on the fly, no buffer is needed.
+pub fn send_frame<S>(serial: &S, payload: &[u8]) -> Result<(), S::Error>
+    where S: hal::serial::Write<u8>
+{
+    encode_with(payload, |byte| block!(serial.write(byte)))
+}
+
+/// Encodes `payload` and its CRC, passing the frame to `emit` byte by byte
+fn encode_with<E, F>(payload: &[u8], mut emit: F) -> Result<(), E>
+    where F: FnMut(u8) -> Result<(), E>
+{
+    let crc = crc16(payload);
+    let trailer = [(crc >> 8) as u8, crc as u8];
+    let len = payload.len() + CRC_LEN;
+    let byte = |i: usize| if i < payload.len() {
+        payload[i]
+    } else {
+        trailer[i - payload.len()]
+    };
+
+    let mut start = 0;
+    loop {
+        let mut end = start;
+        while end < len && end - start < RUN && byte(end) != 0 {
+            end += 1;
+        }
+
+        emit((end - start + 1) as u8)?;
+        for i in start..end {
+            emit(byte(i))?;
+        }
+
+        if end == len {
+            break;
+        }
+        // A full run isn't followed by an implied zero
+        start = if end - start == RUN { end } else { end + 1 };
+    }
+
+    emit(DELIMITER)
+}
+
+/// Gathers received bytes into frames
+pub struct Decoder<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+    /// Bytes left in the current COBS run
+    remaining: u8,
+    /// The current run ends with an implied zero, unless it is the last
+    zero: bool,
+    /// Bytes were received since the last delimiter
+    started: bool,
+    /// The frame overflowed `buffer`
+    overflow: bool,
+    /// Bytes up to the next delimiter are dropped
+    skip: bool,
+}
+
+impl<'a> Decoder<'a> {
+    /// Decoder for payloads up to `buffer.len() - 2` bytes long
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        assert!(buffer.len() > CRC_LEN);
+
+        Decoder {
+            buffer: buffer,
+            len: 0,
+            remaining: 0,
+            zero: false,
+            started: false,
+            overflow: false,
+            skip: false,
+        }
+    }
+
+    /// Adds a received byte, returns the payload of the frame it completes
+    ///
+    /// Bytes up to the first delimiter may be the tail of a frame that was
+    /// on the line before the decoder started; they fail with an error that
+    /// is best ignored. Back to back delimiters are skipped.
+    pub fn push(&mut self, byte: u8) -> Option<Result<&[u8], Error>> {
+        if byte == DELIMITER {
+            return self.end();
+        }
+        if self.skip {
+            return None;
+        }
+        self.started = true;
+
+        if self.remaining == 0 {
+            // COBS code
+            if self.zero {
+                self.store(0);
+            }
+            self.remaining = byte - 1;
+            self.zero = byte != 0xFF;
+        } else {
+            self.store(byte);
+            self.remaining -= 1;
+        }
+        None
+    }
+
+    /// Drops the frame in progress, bytes up to the next delimiter are
+    /// skipped
+    ///
+    /// Call this on a receive error (framing, noise, overrun), the frame in
+    /// progress is missing bytes.
+    pub fn reset(&mut self) {
+        self.clear();
+        self.skip = true;
+    }
+
+    fn store(&mut self, byte: u8) {
+        if self.len == self.buffer.len() {
+            self.overflow = true;
+        } else {
+            self.buffer[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn end(&mut self) -> Option<Result<&[u8], Error>> {
+        let len = self.len;
+        let started = self.started;
+        let overflow = self.overflow;
+        let encoding = self.remaining != 0;
+        let skip = self.skip;
+        self.clear();
+
+        if !started || skip {
+            return None;
+        }
+        if overflow {
+            return Some(Err(Error::TooLong));
+        }
+        if encoding {
+            return Some(Err(Error::Encoding));
+        }
+        if len < CRC_LEN {
+            return Some(Err(Error::TooShort));
+        }
+
+        let payload = len - CRC_LEN;
+        let crc = (u16::from(self.buffer[payload]) << 8) | u16::from(self.buffer[payload + 1]);
+        if crc != crc16(&self.buffer[..payload]) {
+            return Some(Err(Error::Crc));
+        }
+        Some(Ok(&self.buffer[..payload]))
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+        self.remaining = 0;
+        self.zero = false;
+        self.started = false;
+        self.overflow = false;
+        self.skip = false;
+    }
+}
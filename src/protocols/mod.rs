@@ -0,0 +1,7 @@
+//! Wire protocols built on the peripheral drivers
+
+#[cfg(all(feature = "usart", feature = "dma"))]
+pub mod dmx512;
+pub mod framed;
+pub mod ppm;
+pub mod sbus;
@@ -0,0 +1,261 @@
+//! PPM sum signal
+//!
+//! A PPM stream carries up to 8 RC channels on one wire: a short pulse
+//! starts each channel, the time from one pulse to the next is the channel
+//! value (~1000 - 2000 µs), and a gap longer than any channel ends the
+//! frame. Both sides run on a `MicroTimer`.
+//!
+//! `Decoder` timestamps the leading edges with an input capture:
+//!
+//! ``` ignore
+//! let timer = Timer::new(tim3).into_microsecond_base(&clocks)?;
+//! timer.enable_capture(Channel::_1);
+//! timer.listen(Event::Cc1);
+//!
+//! // TIM3 interrupt
+//! if let Some(frame) = decoder.on_capture(&timer, Channel::_1) {
+//!     let throttle = frame.channel(2);
+//! }
+//! ```
+//!
+//! `Encoder` generates a stream with PWM: one timer period per channel,
+//! retimed from the update interrupt, and a final period for the sync gap:
+//!
+//! ``` ignore
+//! let timer = Timer::new(tim4).into_microsecond_base(&clocks)?;
+//! let mut encoder = Encoder::new(timer, Channel::_1, 8);
+//! encoder.start();
+//!
+//! // TIM4 interrupt
+//! encoder.on_interrupt();
+//!
+//! encoder.set_channel(0, Microseconds(1_500));
+//! ```
+//!
+//! The encoder outputs positive pulses (high for 300 µs, low in between);
+//! receivers that expect the other polarity need an inverter.
+
+use core::any::Any;
+
+use time::Microseconds;
+use timer::{Channel, Event, MicroTimer, TIM, TIMBase};
+
+/// Channels of a frame at most
+pub const MAX_CHANNELS: usize = 8;
+
+/// Shortest and longest valid channel, wider than the 1000 - 2000 µs RC
+/// range to allow for trims
+const MIN_US: u16 = 700;
+const MAX_US: u16 = 2300;
+/// Interval that marks the end of a frame by default
+const SYNC_US: u16 = 3000;
+/// Pulse that starts each channel of the encoder
+const PULSE_US: u32 = 300;
+/// Shortest sync gap of the encoder
+const ENCODER_SYNC_US: u32 = 4000;
+
+/// Channel values of a PPM frame
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Frame {
+    channels: [u16; MAX_CHANNELS],
+    len: u8,
+}
+
+impl Frame {
+    /// Number of channels in the frame
+    pub fn len(&self) -> usize {
+        usize::from(self.len)
+    }
+
+    /// Width of `channel`, in µs
+    pub fn channel(&self, channel: usize) -> u16 {
+        self.channels[..self.len()][channel]
+    }
+
+    /// Channel widths, in µs
+    pub fn channels(&self) -> &[u16] {
+        &self.channels[..self.len()]
+    }
+}
+
+/// PPM decoder fed with the timestamps of the channel pulses
+pub struct Decoder {
+    frame: Frame,
+    /// Timestamp of the previous edge, `None` before the first one
+    last: Option<u16>,
+    /// The frame in progress follows a sync gap and is still valid
+    synced: bool,
+    sync_us: u16,
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Decoder {
+            frame: Frame { channels: [0; MAX_CHANNELS], len: 0 },
+            last: None,
+            synced: false,
+            sync_us: SYNC_US,
+        }
+    }
+
+    /// Shortest interval taken as the end of a frame, 3 ms by default
+    pub fn sync_gap(mut self, gap: Microseconds) -> Self {
+        assert!(gap.0 > u32::from(MAX_US) && gap.0 <= 0xFFFF);
+        self.sync_us = gap.0 as u16;
+        self
+    }
+
+    /// Handles the leading edge of a pulse at `timestamp`
+    ///
+    /// Returns the frame the edge completes, when the edge ends a sync gap
+    /// after a full, valid frame. Timestamps may wrap at 16 bits, as the
+    /// captures of a `MicroTimer` do.
+    pub fn on_edge(&mut self, timestamp: Microseconds) -> Option<Frame> {
+        let now = timestamp.0 as u16;
+        let last = match self.last {
+            Some(last) => last,
+            None => {
+                self.last = Some(now);
+                return None;
+            }
+        };
+        self.last = Some(now);
+        let width = now.wrapping_sub(last);
+
+        if width >= self.sync_us {
+            let complete = self.synced && self.frame.len != 0;
+            let frame = self.frame;
+            self.frame.len = 0;
+            self.synced = true;
+            return if complete { Some(frame) } else { None };
+        }
+
+        if !self.synced {
+            return None;
+        }
+        if width < MIN_US || width > MAX_US || self.frame.len() == MAX_CHANNELS {
+            // Glitch or too many channels: drop the frame, wait for the
+            // next sync gap
+            self.synced = false;
+            self.frame.len = 0;
+            return None;
+        }
+
+        self.frame.channels[self.frame.len()] = width;
+        self.frame.len += 1;
+        None
+    }
+
+    /// Reads the capture of `channel` and handles it, see `on_edge`
+    pub fn on_capture<T, R>(&mut self, timer: &MicroTimer<T, R>, channel: Channel)
+        -> Option<Frame>
+        where T: Any + TIM<R>, R: TIMBase
+    {
+        match timer.capture_us(channel) {
+            Ok(timestamp) => self.on_edge(timestamp),
+            Err(_) => None,
+        }
+    }
+}
+
+/// PPM generator on a PWM channel of a microsecond timer
+pub struct Encoder<'a, T, R>
+    where T: 'a + Any + TIM<R>,
+          R: TIMBase
+{
+    timer: MicroTimer<'a, T, R>,
+    channel: Channel,
+    widths: [u16; MAX_CHANNELS],
+    len: usize,
+    frame_us: u32,
+    /// Slot whose period is loaded at the next update, `len` is the sync
+    next: usize,
+}
+
+impl<'a, T, R> Encoder<'a, T, R>
+    where T: Any + TIM<R>,
+          R: TIMBase
+{
+    /// `len` channels on `channel` of `timer`, all centered (1500 µs), in
+    /// 22.5 ms frames
+    ///
+    /// The timer's pin for `channel` must be in its alternate function.
+    pub fn new(timer: MicroTimer<'a, T, R>, channel: Channel, len: usize) -> Self {
+        assert!(len != 0 && len <= MAX_CHANNELS);
+
+        Encoder {
+            timer: timer,
+            channel: channel,
+            widths: [1500; MAX_CHANNELS],
+            len: len,
+            frame_us: 22_500,
+            next: 0,
+        }
+    }
+
+    /// Length of a frame, channels and sync gap
+    ///
+    /// The sync gap is at least 4 ms; with long channels the frame gets
+    /// longer than this.
+    pub fn frame_length(mut self, length: Microseconds) -> Self {
+        self.frame_us = length.0;
+        self
+    }
+
+    /// Starts the stream
+    ///
+    /// The timer's update interrupt must be enabled in the NVIC and call
+    /// `on_interrupt`. The first frame starts at the end of the timer's
+    /// current period.
+    pub fn start(&mut self) {
+        self.timer.set_pulse(self.channel, Microseconds(PULSE_US));
+        self.next = 0;
+        self.load_next();
+        self.timer.clear_interrupt(Event::Update);
+        self.timer.listen(Event::Update);
+    }
+
+    /// Stops the stream, the output stays low
+    pub fn stop(&mut self) {
+        self.timer.unlisten(Event::Update);
+        self.timer.set_pulse(self.channel, Microseconds(0));
+    }
+
+    /// Sets `channel` to `width` from its next slot on
+    pub fn set_channel(&mut self, channel: usize, width: Microseconds) {
+        assert!(channel < self.len);
+        assert!(width.0 >= u32::from(MIN_US) && width.0 <= u32::from(MAX_US));
+        self.widths[channel] = width.0 as u16;
+    }
+
+    /// Loads the next slot, call this from the timer's update interrupt
+    pub fn on_interrupt(&mut self) {
+        if !self.timer.is_pending(Event::Update) {
+            return;
+        }
+        self.timer.clear_interrupt(Event::Update);
+        self.load_next();
+    }
+
+    /// Preloads the period of slot `next`, it takes effect at the next
+    /// update
+    fn load_next(&mut self) {
+        let period = if self.next < self.len {
+            u32::from(self.widths[self.next])
+        } else {
+            let channels: u32 = self.widths[..self.len].iter().map(|w| u32::from(*w)).sum();
+            let sync = self.frame_us.saturating_sub(channels);
+            if sync < ENCODER_SYNC_US {
+                ENCODER_SYNC_US
+            } else if sync > 0xFFFF {
+                0xFFFF
+            } else {
+                sync
+            }
+        };
+
+        // NOTE the periods are within the 2 µs - 65.536 ms range
+        let _ = self.timer.set_pwm_period(Microseconds(period));
+        self.next = if self.next < self.len { self.next + 1 } else { 0 };
+    }
+}
@@ -0,0 +1,400 @@
+//! SBUS receiver
+//!
+//! SBUS is the serial link of Futaba-style RC receivers: 25 byte frames at
+//! 100000 baud, 8 data bits, even parity and 2 stop bits, every 7 or 14 ms.
+//! A frame holds 16 proportional channels of 11 bits, 2 digital channels
+//! and the frame lost / failsafe flags.
+//!
+//! The line is inverted (idle low) and the USARTs of the F411 can't invert
+//! their input, so `Receiver` needs an inverter in front of the RX pin, one
+//! transistor or a 74LVC1G04:
+//!
+//! ``` ignore
+//! serial::usart6_pins_pc(gpioc);
+//! let mut sbus = Receiver::new(Serial::new(usart6), &clocks)?;
+//! Serial::new(usart6).listen(serial::Event::Rxne);
+//!
+//! // USART6 interrupt
+//! match sbus.read() {
+//!     Ok(frame) => if !frame.failsafe { mixer.update(frame.channels) },
+//!     Err(nb::Error::WouldBlock) => {}
+//!     Err(nb::Error::Other(e)) => { /* the partial frame is dropped */ }
+//! }
+//! ```
+//!
+//! Without an inverter `EdgeDecoder` decodes the line in software from the
+//! timestamps of its edges. Route the signal to a timer channel, capture
+//! both edges at 1 µs resolution and pass every capture along with the pin
+//! level after the edge:
+//!
+//! ``` ignore
+//! let timer = Timer::new(tim3).into_microsecond_base(&clocks)?;
+//! timer.enable_capture_both_edges(Channel::_1);
+//! timer.listen(Event::Cc1);
+//! let ch1 = TypedPin::<GPIOA, N6, Input>::new().into_alternate(gpioa, AF2, Speed::Low);
+//! let mut decoder = EdgeDecoder::new();
+//!
+//! // TIM3 interrupt
+//! if let Ok(timestamp) = timer.capture_us(Channel::_1) {
+//!     if let Some(Ok(frame)) = decoder.on_edge(timestamp, ch1.get(gpioa)) {
+//!         ..
+//!     }
+//! }
+//! ```
+//!
+//! The capture interrupt has to be served within a bit time (10 µs), so it
+//! needs a high priority; an edge every 10 µs at worst is ~100k interrupts
+//! per second while a frame is on the line.
+
+use core::fmt;
+
+use gpio::Io;
+use time::Microseconds;
+
+#[cfg(feature = "usart")]
+use core::any::Any;
+#[cfg(feature = "usart")]
+use core::ptr;
+#[cfg(feature = "usart")]
+use nb;
+#[cfg(feature = "usart")]
+use rcc::{ClockError, Clocks};
+#[cfg(feature = "usart")]
+use serial::{Serial, Usart};
+#[cfg(feature = "usart")]
+use time::U32Ext;
+
+/// Length of a frame
+pub const FRAME_LEN: usize = 25;
+/// Proportional channels of a frame
+pub const CHANNELS: usize = 16;
+
+const HEADER: u8 = 0x0F;
+#[cfg(feature = "usart")]
+const BAUD_RATE: u32 = 100_000;
+/// Bit time at 100000 baud
+const BIT_US: u32 = 10;
+/// Start bit, 8 data bits, parity, 2 stop bits
+const CHARACTER_BITS: u8 = 12;
+
+/// SBUS error, the frame in progress is dropped
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The frame didn't end with a valid footer
+    Sync,
+    /// Parity error
+    Parity,
+    /// Missing stop bits
+    Framing,
+    /// Noise detected on the line
+    Noise,
+    /// RX buffer overrun
+    Overrun,
+    #[doc(hidden)]
+    _Extensible,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Error::Sync => "SBUS frame out of sync",
+            Error::Parity => "SBUS parity error",
+            Error::Framing => "SBUS framing error",
+            Error::Noise => "SBUS noise error",
+            Error::Overrun => "SBUS RX overrun",
+            Error::_Extensible => "unknown error",
+        })
+    }
+}
+
+/// Decoded frame
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Frame {
+    /// Proportional channels, 11 bits; 172 - 1811 on most transmitters
+    pub channels: [u16; CHANNELS],
+    /// Digital channel 17
+    pub ch17: bool,
+    /// Digital channel 18
+    pub ch18: bool,
+    /// The receiver missed the transmitter's last frame
+    pub frame_lost: bool,
+    /// The receiver lost the link and sends its failsafe values
+    pub failsafe: bool,
+}
+
+impl Frame {
+    /// `channel` as the width of an RC servo pulse, 172 - 1811 maps to
+    /// 988 - 2012 µs
+    pub fn pulse(&self, channel: usize) -> Microseconds {
+        Microseconds(u32::from(self.channels[channel]) * 5 / 8 + 880)
+    }
+}
+
+/// Decodes a frame
+pub fn parse(bytes: &[u8; FRAME_LEN]) -> Result<Frame, Error> {
+    // NOTE SBUS2 receivers end frames with 0x04, 0x14, 0x24 or 0x34
+    let footer = bytes[FRAME_LEN - 1];
+    if bytes[0] != HEADER || (footer != 0x00 && footer & 0x0F != 0x04) {
+        return Err(Error::Sync);
+    }
+
+    // 16 x 11 bits, LSB first
+    let mut channels = [0; CHANNELS];
+    let mut bits = 0u32;
+    let mut count = 0;
+    let mut data = bytes[1..23].iter();
+    for channel in channels.iter_mut() {
+        while count < 11 {
+            bits |= u32::from(*data.next().unwrap()) << count;
+            count += 8;
+        }
+        *channel = (bits & 0x7FF) as u16;
+        bits >>= 11;
+        count -= 11;
+    }
+
+    let flags = bytes[23];
+    Ok(Frame {
+        channels: channels,
+        ch17: flags & (1 << 0) != 0,
+        ch18: flags & (1 << 1) != 0,
+        frame_lost: flags & (1 << 2) != 0,
+        failsafe: flags & (1 << 3) != 0,
+    })
+}
+
+/// Gathers received bytes into frames
+pub struct Decoder {
+    buffer: [u8; FRAME_LEN],
+    len: usize,
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Decoder { buffer: [0; FRAME_LEN], len: 0 }
+    }
+
+    /// Adds a received byte, returns the frame it completes
+    ///
+    /// Bytes before a header are skipped. A data byte that looks like a
+    /// header can make the decoder start mid-frame, the footer check then
+    /// fails with `Error::Sync`; call `reset` on the gap between two frames
+    /// (an idle line) to lock on right away.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Frame, Error>> {
+        if self.len == 0 && byte != HEADER {
+            return None;
+        }
+
+        self.buffer[self.len] = byte;
+        self.len += 1;
+        if self.len < FRAME_LEN {
+            return None;
+        }
+
+        self.len = 0;
+        Some(parse(&self.buffer))
+    }
+
+    /// Drops the frame in progress
+    pub fn reset(&mut self) {
+        self.len = 0;
+    }
+}
+
+/// SBUS receiver on a USART, behind an inverter
+#[cfg(feature = "usart")]
+pub struct Receiver<'a, U>
+    where U: 'a + Any + Usart
+{
+    serial: Serial<'a, U>,
+    decoder: Decoder,
+}
+
+#[cfg(feature = "usart")]
+impl<'a, U> Receiver<'a, U>
+    where U: Any + Usart
+{
+    /// Configures the USART for 100000 baud 8E2, receive only
+    ///
+    /// Also enables the IDLE interrupt, which marks the end of each frame;
+    /// `read` handles it.
+    pub fn new(serial: Serial<'a, U>, clocks: &Clocks) -> Result<Self, ClockError> {
+        serial.try_set_baud_rate(clocks, BAUD_RATE.hz())?;
+        let usart = serial.0;
+        // 2 stop bits
+        usart.cr2.modify(|r, w| unsafe { w.bits((r.bits() & !(0b11 << 12)) | (0b10 << 12)) });
+        // UE, M (8 data bits + parity), PCE, even parity, IDLEIE, RE
+        usart.cr1.modify(|r, w| unsafe {
+            w.bits((r.bits() & !(1 << 9)) | (1 << 13) | (1 << 12) | (1 << 10) | (1 << 4) | (1 << 2))
+        });
+
+        Ok(Receiver { serial: serial, decoder: Decoder::new() })
+    }
+
+    /// Reads the pending byte, returns the frame it completes
+    ///
+    /// Call this from the USART interrupt (RXNE, IDLE), or poll it. The gap
+    /// between two frames resynchronizes the decoder.
+    pub fn read(&mut self) -> nb::Result<Frame, Error> {
+        let usart = self.serial.0;
+        let sr = usart.sr.read().bits();
+
+        // PE, FE, NF, ORE, IDLE are cleared by reading SR then DR
+        let error = if sr & (1 << 3) != 0 {
+            Some(Error::Overrun)
+        } else if sr & (1 << 2) != 0 {
+            Some(Error::Noise)
+        } else if sr & (1 << 1) != 0 {
+            Some(Error::Framing)
+        } else if sr & (1 << 0) != 0 {
+            Some(Error::Parity)
+        } else {
+            None
+        };
+        // NOTE the data bits, DR bit 8 is the parity bit
+        let byte = if sr & ((1 << 5) | (1 << 4)) != 0 || error.is_some() {
+            unsafe { ptr::read_volatile(&usart.dr as *const _ as *const u8) }
+        } else {
+            return Err(nb::Error::WouldBlock);
+        };
+
+        if let Some(error) = error {
+            self.decoder.reset();
+            return Err(nb::Error::Other(error));
+        }
+
+        let result = if sr & (1 << 5) != 0 { self.decoder.push(byte) } else { None };
+        // NOTE after the byte: with a late interrupt IDLE and the last byte
+        // of the frame are pending together
+        if sr & (1 << 4) != 0 {
+            self.decoder.reset();
+        }
+
+        match result {
+            Some(Ok(frame)) => Ok(frame),
+            Some(Err(e)) => Err(nb::Error::Other(e)),
+            None => Err(nb::Error::WouldBlock),
+        }
+    }
+
+    /// Gives the USART back, still configured for SBUS
+    pub fn free(self) -> Serial<'a, U> {
+        self.serial
+    }
+}
+
+/// Software SBUS decoder fed with the edges of the (inverted) line
+///
+/// Timestamps are in µs and may wrap at 16 bits, as the captures of a
+/// `MicroTimer` do.
+pub struct EdgeDecoder {
+    decoder: Decoder,
+    /// Timestamp of the last edge
+    last: u16,
+    /// UART level since the last edge, the line inverted
+    mark: bool,
+    /// Bits of the character in progress, LSB first
+    bits: u16,
+    /// Bits received of the character in progress, 0 while idle
+    count: u8,
+}
+
+impl EdgeDecoder {
+    pub const fn new() -> Self {
+        EdgeDecoder {
+            decoder: Decoder::new(),
+            last: 0,
+            mark: true,
+            bits: 0,
+            count: 0,
+        }
+    }
+
+    /// Handles an edge at `timestamp`, the line being at `level` after it
+    ///
+    /// Returns the frame completed by the bits before the edge. The last
+    /// character of a frame is only complete once its stop bits are over:
+    /// without a later edge, `idle` finishes it.
+    pub fn on_edge(&mut self, timestamp: Microseconds, level: Io) -> Option<Result<Frame, Error>> {
+        let now = timestamp.0 as u16;
+        let elapsed = u32::from(now.wrapping_sub(self.last));
+        let run = (elapsed + BIT_US / 2) / BIT_US;
+        // NOTE longer runs are idle time, two characters are plenty
+        let run = if run > 2 * u32::from(CHARACTER_BITS) { 2 * u32::from(CHARACTER_BITS) } else { run };
+
+        let mark = self.mark;
+        let mut result = None;
+        for _ in 0..run {
+            if let Some(r) = self.bit(mark) {
+                result = Some(r);
+            }
+        }
+
+        self.last = now;
+        // Inverted line: high is a space (0)
+        self.mark = match level {
+            Io::High => false,
+            Io::Low => true,
+        };
+        result
+    }
+
+    /// Finishes the character in progress if its stop bits are over at
+    /// `now`
+    ///
+    /// Call this periodically, e.g. every few ms, to get frames without
+    /// waiting for the first edge of the next one.
+    pub fn idle(&mut self, now: Microseconds) -> Option<Result<Frame, Error>> {
+        if !self.mark || self.count == 0 {
+            return None;
+        }
+
+        let remaining = CHARACTER_BITS - self.count;
+        let elapsed = u32::from((now.0 as u16).wrapping_sub(self.last));
+        if elapsed < u32::from(remaining) * BIT_US {
+            return None;
+        }
+
+        self.last = self.last.wrapping_add(remaining as u16 * BIT_US as u16);
+        let mut result = None;
+        for _ in 0..remaining {
+            if let Some(r) = self.bit(true) {
+                result = Some(r);
+            }
+        }
+        result
+    }
+
+    /// Shifts in a bit of the UART frame
+    fn bit(&mut self, mark: bool) -> Option<Result<Frame, Error>> {
+        if self.count == 0 {
+            // Waiting for a start bit
+            if !mark {
+                self.bits = 0;
+                self.count = 1;
+            }
+            return None;
+        }
+
+        if mark {
+            self.bits |= 1 << self.count;
+        }
+        self.count += 1;
+        if self.count < CHARACTER_BITS {
+            return None;
+        }
+        self.count = 0;
+
+        let byte = (self.bits >> 1) as u8;
+        let parity = (self.bits >> 9) & 1;
+        if (self.bits >> 10) & 0b11 != 0b11 {
+            self.decoder.reset();
+            Some(Err(Error::Framing))
+        } else if (byte.count_ones() + u32::from(parity)) % 2 != 0 {
+            self.decoder.reset();
+            Some(Err(Error::Parity))
+        } else {
+            self.decoder.push(byte)
+        }
+    }
+}
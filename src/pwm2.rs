@@ -1,27 +1,158 @@
 use core::any::{Any, TypeId};
 use core::marker::Unsize;
 
-use cast::{u16, u32};
+use cast::u32;
 use hal;
-use stm32f411::{GPIOA, RCC, TIM1};
+use nb;
+use stm32f411::{GPIOA, RCC, TIM1, TIM3, TIM4};
 
-use timer::{Channel, TIM};
+use clock_switch::ClockListener;
+use rcc::{ClockError, Clocks};
+use time::Hertz;
+use timer::{Channel, TIM, TIMBase};
+
+const CHANNELS: [Channel; 4] = [Channel::_1, Channel::_2, Channel::_3, Channel::_4];
+
+/// Periods a single one pulse mode burst can cover, the limit of the 8-bit
+/// repetition counter
+const MAX_BURST: u32 = 256;
+
+// CR1
+const CR1_UDIS: u32 = 1 << 1;
+const CR1_URS: u32 = 1 << 2;
+
+// EGR
+const EGR_UG: u32 = 1 << 0;
+
+// Output compare modes (OCxM)
+const OC_FORCE_INACTIVE: u32 = 0b100;
+const OC_PWM2: u32 = 0b111;
+
+/// Pulse train in progress, see `Pwm::pulse_train`
+pub struct PulseTrain {
+    channel: Channel,
+    duty: u16,
+    remaining: u32,
+    burst: u32,
+}
+
+impl PulseTrain {
+    /// Periods not emitted yet, the current burst included
+    pub fn remaining(&self) -> u32 {
+        self.remaining
+    }
+}
+
+/// Duty cycles of several channels staged together, see `Pwm::group`
+pub struct PwmGroup<'a> {
+    tim: &'a TIM1,
+    duties: [Option<u16>; 4],
+}
+
+impl<'a> PwmGroup<'a> {
+    /// Stages the duty cycle of `channel`
+    pub fn duty(mut self, channel: Channel, duty: u16) -> Self {
+        self.duties[channel.index() as usize] = Some(duty);
+        self
+    }
+
+    /// Stages the duty cycle of `channel` as `num / denom`
+    ///
+    /// # Panics
+    ///
+    /// If `denom` is zero or `num` is greater than `denom`
+    pub fn duty_fraction(self, channel: Channel, num: u16, denom: u16) -> Self {
+        assert!(denom != 0 && num <= denom);
+
+        let period = u32(self.tim.arr.read().arr().bits()) + 1;
+        self.duty(channel, fraction_of(period, num, denom))
+    }
+
+    /// Latches the staged duty cycles together at the next update event
+    ///
+    /// The current period finishes with the old values, none of the
+    /// channels changes before the others.
+    pub fn apply(self) {
+        let tim1 = self.tim;
+
+        // UDIS: no update event, so no preload transfer, while the compare
+        // registers are half written
+        tim1.cr1.modify(|r, w| unsafe { w.bits(r.bits() | CR1_UDIS) });
+        self.write();
+        tim1.cr1.modify(|r, w| unsafe { w.bits(r.bits() & !CR1_UDIS) });
+    }
+
+    /// Applies the staged duty cycles right away
+    ///
+    /// Restarts the period: the one in progress is cut short. Doesn't raise
+    /// the update interrupt.
+    pub fn apply_now(self) {
+        let tim1 = self.tim;
+
+        self.write();
+        // URS keeps UG from raising the update interrupt
+        tim1.cr1.modify(|r, w| unsafe { w.bits(r.bits() | CR1_URS) });
+        tim1.egr.write(|w| unsafe { w.bits(EGR_UG) });
+        tim1.cr1.modify(|r, w| unsafe { w.bits(r.bits() & !CR1_URS) });
+    }
+
+    fn write(&self) {
+        let pwm = Pwm(self.tim);
+
+        for (duty, channel) in self.duties.iter().zip(CHANNELS.iter()) {
+            if let Some(duty) = *duty {
+                hal::Pwm::set_duty(&pwm, *channel, duty);
+            }
+        }
+    }
+}
 
 /// PWM driver
 pub struct Pwm<'a, T>(pub &'a T)
 where
     T: 'a;
 
+impl<'a, T> Pwm<'a, T> {
+    /// Runs `f` on the timer registers, for features this driver lacks
+    ///
+    /// Safe to change: the dead time, break and lock setup (BDTR, TIM1
+    /// only, before MOE is set), the output polarity bits of CCER, the
+    /// input capture setup of the channels not used for PWM, and the slave
+    /// mode controller (SMCR). PSC, ARR, the PWM channels' CCMRx fields and
+    /// CCRx belong to this driver, change them through its methods.
+    pub fn with_tim<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        f(self.0)
+    }
+}
+
 impl<'a> Pwm<'a, TIM1> {
     /// Initializes the PWM module
+    ///
+    /// # Panics
+    ///
+    /// If `period` can't be represented, see `try_init`
     pub fn init<P>(&self, period: P)
     where
         P: Into<::apb2::Ticks>,
     {
-        self._init(period.into())
+        self.try_init(period).unwrap()
+    }
+
+    /// Like `init` but returns an error, and leaves the timer untouched, if
+    /// `period` can't be represented
+    pub fn try_init<P>(&self, period: P) -> Result<(), ClockError>
+    where
+        P: Into<::apb2::Ticks>,
+    {
+        let (psc, arr) = psc_arr(period.into())?;
+        self._init(psc, arr);
+        Ok(())
     }
 
-    fn _init(&self, period: ::apb2::Ticks) {
+    fn _init(&self, psc: u16, arr: u16) {
         let tim1 = self.0;
 
         // PWM mode 1
@@ -48,7 +179,7 @@ impl<'a> Pwm<'a, TIM1> {
 
         tim1.bdtr.modify(|_, w| w.moe().set_bit());
 
-        self._set_period(period);
+        self._set_period(psc, arr);
 
         tim1.cr1.write(|w| unsafe {
             w.cms().bits(0b00)
@@ -58,15 +189,262 @@ impl<'a> Pwm<'a, TIM1> {
         });
     }
 
-    fn _set_period(&self, period: ::apb2::Ticks) {
-        let period = period.0;
+    /// Sets the duty cycle of `channel` in percent, values above 100 are
+    /// clamped
+    pub fn set_duty_percent(&self, channel: Channel, percent: u8) {
+        let percent = if percent > 100 { 100 } else { percent };
+        self.set_duty_fraction(channel, u16::from(percent), 100);
+    }
 
-        let psc = u16((period - 1) / (1 << 16)).unwrap();
-        self.0.psc.write(|w| unsafe{ w.psc().bits(psc) });
+    /// Sets the duty cycle of `channel` to `num / denom`
+    ///
+    /// # Panics
+    ///
+    /// If `denom` is zero or `num` is greater than `denom`
+    pub fn set_duty_fraction(&self, channel: Channel, num: u16, denom: u16) {
+        assert!(denom != 0 && num <= denom);
+
+        let period = u32(hal::Pwm::get_max_duty(self)) + 1;
+        hal::Pwm::set_duty(self, channel, fraction_of(period, num, denom));
+    }
+
+    /// Starts a multi-channel update
+    ///
+    /// Compare values are preloaded, but the update event can still fall
+    /// between two `set_duty` calls and latch half of them: a visible
+    /// flicker on RGB LEDs, a torque step on a motor. The channels staged in
+    /// the group change together:
+    ///
+    /// ``` ignore
+    /// pwm.group()
+    ///     .duty_fraction(Channel::_1, r, 255)
+    ///     .duty_fraction(Channel::_2, g, 255)
+    ///     .duty_fraction(Channel::_3, b, 255)
+    ///     .apply();
+    /// ```
+    pub fn group(&self) -> PwmGroup<'a> {
+        PwmGroup { tim: self.0, duties: [None; 4] }
+    }
+
+    /// Changes the PWM frequency, keeping the duty cycle ratio of every
+    /// channel
+    ///
+    /// Returns the achieved frequency
+    ///
+    /// # Panics
+    ///
+    /// If `freq` is zero or above the timer clock, see `try_set_frequency`
+    pub fn set_frequency(&self, clocks: &Clocks, freq: Hertz) -> Hertz {
+        self.try_set_frequency(clocks, freq).unwrap()
+    }
+
+    /// Like `set_frequency` but returns an error, and leaves the timer
+    /// untouched, if `freq` can't be generated
+    pub fn try_set_frequency(&self, clocks: &Clocks, freq: Hertz)
+        -> Result<Hertz, ClockError>
+    {
+        let timclk = clocks.timclk2().0;
+        if freq.0 == 0 || freq.0 > timclk {
+            return Err(ClockError::FrequencyOutOfRange);
+        }
+        let (psc, arr) = psc_arr(::apb2::Ticks(timclk / freq.0))?;
+
+        // A channel is active for CCR of the ARR + 1 counts of a period
+        let old_period = u64::from(hal::Pwm::get_max_duty(self)) + 1;
+        let mut duties = [0u64; 4];
+        for (duty, channel) in duties.iter_mut().zip(CHANNELS.iter()) {
+            *duty = u64::from(hal::Pwm::get_duty(self, *channel));
+        }
 
-        let arr = u16(period / u32(psc + 1)).unwrap();
+        self._set_period(psc, arr);
+
+        let new_period = u64::from(arr) + 1;
+        for (duty, channel) in duties.iter().zip(CHANNELS.iter()) {
+            let duty = duty * new_period / old_period;
+            let duty = if duty > 0xFFFF { 0xFFFF } else { duty };
+            hal::Pwm::set_duty(self, *channel, duty as u16);
+        }
+
+        Ok(Hertz(timclk / ((u32(psc) + 1) * (u32(arr) + 1))))
+    }
+
+    fn _set_period(&self, psc: u16, arr: u16) {
+        self.0.psc.write(|w| unsafe{ w.psc().bits(psc) });
         self.0.arr.write(|w| unsafe{ w.arr().bits(arr) });
     }
+
+    /// Emits exactly `count` PWM periods on `channel`, at the current
+    /// frequency and duty cycle, then stops the timer
+    ///
+    /// Uses one pulse mode and the repetition counter; trains longer than
+    /// 256 periods are split in bursts chained by `poll_pulse_train`, which
+    /// must be called until it returns `Ok`: from the TIM1 update interrupt
+    /// (`Timer::listen(Event::Update)`) for gapless chaining, or in a loop.
+    ///
+    /// During the train the active part of each period comes last, so the
+    /// output is inactive while the counter is stopped. Afterwards the output
+    /// is forced inactive; `init` goes back to continuous PWM. Every channel
+    /// of the timer is paused during the train.
+    ///
+    /// # Panics
+    ///
+    /// If `count` is zero
+    pub fn pulse_train(&self, channel: Channel, count: u32) -> PulseTrain {
+        assert!(count != 0);
+
+        let tim1 = self.0;
+        tim1.cr1.modify(|_, w| w.cen().clear_bit());
+
+        // Same active time, moved to the end of the period
+        let duty = hal::Pwm::get_duty(self, channel);
+        let max = u32(hal::Pwm::get_max_duty(self));
+        let delay = max + 1 - u32(duty).min(max + 1);
+        self.set_oc_mode(channel, OC_PWM2);
+        hal::Pwm::set_duty(self, channel, delay as u16);
+        hal::Pwm::enable(self, channel);
+
+        // OPM
+        tim1.cr1.modify(|_, w| w.opm().set_bit());
+
+        let mut train = PulseTrain {
+            channel: channel,
+            duty: duty,
+            remaining: count,
+            burst: 0,
+        };
+        self.start_burst(&mut train);
+        train
+    }
+
+    /// Advances `train`: starts the next burst when the current one is over
+    ///
+    /// Returns `Ok` once every period has been emitted. Clears the update
+    /// flag.
+    pub fn poll_pulse_train(&self, train: &mut PulseTrain) -> nb::Result<(), !> {
+        let tim1 = self.0;
+
+        if train.remaining == 0 {
+            return Ok(());
+        }
+        // The hardware clears CEN at the update event that ends the burst
+        if tim1.cr1.read().cen().bit_is_set() {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        tim1.sr.write(|w| unsafe { w.bits(!1) });
+        train.remaining -= train.burst;
+
+        if train.remaining == 0 {
+            self.set_oc_mode(train.channel, OC_FORCE_INACTIVE);
+            hal::Pwm::set_duty(self, train.channel, train.duty);
+            tim1.cr1.modify(|_, w| w.opm().clear_bit());
+            Ok(())
+        } else {
+            self.start_burst(train);
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
+    fn start_burst(&self, train: &mut PulseTrain) {
+        let tim1 = self.0;
+
+        train.burst = train.remaining.min(MAX_BURST);
+        tim1.rcr.write(|w| unsafe { w.bits(train.burst - 1) });
+
+        // UG loads RCR and the preloaded compare value; URS keeps it from
+        // raising the update interrupt
+        tim1.cr1.modify(|r, w| unsafe { w.bits(r.bits() | CR1_URS) });
+        tim1.egr.write(|w| unsafe { w.bits(EGR_UG) });
+        tim1.cr1.modify(|r, w| unsafe { w.bits(r.bits() & !CR1_URS) });
+
+        tim1.cr1.modify(|_, w| w.cen().set_bit());
+    }
+
+    fn set_oc_mode(&self, channel: Channel, mode: u32) {
+        let tim1 = self.0;
+
+        // OCxM sits at bits 4..7 and 12..15 of CCMRx
+        let shift = match channel {
+            Channel::_1 | Channel::_3 => 4,
+            Channel::_2 | Channel::_4 => 12,
+        };
+        let bits = |r: u32| (r & !(0b111 << shift)) | (mode << shift);
+        match channel {
+            Channel::_1 | Channel::_2 => {
+                tim1.ccmr1_output.modify(|r, w| unsafe { w.bits(bits(r.bits())) })
+            }
+            Channel::_3 | Channel::_4 => {
+                tim1.ccmr2_output.modify(|r, w| unsafe { w.bits(bits(r.bits())) })
+            }
+        }
+    }
+}
+
+/// Splits `period` into prescaler and auto-reload values, the counter
+/// period is `(PSC + 1) * (ARR + 1)`
+fn psc_arr(period: ::apb2::Ticks) -> Result<(u16, u16), ClockError> {
+    let period = period.0;
+    if period < 2 {
+        return Err(ClockError::FrequencyOutOfRange);
+    }
+
+    // Smallest prescaler that leaves at most 65536 counts per period
+    let psc = (period - 1) / (1 << 16);
+    let arr = period / (psc + 1) - 1;
+    Ok((psc as u16, arr as u16))
+}
+
+/// `num / denom` of `period` counts, as a compare value
+///
+/// # Panics
+///
+/// If `denom` is zero or `num` is greater than `denom`
+fn fraction_of(period: u32, num: u16, denom: u16) -> u16 {
+    assert!(denom != 0 && num <= denom);
+
+    let duty = period * u32(num) / u32(denom);
+    if duty > 0xFFFF { 0xFFFF } else { duty as u16 }
+}
+
+macro_rules! duty_helpers {
+    ($($TIM:ident),+) => {
+        $(
+            impl<'a> Pwm<'a, $TIM> {
+                /// Sets the duty cycle of `channel` in percent, values above
+                /// 100 are clamped
+                ///
+                /// The channel must already be in PWM mode, e.g. through
+                /// `timer::MicroTimer::set_pulse`
+                pub fn set_duty_percent(&self, channel: Channel, percent: u8) {
+                    let percent = if percent > 100 { 100 } else { percent };
+                    self.set_duty_fraction(channel, u16::from(percent), 100);
+                }
+
+                /// Sets the duty cycle of `channel` to `num / denom`
+                ///
+                /// # Panics
+                ///
+                /// If `denom` is zero or `num` is greater than `denom`
+                pub fn set_duty_fraction(&self, channel: Channel, num: u16, denom: u16) {
+                    let period = u32(self.0.arr_bits()) + 1;
+                    self.0.set_ccr(channel, fraction_of(period, num, denom));
+                }
+            }
+        )+
+    }
+}
+
+duty_helpers!(TIM3, TIM4);
+
+impl<'a> ClockListener for Pwm<'a, TIM1> {
+    /// Keeps the PWM frequency and the duty cycles, if the new timer clock
+    /// can still produce it
+    fn clocks_changed(&self, old: &Clocks, new: &Clocks) {
+        let period = (u64::from(self.0.psc.read().bits()) + 1) *
+            (u64::from(hal::Pwm::get_max_duty(self)) + 1);
+        let freq = u64::from(old.timclk2().0) / period;
+        let _ = self.try_set_frequency(new, Hertz(freq as u32));
+    }
 }
 
 impl<'a> hal::Pwm for Pwm<'a, TIM1> {
@@ -106,7 +484,8 @@ impl<'a> hal::Pwm for Pwm<'a, TIM1> {
     }
 
     fn get_period(&self) -> ::apb2::Ticks {
-        ::apb2::Ticks(u32(self.0.psc.read().bits() * self.0.arr.read().bits()))
+        let psc = self.0.psc.read().bits() + 1;
+        ::apb2::Ticks(psc.saturating_mul(u32(self.0.arr.read().bits()) + 1))
     }
 
     fn set_duty(&self, channel: Channel, duty: u16) {
@@ -124,6 +503,7 @@ impl<'a> hal::Pwm for Pwm<'a, TIM1> {
     where
         P: Into<::apb2::Ticks>,
     {
-        self._set_period(period.into())
+        let (psc, arr) = psc_arr(period.into()).unwrap();
+        self._set_period(psc, arr)
     }
 }
\ No newline at end of file
@@ -0,0 +1,245 @@
+//! Power control
+//!
+//! The regulator output (VOS) limits the AHB clock: the higher the scale the
+//! faster the core can run and the more it draws. `Cfgr::freeze` picks the
+//! lowest scale that supports the requested `hclk`; applications that want a
+//! different trade-off can pass one with `Cfgr::voltage_scale`, or set it
+//! here directly before the PLL is started.
+//!
+//! ``` ignore
+//! // 64 MHz at the lowest regulator output
+//! let clocks = Cfgr::new()
+//!     .sysclk(64_000_000.hz())
+//!     .voltage_scale(VoltageScale::Scale3)
+//!     .freeze(rcc, flash, pwr);
+//! ```
+//!
+//! NOTE Unlike the F42x / F43x parts the F411 has no over-drive mode, scale 1
+//! already covers the full 100 MHz.
+//!
+//! `stop` enters Stop mode, the deepest state that keeps the RAM and the
+//! registers; together with `rtc::LowPowerTicker` it makes the usual battery
+//! powered loop:
+//!
+//! ``` ignore
+//! ticker.start(1_000.ms())?;
+//! loop {
+//!     ticker.sleep(scb, pwr, rcc);
+//!     sample_sensor();
+//! }
+//! ```
+//!
+//! A pin can end Stop mode as well, `WakeupPin` does the whole setup:
+//!
+//! ``` ignore
+//! let button = WakeupPin::new(gpioa, Port::A, 0, Pupd::No, Edge::Rising,
+//!                             WakeOn::Event, exti, syscfg, rcc);
+//! loop {
+//!     button.stop(scb, pwr, rcc, exti, Regulator::LowPower);
+//!     handle_press();
+//! }
+//! ```
+
+use core::ops::Deref;
+
+use cortex_m::asm;
+use stm32f411::{gpioa, EXTI, PWR, RCC, SCB, SYSCFG};
+
+use exti::{Edge, Exti, Port};
+use gpio::{Mode, Pin, Pupd};
+
+/// Regulator voltage scaling
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum VoltageScale {
+    /// HCLK up to 64 MHz
+    Scale3 = 0b01,
+    /// HCLK up to 84 MHz (reset value)
+    Scale2 = 0b10,
+    /// HCLK up to 100 MHz
+    Scale1 = 0b11,
+}
+
+impl VoltageScale {
+    /// Lowest scale that supports `hclk`
+    pub fn for_hclk(hclk: u32) -> Self {
+        match hclk {
+            0...64_000_000 => VoltageScale::Scale3,
+            64_000_001...84_000_000 => VoltageScale::Scale2,
+            _ => VoltageScale::Scale1,
+        }
+    }
+
+    /// Fastest AHB clock this scale supports
+    pub fn max_hclk(self) -> u32 {
+        match self {
+            VoltageScale::Scale3 => 64_000_000,
+            VoltageScale::Scale2 => 84_000_000,
+            VoltageScale::Scale1 => 100_000_000,
+        }
+    }
+}
+
+/// Programs the regulator voltage scaling
+///
+/// VOS can only be written while the PLL is off and takes effect when it's
+/// started again; with the PLL off the regulator always runs at scale 3.
+///
+/// # Panics
+///
+/// If the PLL is on
+pub fn set_voltage_scale(rcc: &RCC, pwr: &PWR, scale: VoltageScale) {
+    assert!(rcc.cr.read().pllon().bit_is_clear());
+
+    rcc.apb1enr.modify(|_, w| w.pwren().set_bit());
+    pwr.cr.modify(|r, w| unsafe {
+        w.bits((r.bits() & !(0b11 << 14)) | ((scale as u32) << 14))
+    });
+}
+
+/// Programmed regulator voltage scaling
+pub fn voltage_scale(pwr: &PWR) -> VoltageScale {
+    match (pwr.cr.read().bits() >> 14) & 0b11 {
+        0b11 => VoltageScale::Scale1,
+        0b10 => VoltageScale::Scale2,
+        _ => VoltageScale::Scale3,
+    }
+}
+
+/// Whether the regulator reached the programmed scale
+///
+/// Only meaningful while the PLL is on
+pub fn is_voltage_ready(pwr: &PWR) -> bool {
+    // VOSRDY
+    pwr.csr.read().bits() & (1 << 14) != 0
+}
+
+/// Regulator state in Stop mode
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Regulator {
+    /// Main regulator on, faster wake-up
+    Main,
+    /// Low-power regulator, lowest consumption, adds a few us to the wake-up
+    LowPower,
+}
+
+/// What ends Stop mode
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WakeOn {
+    /// `WFI`: an interrupt enabled in the NVIC
+    Interrupt,
+    /// `WFE`: an EXTI event (line unmasked in EMR), no handler involved
+    Event,
+}
+
+/// Enters Stop mode and restores the clock tree once woken up
+///
+/// All clocks of the 1.2 V domain stop; the chip wakes up on the HSI. If
+/// the HSE and / or the PLL were running they are restarted and the system
+/// clock is switched back, so `Clocks` stay valid. PLL, prescaler and flash
+/// settings survive Stop mode.
+///
+/// NOTE Pending EXTI lines and the RTC flags of the wake-up source must be
+/// cleared before calling this again, otherwise Stop mode is left right away
+pub fn stop(scb: &SCB, pwr: &PWR, rcc: &RCC, regulator: Regulator, wake: WakeOn) {
+    let cr = rcc.cr.read().bits();
+    let sw = rcc.cfgr.read().bits() & 0b11;
+
+    rcc.apb1enr.modify(|_, w| w.pwren().set_bit());
+    // PDDS = 0 (Stop, not Standby), LPDS
+    pwr.cr.modify(|r, w| unsafe {
+        let bits = r.bits() & !0b11;
+        w.bits(if regulator == Regulator::LowPower { bits | 1 } else { bits })
+    });
+
+    unsafe {
+        // SLEEPDEEP
+        scb.scr.modify(|r| r | (1 << 2));
+    }
+    match wake {
+        WakeOn::Interrupt => asm::wfi(),
+        WakeOn::Event => asm::wfe(),
+    }
+    unsafe {
+        scb.scr.modify(|r| r & !(1 << 2));
+    }
+
+    // HSEON
+    if cr & (1 << 16) != 0 {
+        rcc.cr.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 16)) });
+        // HSERDY
+        while rcc.cr.read().bits() & (1 << 17) == 0 {}
+    }
+    // PLLON
+    if cr & (1 << 24) != 0 {
+        rcc.cr.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 24)) });
+        // PLLRDY
+        while rcc.cr.read().bits() & (1 << 25) == 0 {}
+    }
+    rcc.cfgr.modify(|r, w| unsafe { w.bits((r.bits() & !0b11) | sw) });
+    // SWS
+    while (rcc.cfgr.read().bits() >> 2) & 0b11 != sw {}
+}
+
+/// GPIO pin configured to end Stop mode
+#[derive(Clone, Copy, Debug)]
+pub struct WakeupPin {
+    line: u8,
+    wake: WakeOn,
+}
+
+impl WakeupPin {
+    /// Configures pin `pin` of `port` as a Stop mode wake-up source
+    ///
+    /// Enables the port clock, makes the pin an input with `pupd` (a
+    /// floating input picks up noise and wakes the chip at random),
+    /// connects it to its EXTI line with `edge` and unmasks the line as an
+    /// event or an interrupt, matching `wake`. With `WakeOn::Interrupt` the
+    /// `EXTIn` interrupt must also be enabled in the NVIC.
+    pub fn new<P>(
+        gpio: &P,
+        port: Port,
+        pin: u8,
+        pupd: Pupd,
+        edge: Edge,
+        wake: WakeOn,
+        exti: &EXTI,
+        syscfg: &SYSCFG,
+        rcc: &RCC,
+    ) -> Self
+        where P: Deref<Target = gpioa::RegisterBlock>
+    {
+        // GPIOxEN, same bit order as the SYSCFG port numbers
+        rcc.ahb1enr.modify(|r, w| unsafe { w.bits(r.bits() | (1 << port as u32)) });
+
+        let p: Pin<P> = Pin::new(pin);
+        p.set_pupd(gpio, pupd);
+        p.set_mode(gpio, Mode::Input);
+
+        let exti = Exti(exti);
+        exti.init(pin, port, edge, syscfg, rcc);
+        exti.clear_pending(pin);
+        match wake {
+            WakeOn::Event => exti.listen_event(pin),
+            WakeOn::Interrupt => exti.listen(pin),
+        }
+
+        WakeupPin { line: pin, wake: wake }
+    }
+
+    /// EXTI line of the pin
+    pub fn line(&self) -> u8 {
+        self.line
+    }
+
+    /// Enters Stop mode until the pin triggers
+    ///
+    /// Clears the line before entering (a stale trigger would end Stop mode
+    /// right away) and after waking up. The clock tree is restored, see
+    /// `stop`. Returns early if another source wakes the core up.
+    pub fn stop(&self, scb: &SCB, pwr: &PWR, rcc: &RCC, exti: &EXTI, regulator: Regulator) {
+        let exti = Exti(exti);
+        exti.clear_pending(self.line);
+        stop(scb, pwr, rcc, regulator, self.wake);
+        exti.clear_pending(self.line);
+    }
+}
@@ -0,0 +1,817 @@
+//! Reset and Clock Control
+//!
+//! `Clocks` is a snapshot of the clock tree, decoded from the RCC and FLASH
+//! registers, that drivers use to compute prescalers and delays at runtime.
+//!
+//! `Cfgr` configures the clock tree:
+//!
+//! ``` ignore
+//! let clocks = Cfgr::new()
+//!     .use_hse(25_000_000.hz())
+//!     .sysclk(100_000_000.hz())
+//!     .freeze(rcc, flash, pwr);
+//! ```
+//!
+//! `freeze` also programs the flash wait states and caches (see `flash`) and
+//! the regulator voltage scaling (see `pwr`) to match the new clocks.
+//!
+//! Presets cover the common setups, e.g. `Cfgr::preset_100mhz_hse25()`.
+//!
+//! Pass `clocks` to the drivers that need it. Code that can't easily get
+//! hold of it, like interrupt handlers, can use `Clocks::get()` instead.
+
+use core::fmt;
+use core::ops::Deref;
+use core::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+
+use stm32f411::{FLASH, PWR, RCC, TIM5, gpioa};
+
+use flash::AcrConfig;
+use gpio::{AF0, Pin, Speed};
+use pwr::{self, VoltageScale};
+use time::Hertz;
+
+/// Frequency of the internal RC oscillator
+pub const HSI: u32 = 16_000_000;
+
+/// Frequency of the external 32.768 kHz crystal
+pub const LSE: u32 = 32_768;
+
+/// Reset value of HSITRIM
+pub const HSI_TRIM_DEFAULT: u8 = 16;
+
+/// Maximum system clock frequency
+pub const SYSCLK_MAX: u32 = 100_000_000;
+
+/// Maximum APB1 clock frequency
+pub const PCLK1_MAX: u32 = 50_000_000;
+
+/// Maximum APB2 clock frequency
+pub const PCLK2_MAX: u32 = 100_000_000;
+
+/// Frequency USB OTG FS needs on the 48 MHz domain (PLLQ output)
+pub const USB_CLK: u32 = 48_000_000;
+
+// `FROZEN` states
+const UNSET: usize = 0;
+const WRITING: usize = 1;
+const SET: usize = 2;
+
+static STATE: AtomicUsize = ATOMIC_USIZE_INIT;
+static mut FROZEN: Option<Clocks> = None;
+
+/// Unachievable clock related configuration
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ClockError {
+    /// The system clock is zero or above `SYSCLK_MAX`
+    SysclkOutOfRange,
+    /// No PLL setting produces the requested system clock from the source
+    PllOutOfRange,
+    /// The baud rate can't be generated within tolerance from the bus clock
+    BaudUnachievable,
+    /// The requested frequency can't be generated from the peripheral clock
+    FrequencyOutOfRange,
+    /// The LSE oscillator didn't start
+    LseUnavailable,
+    /// The PLL can't produce exactly 48 MHz for USB with this system clock
+    /// (`usb` feature)
+    UsbClock,
+    /// The requested voltage scale doesn't support the AHB clock
+    VoltageScale,
+    /// The RTC didn't acknowledge a configuration change
+    RtcUnavailable,
+    /// The bus clock is outside the range the peripheral supports
+    BusClockOutOfRange,
+    #[doc(hidden)]
+    _Extensible,
+}
+
+impl fmt::Display for ClockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ClockError::SysclkOutOfRange => "system clock out of range",
+            ClockError::PllOutOfRange => "no PLL setting for the requested clock",
+            ClockError::BaudUnachievable => "baud rate not achievable",
+            ClockError::FrequencyOutOfRange => "frequency out of range",
+            ClockError::LseUnavailable => "LSE oscillator didn't start",
+            ClockError::UsbClock => "no 48 MHz USB clock with this configuration",
+            ClockError::VoltageScale => "voltage scale too low for the AHB clock",
+            ClockError::RtcUnavailable => "RTC didn't respond",
+            ClockError::BusClockOutOfRange => "bus clock out of range for the peripheral",
+            ClockError::_Extensible => "unknown error",
+        })
+    }
+}
+
+/// Clock configuration builder
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cfgr {
+    hse: Option<u32>,
+    sysclk: Option<u32>,
+    hclk: Option<u32>,
+    pclk1: Option<u32>,
+    pclk2: Option<u32>,
+    vos: Option<VoltageScale>,
+}
+
+impl Cfgr {
+    /// Default configuration: 16 MHz HSI, no prescalers
+    pub fn new() -> Self {
+        Cfgr::default()
+    }
+
+    /// 100 MHz from a 25 MHz crystal, the fastest the F411 runs
+    ///
+    /// AHB and APB2 at 100 MHz, APB1 at 50 MHz. The 48 MHz domain runs at
+    /// 40 MHz, too slow for USB, see `preset_96mhz_usb`.
+    pub fn preset_100mhz_hse25() -> Self {
+        Cfgr::new()
+            .use_hse(Hertz(25_000_000))
+            .sysclk(Hertz(100_000_000))
+    }
+
+    /// 96 MHz from a 25 MHz crystal, with exactly 48 MHz for USB
+    ///
+    /// AHB and APB2 at 96 MHz, APB1 at 48 MHz.
+    pub fn preset_96mhz_usb() -> Self {
+        Cfgr::new()
+            .use_hse(Hertz(25_000_000))
+            .sysclk(Hertz(96_000_000))
+    }
+
+    /// 16 MHz straight from the HSI, PLL and crystal off
+    ///
+    /// Zero flash wait states; the lowest power setting that keeps the
+    /// reset clock tree.
+    pub fn preset_low_power_16mhz() -> Self {
+        Cfgr::new().sysclk(Hertz(HSI))
+    }
+
+    /// Uses an external oscillator as clock source
+    pub fn use_hse(mut self, freq: Hertz) -> Self {
+        self.hse = Some(freq.0);
+        self
+    }
+
+    /// System clock frequency, the PLL is used if it differs from the source
+    pub fn sysclk(mut self, freq: Hertz) -> Self {
+        self.sysclk = Some(freq.0);
+        self
+    }
+
+    /// AHB clock frequency, defaults to `sysclk`
+    pub fn hclk(mut self, freq: Hertz) -> Self {
+        self.hclk = Some(freq.0);
+        self
+    }
+
+    /// APB1 clock frequency, defaults to the fastest allowed
+    pub fn pclk1(mut self, freq: Hertz) -> Self {
+        self.pclk1 = Some(freq.0);
+        self
+    }
+
+    /// APB2 clock frequency, defaults to the fastest allowed
+    pub fn pclk2(mut self, freq: Hertz) -> Self {
+        self.pclk2 = Some(freq.0);
+        self
+    }
+
+    /// Regulator voltage scaling, defaults to the lowest one that supports
+    /// `hclk`
+    ///
+    /// Only used when the PLL is the clock source, the regulator runs at
+    /// scale 3 otherwise.
+    pub fn voltage_scale(mut self, scale: VoltageScale) -> Self {
+        self.vos = Some(scale);
+        self
+    }
+
+    /// Applies the configuration
+    ///
+    /// Frequencies are rounded down to the closest achievable ones, check
+    /// the returned `Clocks` for the actual values. The result is also made
+    /// available through `Clocks::get`.
+    ///
+    /// Call this once, early in `init`; use `clock_switch` to change the
+    /// clocks afterwards.
+    ///
+    /// # Panics
+    ///
+    /// If the configuration can't be achieved, see `try_freeze`
+    pub fn freeze(self, rcc: &RCC, flash: &FLASH, pwr: &PWR) -> Clocks {
+        match self.try_freeze(rcc, flash, pwr) {
+            Ok(clocks) => clocks,
+            Err(e) => panic!("invalid clock configuration: {:?}", e),
+        }
+    }
+
+    /// Like `freeze` but reports unachievable configurations instead of
+    /// panicking. The hardware is left untouched on error
+    pub fn try_freeze(self, rcc: &RCC, flash: &FLASH, pwr: &PWR) -> Result<Clocks, ClockError> {
+        let clocks = self.validate()?.apply(rcc, flash, pwr);
+        clocks.publish();
+        Ok(clocks)
+    }
+
+    /// Checks the configuration without touching the hardware
+    ///
+    /// The `ClockProfile` can be applied later, any number of times, see
+    /// `clock_switch`.
+    pub fn validate(self) -> Result<ClockProfile, ClockError> {
+        let src = self.hse.unwrap_or(HSI);
+        let sysclk = self.sysclk.unwrap_or(src);
+        if sysclk == 0 || sysclk > SYSCLK_MAX {
+            return Err(ClockError::SysclkOutOfRange);
+        }
+
+        if self.hclk == Some(0) || self.pclk1 == Some(0) || self.pclk2 == Some(0) {
+            return Err(ClockError::FrequencyOutOfRange);
+        }
+
+        let pllcfgr = if sysclk == src {
+            None
+        } else {
+            Some(pll_config(src, self.hse.is_some(), sysclk)?)
+        };
+
+        if cfg!(feature = "usb") {
+            match pllcfgr {
+                Some(pllcfgr) if pll48clk(src, pllcfgr) == USB_CLK => {}
+                _ => return Err(ClockError::UsbClock),
+            }
+        }
+
+        let (hpre, hdiv) = match sysclk / self.hclk.unwrap_or(sysclk) {
+            0...1 => (0b0000, 1),
+            2 => (0b1000, 2),
+            3...5 => (0b1001, 4),
+            6...11 => (0b1010, 8),
+            12...39 => (0b1011, 16),
+            40...95 => (0b1100, 64),
+            96...191 => (0b1101, 128),
+            192...383 => (0b1110, 256),
+            _ => (0b1111, 512),
+        };
+        let hclk = sysclk / hdiv;
+
+        let vos = self.vos.unwrap_or_else(|| VoltageScale::for_hclk(hclk));
+        if hclk > vos.max_hclk() {
+            return Err(ClockError::VoltageScale);
+        }
+
+        Ok(ClockProfile {
+            hse: self.hse,
+            pllcfgr: pllcfgr,
+            hpre: hpre,
+            ppre1: ppre_bits(hclk, self.pclk1.unwrap_or(PCLK1_MAX), PCLK1_MAX),
+            ppre2: ppre_bits(hclk, self.pclk2.unwrap_or(PCLK2_MAX), PCLK2_MAX),
+            hclk: hclk,
+            vos: vos,
+        })
+    }
+}
+
+/// Validated clock configuration, see `Cfgr::validate`
+#[derive(Clone, Copy, Debug)]
+pub struct ClockProfile {
+    hse: Option<u32>,
+    pllcfgr: Option<u32>,
+    hpre: u32,
+    ppre1: u32,
+    ppre2: u32,
+    hclk: u32,
+    vos: VoltageScale,
+}
+
+impl ClockProfile {
+    /// AHB clock frequency of the profile
+    pub fn hclk(&self) -> Hertz {
+        Hertz(self.hclk)
+    }
+
+    /// Programs the clock tree, from whatever it is now
+    ///
+    /// Doesn't publish the result, see `Clocks::get`.
+    pub(crate) fn apply(&self, rcc: &RCC, flash: &FLASH, pwr: &PWR) -> Clocks {
+        if self.hse.is_some() {
+            rcc.cr.modify(|_, w| w.hseon().set_bit());
+            while rcc.cr.read().hserdy().bit_is_clear() {}
+        }
+
+        // Raise the wait states before speeding up, lower them afterwards
+        let acr = AcrConfig::for_hclk(self.hclk);
+        if acr.wait_states() > AcrConfig::read(flash).wait_states() {
+            acr.apply(flash);
+        }
+
+        let sw = match self.pllcfgr {
+            Some(pllcfgr) => {
+                // The PLL can't be reconfigured while it clocks the system
+                if (rcc.cfgr.read().bits() >> 2) & 0b11 == 0b10 {
+                    switch_to_hsi(rcc);
+                }
+                start_pll(rcc, pwr, pllcfgr, self.vos);
+                0b10
+            }
+            None => if self.hse.is_some() {
+                0b01
+            } else {
+                rcc.cr.modify(|_, w| w.hsion().set_bit());
+                while rcc.cr.read().hsirdy().bit_is_clear() {}
+                0b00
+            },
+        };
+
+        let (hpre, ppre1, ppre2) = (self.hpre, self.ppre1, self.ppre2);
+        rcc.cfgr.modify(|r, w| unsafe {
+            w.bits((r.bits() & !0xFCF3) | (ppre2 << 13) | (ppre1 << 10) | (hpre << 4) | sw)
+        });
+        while (rcc.cfgr.read().bits() >> 2) & 0b11 != sw {}
+
+        acr.apply(flash);
+
+        // The PLL draws a few mA, don't leave it running unused
+        if sw != 0b10 {
+            rcc.cr.modify(|_, w| w.pllon().clear_bit());
+        }
+
+        Clocks::read(rcc, flash, self.hse.map(Hertz))
+    }
+}
+
+/// Frozen clock configuration
+#[derive(Clone, Copy, Debug)]
+pub struct Clocks {
+    sysclk: Hertz,
+    hclk: Hertz,
+    pclk1: Hertz,
+    pclk2: Hertz,
+    ppre1: u8,
+    ppre2: u8,
+    latency: u8,
+    prefetch: bool,
+    icache: bool,
+}
+
+impl Clocks {
+    /// Decodes the current clock configuration
+    ///
+    /// `hse` is the frequency of the external oscillator, if any; it can't be
+    /// read back from the registers.
+    pub fn read(rcc: &RCC, flash: &FLASH, hse: Option<Hertz>) -> Self {
+        let cfgr = rcc.cfgr.read().bits();
+        let hse = hse.map(|f| f.0).unwrap_or(0);
+
+        let sysclk = match (cfgr >> 2) & 0b11 {
+            0b01 => hse,
+            0b10 => {
+                let pllcfgr = rcc.pllcfgr.read().bits();
+                let input = if pllcfgr & (1 << 22) != 0 { hse } else { HSI };
+                let m = pllcfgr & 0x3F;
+                let n = (pllcfgr >> 6) & 0x1FF;
+                let p = (((pllcfgr >> 16) & 0b11) + 1) * 2;
+                input / m * n / p
+            }
+            _ => HSI,
+        };
+
+        let hpre = match (cfgr >> 4) & 0xF {
+            0b1000 => 2,
+            0b1001 => 4,
+            0b1010 => 8,
+            0b1011 => 16,
+            0b1100 => 64,
+            0b1101 => 128,
+            0b1110 => 256,
+            0b1111 => 512,
+            _ => 1,
+        };
+        let hclk = sysclk / hpre;
+
+        let ppre1 = apb_prescaler((cfgr >> 10) & 0b111);
+        let ppre2 = apb_prescaler((cfgr >> 13) & 0b111);
+
+        let acr = flash.acr.read().bits();
+
+        Clocks {
+            sysclk: Hertz(sysclk),
+            hclk: Hertz(hclk),
+            pclk1: Hertz(hclk / ppre1 as u32),
+            pclk2: Hertz(hclk / ppre2 as u32),
+            ppre1: ppre1,
+            ppre2: ppre2,
+            latency: (acr & 0xF) as u8,
+            prefetch: acr & (1 << 8) != 0,
+            icache: acr & (1 << 9) != 0,
+        }
+    }
+
+    /// Clock configuration out of reset: HSI, no bus prescalers, zero wait
+    /// states
+    pub(crate) fn reset() -> Self {
+        Clocks {
+            sysclk: Hertz(HSI),
+            hclk: Hertz(HSI),
+            pclk1: Hertz(HSI),
+            pclk2: Hertz(HSI),
+            ppre1: 1,
+            ppre2: 1,
+            latency: 0,
+            prefetch: false,
+            icache: false,
+        }
+    }
+
+    /// Clock configuration applied by `Cfgr::freeze`
+    ///
+    /// Returns `None` if the clocks haven't been frozen yet
+    pub fn get() -> Option<Clocks> {
+        if STATE.load(Ordering::Acquire) == SET {
+            unsafe { FROZEN }
+        } else {
+            None
+        }
+    }
+
+    /// Makes `self` available through `Clocks::get`
+    ///
+    /// Only the first call has an effect, the clock tree is not meant to
+    /// change after `freeze`
+    fn publish(self) {
+        if STATE.compare_and_swap(UNSET, WRITING, Ordering::Acquire) == UNSET {
+            unsafe { FROZEN = Some(self) };
+            STATE.store(SET, Ordering::Release);
+        }
+    }
+
+    /// Replaces the published clocks after a `clock_switch::switch`
+    ///
+    /// Must run in a critical section, so no `Clocks::get` sees a half
+    /// written value.
+    pub(crate) fn republish(self) {
+        STATE.store(WRITING, Ordering::Release);
+        unsafe { FROZEN = Some(self) };
+        STATE.store(SET, Ordering::Release);
+    }
+
+    /// System clock frequency
+    pub fn sysclk(&self) -> Hertz {
+        self.sysclk
+    }
+
+    /// AHB clock frequency
+    pub fn hclk(&self) -> Hertz {
+        self.hclk
+    }
+
+    /// APB1 clock frequency
+    pub fn pclk1(&self) -> Hertz {
+        self.pclk1
+    }
+
+    /// APB2 clock frequency
+    pub fn pclk2(&self) -> Hertz {
+        self.pclk2
+    }
+
+    /// Clock of the timers on APB1, twice `pclk1` when APB1 is prescaled
+    pub fn timclk1(&self) -> Hertz {
+        Hertz(if self.ppre1 == 1 { self.pclk1.0 } else { self.pclk1.0 * 2 })
+    }
+
+    /// Clock of the timers on APB2, twice `pclk2` when APB2 is prescaled
+    pub fn timclk2(&self) -> Hertz {
+        Hertz(if self.ppre2 == 1 { self.pclk2.0 } else { self.pclk2.0 * 2 })
+    }
+
+    /// APB1 prescaler
+    pub fn ppre1(&self) -> u8 {
+        self.ppre1
+    }
+
+    /// APB2 prescaler
+    pub fn ppre2(&self) -> u8 {
+        self.ppre2
+    }
+
+    /// Flash wait states
+    pub fn flash_latency(&self) -> u8 {
+        self.latency
+    }
+
+    /// Whether the flash prefetch buffer is enabled
+    pub fn flash_prefetch(&self) -> bool {
+        self.prefetch
+    }
+
+    /// Whether the flash instruction cache (ART accelerator) is enabled
+    pub fn flash_icache(&self) -> bool {
+        self.icache
+    }
+}
+
+/// Sets the HSI trimming value (0 - 31, one step is roughly 0.3%)
+pub fn set_hsi_trim(rcc: &RCC, trim: u8) {
+    assert!(trim < 32);
+    rcc.cr.modify(|r, w| unsafe {
+        w.bits((r.bits() & !(0b11111 << 3)) | (u32::from(trim) << 3))
+    });
+}
+
+/// Current HSI trimming value
+pub fn hsi_trim(rcc: &RCC) -> u8 {
+    ((rcc.cr.read().bits() >> 3) & 0b11111) as u8
+}
+
+/// Clock sources of MCO1
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mco1Source {
+    Hsi = 0b00,
+    Lse = 0b01,
+    Hse = 0b10,
+    /// Main PLL output, the one that feeds `sysclk`
+    Pll = 0b11,
+}
+
+/// Fastest MCO output the pad supports
+const MCO_MAX: u32 = 100_000_000;
+
+/// Outputs a clock as close as possible to `freq` on PA8 (MCO1), e.g. for an
+/// audio codec or a camera module
+///
+/// Every running oscillator (HSI, LSE, HSE, PLL) and MCO1 prescaler
+/// (1 - 5) is considered; `hse` is the HSE frequency, if one is running. The
+/// closest match is used if it's within 1%, otherwise `FrequencyOutOfRange`
+/// is returned and nothing changes. PA8 is switched to the MCO alternate
+/// function, at the highest speed above 25 MHz. Returns the actual output
+/// frequency.
+///
+/// NOTE The PLL can be used with `freeze`d clocks but its frequency can't be
+/// changed afterwards; pick `sysclk` with the MCO frequency in mind.
+pub fn provide_clock_on_pa8<A>(rcc: &RCC, gpioa: &A, hse: Option<Hertz>, freq: Hertz)
+    -> Result<Hertz, ClockError>
+    where A: Deref<Target = gpioa::RegisterBlock>
+{
+    let cr = rcc.cr.read().bits();
+    let pll = if cr & (1 << 25) != 0 { pll_output(rcc, hse) } else { None };
+    let sources = [
+        // HSIRDY
+        (Mco1Source::Hsi, if cr & (1 << 1) != 0 { Some(HSI) } else { None }),
+        // LSERDY
+        (Mco1Source::Lse, if rcc.bdcr.read().bits() & (1 << 1) != 0 { Some(LSE) } else { None }),
+        // HSERDY
+        (Mco1Source::Hse, if cr & (1 << 17) != 0 { hse.map(|f| f.0) } else { None }),
+        (Mco1Source::Pll, pll),
+    ];
+
+    let mut best: Option<(Mco1Source, u32, u32)> = None;
+    for &(source, input) in sources.iter() {
+        let input = match input {
+            Some(input) => input,
+            None => continue,
+        };
+        for div in 1..6 {
+            let output = input / div;
+            if output > MCO_MAX {
+                continue;
+            }
+            let error = if output > freq.0 { output - freq.0 } else { freq.0 - output };
+            let better = match best {
+                Some((_, _, best_output)) => {
+                    let best_error = if best_output > freq.0 {
+                        best_output - freq.0
+                    } else {
+                        freq.0 - best_output
+                    };
+                    error < best_error
+                }
+                None => true,
+            };
+            if better {
+                best = Some((source, div, output));
+            }
+        }
+    }
+
+    let (source, div, output) = match best {
+        Some(best) => best,
+        None => return Err(ClockError::FrequencyOutOfRange),
+    };
+    let error = if output > freq.0 { output - freq.0 } else { freq.0 - output };
+    if freq.0 == 0 || error > freq.0 / 100 {
+        return Err(ClockError::FrequencyOutOfRange);
+    }
+
+    // MCO1PRE: 0b0xx = no division, 0b1xx = 2 + xx
+    let pre = if div == 1 { 0 } else { 0b100 | (div - 2) };
+    rcc.cfgr.modify(|r, w| unsafe {
+        w.bits((r.bits() & !((0b111 << 24) | (0b11 << 21)))
+               | (pre << 24) | ((source as u32) << 21))
+    });
+
+    // GPIOAEN
+    rcc.ahb1enr.modify(|r, w| unsafe { w.bits(r.bits() | 1) });
+    let speed = if output > 25_000_000 { Speed::High } else { Speed::Medium };
+    Pin::<A>::new(8).set_alternate(gpioa, AF0, speed);
+
+    Ok(Hertz(output))
+}
+
+/// Main PLL output (the P divider), from the PLLCFGR register
+fn pll_output(rcc: &RCC, hse: Option<Hertz>) -> Option<u32> {
+    let pllcfgr = rcc.pllcfgr.read().bits();
+    let input = if pllcfgr & (1 << 22) != 0 {
+        match hse {
+            Some(hse) => hse.0,
+            None => return None,
+        }
+    } else {
+        HSI
+    };
+    let m = pllcfgr & 0x3F;
+    let n = (pllcfgr >> 6) & 0x1FF;
+    let p = (((pllcfgr >> 16) & 0b11) + 1) * 2;
+    if m == 0 {
+        return None;
+    }
+    Some((u64::from(input) * u64::from(n) / u64::from(m) / u64::from(p)) as u32)
+}
+
+/// Starts the LSE oscillator
+///
+/// The LSE lives in the backup domain, this enables write access to it.
+pub fn enable_lse(rcc: &RCC, pwr: &PWR) -> Result<(), ClockError> {
+    rcc.apb1enr.modify(|_, w| w.pwren().set_bit());
+    pwr.cr.modify(|_, w| w.dbp().set_bit());
+
+    // LSEON
+    rcc.bdcr.modify(|r, w| unsafe { w.bits(r.bits() | 1) });
+
+    // The crystal takes up to ~2 s to start
+    for _ in 0..10_000_000 {
+        // LSERDY
+        if rcc.bdcr.read().bits() & (1 << 1) != 0 {
+            return Ok(());
+        }
+    }
+    Err(ClockError::LseUnavailable)
+}
+
+/// Trims the HSI against the LSE
+///
+/// The system clock must come from the HSI (directly or through the PLL)
+/// and the LSE must be running, see `enable_lse`. TIM5 channel 4 is
+/// internally connected to the LSE and measures it with the APB1 timer
+/// clock; every trim value is tried and the one closest to the nominal
+/// `clocks` is kept and returned. Takes about 100 ms. TIM5 is left
+/// disabled.
+pub fn calibrate_hsi(rcc: &RCC, tim5: &TIM5, clocks: &Clocks) -> Result<u8, ClockError> {
+    // Timer ticks per capture (LSE / 8) when the HSI is exactly 16 MHz
+    let expected = (u64::from(clocks.timclk1().0) * 8 / u64::from(LSE)) as u32;
+
+    rcc.apb1enr.modify(|_, w| w.tim5en().set_bit());
+    unsafe {
+        tim5.cr1.write(|w| w.bits(0));
+        // TI4_RMP = LSE
+        tim5.or.write(|w| w.bits(0b10 << 6));
+        // CC4S = TI4, IC4PSC = 8
+        tim5.ccmr2_input.write(|w| w.bits((0b01 << 8) | (0b11 << 10)));
+        // CC4E
+        tim5.ccer.write(|w| w.bits(1 << 12));
+        tim5.psc.write(|w| w.bits(0));
+        tim5.arr.write(|w| w.bits(0xFFFF_FFFF));
+        tim5.cr1.write(|w| w.bits(1));
+    }
+
+    let mut best = (hsi_trim(rcc), u32::max_value());
+    let mut result = Ok(());
+    for trim in 0..32 {
+        set_hsi_trim(rcc, trim);
+        match measure_lse(tim5) {
+            Some(ticks) => {
+                let error = if ticks > expected { ticks - expected } else { expected - ticks };
+                if error < best.1 {
+                    best = (trim, error);
+                }
+            }
+            None => {
+                result = Err(ClockError::LseUnavailable);
+                break;
+            }
+        }
+    }
+
+    unsafe { tim5.cr1.write(|w| w.bits(0)) };
+    rcc.apb1enr.modify(|_, w| w.tim5en().clear_bit());
+
+    set_hsi_trim(rcc, best.0);
+    result.map(|_| best.0)
+}
+
+/// Average TIM5 ticks between two captures of LSE / 8
+fn measure_lse(tim5: &TIM5) -> Option<u32> {
+    const CAPTURES: u32 = 4;
+
+    let capture = || {
+        // CC4IF is cleared by reading CCR4
+        tim5.ccr4.read().bits();
+        for _ in 0..1_000_000 {
+            if tim5.sr.read().bits() & (1 << 4) != 0 {
+                return Some(tim5.ccr4.read().bits());
+            }
+        }
+        None
+    };
+
+    // Let the new trim settle
+    capture()?;
+    let start = capture()?;
+    let mut end = start;
+    for _ in 0..CAPTURES {
+        end = capture()?;
+    }
+    Some(end.wrapping_sub(start) / CAPTURES)
+}
+
+fn apb_prescaler(ppre: u32) -> u8 {
+    match ppre {
+        0b100 => 2,
+        0b101 => 4,
+        0b110 => 8,
+        0b111 => 16,
+        _ => 1,
+    }
+}
+
+/// APB prescaler bits for the fastest `pclk <= min(target, max)`
+fn ppre_bits(hclk: u32, target: u32, max: u32) -> u32 {
+    let target = if target < max { target } else { max };
+    // Round the divider up so the result doesn't exceed the target
+    match (hclk + target - 1) / target {
+        0...1 => 0b000,
+        2 => 0b100,
+        3...4 => 0b101,
+        5...8 => 0b110,
+        _ => 0b111,
+    }
+}
+
+/// PLLCFGR value that produces `sysclk` from `src`
+fn pll_config(src: u32, hse: bool, sysclk: u32) -> Result<u32, ClockError> {
+    // VCO input: 2 MHz if possible (less jitter), otherwise 1 MHz
+    let vco_in = if src % 2_000_000 == 0 { 2_000_000 } else { 1_000_000 };
+    let m = src / vco_in;
+    if src % vco_in != 0 || m < 2 || m > 63 {
+        return Err(ClockError::PllOutOfRange);
+    }
+
+    // VCO output must be in 100 - 432 MHz
+    let p = match [2, 4, 6, 8].iter().cloned().find(|p| sysclk * p >= 100_000_000) {
+        Some(p) => p,
+        None => return Err(ClockError::PllOutOfRange),
+    };
+    let n = sysclk * p / vco_in;
+    if n < 50 || n > 432 {
+        return Err(ClockError::PllOutOfRange);
+    }
+
+    // Keep the 48 MHz domain at or below 48 MHz
+    let vco = vco_in * n;
+    let q = (vco + 47_999_999) / 48_000_000;
+    let q = if q < 2 { 2 } else { q };
+
+    Ok(m | (n << 6) | ((p / 2 - 1) << 16) | ((hse as u32) << 22) | (q << 24))
+}
+
+/// Frequency of the 48 MHz domain (PLLQ output) of `pllcfgr`
+fn pll48clk(src: u32, pllcfgr: u32) -> u32 {
+    let m = pllcfgr & 0x3F;
+    let n = (pllcfgr >> 6) & 0x1FF;
+    let q = (pllcfgr >> 24) & 0xF;
+    src / m * n / q
+}
+
+/// Runs the system from the HSI, keeping the bus prescalers
+fn switch_to_hsi(rcc: &RCC) {
+    rcc.cr.modify(|_, w| w.hsion().set_bit());
+    while rcc.cr.read().hsirdy().bit_is_clear() {}
+
+    rcc.cfgr.modify(|r, w| unsafe { w.bits(r.bits() & !0b11) });
+    while (rcc.cfgr.read().bits() >> 2) & 0b11 != 0 {}
+}
+
+/// Locks the main PLL with the given configuration
+///
+/// VOS can only be changed while the PLL is off, so it's programmed here
+fn start_pll(rcc: &RCC, pwr: &PWR, pllcfgr: u32, vos: VoltageScale) {
+    rcc.cr.modify(|_, w| w.pllon().clear_bit());
+    while rcc.cr.read().pllrdy().bit_is_set() {}
+
+    pwr::set_voltage_scale(rcc, pwr, vos);
+    rcc.pllcfgr.write(|w| unsafe { w.bits(pllcfgr) });
+
+    rcc.cr.modify(|_, w| w.pllon().set_bit());
+    while rcc.cr.read().pllrdy().bit_is_clear() {}
+    while !pwr::is_voltage_ready(pwr) {}
+}
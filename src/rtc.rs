@@ -0,0 +1,279 @@
+//! Real-time clock wake-up timer
+//!
+//! `LowPowerTicker` is a periodic timer that, unlike the TIM peripherals,
+//! keeps running in Stop mode: the RTC is clocked from the LSE (or LSI) in
+//! the backup domain and its wake-up event is routed to EXTI line 22.
+//!
+//! ``` ignore
+//! let ticker = LowPowerTicker::new(rtc, rcc, pwr, exti, RtcClock::Lse)?;
+//! ticker.start(1_000.ms())?;
+//!
+//! loop {
+//!     // Stop mode until the next tick, clocks are restored on wake-up
+//!     ticker.sleep(scb, pwr, rcc);
+//!     sample_sensor();
+//! }
+//! ```
+//!
+//! With RTFM enable the `RTC_WKUP` interrupt and `listen` instead; `wait`
+//! in the task clears the tick:
+//!
+//! ``` ignore
+//! fn tick(_t: &mut Threshold, r: RTC_WKUP::Resources) {
+//!     let ticker = LowPowerTicker::wrap(&**r.RTC, &**r.EXTI);
+//!     ticker.wait().unwrap();
+//!     ..
+//! }
+//! ```
+//!
+//! Periods up to 32 s have a resolution of 16 RTC clock cycles (~0.5 ms),
+//! longer ones (up to ~18 h) of one second.
+
+use nb;
+use stm32f411::{EXTI, PWR, RCC, RTC, SCB};
+
+use pwr::{self, Regulator, WakeOn};
+use rcc::{self, ClockError, LSE};
+use time::Milliseconds;
+
+/// Nominal LSI frequency, the actual one is 17 - 47 kHz
+const LSI: u32 = 32_000;
+
+/// EXTI line of the RTC wake-up event
+const EXTI_LINE: u32 = 1 << 22;
+
+// RTC_CR
+const WUCKSEL_DIV16: u32 = 0b000;
+const WUCKSEL_SPRE: u32 = 0b100;
+const WUTE: u32 = 1 << 10;
+const WUTIE: u32 = 1 << 14;
+
+// RTC_ISR
+const WUTWF: u32 = 1 << 2;
+const INITF: u32 = 1 << 6;
+const INIT: u32 = 1 << 7;
+const WUTF: u32 = 1 << 10;
+
+/// Iterations to wait for the RTC to acknowledge a configuration change
+const TIMEOUT: u32 = 100_000;
+
+/// RTC clock source
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RtcClock {
+    /// 32.768 kHz crystal, accurate
+    Lse,
+    /// Internal RC, no external parts but +-50% off
+    Lsi,
+}
+
+/// Periodic wake-up timer, works in Stop mode
+pub struct LowPowerTicker<'a> {
+    rtc: &'a RTC,
+    exti: &'a EXTI,
+}
+
+impl<'a> LowPowerTicker<'a> {
+    /// Starts the RTC clock and routes the wake-up timer to EXTI line 22
+    ///
+    /// The wake-up event is unmasked as an *event*, which is what `sleep`
+    /// waits for; see `listen` for interrupts. The calendar keeps counting
+    /// seconds from whatever it holds.
+    ///
+    /// NOTE The RTC clock source can only be changed after a backup domain
+    /// reset; if the RTC already runs from the other source this resets the
+    /// backup domain, clearing the backup registers (see `boot`).
+    pub fn new(
+        rtc: &'a RTC,
+        rcc: &RCC,
+        pwr: &PWR,
+        exti: &'a EXTI,
+        clock: RtcClock,
+    ) -> Result<Self, ClockError> {
+        let (rtcsel, freq) = match clock {
+            RtcClock::Lse => {
+                rcc::enable_lse(rcc, pwr)?;
+                (0b01, LSE)
+            }
+            RtcClock::Lsi => {
+                // LSION, LSIRDY
+                rcc.csr.modify(|r, w| unsafe { w.bits(r.bits() | 1) });
+                if !wait_for(|| rcc.csr.read().bits() & (1 << 1) != 0) {
+                    return Err(ClockError::RtcUnavailable);
+                }
+                (0b10, LSI)
+            }
+        };
+
+        // Backup domain write access, kept on: the RTC registers need it
+        rcc.apb1enr.modify(|_, w| w.pwren().set_bit());
+        pwr.cr.modify(|_, w| w.dbp().set_bit());
+
+        let bdcr = rcc.bdcr.read().bits();
+        let current = (bdcr >> 8) & 0b11;
+        if current != rtcsel {
+            if current != 0 {
+                // BDRST, keeps the LSE running
+                let lse = bdcr & 0b101;
+                rcc.bdcr.write(|w| unsafe { w.bits(1 << 16) });
+                rcc.bdcr.write(|w| unsafe { w.bits(lse) });
+                if lse & 1 != 0 {
+                    while rcc.bdcr.read().bits() & (1 << 1) == 0 {}
+                }
+            }
+            rcc.bdcr.modify(|r, w| unsafe { w.bits(r.bits() | (rtcsel << 8)) });
+        }
+        // RTCEN
+        rcc.bdcr.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 15)) });
+
+        let ticker = LowPowerTicker { rtc: rtc, exti: exti };
+
+        // 1 Hz calendar clock: PREDIV_A = 128, PREDIV_S = freq / 128
+        ticker.unlocked(|| {
+            rtc.isr.modify(|r, w| unsafe { w.bits(r.bits() | INIT) });
+            let ready = wait_for(|| rtc.isr.read().bits() & INITF != 0);
+            if ready {
+                rtc.prer.write(|w| unsafe { w.bits((127 << 16) | (freq / 128 - 1)) });
+            }
+            rtc.isr.modify(|r, w| unsafe { w.bits(r.bits() & !INIT) });
+            if ready { Ok(()) } else { Err(ClockError::RtcUnavailable) }
+        })?;
+
+        // Rising edge, event mask
+        unsafe {
+            exti.rtsr.modify(|r, w| w.bits(r.bits() | EXTI_LINE));
+            exti.emr.modify(|r, w| w.bits(r.bits() | EXTI_LINE));
+        }
+
+        Ok(ticker)
+    }
+
+    /// Wraps an RTC configured by `new`, e.g. in an interrupt handler
+    pub fn wrap(rtc: &'a RTC, exti: &'a EXTI) -> Self {
+        LowPowerTicker { rtc: rtc, exti: exti }
+    }
+
+    /// (Re)starts the ticker with `period`
+    ///
+    /// Returns `FrequencyOutOfRange` if the period is 0 or longer than
+    /// 65536 s.
+    pub fn start(&self, period: Milliseconds) -> Result<(), ClockError> {
+        let (wucksel, reload) = reload(period.0, self.rtc_clock())
+            .ok_or(ClockError::FrequencyOutOfRange)?;
+
+        let rtc = self.rtc;
+        self.unlocked(|| {
+            rtc.cr.modify(|r, w| unsafe { w.bits(r.bits() & !WUTE) });
+            if !wait_for(|| rtc.isr.read().bits() & WUTWF != 0) {
+                return Err(ClockError::RtcUnavailable);
+            }
+            rtc.wutr.write(|w| unsafe { w.bits(reload) });
+            rtc.cr.modify(|r, w| unsafe {
+                w.bits((r.bits() & !0b111) | wucksel | WUTE | WUTIE)
+            });
+            Ok(())
+        })?;
+        self.clear();
+        Ok(())
+    }
+
+    /// Stops the ticker
+    pub fn cancel(&self) {
+        let rtc = self.rtc;
+        self.unlocked(|| {
+            rtc.cr.modify(|r, w| unsafe { w.bits(r.bits() & !(WUTE | WUTIE)) });
+        });
+        self.clear();
+    }
+
+    /// Checks for, and clears, a tick
+    pub fn wait(&self) -> nb::Result<(), !> {
+        if self.rtc.isr.read().bits() & WUTF == 0 {
+            Err(nb::Error::WouldBlock)
+        } else {
+            self.clear();
+            Ok(())
+        }
+    }
+
+    /// Spends the time until the next tick in Stop mode
+    ///
+    /// Other EXTI events (not interrupts) also wake the core up, the ticker
+    /// then goes back to Stop mode.
+    pub fn sleep(&self, scb: &SCB, pwr: &PWR, rcc: &RCC) {
+        while self.wait().is_err() {
+            pwr::stop(scb, pwr, rcc, Regulator::LowPower, WakeOn::Event);
+        }
+    }
+
+    /// Raises the `RTC_WKUP` interrupt on every tick
+    ///
+    /// The interrupt must also be enabled in the NVIC; `wait` clears it.
+    pub fn listen(&self) {
+        self.exti.imr.modify(|r, w| unsafe { w.bits(r.bits() | EXTI_LINE) });
+    }
+
+    /// Stops raising the `RTC_WKUP` interrupt
+    pub fn unlisten(&self) {
+        self.exti.imr.modify(|r, w| unsafe { w.bits(r.bits() & !EXTI_LINE) });
+    }
+
+    /// Clears WUTF and the EXTI line, a pending line would end the next Stop
+    /// right away
+    fn clear(&self) {
+        // NOTE the flags are cleared by writing 0, write 1 to the others
+        self.rtc.isr.modify(|r, w| unsafe { w.bits(!(WUTF | INIT) | (r.bits() & INIT)) });
+        // NOTE(write) PR is write 1 to clear
+        self.exti.pr.write(|w| unsafe { w.bits(EXTI_LINE) });
+    }
+
+    /// RTC clock frequency, from the prescalers programmed by `new`
+    fn rtc_clock(&self) -> u32 {
+        let prer = self.rtc.prer.read().bits();
+        (((prer >> 16) & 0x7F) + 1) * ((prer & 0x7FFF) + 1)
+    }
+
+    /// Runs `f` with the RTC write protection lifted
+    fn unlocked<F, R>(&self, f: F) -> R
+        where F: FnOnce() -> R
+    {
+        unsafe {
+            self.rtc.wpr.write(|w| w.bits(0xCA));
+            self.rtc.wpr.write(|w| w.bits(0x53));
+        }
+        let r = f();
+        unsafe {
+            self.rtc.wpr.write(|w| w.bits(0xFF));
+        }
+        r
+    }
+}
+
+/// WUCKSEL and WUTR for `ms` milliseconds with an `rtcclk` Hz RTC clock
+fn reload(ms: u32, rtcclk: u32) -> Option<(u32, u32)> {
+    if ms == 0 {
+        return None;
+    }
+
+    let ticks = u64::from(ms) * u64::from(rtcclk / 16) / 1_000;
+    if ticks <= 0x1_0000 {
+        Some((WUCKSEL_DIV16, if ticks == 0 { 0 } else { ticks as u32 - 1 }))
+    } else {
+        let seconds = (ms + 500) / 1_000;
+        if seconds <= 0x1_0000 {
+            Some((WUCKSEL_SPRE, seconds - 1))
+        } else {
+            None
+        }
+    }
+}
+
+fn wait_for<F>(mut ready: F) -> bool
+    where F: FnMut() -> bool
+{
+    for _ in 0..TIMEOUT {
+        if ready() {
+            return true;
+        }
+    }
+    false
+}
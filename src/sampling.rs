@@ -0,0 +1,108 @@
+//! PWM synchronized ADC sampling
+//!
+//! `SyncedSampling` starts an ADC1 injected sequence on the TIM1 channel 4
+//! compare event, i.e. at a fixed point of every PWM period. Channels 1 to 3
+//! keep driving the bridge through `Pwm`, channel 4 only positions the
+//! sampling instant (e.g. in the middle of the low-side on time for shunt
+//! current sensing).
+//!
+//! ``` ignore
+//! let sampling = SyncedSampling::new(tim1, adc1);
+//! sampling.init(&[adc2::channel(&pa1), adc2::channel(&pa2)]);
+//! sampling.set_sample_point(3, 4);
+//! sampling.listen();
+//!
+//! // ADC interrupt
+//! if let Some(samples) = sampling.read() { control(samples[0], samples[1]); }
+//! ```
+
+use stm32f411::{ADC1, TIM1};
+
+/// Maximum length of an injected sequence
+pub const MAX_CHANNELS: usize = 4;
+
+/// Injected conversions triggered by TIM1 CC4
+pub struct SyncedSampling<'a> {
+    tim: &'a TIM1,
+    adc: &'a ADC1,
+}
+
+impl<'a> SyncedSampling<'a> {
+    pub fn new(tim: &'a TIM1, adc: &'a ADC1) -> Self {
+        SyncedSampling { tim: tim, adc: adc }
+    }
+
+    /// Sets up the injected sequence and its trigger
+    ///
+    /// The ADC must be initialized (`Adc::init`) and TIM1 running as PWM.
+    /// `channels` are converted in order, at most `MAX_CHANNELS`.
+    pub fn init(&self, channels: &[u8]) {
+        assert!(!channels.is_empty() && channels.len() <= MAX_CHANNELS);
+
+        // With JL = n - 1 the sequence uses the last n JSQx slots
+        let first = MAX_CHANNELS - channels.len();
+        let mut jsqr = ((channels.len() as u32) - 1) << 20;
+        for (i, channel) in channels.iter().enumerate() {
+            assert!(*channel < 19);
+            jsqr |= u32::from(*channel) << (5 * (first + i));
+        }
+        self.adc.jsqr.write(|w| unsafe { w.bits(jsqr) });
+
+        // JEXTSEL = TIM1_CC4, JEXTEN = rising edge
+        self.adc.cr2.modify(|r, w| unsafe {
+            w.bits((r.bits() & !(0b11_1111 << 16)) | (0b01 << 20))
+        });
+
+        // OC4 in PWM mode 1 with preload, output left disabled
+        self.tim.ccmr2_output.modify(|_, w| unsafe {
+            w.oc4pe().set_bit().oc4m().bits(0b110)
+        });
+    }
+
+    /// Places the sampling instant at `num / denom` of the PWM period
+    pub fn set_sample_point(&self, num: u16, denom: u16) {
+        assert!(denom != 0 && num <= denom);
+
+        let arr = u32::from(self.tim.arr.read().arr().bits());
+        let ccr = arr * u32::from(num) / u32::from(denom);
+        self.tim.ccr4.write(|w| unsafe { w.ccr4().bits(ccr as u16) });
+    }
+
+    /// Enables the end of injected sequence interrupt (ADC IRQ)
+    pub fn listen(&self) {
+        // JEOCIE
+        self.adc.cr1.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 7)) });
+    }
+
+    /// Disables the end of injected sequence interrupt
+    pub fn unlisten(&self) {
+        self.adc.cr1.modify(|r, w| unsafe { w.bits(r.bits() & !(1 << 7)) });
+    }
+
+    /// Results of the last sequence, if one completed since the last call
+    ///
+    /// Entries past the sequence length are zero
+    pub fn read(&self) -> Option<[u16; MAX_CHANNELS]> {
+        let adc = self.adc;
+
+        // JEOC
+        if adc.sr.read().bits() & (1 << 2) == 0 {
+            return None;
+        }
+        // rc_w0, leave the other flags alone
+        adc.sr.write(|w| unsafe { w.bits(!(1 << 2)) });
+
+        let len = ((adc.jsqr.read().bits() >> 20) & 0b11) as usize + 1;
+        let mut samples = [0; MAX_CHANNELS];
+        let jdr = [
+            adc.jdr1.read().bits(),
+            adc.jdr2.read().bits(),
+            adc.jdr3.read().bits(),
+            adc.jdr4.read().bits(),
+        ];
+        for (sample, value) in samples.iter_mut().zip(jdr.iter()).take(len) {
+            *sample = (*value & 0xFFFF) as u16;
+        }
+        Some(samples)
+    }
+}
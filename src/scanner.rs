@@ -0,0 +1,115 @@
+//! Polled GPIO change detection
+//!
+//! `PortScanner` samples a set of pins of one port on every timer tick and
+//! queues a timestamped event for every pin that changed. It's an
+//! alternative to EXTI when the 16 lines are used up or when pins with the
+//! same number on different ports must be watched.
+//!
+//! ``` ignore
+//! static mut SCANNER: PortScanner<GPIOB, [Change; 16]> = PortScanner::new(0x00F0);
+//!
+//! // timer interrupt
+//! SCANNER.scan(gpiob);
+//!
+//! // idle loop
+//! while let Some(change) = SCANNER.poll() { .. }
+//! ```
+//!
+//! Changes shorter than a tick are missed; the queue is not shared safely
+//! between contexts on its own, wrap the scanner in a resource or critical
+//! section.
+
+use core::marker::{PhantomData, Unsize};
+use core::ops::Deref;
+
+use heapless::RingBuffer;
+use stm32f411::gpioa;
+
+use gpio::{self, Io};
+
+/// A pin changed state
+#[derive(Clone, Copy)]
+pub struct Change {
+    /// Pin number
+    pub pin: u8,
+    /// New level
+    pub level: Io,
+    /// Tick at which the change was seen
+    pub timestamp: u32,
+}
+
+/// Samples a set of pins and queues their changes
+pub struct PortScanner<T, A>
+    where T: Deref<Target=gpioa::RegisterBlock>,
+          A: Unsize<[Change]>
+{
+    mask: u16,
+    last: u16,
+    ticks: u32,
+    dropped: u32,
+    queue: RingBuffer<Change, A>,
+    _port: PhantomData<fn() -> T>,
+}
+
+impl<T, A> PortScanner<T, A>
+    where T: Deref<Target=gpioa::RegisterBlock>,
+          A: Unsize<[Change]>
+{
+    /// Creates a scanner watching the pins in `mask`
+    pub const fn new(mask: u16) -> Self {
+        PortScanner {
+            mask: mask,
+            last: 0,
+            ticks: 0,
+            dropped: 0,
+            queue: RingBuffer::new(),
+            _port: PhantomData,
+        }
+    }
+
+    /// Takes the reference sample, no events are generated for the initial
+    /// state
+    pub fn init(&mut self, port: &T) {
+        self.last = gpio::read_port(port) & self.mask;
+    }
+
+    /// Samples the pins, must be called once per tick
+    pub fn scan(&mut self, port: &T) {
+        self.ticks = self.ticks.wrapping_add(1);
+
+        let now = gpio::read_port(port) & self.mask;
+        let mut changed = now ^ self.last;
+        self.last = now;
+
+        while changed != 0 {
+            let pin = changed.trailing_zeros() as u8;
+            changed &= changed - 1;
+
+            let level = if now & (1 << pin) != 0 { Io::High } else { Io::Low };
+            let change = Change { pin: pin, level: level, timestamp: self.ticks };
+            if self.queue.enqueue(change).is_err() {
+                self.dropped = self.dropped.wrapping_add(1);
+            }
+        }
+    }
+
+    /// Takes the oldest change from the queue
+    pub fn poll(&mut self) -> Option<Change> {
+        self.queue.dequeue()
+    }
+
+    /// Number of changes lost because the queue was full
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Current tick count, the time base of the timestamps
+    pub fn ticks(&self) -> u32 {
+        self.ticks
+    }
+
+    /// Last sampled state of the watched pins
+    pub fn state(&self) -> u16 {
+        self.last
+    }
+}
@@ -0,0 +1,198 @@
+//! Production self-tests
+//!
+//! Loopback checks meant for hardware test firmware. Each test returns
+//! `true` on success; collect the results in a `Report` to get a bitmask of
+//! the failed tests:
+//!
+//! ``` ignore
+//! let mut report = Report::new();
+//! report.check(Test::Spi, selftest::spi_loopback(&spi));
+//! report.check(Test::Usart, selftest::usart_loopback(&serial));
+//! report.check(Test::Dma, selftest::dma_mem_to_mem(&stream));
+//! if !report.passed() { /* report.failures() */ }
+//! ```
+//!
+//! No test leaves a peripheral in a state the drivers can't recover from, but
+//! they do consume any data pending in the peripheral.
+
+use core::any::Any;
+use core::ptr;
+use core::sync::atomic::{self, Ordering};
+
+use hal;
+use hal::serial::{Read, Write};
+use stm32f411::DMA2;
+
+use dma2::{DMA, Dma};
+use serial::{Serial, Usart};
+use spi2::{SPI, Spi};
+
+/// Busy-poll iterations before a test step is considered failed
+const TIMEOUT: u32 = 100_000;
+
+/// Test pattern: all zeros, all ones, alternating bits and walking ones
+const PATTERN: [u8; 12] = [
+    0x00, 0xFF, 0x55, 0xAA, 0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80,
+];
+
+/// Self-test identifiers, the discriminant is the bit position in the report
+#[derive(Clone, Copy, Debug)]
+pub enum Test {
+    Spi = 0,
+    Usart = 1,
+    Dma = 2,
+}
+
+/// Bitmask of failed tests
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Report(u32);
+
+impl Report {
+    pub fn new() -> Self {
+        Report(0)
+    }
+
+    /// Records the outcome of `test`
+    pub fn check(&mut self, test: Test, passed: bool) {
+        if passed {
+            self.0 &= !(1 << test as u32);
+        } else {
+            self.0 |= 1 << test as u32;
+        }
+    }
+
+    /// Bitmask with a bit set for every failed test
+    pub fn failures(&self) -> u32 {
+        self.0
+    }
+
+    pub fn failed(&self, test: Test) -> bool {
+        self.0 & (1 << test as u32) != 0
+    }
+
+    pub fn passed(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+/// SPI loopback, MOSI must be bridged to MISO externally
+///
+/// The SPI must be configured as master and enabled
+pub fn spi_loopback<'a, S, D>(spi: &Spi<'a, S, D>) -> bool
+    where S: Any + SPI,
+          D: Any + DMA
+{
+    let reg = spi.reg;
+
+    // Flush stale data and a possible overrun
+    unsafe { ptr::read_volatile(&reg.dr as *const _ as *const u8) };
+    reg.sr.read();
+
+    for byte in PATTERN.iter() {
+        if poll(|| hal::Spi::send(spi, *byte)).is_none() {
+            return false;
+        }
+        match poll(|| hal::Spi::read(spi)) {
+            Some(echo) if echo == *byte => {}
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// USART internal loopback using half-duplex mode
+///
+/// In half-duplex mode the receiver listens on the TX line so no wiring is
+/// needed, but the TX pin must be in alternate function mode. The previous
+/// mode is restored afterwards.
+pub fn usart_loopback<'a, U>(serial: &Serial<'a, U>) -> bool
+    where U: Any + Usart
+{
+    let usart = serial.0;
+    let cr3 = usart.cr3.read().bits();
+
+    serial.disable();
+    usart.cr3.modify(|_, w| w.hdsel().set_bit());
+    serial.enable();
+
+    // Flush stale data
+    let _ = serial.read();
+
+    let mut passed = true;
+    for byte in PATTERN.iter() {
+        if poll(|| serial.write(*byte)).is_none() {
+            passed = false;
+            break;
+        }
+        match poll(|| serial.read()) {
+            Some(echo) if echo == *byte => {}
+            _ => {
+                passed = false;
+                break;
+            }
+        }
+    }
+
+    // Let the last frame go out before switching modes
+    let mut timeout = TIMEOUT;
+    while usart.sr.read().tc().bit_is_clear() && timeout > 0 {
+        timeout -= 1;
+    }
+
+    serial.disable();
+    usart.cr3.write(|w| unsafe { w.bits(cr3) });
+    serial.enable();
+
+    passed
+}
+
+/// DMA memory to memory copy and compare
+///
+/// Only DMA2 can do memory to memory transfers. The stream must be disabled
+/// and the DMA2 clock enabled; its configuration is overwritten.
+pub fn dma_mem_to_mem<'a>(stream: &Dma<'a, DMA2>) -> bool {
+    let mut src = [0u8; 64];
+    let mut dst = [0u8; 64];
+    for (i, byte) in src.iter_mut().enumerate() {
+        *byte = PATTERN[i % PATTERN.len()] ^ (i as u8);
+    }
+
+    if stream.is_enabled() {
+        return false;
+    }
+
+    let scr = stream.reg.scr(stream.stream());
+    // DIR = memory to memory, PINC, MINC, byte sized transfers
+    scr.write(|w| unsafe { w.bits((0b10 << 6) | (1 << 9) | (1 << 10)) });
+    stream.clear_flags();
+    stream.set_config(src.as_ptr() as u32, dst.as_mut_ptr() as u32, src.len() as u16);
+    stream.enable();
+
+    let mut timeout = TIMEOUT;
+    while !stream.is_transfer_complete() && !stream.has_transfer_error() && timeout > 0 {
+        timeout -= 1;
+    }
+
+    // The DMA wrote `dst` behind the compiler's back
+    atomic::compiler_fence(Ordering::SeqCst);
+
+    let passed = stream.is_transfer_complete() && !stream.has_transfer_error();
+    stream.disable();
+    stream.clear_flags();
+
+    passed && src.iter().zip(dst.iter()).all(|(a, b)| a == b)
+}
+
+/// Polls a non-blocking operation at most `TIMEOUT` times
+fn poll<T, E, F>(mut f: F) -> Option<T>
+    where F: FnMut() -> ::nb::Result<T, E>
+{
+    for _ in 0..TIMEOUT {
+        match f() {
+            Ok(value) => return Some(value),
+            Err(::nb::Error::WouldBlock) => {}
+            Err(::nb::Error::Other(_)) => return None,
+        }
+    }
+    None
+}
@@ -1,16 +1,35 @@
 use core::any::{Any, TypeId};
+use core::fmt;
+#[cfg(feature = "dma")]
 use core::marker::Unsize;
 use core::ops::Deref;
 use core::ptr;
 
-use cast::u16;
+use cast::u32;
 use hal;
 use hal::serial::Write;
 use nb;
-use time::U32Ext;
+#[cfg(feature = "dma")]
+use static_ref::Static;
+#[cfg(feature = "dma")]
+use circular::CircularSampler;
+#[cfg(feature = "dma")]
+use dma2::{self, Buffer, Chain, DMA, DMAStream, Dma, dma_len};
+#[cfg(feature = "dma")]
+use fault::{self, Fault};
+use cortex_m::interrupt;
+use clock_switch::ClockListener;
+use gpio::{AF7, AF8, AltFn, Alternate, Io, Mode, Pin, PinNumber, Speed, TypedPin};
+use gpio::{NoPin, PA2, PA3, PA9, PA10, PA11, PA12, PA15, PB3, PB6, PB7, PC6, PC7, PD5,
+           PD6};
+use rcc::{ClockError, Clocks};
+use time::{Hertz, Milliseconds, U32Ext};
+use timer::{TIM, TIMBase, Timer};
 
 // use static_ref::Ref;
-use stm32f411::{usart1, USART1, USART2, USART6};
+use stm32f411::{gpioa, usart1, USART1, USART2, USART6};
+#[cfg(feature = "dma")]
+use stm32f411::DMA2;
 
 /// Specialized `Result` type
 pub type Result<T> = ::core::result::Result<T, nb::Error<Error>>;
@@ -19,22 +38,166 @@ pub type Result<T> = ::core::result::Result<T, nb::Error<Error>>;
 pub unsafe trait Usart: Deref<Target = usart1::RegisterBlock> {
     /// IMPLEMENTATION DETAIL
     type Ticks: Into<u32>;
+
+    /// Clock of the bus the instance is attached to
+    fn pclk(clocks: &Clocks) -> Hertz;
 }
 
 unsafe impl Usart for USART1 {
     type Ticks = ::apb2::Ticks;
+
+    fn pclk(clocks: &Clocks) -> Hertz {
+        clocks.pclk2()
+    }
 }
 
 unsafe impl Usart for USART2 {
     type Ticks = ::apb1::Ticks;
+
+    fn pclk(clocks: &Clocks) -> Hertz {
+        clocks.pclk1()
+    }
 }
 
 unsafe impl Usart for USART6 {
-    type Ticks = ::apb1::Ticks;
+    type Ticks = ::apb2::Ticks;
+
+    fn pclk(clocks: &Clocks) -> Hertz {
+        clocks.pclk2()
+    }
+}
+
+/// Alternate function of USART6 on every pin it's available on
+pub const USART6_AF: AF8 = AF8;
+
+/// DMA2 stream that carries the USART6 TX requests, on channel 5
+#[cfg(feature = "dma")]
+pub const USART6_TX_STREAM: DMAStream = DMAStream::Stream6;
+
+/// DMA2 stream that carries the USART6 RX requests, on channel 5
+///
+/// Stream 2 can be used as well
+#[cfg(feature = "dma")]
+pub const USART6_RX_STREAM: DMAStream = DMAStream::Stream1;
+
+/// DMA2 channel of the USART6 requests
+#[cfg(feature = "dma")]
+pub(crate) const USART6_DMA_CHANNEL: u32 = 5;
+
+/// Timer tick rate of `auto_baud`, resolves 115200 baud to ~0.2%
+const AUTO_BAUD_TICK: u32 = 8_000_000;
+/// Longest wait for an edge of the sync character, ~8 ms (1200 baud)
+const AUTO_BAUD_MAX_TICKS: u16 = 60_000;
+/// CR1 OVER8, oversampling by 8
+const OVER8: u32 = 1 << 15;
+
+/// Routes USART6 to PC6 (TX) and PC7 (RX)
+///
+/// The GPIOC clock must be enabled
+pub fn usart6_pins_pc<C>(gpioc: &C)
+    where C: Deref<Target = gpioa::RegisterBlock>
+{
+    Pin::new(6).set_alternate(gpioc, USART6_AF, Speed::High);
+    Pin::new(7).set_alternate(gpioc, USART6_AF, Speed::High);
 }
 
+/// Routes USART6 to PA11 (TX) and PA12 (RX)
+///
+/// The GPIOA clock must be enabled
+pub fn usart6_pins_pa<A>(gpioa: &A)
+    where A: Deref<Target = gpioa::RegisterBlock>
+{
+    Pin::new(11).set_alternate(gpioa, USART6_AF, Speed::High);
+    Pin::new(12).set_alternate(gpioa, USART6_AF, Speed::High);
+}
+
+/// Pin that can carry the TX signal of `U`
+pub unsafe trait TxPin<U> {
+    #[doc(hidden)]
+    fn route(&self);
+}
+
+/// Pin that can carry the RX signal of `U`
+pub unsafe trait RxPin<U> {
+    #[doc(hidden)]
+    fn route(&self);
+}
+
+unsafe impl<U> TxPin<U> for NoPin {
+    fn route(&self) {}
+}
+
+unsafe impl<U> RxPin<U> for NoPin {
+    fn route(&self) {}
+}
+
+/// Routes `usart` to the given pins
+///
+/// ``` ignore
+/// serial::route(usart2, PA2(gpioa), PA3(gpioa));
+/// // receive only USART1
+/// serial::route(usart1, NoPin, PB7(gpiob));
+/// ```
+///
+/// The clocks of the GPIO ports involved must be enabled
+pub fn route<U, TX, RX>(_usart: &U, tx: TX, rx: RX)
+    where U: Usart,
+          TX: TxPin<U>,
+          RX: RxPin<U>
+{
+    tx.route();
+    rx.route();
+}
+
+/// BRR value for a divider of `div` = pclk / baud, `None` if out of range
+///
+/// With OVER8 the fraction has 3 bits and BRR bit 3 must stay clear.
+fn encode_brr(div: u32, over8: bool) -> Option<u32> {
+    if over8 {
+        if div < 8 || div > 0x7FFF {
+            None
+        } else {
+            Some(((div >> 3) << 4) | (div & 0b111))
+        }
+    } else if div < 16 || div > 0xFFFF {
+        None
+    } else {
+        Some(div)
+    }
+}
+
+/// Inverse of `encode_brr`
+fn decode_brr(brr: u32, over8: bool) -> u32 {
+    if over8 {
+        ((brr >> 4) << 3) | (brr & 0b111)
+    } else {
+        brr
+    }
+}
+
+macro_rules! usart_pins {
+    ($USART:ident, $Signal:ident: [$($PXi:ident: $AF:ident),+]) => {
+        $(
+            unsafe impl<'a> $Signal<$USART> for $PXi<'a> {
+                fn route(&self) {
+                    self.set_alternate($AF);
+                }
+            }
+        )+
+    }
+}
+
+usart_pins!(USART1, TxPin: [PA9: AF7, PA15: AF7, PB6: AF7]);
+usart_pins!(USART1, RxPin: [PA10: AF7, PB3: AF7, PB7: AF7]);
+
+usart_pins!(USART2, TxPin: [PA2: AF7, PD5: AF7]);
+usart_pins!(USART2, RxPin: [PA3: AF7, PD6: AF7]);
+
+usart_pins!(USART6, TxPin: [PA11: AF8, PC6: AF8]);
+usart_pins!(USART6, RxPin: [PA12: AF8, PC7: AF8]);
+
 /// An error
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Error {
     /// De-synchronization, excessive noise or a break character detected
     Framing,
@@ -46,6 +209,38 @@ pub enum Error {
     _Extensible,
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Error::Framing => "serial framing error",
+            Error::Noise => "serial noise error",
+            Error::Overrun => "serial RX overrun",
+            Error::_Extensible => "unknown error",
+        })
+    }
+}
+
+/// Auto-baud detection error, see `Serial::auto_baud`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AutoBaudError {
+    /// No start bit before the timeout
+    Timeout,
+    /// The frame wasn't a 0x55 sync character
+    Pattern,
+    /// The measured baud rate can't be set
+    Clock(ClockError),
+}
+
+impl fmt::Display for AutoBaudError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AutoBaudError::Timeout => f.write_str("no auto-baud character received"),
+            AutoBaudError::Pattern => f.write_str("invalid auto-baud character"),
+            AutoBaudError::Clock(ref e) => e.fmt(f),
+        }
+    }
+}
+
 /// Interrupt event
 pub enum Event {
     /// RX buffer Not Empty (new data available)
@@ -56,44 +251,224 @@ pub enum Event {
     Txe,
 }
 
+/// Frame data length, parity included
+#[derive(Clone, Copy, Debug)]
+pub enum WordLength {
+    Bits8,
+    Bits9,
+}
+
+/// Receiver wakeup method, used in multiprocessor mode
+#[derive(Clone, Copy, Debug)]
+pub enum WakeUp {
+    /// Wake up on an idle line
+    IdleLine,
+    /// Wake up on a frame whose MSB is set and whose 4 LSBs match the node
+    /// address
+    AddressMark,
+}
+
 /// Serial interface
 ///
+/// Not `Copy`: `split` consumes the interface, so the `Tx` / `Rx` halves
+/// are the only handles left to this `Serial`.
+///
 /// # Interrupts
 ///
 /// - RXNE
-pub struct Serial<'a, U>(pub &'a U) where U: Any + Usart;
+pub struct Serial<'a, U>(pub(crate) &'a U) where U: Any + Usart;
 
-impl<'a, U> Clone for Serial<'a, U>
+impl<'a, U> Serial<'a, U>
     where U: Any + Usart
 {
-    fn clone(&self) -> Self {
-        *self
+    /// Serial interface on `usart`
+    pub fn new(usart: &'a U) -> Self {
+        Serial(usart)
     }
-}
 
-impl<'a, U> Copy for Serial<'a, U> where U: Any + Usart {}
+    /// Runs `f` on the USART registers, for features this driver lacks
+    ///
+    /// Safe to change: the guard time and prescaler (GTPR, smartcard and
+    /// IrDA), LIN / smartcard / IrDA / half-duplex mode bits of CR2 and CR3
+    /// while UE is clear, and the CTS / RTS flow control bits. Leave UE, TE,
+    /// RE, the DMA enables of CR3, SR and DR alone while data is moving.
+    pub fn with_raw<F, R>(&self, f: F) -> R
+        where F: FnOnce(&U) -> R
+    {
+        f(self.0)
+    }
 
-impl<'a, U> Serial<'a, U>
-    where U: Any + Usart
-{
     /// Initializes the serial interface with a baud rate of `baut_rate` bits
     /// per second
     ///
     /// The serial interface will be configured to use 8 bits of data, 1 stop
-    /// bit, no hardware control and to omit parity checking
-    pub fn init<B>(&self, baud_rate: B)
+    /// bit, no hardware control and to omit parity checking. The interface is
+    /// left disabled if the baud rate can't be set, see `set_baud_rate`.
+    pub fn init<B>(&self, baud_rate: B) -> ::core::result::Result<Hertz, ClockError>
         where B: Into<U::Ticks>
     {
-        self.set_baud_rate(baud_rate);
+        let actual = self.set_baud_rate(baud_rate)?;
         self.enable();
+        Ok(actual)
     }
 
-    pub fn set_baud_rate<B>(&self, baud_rate: B)
+    /// Sets the baud rate given as bus `Ticks` per bit
+    ///
+    /// The `Ticks` count at the 16 MHz reset clock; they're turned back into
+    /// a baud rate and set with `try_set_baud_rate`, for the frozen clocks
+    /// (`Clocks::get`) or the reset clock if `freeze` wasn't called.
+    pub fn set_baud_rate<B>(&self, baud_rate: B) -> ::core::result::Result<Hertz, ClockError>
         where B: Into<U::Ticks>
     {
-        let ticks = baud_rate.into();
-        let baud = ticks.into();
-        self.0.brr.write(|w| unsafe { w.bits(baud) });
+        let ticks: u32 = baud_rate.into().into();
+        if ticks == 0 {
+            return Err(ClockError::BaudUnachievable);
+        }
+        // Both APB `Ticks` have the same frequency
+        let baud_rate = Hertz((::apb1::FREQUENCY + ticks / 2) / ticks);
+
+        let clocks = Clocks::get().unwrap_or_else(Clocks::reset);
+        self.try_set_baud_rate(&clocks, baud_rate)
+    }
+
+    /// Sets the baud rate from the runtime bus clock
+    ///
+    /// Honors the oversampling (OVER8) already selected in CR1. Returns the
+    /// achieved baud rate, or an error if it's off by more than 2% (or out
+    /// of the BRR range), in which case nothing is changed.
+    pub fn try_set_baud_rate(&self, clocks: &Clocks, baud_rate: Hertz)
+        -> ::core::result::Result<Hertz, ClockError>
+    {
+        let pclk = U::pclk(clocks).0;
+        if baud_rate.0 == 0 {
+            return Err(ClockError::BaudUnachievable);
+        }
+
+        // pclk / baud = USARTDIV * 16 (or * 8 with OVER8)
+        let div = (pclk + baud_rate.0 / 2) / baud_rate.0;
+        let brr = match encode_brr(div, self.over8()) {
+            Some(brr) => brr,
+            None => return Err(ClockError::BaudUnachievable),
+        };
+
+        let actual = pclk / div;
+        let error = if actual > baud_rate.0 {
+            actual - baud_rate.0
+        } else {
+            baud_rate.0 - actual
+        };
+        if error * 50 > baud_rate.0 {
+            return Err(ClockError::BaudUnachievable);
+        }
+
+        self.0.brr.write(|w| unsafe { w.bits(brr) });
+        Ok(Hertz(actual))
+    }
+
+    /// Sets the baud rate from a 0x55 sync character sent by the other end
+    ///
+    /// `rx` (the RX pin of this USART, on `port`) is turned into a GPIO
+    /// input while the character comes in, then handed back to the USART.
+    /// The bit edges are timestamped with `timer`,
+    /// which is reconfigured to tick at ~8 MHz; the 8 bit times between the
+    /// end of the start bit and the stop bit are measured with interrupts
+    /// disabled (up to ~7 ms at 1200 baud).
+    ///
+    /// Waits up to `timeout` for the start bit. Returns the baud rate that
+    /// was set.
+    pub fn auto_baud<T, R, P, N, A>(
+        &self,
+        clocks: &Clocks,
+        timer: &Timer<T, R>,
+        port: &P,
+        rx: &TypedPin<P, N, Alternate<A>>,
+        timeout: Milliseconds,
+    ) -> ::core::result::Result<Hertz, AutoBaudError>
+        where T: Any + TIM<R>,
+              R: TIMBase,
+              P: Deref<Target = gpioa::RegisterBlock>,
+              N: PinNumber,
+              A: AltFn
+    {
+        let rx = Pin::<P>::new(rx.number());
+        let timclk = <T as TIM<R>>::timclk(clocks).0;
+        let psc = (timclk + AUTO_BAUD_TICK - 1) / AUTO_BAUD_TICK;
+        let tick = timclk / psc;
+        timer.0.start_with((psc - 1) as u16, 0xFFFF);
+
+        rx.set_mode(port, Mode::Input);
+        let result = self.measure_bit_time(timer.0, port, &rx, tick, timeout);
+        // AFR and the speed are left as they were
+        rx.set_mode(port, Mode::AlternateFunction);
+
+        // Drop what the receiver made of the sync character
+        let _ = self.0.sr.read();
+        let _ = self.0.dr.read();
+
+        let ticks = result?;
+        let baud_rate = Hertz((tick * 8 + ticks / 2) / ticks);
+        self.try_set_baud_rate(clocks, baud_rate).map_err(AutoBaudError::Clock)
+    }
+
+    /// Timer ticks taken by 8 bits of a 0x55 character
+    fn measure_bit_time<T, R, P>(
+        &self,
+        timer: &T,
+        port: &P,
+        rx: &Pin<P>,
+        tick: u32,
+        timeout: Milliseconds,
+    ) -> ::core::result::Result<u32, AutoBaudError>
+        where T: Deref<Target = R>,
+              R: TIMBase,
+              P: Deref<Target = gpioa::RegisterBlock>
+    {
+        let is_high = || match rx.get(port) {
+            Io::High => true,
+            Io::Low => false,
+        };
+
+        // Start bit, with interrupts enabled: the measurement starts at its
+        // end
+        let limit = u64::from(timeout.0) * u64::from(tick) / 1_000;
+        let mut waited = 0u64;
+        let mut last = timer.counter();
+        while is_high() {
+            let now = timer.counter();
+            waited += u64::from(now.wrapping_sub(last));
+            last = now;
+            if waited > limit {
+                return Err(AutoBaudError::Timeout);
+            }
+        }
+
+        interrupt::free(|_| {
+            // 0x55, LSB first: every bit differs from the previous one, 9
+            // edges from the end of the start bit to the stop bit
+            let mut edges = [0u16; 9];
+            let start = timer.counter();
+            let mut level = false;
+            for edge in edges.iter_mut() {
+                while is_high() == level {
+                    // Longest frame at the slowest baud rate
+                    if timer.counter().wrapping_sub(start) > AUTO_BAUD_MAX_TICKS {
+                        return Err(AutoBaudError::Pattern);
+                    }
+                }
+                *edge = timer.counter();
+                level = !level;
+            }
+
+            let total = u32(edges[8].wrapping_sub(edges[0]));
+            // Every bit must be 1/8 of the total, +- 25%
+            for pair in edges.windows(2) {
+                let bit = u32(pair[1].wrapping_sub(pair[0])) * 8;
+                if bit * 4 < total * 3 || bit * 4 > total * 5 {
+                    return Err(AutoBaudError::Pattern);
+                }
+            }
+            Ok(total)
+        })
     }
 
     pub fn enable(&self) {
@@ -106,6 +481,417 @@ impl<'a, U> Serial<'a, U>
     pub fn disable(&self) {
         self.0.cr1.modify(|_, w| w.ue().clear_bit());
     }
+
+    /// Whether CR1 selects oversampling by 8
+    fn over8(&self) -> bool {
+        self.0.cr1.read().bits() & OVER8 != 0
+    }
+
+    /// Sets the frame length, 9 bit frames are read and written with
+    /// `read_u16` / `write_u16`
+    pub fn set_word_length(&self, length: WordLength) {
+        match length {
+            WordLength::Bits8 => self.0.cr1.modify(|_, w| w.m().clear_bit()),
+            WordLength::Bits9 => self.0.cr1.modify(|_, w| w.m().set_bit()),
+        }
+    }
+
+    /// Configures multiprocessor communication
+    ///
+    /// `address` is the 4-bit node address used with `WakeUp::AddressMark`
+    pub fn set_wakeup(&self, wakeup: WakeUp, address: u8) {
+        assert!(address < 16);
+
+        let usart = self.0;
+        usart.cr2.modify(|r, w| unsafe {
+            w.bits((r.bits() & !0xF) | u32::from(address))
+        });
+        match wakeup {
+            WakeUp::IdleLine => usart.cr1.modify(|_, w| w.wake().clear_bit()),
+            WakeUp::AddressMark => usart.cr1.modify(|_, w| w.wake().set_bit()),
+        }
+    }
+
+    /// Puts the receiver in mute mode, it ignores frames until the wakeup
+    /// condition configured with `set_wakeup` occurs
+    pub fn mute(&self) {
+        self.0.cr1.modify(|_, w| w.rwu().set_bit());
+    }
+
+    /// Leaves mute mode
+    pub fn unmute(&self) {
+        self.0.cr1.modify(|_, w| w.rwu().clear_bit());
+    }
+
+    /// Checks if the receiver is in mute mode, the hardware leaves it on its
+    /// own when woken up
+    pub fn is_muted(&self) -> bool {
+        self.0.cr1.read().rwu().bit_is_set()
+    }
+
+    /// Reads a 9 bit frame
+    pub fn read_u16(&self) -> Result<u16> {
+        let usart = self.0;
+        let sr = usart.sr.read();
+
+        if sr.ore().bit_is_set() {
+            Err(nb::Error::Other(Error::Overrun))
+        } else if sr.nf().bit_is_set() {
+            Err(nb::Error::Other(Error::Noise))
+        } else if sr.fe().bit_is_set() {
+            Err(nb::Error::Other(Error::Framing))
+        } else if sr.rxne().bit_is_set() {
+            Ok((usart.dr.read().bits() & 0x1FF) as u16)
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
+    /// Writes a 9 bit frame, bit 8 is the address mark in multiprocessor
+    /// mode
+    pub fn write_u16(&self, word: u16) -> Result<()> {
+        let usart = self.0;
+        let sr = usart.sr.read();
+
+        if sr.ore().bit_is_set() {
+            Err(nb::Error::Other(Error::Overrun))
+        } else if sr.nf().bit_is_set() {
+            Err(nb::Error::Other(Error::Noise))
+        } else if sr.fe().bit_is_set() {
+            Err(nb::Error::Other(Error::Framing))
+        } else if sr.txe().bit_is_set() {
+            usart.dr.write(|w| unsafe { w.bits(u32::from(word & 0x1FF)) });
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
+    /// Starts continuous DMA reception into `buffer`
+    ///
+    /// `dma` must be a stream / channel wired to this USART's RX request
+    #[cfg(feature = "dma")]
+    pub fn circular_rx<'d, D>(&self, dma: &'d Dma<'d, D>, buffer: &'static mut [u8])
+        -> CircularSampler<'d, D, u8>
+        where D: Any + DMA
+    {
+        let usart = self.0;
+        // DMAR
+        usart.cr3.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 6)) });
+        CircularSampler::start(dma, &usart.dr as *const _ as u32, buffer)
+    }
+
+    /// Starts listening for an interrupt `event`
+    pub fn listen(&self, event: Event) {
+        let usart = self.0;
+
+        match event {
+            Event::Rxne => usart.cr1.modify(|_, w| w.rxneie().set_bit()),
+            Event::Tc => usart.cr1.modify(|_, w| w.tcie().set_bit()),
+            Event::Txe => usart.cr1.modify(|_, w| w.txeie().set_bit()),
+        }
+    }
+
+    /// Stops listening for an interrupt `event`
+    pub fn unlisten(&self, event: Event) {
+        let usart = self.0;
+
+        match event {
+            Event::Rxne => usart.cr1.modify(|_, w| w.rxneie().clear_bit()),
+            Event::Tc => usart.cr1.modify(|_, w| w.tcie().clear_bit()),
+            Event::Txe => usart.cr1.modify(|_, w| w.txeie().clear_bit()),
+        }
+    }
+
+    /// Splits the interface into a transmitter and a receiver, to be used
+    /// from different tasks
+    ///
+    /// Configure the interface (baud rate, interrupts, ...) before splitting:
+    /// the halves can only move data.
+    pub fn split(self) -> (Tx<'a, U>, Rx<'a, U>) {
+        (Tx { usart: self.0 }, Rx { usart: self.0 })
+    }
+}
+
+/// Transmitting half of a `Serial` interface
+pub struct Tx<'a, U>
+    where U: 'a + Any + Usart
+{
+    usart: &'a U,
+}
+
+/// Receiving half of a `Serial` interface
+pub struct Rx<'a, U>
+    where U: 'a + Any + Usart
+{
+    usart: &'a U,
+}
+
+// NOTE(unsafe) `Tx` only reads SR and writes DR; `Rx` only reads SR and DR.
+// Reading SR has no side effects on its own (the error flags are cleared by
+// the SR read *followed by* a DR read, which only `Rx` does) and the
+// transmit and receive data registers are distinct in hardware, so each half
+// can live in a different execution context. `Tx` only looks at TXE, a
+// receive error pending in `Rx` doesn't block it. Neither half can modify
+// the control registers.
+unsafe impl<'a, U> Send for Tx<'a, U> where U: Any + Usart {}
+unsafe impl<'a, U> Send for Rx<'a, U> where U: Any + Usart {}
+
+/// Stream style transmission
+///
+/// Modeled on the `embedded-io` `Write` / `WriteReady` traits, but these
+/// are inherent methods: `embedded-io` needs a newer compiler than this
+/// crate builds with, so the traits aren't implemented.
+impl<'a, U> Tx<'a, U>
+    where U: Any + Usart
+{
+    /// Checks if a byte can be written without blocking (TXE)
+    pub fn write_ready(&self) -> bool {
+        self.usart.sr.read().txe().bit_is_set()
+    }
+
+    /// Writes some of `bytes`, returns how many
+    ///
+    /// Blocks until at least one byte is written, then only writes as long
+    /// as that doesn't block. `Ok(0)` only for an empty `bytes`.
+    pub fn write_some(&self, bytes: &[u8]) -> ::core::result::Result<usize, Error> {
+        let mut written = 0;
+        for byte in bytes {
+            match Write::write(self, *byte) {
+                Ok(()) => written += 1,
+                Err(nb::Error::WouldBlock) if written > 0 => break,
+                Err(nb::Error::WouldBlock) => {
+                    block!(Write::write(self, *byte))?;
+                    written += 1;
+                }
+                Err(nb::Error::Other(e)) => return Err(e),
+            }
+        }
+        Ok(written)
+    }
+
+    /// Writes all of `bytes`
+    pub fn write_all(&self, bytes: &[u8]) -> ::core::result::Result<(), Error> {
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            let n = self.write_some(rest)?;
+            rest = &rest[n..];
+        }
+        Ok(())
+    }
+
+    /// Waits until the last byte has left the shift register (TC)
+    pub fn flush(&self) {
+        while self.usart.sr.read().tc().bit_is_clear() {}
+    }
+}
+
+impl<'a, U> fmt::Write for Tx<'a, U>
+    where U: Any + Usart
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_all(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+impl<'a, U> Write<u8> for Tx<'a, U>
+where
+    U: Any + Usart,
+{
+    type Error = Error;
+
+    fn write(&self, byte: u8) -> Result<()> {
+        // NOTE unlike `Serial::write` this ignores the receive error flags,
+        // only the `Rx` half can clear them
+        let usart = self.usart;
+        if usart.sr.read().txe().bit_is_set() {
+            // NOTE(write_volatile) see NOTE in `Serial::read`
+            unsafe {
+                ptr::write_volatile(&usart.dr as *const _ as *mut u8, byte)
+            }
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+}
+
+impl<'a, U> hal::serial::Write<&'a [u8]> for Tx<'a, U>
+where
+    U: Any + Usart,
+{
+    type Error = Error;
+
+    fn write(&self, buffer: &'a [u8]) -> Result<()> {
+        for byte in buffer {
+            block!(Write::<u8>::write(self, *byte)).map_err(nb::Error::Other)?;
+        }
+        Ok(())
+    }
+}
+
+/// Stream style reception
+///
+/// Modeled on the `embedded-io` `Read` / `ReadReady` traits, but these are
+/// inherent methods, the traits aren't implemented (see `Tx`).
+impl<'a, U> Rx<'a, U>
+    where U: Any + Usart
+{
+    /// Checks if a byte can be read without blocking (RXNE)
+    pub fn read_ready(&self) -> bool {
+        self.usart.sr.read().rxne().bit_is_set()
+    }
+
+    /// Reads some bytes into `buffer`, returns how many
+    ///
+    /// Blocks until at least one byte arrives, then only reads the bytes
+    /// already received. An error after the first byte ends the read early
+    /// and is reported by the next call. `Ok(0)` only for an empty
+    /// `buffer`.
+    pub fn read_some(&self, buffer: &mut [u8]) -> ::core::result::Result<usize, Error> {
+        let mut read = 0;
+        for slot in buffer.iter_mut() {
+            match hal::serial::Read::read(self) {
+                Ok(byte) => *slot = byte,
+                Err(nb::Error::WouldBlock) if read == 0 => {
+                    *slot = block!(hal::serial::Read::read(self))?;
+                }
+                Err(nb::Error::Other(e)) if read == 0 => return Err(e),
+                Err(_) => break,
+            }
+            read += 1;
+        }
+        Ok(read)
+    }
+}
+
+impl<'a, U> hal::serial::Read<u8> for Rx<'a, U>
+where
+    U: Any + Usart,
+{
+    type Error = Error;
+
+    fn read(&self) -> Result<u8> {
+        hal::serial::Read::read(&Serial(self.usart))
+    }
+}
+
+#[cfg(feature = "dma")]
+impl<'a> Serial<'a, USART6> {
+    /// Starts sending `buffer` through DMA2 stream 6
+    ///
+    /// USART6 sits on APB2 and can run at up to PCLK2 / 16 (6.25 Mbps at
+    /// 100 MHz), too fast to feed byte by byte. `release` the buffer to wait
+    /// for the end of the transfer. The DMA2 clock must be enabled.
+    pub fn write_dma<B>(&self, dma: &Dma<DMA2>, buffer: &Static<Buffer<B>>)
+        -> ::core::result::Result<(), dma2::Error>
+        where B: Unsize<[u8]>
+    {
+        let usart = self.0;
+
+        // USART6_TX requests are routed to DMA2 stream 6
+        match dma.stream() {
+            DMAStream::Stream6 => {}
+            _ => return Err(dma2::Error::Fault(fault::raise(Fault::WrongStream))),
+        }
+        if dma.is_enabled() {
+            return Err(dma2::Error::InUse);
+        }
+
+        buffer.check_stream(dma.stream())?;
+        let slice: &[u8] = buffer.try_lock()?;
+        let len = match dma_len(slice.len()) {
+            Ok(len) => len,
+            Err(e) => {
+                buffer.cancel_lock();
+                return Err(e);
+            }
+        };
+
+        // CHSEL = 5, byte sized, MINC, memory to peripheral
+        dma.reg.scr(dma.stream()).write(|w| unsafe {
+            w.bits((USART6_DMA_CHANNEL << 25) | (1 << 10) | (0b01 << 6))
+        });
+        dma.clear_flags();
+        dma.set_config(slice.as_ptr() as u32, &usart.dr as *const _ as u32, len);
+
+        // DMAT
+        usart.cr3.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 7)) });
+        dma.enable();
+        Ok(())
+    }
+
+    /// Sends `segments` back-to-back through DMA2 stream 6
+    ///
+    /// See `dma2::Chain`; the slices must be `'static` because the chain
+    /// proceeds from the DMA interrupt. The DMA2 clock must be enabled.
+    pub fn send_chain<'d>(&self, dma: &'d Dma<'d, DMA2>, segments: &'static [&'static [u8]])
+        -> ::core::result::Result<Chain<'d, DMA2>, dma2::Error>
+    {
+        let usart = self.0;
+
+        match dma.stream() {
+            DMAStream::Stream6 => {}
+            _ => return Err(dma2::Error::Fault(fault::raise(Fault::WrongStream))),
+        }
+        if dma.is_enabled() {
+            return Err(dma2::Error::InUse);
+        }
+
+        // CHSEL = 5, byte sized, MINC, memory to peripheral
+        dma.reg.scr(dma.stream()).write(|w| unsafe {
+            w.bits((USART6_DMA_CHANNEL << 25) | (1 << 10) | (0b01 << 6))
+        });
+
+        // DMAT
+        usart.cr3.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 7)) });
+        dma.start_chain(&usart.dr as *const _ as u32, segments)
+    }
+
+    /// Starts continuous reception into `buffer` through DMA2 stream 1
+    ///
+    /// Like `circular_rx` but also selects the DMA channel. The DMA2 clock
+    /// must be enabled.
+    pub fn circular_rx_dma<'d>(&self, dma: &'d Dma<'d, DMA2>, buffer: &'static mut [u8])
+        -> ::core::result::Result<CircularSampler<'d, DMA2, u8>, dma2::Error>
+    {
+        // USART6_RX requests are routed to DMA2 stream 1 or 2
+        match dma.stream() {
+            DMAStream::Stream1 | DMAStream::Stream2 => {}
+            _ => return Err(dma2::Error::Fault(fault::raise(Fault::WrongStream))),
+        }
+        if dma.is_enabled() {
+            return Err(dma2::Error::InUse);
+        }
+        dma_len(buffer.len())?;
+
+        dma.reg.scr(dma.stream()).modify(|r, w| unsafe {
+            w.bits((r.bits() & !(0b111 << 25)) | (USART6_DMA_CHANNEL << 25))
+        });
+        Ok(self.circular_rx(dma, buffer))
+    }
+}
+
+impl<'a, U> ClockListener for Serial<'a, U>
+    where U: Any + Usart
+{
+    /// Keeps the baud rate, within the BRR resolution
+    fn clocks_changed(&self, old: &Clocks, new: &Clocks) {
+        let old_pclk = u64::from(U::pclk(old).0);
+        if old_pclk == 0 {
+            return;
+        }
+
+        let over8 = self.over8();
+        let (min, max) = if over8 { (8, 0x7FFF) } else { (16, 0xFFFF) };
+
+        let div = u64::from(decode_brr(self.0.brr.read().bits() & 0xFFFF, over8));
+        let div = (div * u64::from(U::pclk(new).0) + old_pclk / 2) / old_pclk;
+        let div = if div < min { min } else if div > max { max } else { div };
+        if let Some(brr) = encode_brr(div as u32, over8) {
+            self.0.brr.write(|w| unsafe { w.bits(brr) });
+        }
+    }
 }
 
 impl<'a, U> hal::serial::Read<u8> for Serial<'a, U>
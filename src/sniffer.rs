@@ -0,0 +1,155 @@
+//! Passive bus capture for bring-up
+//!
+//! Turns the board into a simple protocol analyzer for another board's SPI
+//! or UART lines: the peripheral only receives, a DMA stream keeps a ring
+//! buffer filled and `stream` forwards the captured bytes to an ITM
+//! stimulus port, where the debug probe picks them up over SWO.
+//!
+//! ``` ignore
+//! // SPI1 as a receive-only slave on the target's SCK / MOSI / CS
+//! static mut RING: [u8; 4096] = [0; 4096];
+//!
+//! let mut sniffer = Sniffer::spi(&spi1, NSS::HardSlaveInput, config, unsafe { &mut RING })?;
+//! loop {
+//!     if let Err(circular::Error::Overrun) = sniffer.stream(&itm.stim[1]) {
+//!         // bytes were lost, `overruns` counts these
+//!     }
+//! }
+//! ```
+//!
+//! One peripheral captures one data line. MOSI and MISO take two SPIs (a
+//! slave receives on its MOSI pin, so the target's MISO goes to the MOSI
+//! pin of the second one) and TX and RX two USARTs; stream them to
+//! different stimulus ports to tell them apart on the host. The ring must
+//! hold what arrives while the SWO output lags behind: SWO is usually
+//! slower than the bus being captured.
+//!
+//! The outputs stay off (RXONLY, TE cleared), but leave the MISO / TX pins
+//! unrouted or as inputs so nothing can drive the target's lines. I2C can't
+//! be captured this way: the I2C peripheral takes part in the bus, it
+//! acknowledges its own address and stretches the clock.
+
+use core::any::Any;
+
+use cortex_m::itm;
+use cortex_m::peripheral::Stim;
+
+use circular::{CircularSampler, Error};
+use dma2::{DMA, Dma};
+#[cfg(feature = "spi")]
+use dma2;
+#[cfg(feature = "spi")]
+use spi2::{Config, NSS, Role, SPI, Spi};
+#[cfg(feature = "usart")]
+use serial::{Serial, Usart};
+
+/// Receive-only capture into a ring buffer
+pub struct Sniffer<'a, U>
+    where U: 'a + Any + DMA
+{
+    rx: CircularSampler<'a, U, u8>,
+    captured: u32,
+    overruns: u32,
+}
+
+impl<'a, U> Sniffer<'a, U>
+    where U: Any + DMA
+{
+    /// Captures from an already running circular reception
+    pub fn new(rx: CircularSampler<'a, U, u8>) -> Self {
+        Sniffer { rx: rx, captured: 0, overruns: 0 }
+    }
+
+    /// Turns `spi` into a receive-only slave and starts capturing on its RX
+    /// stream, 8-bit frames
+    ///
+    /// With `NSS::HardSlaveInput` only the frames sent while the target
+    /// selects its slave (NSS pin wired to its chip select) are captured,
+    /// with `NSS::SoftSlave` every clock edge is. `config` gives the clock
+    /// polarity / phase and bit order of the target's bus. Fails if `spi`
+    /// has no RX stream, or it is in use.
+    #[cfg(feature = "spi")]
+    pub fn spi<S>(spi: &Spi<'a, S, U>, nss: NSS, config: Config, buffer: &'static mut [u8])
+        -> Result<Self, dma2::Error>
+        where S: Any + SPI
+    {
+        spi.disable();
+        spi.nss(nss);
+        spi.init(Role::SLAVE);
+        // BIDIMODE = 0, RXONLY = 1: MISO is never driven
+        spi.reg.cr1.modify(|r, w| unsafe {
+            w.bits((r.bits() & !(1 << 15)) | (1 << 10))
+        });
+        spi.set_config_disabled(config);
+
+        let rx = spi.circular_rx(buffer)?;
+        spi.enable();
+        Ok(Sniffer::new(rx))
+    }
+
+    /// Turns `serial` into a receiver only and starts capturing through
+    /// `dma`, a stream / channel wired to its RX request
+    ///
+    /// The baud rate and frame format must already match the target's,
+    /// e.g. through `Serial::auto_baud`.
+    #[cfg(feature = "usart")]
+    pub fn usart<S>(serial: Serial<S>, dma: &'a Dma<'a, U>, buffer: &'static mut [u8]) -> Self
+        where S: Any + Usart
+    {
+        serial.0.cr1.modify(|_, w| w.te().clear_bit().re().set_bit().ue().set_bit());
+        Sniffer::new(serial.circular_rx(dma, buffer))
+    }
+
+    /// Passes the bytes captured since the last call to `f`, oldest first,
+    /// as two slices
+    ///
+    /// Returns the number of bytes. After an overrun the capture goes on
+    /// from the newest data.
+    pub fn poll<F>(&mut self, f: F) -> Result<usize, Error>
+        where F: FnOnce(&[u8], &[u8])
+    {
+        let result = self.rx.read_available(|a, b| {
+            f(a, b);
+            a.len() + b.len()
+        });
+
+        match result {
+            Ok(n) => {
+                self.captured = self.captured.wrapping_add(n as u32);
+                Ok(n)
+            }
+            Err(Error::Overrun) => {
+                self.overruns = self.overruns.wrapping_add(1);
+                Err(Error::Overrun)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes the bytes captured since the last call to `stim`
+    ///
+    /// Blocks while the stimulus port FIFO is full.
+    pub fn stream(&mut self, stim: &Stim) -> Result<usize, Error> {
+        self.poll(|a, b| {
+            itm::write_all(stim, a);
+            itm::write_all(stim, b);
+        })
+    }
+
+    /// Bytes captured so far, wraps around
+    pub fn captured(&self) -> u32 {
+        self.captured
+    }
+
+    /// Number of times the ring overflowed and data was lost
+    pub fn overruns(&self) -> u32 {
+        self.overruns
+    }
+
+    /// Stops the capture and gives the ring buffer back
+    ///
+    /// The peripheral is left in its receive-only configuration.
+    pub fn stop(self) -> &'static mut [u8] {
+        self.rx.stop()
+    }
+}
@@ -0,0 +1,299 @@
+//! Software (bit-banged) I2C master
+//!
+//! SCL and SDA can be any two GPIOs, on the same port or not. Both are
+//! driven open-drain, the bus needs pull ups (the internal ones, ~40k, are
+//! enabled as a fallback but are only good for short, slow buses). Slaves
+//! that stretch the clock are waited for, up to 10 ms.
+//!
+//! ``` ignore
+//! let i2c = SoftI2c::new(gpiob, Pin::new(10), gpioc, Pin::new(12), &clocks, 100_000.hz());
+//! i2c.write_read(ADDRESS, &[WHO_AM_I], &mut id)?;
+//! ```
+//!
+//! Same transactions as the hardware `i2c::I2c`. The bit timing comes from
+//! `delay::CyclesToTime` busy-waits, interrupts stretch it (harmless, I2C is
+//! clocked by the master) but don't break it; the actual SCL frequency is a
+//! bit lower than requested because of the GPIO accesses.
+
+use core::fmt;
+use core::ops::Deref;
+
+use stm32f411::gpioa;
+
+use clock_switch::ClockListener;
+use delay::CyclesToTime;
+use gpio::{Io, Mode, Pin, Pupd};
+use rcc::Clocks;
+use time::{Hertz, Microseconds};
+
+/// Longest clock stretching accepted from a slave
+const STRETCH_TIMEOUT_US: u32 = 10_000;
+/// Fastest supported SCL, fast mode
+const SCL_MAX: u32 = 400_000;
+
+/// An error
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The slave didn't acknowledge its address or a byte
+    Nack,
+    /// SDA was low while released: another master, or a stuck slave
+    ArbitrationLost,
+    /// A slave stretched the clock for too long
+    Timeout,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Error::Nack => "I2C NACK",
+            Error::ArbitrationLost => "I2C arbitration lost",
+            Error::Timeout => "I2C clock stretching timeout",
+        })
+    }
+}
+
+/// I2C master on two GPIO pins
+///
+/// Addresses are 7-bit, unshifted.
+pub struct SoftI2c<'a, C, D>
+    where C: 'a + Deref<Target=gpioa::RegisterBlock>,
+          D: 'a + Deref<Target=gpioa::RegisterBlock>
+{
+    scl_port: &'a C,
+    scl: Pin<C>,
+    sda_port: &'a D,
+    sda: Pin<D>,
+    delay: CyclesToTime,
+    half_period_ns: u32,
+}
+
+impl<'a, C, D> SoftI2c<'a, C, D>
+    where C: Deref<Target=gpioa::RegisterBlock>,
+          D: Deref<Target=gpioa::RegisterBlock>
+{
+    /// Configures both pins as open-drain outputs, released (high)
+    ///
+    /// `clocks` calibrates the bit timing, `speed` is at most 400 kHz.
+    pub fn new(
+        scl_port: &'a C,
+        scl: Pin<C>,
+        sda_port: &'a D,
+        sda: Pin<D>,
+        clocks: &Clocks,
+        speed: Hertz,
+    ) -> Self {
+        assert!(speed.0 > 0 && speed.0 <= SCL_MAX);
+
+        scl.set(scl_port, Io::High);
+        scl.set_open_drain(scl_port, true);
+        scl.set_pupd(scl_port, Pupd::PullUp);
+        scl.set_mode(scl_port, Mode::Output);
+        sda.set(sda_port, Io::High);
+        sda.set_open_drain(sda_port, true);
+        sda.set_pupd(sda_port, Pupd::PullUp);
+        sda.set_mode(sda_port, Mode::Output);
+
+        SoftI2c {
+            scl_port: scl_port,
+            scl: scl,
+            sda_port: sda_port,
+            sda: sda,
+            delay: CyclesToTime::new(clocks),
+            half_period_ns: 500_000_000 / speed.0,
+        }
+    }
+
+    /// Frees a bus left with SDA held low by a slave, e.g. after a reset of
+    /// the MCU in the middle of a read
+    ///
+    /// Clocks SCL until the slave releases SDA (at most 9 pulses), then
+    /// sends a STOP.
+    pub fn recover(&self) -> Result<(), Error> {
+        self.sda.set(self.sda_port, Io::High);
+        for _ in 0..9 {
+            if self.sda_is_high() {
+                break;
+            }
+            self.scl.set(self.scl_port, Io::Low);
+            self.half_period();
+            self.scl_release()?;
+            self.half_period();
+        }
+        self.stop()
+    }
+
+    /// Writes `bytes` to `address`
+    pub fn write(&self, address: u8, bytes: &[u8]) -> Result<(), Error> {
+        self.transaction(|i2c| {
+            i2c.start(address, false)?;
+            i2c.send(bytes)?;
+            i2c.stop()
+        })
+    }
+
+    /// Reads `buffer.len()` bytes from `address`
+    pub fn read(&self, address: u8, buffer: &mut [u8]) -> Result<(), Error> {
+        self.transaction(|i2c| {
+            i2c.start(address, true)?;
+            i2c.receive(buffer)?;
+            i2c.stop()
+        })
+    }
+
+    /// Writes `bytes` then, after a repeated START, reads `buffer.len()`
+    /// bytes; the usual register read
+    pub fn write_read(&self, address: u8, bytes: &[u8], buffer: &mut [u8])
+        -> Result<(), Error>
+    {
+        self.transaction(|i2c| {
+            i2c.start(address, false)?;
+            i2c.send(bytes)?;
+            i2c.start(address, true)?;
+            i2c.receive(buffer)?;
+            i2c.stop()
+        })
+    }
+
+    /// Runs `f`; on error the bus is released with a STOP
+    fn transaction<F>(&self, f: F) -> Result<(), Error>
+        where F: FnOnce(&Self) -> Result<(), Error>
+    {
+        let result = f(self);
+        if result.is_err() {
+            let _ = self.stop();
+        }
+        result
+    }
+
+    /// Sends a (repeated) START and the address, `read` sets the R/W bit
+    fn start(&self, address: u8, read: bool) -> Result<(), Error> {
+        assert!(address < 0x80);
+
+        // SCL may be low, before a repeated START
+        self.sda.set(self.sda_port, Io::High);
+        self.half_period();
+        self.scl_release()?;
+        if !self.sda_is_high() {
+            return Err(Error::ArbitrationLost);
+        }
+
+        self.sda.set(self.sda_port, Io::Low);
+        self.half_period();
+        self.scl.set(self.scl_port, Io::Low);
+
+        self.write_byte(address << 1 | if read { 1 } else { 0 })
+    }
+
+    fn stop(&self) -> Result<(), Error> {
+        self.scl.set(self.scl_port, Io::Low);
+        self.sda.set(self.sda_port, Io::Low);
+        self.half_period();
+        self.scl_release()?;
+        self.half_period();
+        self.sda.set(self.sda_port, Io::High);
+        self.half_period();
+        Ok(())
+    }
+
+    fn send(&self, bytes: &[u8]) -> Result<(), Error> {
+        for byte in bytes {
+            self.write_byte(*byte)?;
+        }
+        Ok(())
+    }
+
+    /// Reads into `buffer`, NACKing the last byte
+    fn receive(&self, buffer: &mut [u8]) -> Result<(), Error> {
+        let last = buffer.len().saturating_sub(1);
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = self.read_byte(i != last)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a byte, MSB first, and checks the slave's ACK
+    fn write_byte(&self, byte: u8) -> Result<(), Error> {
+        for i in (0..8).rev() {
+            self.write_bit(byte & (1 << i) != 0)?;
+        }
+        if self.read_bit()? {
+            Err(Error::Nack)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Reads a byte, MSB first, then ACKs it if `ack`
+    fn read_byte(&self, ack: bool) -> Result<u8, Error> {
+        let mut byte = 0;
+        for _ in 0..8 {
+            byte = (byte << 1) | if self.read_bit()? { 1 } else { 0 };
+        }
+        self.write_bit(!ack)?;
+        Ok(byte)
+    }
+
+    /// Clocks out a bit, SCL is low before and after
+    fn write_bit(&self, bit: bool) -> Result<(), Error> {
+        self.sda.set(self.sda_port, if bit { Io::High } else { Io::Low });
+        self.half_period();
+        self.scl_release()?;
+        if bit && !self.sda_is_high() {
+            return Err(Error::ArbitrationLost);
+        }
+        self.half_period();
+        self.scl.set(self.scl_port, Io::Low);
+        Ok(())
+    }
+
+    /// Clocks in a bit, SCL is low before and after
+    fn read_bit(&self) -> Result<bool, Error> {
+        self.sda.set(self.sda_port, Io::High);
+        self.half_period();
+        self.scl_release()?;
+        let bit = self.sda_is_high();
+        self.half_period();
+        self.scl.set(self.scl_port, Io::Low);
+        Ok(bit)
+    }
+
+    /// Releases SCL and waits for it to go high, a slave may be stretching
+    /// the clock
+    fn scl_release(&self) -> Result<(), Error> {
+        self.scl.set(self.scl_port, Io::High);
+
+        let mut waited = 0;
+        loop {
+            match self.scl.get(self.scl_port) {
+                Io::High => return Ok(()),
+                Io::Low => {}
+            }
+            if waited >= STRETCH_TIMEOUT_US {
+                return Err(Error::Timeout);
+            }
+            self.delay.delay_us(Microseconds(1));
+            waited += 1;
+        }
+    }
+
+    fn sda_is_high(&self) -> bool {
+        match self.sda.get(self.sda_port) {
+            Io::High => true,
+            Io::Low => false,
+        }
+    }
+
+    fn half_period(&self) {
+        self.delay.delay_ns(self.half_period_ns)
+    }
+}
+
+impl<'a, C, D> ClockListener for SoftI2c<'a, C, D>
+    where C: Deref<Target=gpioa::RegisterBlock>,
+          D: Deref<Target=gpioa::RegisterBlock>
+{
+    /// Keeps the SCL frequency
+    fn clocks_changed(&self, old: &Clocks, new: &Clocks) {
+        self.delay.clocks_changed(old, new);
+    }
+}
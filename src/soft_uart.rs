@@ -0,0 +1,104 @@
+//! Software (bit-banged) UART transmitter
+//!
+//! Sends 8N1 frames on any GPIO, for debug output when every USART pin is
+//! taken by the application:
+//!
+//! ``` ignore
+//! let mut tx = soft_uart::Tx::new(gpiob, Pin::new(3), &clocks, 115_200.hz());
+//! writeln!(tx, "adc = {}", value).unwrap();
+//! ```
+//!
+//! The bit timing comes from `delay::CyclesToTime` busy-waits and each
+//! frame is sent with interrupts disabled, ~87 us at 115200 baud, so a
+//! long message delays interrupts by one frame at most, not by the whole
+//! string. The GPIO accesses make the bits a bit longer than nominal, well
+//! under the ~4% a receiver tolerates at any supported baud rate.
+
+use core::fmt;
+use core::ops::Deref;
+
+use cortex_m::interrupt;
+use stm32f411::gpioa;
+
+use clock_switch::ClockListener;
+use delay::CyclesToTime;
+use gpio::{Io, Mode, Pin, Speed};
+use rcc::Clocks;
+use time::Hertz;
+
+/// Fastest supported baud rate
+const BAUD_MAX: u32 = 115_200;
+
+/// UART transmitter on one GPIO pin
+pub struct Tx<'a, P>
+    where P: 'a + Deref<Target=gpioa::RegisterBlock>
+{
+    port: &'a P,
+    pin: Pin<P>,
+    delay: CyclesToTime,
+    bit_ns: u32,
+}
+
+impl<'a, P> Tx<'a, P>
+    where P: Deref<Target=gpioa::RegisterBlock>
+{
+    /// Configures `pin` as a push-pull output, idle (high)
+    ///
+    /// `clocks` calibrates the bit timing, `baud_rate` is at most 115200.
+    pub fn new(port: &'a P, pin: Pin<P>, clocks: &Clocks, baud_rate: Hertz) -> Self {
+        assert!(baud_rate.0 > 0 && baud_rate.0 <= BAUD_MAX);
+
+        pin.set(port, Io::High);
+        pin.set_open_drain(port, false);
+        pin.set_speed(port, Speed::Low);
+        pin.set_mode(port, Mode::Output);
+
+        Tx {
+            port: port,
+            pin: pin,
+            delay: CyclesToTime::new(clocks),
+            bit_ns: 1_000_000_000 / baud_rate.0,
+        }
+    }
+
+    /// Sends one frame: start bit, 8 data bits LSB first, stop bit
+    pub fn write(&self, byte: u8) {
+        interrupt::free(|_| {
+            self.bit(Io::Low);
+            for i in 0..8 {
+                self.bit(if byte & (1 << i) != 0 { Io::High } else { Io::Low });
+            }
+            self.bit(Io::High);
+        })
+    }
+
+    /// Sends `bytes`
+    pub fn write_all(&self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write(*byte);
+        }
+    }
+
+    fn bit(&self, level: Io) {
+        self.pin.set(self.port, level);
+        self.delay.delay_ns(self.bit_ns);
+    }
+}
+
+impl<'a, P> ClockListener for Tx<'a, P>
+    where P: Deref<Target=gpioa::RegisterBlock>
+{
+    /// Keeps the baud rate
+    fn clocks_changed(&self, old: &Clocks, new: &Clocks) {
+        self.delay.clocks_changed(old, new);
+    }
+}
+
+impl<'a, P> fmt::Write for Tx<'a, P>
+    where P: 'a + Deref<Target=gpioa::RegisterBlock>
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_all(s.as_bytes());
+        Ok(())
+    }
+}
@@ -2,40 +2,197 @@
 //!
 //! You can use the `Spi` interface with these SPI instances
 //!
+//! - SPI1, SPI4 and SPI5 on APB2
+//! - SPI2 and SPI3 on APB1
+//!
+//! SPI5 (AF6) pins: SCK on PB0, PE2 or PE12; MISO on PA12, PE5 or PE13;
+//! MOSI on PA10, PB8, PE6 or PE14. `spi5_pins` sets up PB0 / PA12 / PA10.
+//!
+//! `route` takes any valid SCK / MISO / MOSI combination of an instance,
+//! invalid ones don't compile:
+//!
+//! ``` ignore
+//! // SPI1 with SCK on PB3 but MISO / MOSI on PA6 / PA7
+//! spi2::route(spi1, PB3(gpiob), PA6(gpioa), PA7(gpioa));
+//! // transmit only SPI2
+//! spi2::route(spi2, PB13(gpiob), NoPin, PB15(gpiob));
+//! ```
 
 use core::any::Any;
+use core::fmt;
 use core::ops::Deref;
 use core::ptr;
 use core::marker::Unsize;
 
-use cast::u16;
 
 use static_ref::Static;
 use hal;
 use nb;
-use stm32f411::{SPI1, SPI4, i2s2ext};
+use stm32f411::{SPI1, SPI2, SPI3, SPI4, SPI5, i2s2ext};
 
-//use dma::{self, Buffer, DmaStream1, DmaStream2};
-use dma2::{self, DMA, Dma, Buffer, DMAStream};
+use dma2::{self, DMA, Dma, Buffer, Chain, DMAStream, Transfer, dma_len};
+use pool::{PoolBuffer, PoolTransfer};
+use circular::CircularSampler;
+use fault::{self, Fault};
+use gpio::{AF5, AF6, AF7, Io, Mode, Pin, Speed};
+pub use gpio::{NoPin, PA5, PA6, PA7, PA10, PA12, PB0, PB3, PB4, PB5, PB8, PB10, PB12,
+               PB13, PB14, PB15, PC2, PC3, PC7, PC10, PC11, PC12, PD3, PD6, PE2, PE5,
+               PE6, PE12, PE13, PE14};
+use clock_switch::ClockListener;
+use rcc::Clocks;
+use time::Hertz;
+use timeout::{self, with_timeout};
 
 /// SPI instance that can be used with the `Spi` abstraction
 pub unsafe trait SPI: Deref<Target = i2s2ext::RegisterBlock> {
     // type Ticks: Into<u32>;
 
     // fn init(&self, role: i2s2ext::cr1::MSTRW);
+
+    /// Clock of the bus the instance is attached to
+    fn pclk(clocks: &Clocks) -> Hertz;
 }
 
 unsafe impl SPI for SPI1 {
+    fn pclk(clocks: &Clocks) -> Hertz {
+        clocks.pclk2()
+    }
+}
+
+unsafe impl SPI for SPI2 {
+    fn pclk(clocks: &Clocks) -> Hertz {
+        clocks.pclk1()
+    }
+}
+
+unsafe impl SPI for SPI3 {
+    fn pclk(clocks: &Clocks) -> Hertz {
+        clocks.pclk1()
+    }
 }
 
 unsafe impl SPI for SPI4 {
+    fn pclk(clocks: &Clocks) -> Hertz {
+        clocks.pclk2()
+    }
+}
+
+unsafe impl SPI for SPI5 {
+    fn pclk(clocks: &Clocks) -> Hertz {
+        clocks.pclk2()
+    }
+}
+
+/// Alternate function of SPI5 on every pin it's available on
+pub const SPI5_AF: AF6 = AF6;
+
+/// Routes SPI5 to PB0 (SCK), PA12 (MISO) and PA10 (MOSI)
+///
+/// The GPIOA and GPIOB clocks must be enabled
+pub fn spi5_pins<A, B>(gpioa: &A, gpiob: &B)
+    where A: Deref<Target = ::stm32f411::gpioa::RegisterBlock>,
+          B: Deref<Target = ::stm32f411::gpioa::RegisterBlock>
+{
+    Pin::new(0).set_alternate(gpiob, SPI5_AF, Speed::High);
+    Pin::new(12).set_alternate(gpioa, SPI5_AF, Speed::High);
+    Pin::new(10).set_alternate(gpioa, SPI5_AF, Speed::High);
+}
+
+/// Pin that can carry the SCK signal of `S`
+pub unsafe trait SckPin<S> {
+    #[doc(hidden)]
+    fn route(&self);
+}
+
+/// Pin that can carry the MISO signal of `S`
+pub unsafe trait MisoPin<S> {
+    #[doc(hidden)]
+    fn route(&self);
+}
+
+/// Pin that can carry the MOSI signal of `S`
+pub unsafe trait MosiPin<S> {
+    #[doc(hidden)]
+    fn route(&self);
+}
+
+unsafe impl<S> MisoPin<S> for NoPin {
+    fn route(&self) {}
+}
+
+unsafe impl<S> MosiPin<S> for NoPin {
+    fn route(&self) {}
+}
+
+/// Routes `spi` to the given pins
+///
+/// The clocks of the GPIO ports involved must be enabled
+pub fn route<S, SCK, MISO, MOSI>(_spi: &S, sck: SCK, miso: MISO, mosi: MOSI)
+    where S: SPI,
+          SCK: SckPin<S>,
+          MISO: MisoPin<S>,
+          MOSI: MosiPin<S>
+{
+    sck.route();
+    miso.route();
+    mosi.route();
+}
+
+macro_rules! spi_pins {
+    ($SPI:ident, $Signal:ident: [$($PXi:ident: $AF:ident),+]) => {
+        $(
+            unsafe impl<'a> $Signal<$SPI> for $PXi<'a> {
+                fn route(&self) {
+                    self.set_alternate($AF);
+                }
+            }
+        )+
+    }
+}
+
+spi_pins!(SPI1, SckPin: [PA5: AF5, PB3: AF5]);
+spi_pins!(SPI1, MisoPin: [PA6: AF5, PB4: AF5]);
+spi_pins!(SPI1, MosiPin: [PA7: AF5, PB5: AF5]);
+
+spi_pins!(SPI2, SckPin: [PB10: AF5, PB13: AF5, PC7: AF5, PD3: AF5]);
+spi_pins!(SPI2, MisoPin: [PB14: AF5, PC2: AF5]);
+spi_pins!(SPI2, MosiPin: [PB15: AF5, PC3: AF5]);
+
+spi_pins!(SPI3, SckPin: [PB3: AF6, PB12: AF7, PC10: AF6]);
+spi_pins!(SPI3, MisoPin: [PB4: AF6, PC11: AF6]);
+spi_pins!(SPI3, MosiPin: [PB5: AF6, PC12: AF6, PD6: AF5]);
+
+spi_pins!(SPI4, SckPin: [PE2: AF5, PE12: AF5]);
+spi_pins!(SPI4, MisoPin: [PE5: AF5, PE13: AF5]);
+spi_pins!(SPI4, MosiPin: [PE6: AF5, PE14: AF5]);
+
+spi_pins!(SPI5, SckPin: [PB0: AF6, PE2: AF6, PE12: AF6]);
+spi_pins!(SPI5, MisoPin: [PA12: AF6, PE5: AF6, PE13: AF6]);
+spi_pins!(SPI5, MosiPin: [PA10: AF6, PB8: AF6, PE6: AF6, PE14: AF6]);
+
+/// SPI frame: `u8` for 8-bit frames, `u16` for 16-bit frames
+pub unsafe trait Word: Copy {
+    #[doc(hidden)]
+    const DFF: bool;
+    #[doc(hidden)]
+    const DMA_SIZE: u32;
+}
+
+unsafe impl Word for u8 {
+    const DFF: bool = false;
+    const DMA_SIZE: u32 = 0b00;
+}
+
+unsafe impl Word for u16 {
+    const DFF: bool = true;
+    const DMA_SIZE: u32 = 0b01;
 }
 
 /// SPI result
 pub type Result<T> = ::core::result::Result<T, nb::Error<Error>>;
 
 /// SPI error
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Error {
     /// Overrun occurred
     Overrun,
@@ -43,10 +200,36 @@ pub enum Error {
     ModeFault,
     /// CRC error
     Crc,
+    /// The requested frequency is below the slowest achievable rate
+    Frequency,
+    /// A blocking operation didn't complete before the timer expired
+    Timeout,
     #[doc(hidden)]
     _Extensible,
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Error::Overrun => "SPI overrun",
+            Error::ModeFault => "SPI mode fault",
+            Error::Crc => "SPI CRC error",
+            Error::Frequency => "SPI frequency out of range",
+            Error::Timeout => "SPI timeout",
+            Error::_Extensible => "unknown error",
+        })
+    }
+}
+
+impl From<timeout::Error<Error>> for Error {
+    fn from(e: timeout::Error<Error>) -> Self {
+        match e {
+            timeout::Error::Timeout => Error::Timeout,
+            timeout::Error::Other(e) => e,
+        }
+    }
+}
+
 /// Interrupt event
 pub enum Event {
     /// RX buffer Not Empty (new data available)
@@ -69,10 +252,82 @@ pub use stm32f411::i2s2ext::cr1::CPHAW as Phase;
 pub use stm32f411::i2s2ext::cr1::BRW as BaudRatePreScale;
 pub use stm32f411::i2s2ext::cr1::MSTRW as Role;
 
+/// Frame settings that can be changed while the driver is in use, see
+/// `Spi::with_config`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Config {
+    /// 16-bit frames instead of 8-bit ones
+    pub data_16bit: bool,
+    /// Least significant bit first
+    pub lsb_first: bool,
+    /// Clock idles high
+    pub cpol: bool,
+    /// Data captured on the second clock edge
+    pub cpha: bool,
+}
+
+// CR1 bits covered by `Config`
+const CR1_CPHA: u32 = 1 << 0;
+const CR1_CPOL: u32 = 1 << 1;
+const CR1_SPE: u32 = 1 << 6;
+const CR1_LSBFIRST: u32 = 1 << 7;
+const CR1_DFF: u32 = 1 << 11;
+/// CR1 bits owned by `Config`
+const CONFIG_MASK: u32 = CR1_DFF | CR1_LSBFIRST | CR1_CPOL | CR1_CPHA;
+
+// CR2
+const CR2_ERRIE: u32 = 1 << 5;
+
+// SR
+const SR_CRCERR: u32 = 1 << 4;
+
+impl Config {
+    fn from_cr1(cr1: u32) -> Self {
+        Config {
+            data_16bit: cr1 & CR1_DFF != 0,
+            lsb_first: cr1 & CR1_LSBFIRST != 0,
+            cpol: cr1 & CR1_CPOL != 0,
+            cpha: cr1 & CR1_CPHA != 0,
+        }
+    }
+
+    fn cr1_bits(&self) -> u32 {
+        let mut bits = 0;
+        if self.data_16bit {
+            bits |= CR1_DFF;
+        }
+        if self.lsb_first {
+            bits |= CR1_LSBFIRST;
+        }
+        if self.cpol {
+            bits |= CR1_CPOL;
+        }
+        if self.cpha {
+            bits |= CR1_CPHA;
+        }
+        bits
+    }
+}
+
+/// Slave select management
+///
+/// A master sees a mode fault (MODF), and drops out of master mode, as soon
+/// as its internal NSS goes low. With software management the internal NSS
+/// is the SSI bit, so `SoftMaster` keeps it high; with `HardMasterOutput`
+/// the NSS pin is driven by the peripheral itself. A master with
+/// `HardSlaveInput` faults whenever another master pulls its NSS pin low,
+/// which is the point of that setting on multi-master buses.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum NSS {
-    Soft,
-    HardInput,
-    HardOutput,
+    /// Master, NSS pin free for other uses (SSM = 1, SSI = 1)
+    SoftMaster,
+    /// Slave permanently selected, NSS pin free (SSM = 1, SSI = 0)
+    SoftSlave,
+    /// Master driving the NSS pin low while enabled (SSM = 0, SSOE = 1)
+    HardMasterOutput,
+    /// NSS pin is an input: the slave select of a slave, or the fault
+    /// input of a multi-master bus (SSM = 0, SSOE = 0)
+    HardSlaveInput,
 }
 
 /// Serial Peripheral Interface
@@ -107,6 +362,18 @@ impl<'a, S, D> Spi<'a, S, D>
         Spi {reg: reg, role: role, dmarx:dmarx, dmatx:dmatx}
     }
 
+    /// Runs `f` on the SPI registers, for features this driver lacks
+    ///
+    /// Safe to change: the CRC polynomial (CRCPR) and CRCEN / CRCNEXT while
+    /// SPE is clear, the I2S registers of SPI2 / SPI3, and the interrupt
+    /// enables of CR2 this driver doesn't `listen` to. Leave SPE, the DMA
+    /// enables of CR2, SR and DR alone while a transfer is in flight.
+    pub fn with_raw<F, R>(&self, f: F) -> R
+        where F: FnOnce(&S) -> R
+    {
+        f(self.reg)
+    }
+
     pub fn init(&self, role: Role) {
         self.reg.cr1.modify(|_, w| w.mstr().variant(role));
     }
@@ -131,21 +398,48 @@ impl<'a, S, D> Spi<'a, S, D>
         self.reg.cr1.modify(|_, w| w.cpha().variant(phase));
     }
 
+    /// Configures the slave select management
+    ///
+    /// Call this before setting MSTR (`init`) and enabling the peripheral,
+    /// so a master never runs with its internal NSS low.
     pub fn nss(&self, nss: NSS) {
-        match nss {
-            NSS::HardInput => self.reg.cr1.modify(|_, w| w.ssm().clear_bit()),
-            NSS::HardOutput => self.reg.cr2.modify(|_, w| w.ssoe().set_bit()),
-            NSS::Soft => {
-                self.reg.cr1.modify(|_, w| w.ssm().set_bit());
-                self.reg.cr2.modify(|_, w| w.ssoe().set_bit());
-            }
-        }
+        let (ssm, ssi, ssoe) = match nss {
+            NSS::SoftMaster => (true, true, false),
+            NSS::SoftSlave => (true, false, false),
+            NSS::HardMasterOutput => (false, false, true),
+            NSS::HardSlaveInput => (false, false, false),
+        };
+
+        self.reg.cr2.modify(|_, w| w.ssoe().bit(ssoe));
+        self.reg.cr1.modify(|_, w| w.ssm().bit(ssm).ssi().bit(ssi));
     }
 
     pub fn baud_rate_prescaler(&self, scale: BaudRatePreScale) {
         self.reg.cr1.modify(|_, w| w.br().variant(scale));
     }
 
+    /// Sets the fastest SCK frequency that doesn't exceed `freq`
+    ///
+    /// Returns the achieved frequency, or `Error::Frequency` if even the
+    /// largest prescaler is too fast, in which case nothing is changed.
+    pub fn set_frequency(&self, clocks: &Clocks, freq: Hertz)
+        -> ::core::result::Result<Hertz, Error>
+    {
+        let pclk = S::pclk(clocks).0;
+
+        // fPCLK / 2^(BR + 1)
+        let br = match (0..8).find(|br| pclk >> (br + 1) <= freq.0) {
+            Some(br) => br,
+            None => return Err(Error::Frequency),
+        };
+
+        self.reg.cr1.modify(|r, w| unsafe {
+            w.bits((r.bits() & !(0b111 << 3)) | (br << 3))
+        });
+
+        Ok(Hertz(pclk >> (br + 1)))
+    }
+
     pub fn msb_first(&self, msb: bool) {
         if msb {
             self.reg.cr1.modify(|_, w| w.lsbfirst().clear_bit());
@@ -154,6 +448,11 @@ impl<'a, S, D> Spi<'a, S, D>
         }
     }
 
+    /// Selects the TI frame format
+    ///
+    /// In TI mode the NSS pin carries the frame sync pulse, generated by
+    /// the master, and the `nss` setting and the clock polarity / phase are
+    /// ignored. The F411 has no NSS pulse mode for the Motorola format.
     pub fn ti_mode(&self, mode: bool) {
         if mode {
             self.reg.cr2.modify(|_, w| w.frf().set_bit());
@@ -170,91 +469,516 @@ impl<'a, S, D> Spi<'a, S, D>
         }
     }
 
+    /// Current frame settings
+    pub fn config(&self) -> Config {
+        Config::from_cr1(self.reg.cr1.read().bits())
+    }
+
+    /// Applies `config`
+    ///
+    /// Waits for the ongoing frame to finish, the peripheral is disabled
+    /// while CR1 is rewritten and re-enabled afterwards if it was enabled.
+    /// Don't call this with a DMA transfer in flight.
+    ///
+    /// `timer` bounds the wait, e.g. a slave whose master stopped clocking;
+    /// on `Error::Timeout` nothing is changed.
+    pub fn set_config<T>(&self, timer: &T, config: Config) -> ::core::result::Result<(), Error>
+        where T: hal::Timer
+    {
+        let spi = self.reg;
+        let cr1 = spi.cr1.read().bits();
+        if cr1 & CONFIG_MASK == config.cr1_bits() {
+            return Ok(());
+        }
+
+        if cr1 & CR1_SPE != 0 {
+            self.timed(timer, |s| with_timeout(timer, || s.poll_idle()).map_err(Error::from))?;
+            spi.cr1.write(|w| unsafe { w.bits(cr1 & !CR1_SPE) });
+        }
+
+        self.write_config(cr1, config);
+        Ok(())
+    }
+
+    /// `set_config` for a disabled peripheral, there is no frame to wait for
+    pub(crate) fn set_config_disabled(&self, config: Config) {
+        let cr1 = self.reg.cr1.read().bits();
+        debug_assert!(cr1 & CR1_SPE == 0);
+        self.write_config(cr1, config);
+    }
+
+    /// Writes `config` into `cr1`, SPE must be clear or the bus idle
+    fn write_config(&self, cr1: u32, config: Config) {
+        let spi = self.reg;
+        let cr1 = (cr1 & !CONFIG_MASK) | config.cr1_bits();
+        spi.cr1.write(|w| unsafe { w.bits(cr1 & !CR1_SPE) });
+        spi.cr1.write(|w| unsafe { w.bits(cr1) });
+    }
+
+    /// Runs `f` with the frame settings changed by `change`, then restores
+    /// the previous ones
+    ///
+    /// ``` ignore
+    /// // shift register that wants LSB first
+    /// let sent = spi.with_config(&timer, |cfg| cfg.lsb_first = true, |spi| {
+    ///     block!(spi.send(0x81))
+    /// })?;
+    /// sent?;
+    /// ```
+    ///
+    /// Fails if either `set_config` times out; `f` doesn't run if the first
+    /// one does.
+    pub fn with_config<T, C, F, R>(&self, timer: &T, change: C, f: F)
+        -> ::core::result::Result<R, Error>
+        where T: hal::Timer,
+              C: FnOnce(&mut Config),
+              F: FnOnce(&Self) -> R
+    {
+        let previous = self.config();
+        let mut config = previous;
+        change(&mut config);
+
+        self.set_config(timer, config)?;
+        let result = f(self);
+        self.set_config(timer, previous)?;
+
+        Ok(result)
+    }
+
     pub fn enable(&self) {
         self.reg.cr1.modify(|_, w| w.spe().set_bit())
     }
 
+    /// Fills `buffer`, clocking out `dummy` for every byte
+    ///
+    /// Only one frame is in flight at a time: the next dummy byte is written
+    /// once the previous frame was read, so RX can't overrun however late
+    /// the CPU gets (interrupts, low `hclk`), the bus just idles. A stale
+    /// byte or overrun left by earlier `send`s without `read`s is discarded
+    /// first.
+    ///
+    /// `timer` (see `timeout`) bounds the whole read; a slave whose master
+    /// stops clocking ends in `Error::Timeout`.
+    pub fn read_exact<T>(&self, timer: &T, buffer: &mut [u8], dummy: u8)
+        -> ::core::result::Result<(), Error>
+        where T: hal::Timer
+    {
+        self.timed(timer, |spi| {
+            // Wait for earlier frames to finish, then drain RXNE
+            with_timeout(timer, || spi.poll_not_busy())?;
+            spi.clear_overrun();
+
+            for byte in buffer.iter_mut() {
+                with_timeout(timer, || hal::Spi::send(spi, dummy))?;
+                *byte = with_timeout(timer, || hal::Spi::read(spi))?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Runs `f` with `timer` restarted, its timeout covers all of `f`
+    fn timed<T, F, R>(&self, timer: &T, f: F) -> ::core::result::Result<R, Error>
+        where T: hal::Timer,
+              F: FnOnce(&Self) -> ::core::result::Result<R, Error>
+    {
+        timer.restart();
+        timer.resume();
+        let result = f(self);
+        timer.pause();
+        result
+    }
+
+    /// `Ok` once no frame is being shifted (BSY clear)
+    fn poll_not_busy(&self) -> nb::Result<(), Error> {
+        if self.reg.sr.read().bsy().bit_is_set() {
+            Err(nb::Error::WouldBlock)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// `Ok` once the last written frame has left the bus (TXE set, BSY
+    /// clear)
+    fn poll_idle(&self) -> nb::Result<(), Error> {
+        if self.reg.sr.read().txe().bit_is_clear() {
+            Err(nb::Error::WouldBlock)
+        } else {
+            self.poll_not_busy()
+        }
+    }
+
     pub fn disable(&self) {
         self.reg.cr1.modify(|_, w| w.spe().clear_bit())
     }
 
+    /// Clears a mode fault and enables the peripheral again
+    ///
+    /// A mode fault (NSS pulled low while master) clears MSTR and SPE, so
+    /// every later `send` / `read` fails with `Error::ModeFault`. This runs
+    /// the reference manual sequence, a SR access then a CR1 write, which
+    /// restores the `role` given to `new`. Release whatever drives NSS
+    /// first, or the fault comes right back.
+    pub fn recover_from_mode_fault(&self) {
+        let spi = self.reg;
+        spi.sr.read();
+        spi.cr1.modify(|_, w| w.mstr().variant(self.role).spe().set_bit());
+    }
+
+    /// Clears an overrun by reading DR then SR
+    ///
+    /// The byte in DR is the last one received before the overrun, the ones
+    /// after it are lost; it's discarded too.
+    pub fn clear_overrun(&self) {
+        let spi = self.reg;
+        unsafe { ptr::read_volatile(&spi.dr as *const _ as *const u8) };
+        spi.sr.read();
+    }
+
+    /// Starts listening for the error interrupt (overrun, mode fault, CRC
+    /// error), see `on_error`
+    pub fn listen_errors(&self) {
+        self.reg.cr2.modify(|r, w| unsafe { w.bits(r.bits() | CR2_ERRIE) });
+    }
+
+    /// Stops listening for the error interrupt
+    pub fn unlisten_errors(&self) {
+        self.reg.cr2.modify(|r, w| unsafe { w.bits(r.bits() & !CR2_ERRIE) });
+    }
+
+    /// Handles the error interrupt: clears the error flag and returns the
+    /// error, `None` if there's none
+    ///
+    /// A mode fault goes through `recover_from_mode_fault`, an overrun
+    /// through `clear_overrun`; a CRC error is just cleared. Transfers in
+    /// flight are not restarted.
+    pub fn on_error(&self) -> Option<Error> {
+        let spi = self.reg;
+        let sr = spi.sr.read();
+
+        if sr.modf().bit_is_set() {
+            self.recover_from_mode_fault();
+            Some(Error::ModeFault)
+        } else if sr.ovr().bit_is_set() {
+            self.clear_overrun();
+            Some(Error::Overrun)
+        } else if sr.crcerr().bit_is_set() {
+            // NOTE CRCERR is cleared by writing 0, the other flags are read
+            // only
+            spi.sr.write(|w| unsafe { w.bits(!SR_CRCERR) });
+            Some(Error::Crc)
+        } else {
+            None
+        }
+    }
+
+    /// Like `on_error`, calling `f` with the error if there was one
+    ///
+    /// ``` ignore
+    /// spi.listen_errors();
+    ///
+    /// // SPI1 interrupt
+    /// SPI.handle_error(|e| ERRORS.fetch_add(1, Ordering::Relaxed));
+    /// ```
+    pub fn handle_error<F>(&self, f: F)
+        where F: FnOnce(Error)
+    {
+        if let Some(error) = self.on_error() {
+            f(error);
+        }
+    }
+
     pub fn send_dma<B>(&self, buffer: &Static<Buffer<B>>)
         -> ::core::result::Result<(), dma2::Error>
     where B: Unsize<[u8]>
+    {
+        self._send_dma::<u8, B>(buffer)
+    }
+
+    /// Like `send_dma` with 16-bit frames
+    pub fn send_dma16<B>(&self, buffer: &Static<Buffer<B>>)
+        -> ::core::result::Result<(), dma2::Error>
+    where B: Unsize<[u16]>
+    {
+        self._send_dma::<u16, B>(buffer)
+    }
+
+    fn _send_dma<W, B>(&self, buffer: &Static<Buffer<B>>)
+        -> ::core::result::Result<(), dma2::Error>
+    where W: Word,
+          B: Unsize<[W]>
     {
         let spi = self.reg;
-        let dma = self.dmatx.unwrap();
+        let dma = self.tx_stream()?;
 
         if dma.is_enabled() {
             return Err(dma2::Error::InUse)
         }
 
-        let buffer: &[u8] = buffer.lock();
+        buffer.check_stream(dma.stream())?;
+        let slice: &[W] = buffer.try_lock()?;
+        let len = match dma_len(slice.len()) {
+            Ok(len) => len,
+            Err(e) => {
+                buffer.cancel_lock();
+                return Err(e);
+            }
+        };
+
+        self.set_frame::<W>(&[dma]);
         dma.set_config(
-            buffer.as_ptr() as u32,
+            slice.as_ptr() as u32,
             &spi.dr as *const _ as u32,
-            u16(buffer.len()).unwrap()
+            len
         );
 
+        // TXDMAEN
+        self.reg.cr2.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 1)) });
+        dma.enable();
+        Ok(())
+    }
+
+    /// Like `send_dma` but takes ownership of `buffer` until the returned
+    /// transfer is `wait`ed on
+    ///
+    /// Works with 8-bit and 16-bit frames, the frame format follows `W`
+    pub fn send_transfer<W, B>(&self, buffer: &'static mut B)
+        -> ::core::result::Result<Transfer<'a, D, B>, dma2::Error>
+    where W: Word,
+          B: Unsize<[W]>
+    {
+        let dma = self.tx_stream()?;
+
+        if dma.is_enabled() {
+            return Err(dma2::Error::InUse)
+        }
+
+        self.set_frame::<W>(&[dma]);
+        // TXDMAEN
+        self.reg.cr2.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 1)) });
+        dma.transfer::<W, B>(&self.reg.dr as *const _ as u32, buffer)
+    }
+
+    /// Like `send_transfer` with a block of a `pool::Pool`
+    pub fn send_pooled<W, B>(&self, buffer: PoolBuffer<B>)
+        -> ::core::result::Result<PoolTransfer<'a, D, B>, dma2::Error>
+    where W: Word,
+          B: Unsize<[W]>
+    {
+        let dma = self.tx_stream()?;
+
+        if dma.is_enabled() {
+            return Err(dma2::Error::InUse)
+        }
+
+        self.set_frame::<W>(&[dma]);
+        // TXDMAEN
+        self.reg.cr2.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 1)) });
+        dma.transfer_pooled::<W, B>(&self.reg.dr as *const _ as u32, buffer)
+    }
+
+    /// Starts sending `slice` through the TX stream, without taking it
+    ///
+    /// NOTE for drivers that stream out parts of a buffer they own: the
+    /// caller keeps `slice` alive and untouched until the stream is done.
+    pub(crate) fn start_tx<W>(&self, slice: &[W])
+        -> ::core::result::Result<(), dma2::Error>
+    where W: Word
+    {
+        let dma = self.tx_stream()?;
+
+        if dma.is_enabled() {
+            return Err(dma2::Error::InUse)
+        }
+        let len = dma_len(slice.len())?;
+
+        self.set_frame::<W>(&[dma]);
+        dma.clear_flags();
+        dma.set_config(slice.as_ptr() as u32, &self.reg.dr as *const _ as u32, len);
+
+        // TXDMAEN
+        self.reg.cr2.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 1)) });
         dma.enable();
         Ok(())
     }
 
+    /// Sends `segments` back-to-back through the TX stream, 8-bit frames
+    ///
+    /// The slices must be `'static` because the chain proceeds from the DMA
+    /// interrupt after this returns, see `dma2::Chain`.
+    pub fn send_chain(&self, segments: &'static [&'static [u8]])
+        -> ::core::result::Result<Chain<'a, D>, dma2::Error>
+    {
+        let dma = self.tx_stream()?;
+
+        if dma.is_enabled() {
+            return Err(dma2::Error::InUse)
+        }
+
+        self.set_frame::<u8>(&[dma]);
+        // TXDMAEN
+        self.reg.cr2.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 1)) });
+        dma.start_chain(&self.reg.dr as *const _ as u32, segments)
+    }
+
     pub fn rxtx_dma<B>(&self,
         tx_buffer: &Buffer<B>,
         rx_buffer: &Buffer<B>)
         -> ::core::result::Result<(), dma2::Error>
     where B: Unsize<[u8]>
+    {
+        self._rxtx_dma::<u8, B>(tx_buffer, rx_buffer)
+    }
+
+    /// Like `rxtx_dma` with 16-bit frames
+    pub fn rxtx_dma16<B>(&self,
+        tx_buffer: &Buffer<B>,
+        rx_buffer: &Buffer<B>)
+        -> ::core::result::Result<(), dma2::Error>
+    where B: Unsize<[u16]>
+    {
+        self._rxtx_dma::<u16, B>(tx_buffer, rx_buffer)
+    }
+
+    fn _rxtx_dma<W, B>(&self,
+        tx_buffer: &Buffer<B>,
+        rx_buffer: &Buffer<B>)
+        -> ::core::result::Result<(), dma2::Error>
+    where W: Word,
+          B: Unsize<[W]>
     {
         let spi = self.reg;
-        let dma_tx = self.dmatx.unwrap();
-        let dma_rx = self.dmarx.unwrap();
+        let dma_tx = self.tx_stream()?;
+        let dma_rx = self.rx_stream()?;
 
         if dma_tx.is_enabled() || dma_rx.is_enabled() {
             return Err(dma2::Error::InUse)
         }
 
-        let _tx_buffer: &[u8] = tx_buffer.lock();
+        tx_buffer.check_stream(dma_tx.stream())?;
+        rx_buffer.check_stream(dma_rx.stream())?;
+        let _tx_buffer: &[W] = tx_buffer.try_lock()?;
+        let _rx_buffer: &mut [W] = match rx_buffer.try_lock_mut() {
+            Ok(slice) => slice,
+            Err(e) => {
+                tx_buffer.cancel_lock();
+                return Err(e);
+            }
+        };
+        let lens = (dma_len(_tx_buffer.len()), dma_len(_rx_buffer.len()));
+        let (tx_len, rx_len) = match lens {
+            (Ok(tx_len), Ok(rx_len)) => (tx_len, rx_len),
+            (Err(e), _) | (_, Err(e)) => {
+                tx_buffer.cancel_lock();
+                rx_buffer.cancel_lock();
+                return Err(e);
+            }
+        };
+
+        self.set_frame::<W>(&[dma_tx, dma_rx]);
         dma_tx.set_config(
             _tx_buffer.as_ptr() as u32,
             &spi.dr as *const _ as u32,
-            u16(_tx_buffer.len()).unwrap()
+            tx_len
         );
-
-        let _rx_buffer: &[u8] = rx_buffer.lock();
         dma_rx.set_config(
             &spi.dr as *const _ as u32,
             _rx_buffer.as_ptr() as u32,
-            u16(_rx_buffer.len()).unwrap()
+            rx_len
         );
 
+        // RXDMAEN, TXDMAEN
+        self.reg.cr2.modify(|r, w| unsafe { w.bits(r.bits() | 0b11) });
         dma_rx.enable();
         dma_tx.enable();
         Ok(())
     }
 
+    /// Starts continuous DMA reception into `buffer` on the RX stream
+    ///
+    /// Useful in slave mode, or in master receive-only mode
+    pub fn circular_rx(&self, buffer: &'static mut [u8])
+        -> ::core::result::Result<CircularSampler<'a, D, u8>, dma2::Error>
+    {
+        let dma = self.rx_stream()?;
+        dma_len(buffer.len())?;
+        if dma.is_enabled() {
+            return Err(dma2::Error::InUse);
+        }
+
+        self.set_frame::<u8>(&[dma]);
+        // RXDMAEN
+        self.reg.cr2.modify(|r, w| unsafe { w.bits(r.bits() | 1) });
+        Ok(CircularSampler::start(dma, &self.reg.dr as *const _ as u32, buffer))
+    }
+
+    /// TX stream, its absence goes through the `fault` policy
+    pub(crate) fn tx_stream(&self) -> ::core::result::Result<&'a Dma<'a, D>, dma2::Error> {
+        match self.dmatx {
+            Some(dma) => Ok(dma),
+            None => Err(dma2::Error::Fault(fault::raise(Fault::NoStream))),
+        }
+    }
+
+    /// RX stream, its absence goes through the `fault` policy
+    pub(crate) fn rx_stream(&self) -> ::core::result::Result<&'a Dma<'a, D>, dma2::Error> {
+        match self.dmarx {
+            Some(dma) => Ok(dma),
+            None => Err(dma2::Error::Fault(fault::raise(Fault::NoStream))),
+        }
+    }
+
+    /// Matches the SPI frame format and the DMA data sizes to `W`
+    ///
+    /// NDTR counts items of `PSIZE`, so with both sizes set from the buffer
+    /// element type the buffer length is the right count and buffers are
+    /// always suitably aligned.
+    pub(crate) fn set_frame<W>(&self, streams: &[&Dma<'a, D>])
+        where W: Word
+    {
+        let cr1 = &self.reg.cr1;
+        let dff = cr1.read().bits() & (1 << 11) != 0;
+        if dff != W::DFF {
+            // DFF can only be changed while the SPI is disabled
+            let spe = cr1.read().bits() & (1 << 6);
+            cr1.modify(|r, w| unsafe { w.bits(r.bits() & !(1 << 6)) });
+            cr1.modify(|r, w| unsafe {
+                let bits = r.bits() & !(1 << 11);
+                w.bits(if W::DFF { bits | (1 << 11) } else { bits })
+            });
+            cr1.modify(|r, w| unsafe { w.bits(r.bits() | spe) });
+        }
+
+        for dma in streams {
+            // PSIZE, MSIZE
+            dma.reg.scr(dma.stream()).modify(|r, w| unsafe {
+                w.bits((r.bits() & !(0b1111 << 11)) | (W::DMA_SIZE << 11) | (W::DMA_SIZE << 13))
+            });
+        }
+    }
+
     pub fn transfer<B>(&self, tx_buffer: &[B], rx_buffer: &[B])
         -> ::core::result::Result<(), dma2::Error>
     where B: Unsize<[u8]>
     {
         let spi = self.reg;
-        let dma_tx = self.dmatx.unwrap();
-        let dma_rx = self.dmarx.unwrap();
+        let dma_tx = self.tx_stream()?;
+        let dma_rx = self.rx_stream()?;
 
         if dma_tx.is_enabled() {
             return Err(dma2::Error::InUse)
         }
 
+        let len = dma_len(tx_buffer.len())?;
         dma_tx.set_config(
             tx_buffer.as_ptr() as u32,
             &spi.dr as *const _ as u32,
-            u16(tx_buffer.len()).unwrap()
+            len
         );
 
         dma_rx.set_config(
             &spi.dr as *const _ as u32,
             rx_buffer.as_ptr() as u32,
-            u16(tx_buffer.len()).unwrap()
+            len
         );
 
         dma_tx.enable();
@@ -263,6 +987,250 @@ impl<'a, S, D> Spi<'a, S, D>
     }
 }
 
+/// Fixed size full-duplex DMA transfer with its configuration computed
+/// up front
+///
+/// Meant for control loops that exchange the same amount of data every
+/// period: `start` only clears the stream flags and rewrites NDTR and the
+/// control register of both streams.
+///
+/// ``` ignore
+/// let mut xfer = spi.prepare(&TX, unsafe { &mut RX })?;
+/// // control ISR
+/// xfer.start()?;
+/// let rx = block!(xfer.wait())?;
+/// ```
+pub struct PreparedTransfer<'a, S, D, W>
+    where S: 'a + Any + SPI,
+          D: 'a + Any + DMA,
+          W: 'static + Word
+{
+    /// NOTE keeps the SPI, and its DMA request setup, borrowed
+    _spi: &'a Spi<'a, S, D>,
+    dma_tx: &'a Dma<'a, D>,
+    dma_rx: &'a Dma<'a, D>,
+    tx: &'static [W],
+    rx: &'static mut [W],
+    tx_scr: u32,
+    rx_scr: u32,
+}
+
+impl<'a, S, D> Spi<'a, S, D>
+    where S: Any + SPI,
+          D: Any + DMA
+{
+    /// Configures both DMA streams for a `tx` / `rx` exchange and caches the
+    /// result
+    ///
+    /// The streams must already be set up (channel, direction, priority...).
+    /// The buffers are handed over for good since the DMA keeps their
+    /// addresses. Buffers of different lengths, or longer than 65535 frames,
+    /// and a missing stream go through the `fault` policy.
+    pub fn prepare<W>(&'a self, tx: &'static [W], rx: &'static mut [W])
+        -> ::core::result::Result<PreparedTransfer<'a, S, D, W>, dma2::Error>
+        where W: 'static + Word
+    {
+        if tx.len() != rx.len() {
+            return Err(dma2::Error::Fault(fault::raise(Fault::LengthMismatch)));
+        }
+        let len = dma_len(tx.len())?;
+
+        let dma_tx = self.tx_stream()?;
+        let dma_rx = self.rx_stream()?;
+        if dma_tx.is_enabled() || dma_rx.is_enabled() {
+            return Err(dma2::Error::InUse);
+        }
+
+        self.set_frame::<W>(&[dma_tx, dma_rx]);
+        dma_tx.set_config(tx.as_ptr() as u32, &self.reg.dr as *const _ as u32, len);
+        dma_rx.set_config(&self.reg.dr as *const _ as u32, rx.as_ptr() as u32, len);
+
+        // RXDMAEN, TXDMAEN
+        self.reg.cr2.modify(|r, w| unsafe { w.bits(r.bits() | 0b11) });
+
+        // EN is cleared by hardware at the end of each transfer
+        let tx_scr = dma_tx.reg.scr(dma_tx.stream()).read().bits() & !1;
+        let rx_scr = dma_rx.reg.scr(dma_rx.stream()).read().bits() & !1;
+
+        Ok(PreparedTransfer {
+            _spi: self,
+            dma_tx: dma_tx,
+            dma_rx: dma_rx,
+            tx: tx,
+            rx: rx,
+            tx_scr: tx_scr,
+            rx_scr: rx_scr,
+        })
+    }
+}
+
+impl<'a, S, D, W> PreparedTransfer<'a, S, D, W>
+    where S: Any + SPI,
+          D: Any + DMA,
+          W: 'static + Word
+{
+    /// Starts the exchange
+    pub fn start(&mut self) -> ::core::result::Result<(), dma2::Error> {
+        let dma_tx = self.dma_tx;
+        let dma_rx = self.dma_rx;
+
+        if dma_tx.is_enabled() || dma_rx.is_enabled() {
+            return Err(dma2::Error::InUse);
+        }
+
+        let len = self.tx.len() as u16;
+        dma_rx.clear_flags();
+        dma_tx.clear_flags();
+        dma_rx.reg.sndtr(dma_rx.stream()).write(|w| unsafe { w.ndt().bits(len) });
+        dma_tx.reg.sndtr(dma_tx.stream()).write(|w| unsafe { w.ndt().bits(len) });
+        // RX first so no frame is missed
+        dma_rx.reg.scr(dma_rx.stream()).write(|w| unsafe { w.bits(self.rx_scr | 1) });
+        dma_tx.reg.scr(dma_tx.stream()).write(|w| unsafe { w.bits(self.tx_scr | 1) });
+        Ok(())
+    }
+
+    /// Frames not received yet
+    pub fn remaining(&self) -> u16 {
+        self.dma_rx.remaining()
+    }
+
+    /// Waits for the exchange to finish and returns the received data
+    pub fn wait(&mut self) -> nb::Result<&[W], dma2::Error> {
+        let dma_tx = self.dma_tx;
+        let dma_rx = self.dma_rx;
+
+        if dma_tx.has_transfer_error() || dma_rx.has_transfer_error() {
+            Err(nb::Error::Other(dma2::Error::Transfer))
+        } else if dma_rx.is_transfer_complete() {
+            Ok(&*self.rx)
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+}
+
+/// Device on a shared bus with a GPIO chip select, driven by DMA
+///
+/// CS is asserted when a transfer starts and released by `finish` /
+/// `finish_exchange`, usually called from the DMA transfer complete
+/// interrupt. The TX stream completes when the last frame is *written to*
+/// DR, not when it leaves the shift register, so these wait for TXE and
+/// !BSY before raising CS; releasing it right away cuts the last byte.
+///
+/// ``` ignore
+/// // task
+/// *r.XFER = Some(device.send(r.FRAME.take().unwrap())?);
+///
+/// // DMA2_STREAM3 task (TCIE / TEIE enabled)
+/// let (frame, result) = device.finish(r.XFER.take().unwrap());
+/// ```
+pub struct SpiDmaDevice<'a, S, D, P>
+    where S: 'a + Any + SPI,
+          D: 'a + Any + DMA,
+          P: 'a + Deref<Target = ::stm32f411::gpioa::RegisterBlock>
+{
+    spi: &'a Spi<'a, S, D>,
+    port: &'a P,
+    cs: Pin<P>,
+}
+
+impl<'a, S, D, P> SpiDmaDevice<'a, S, D, P>
+    where S: Any + SPI,
+          D: Any + DMA,
+          P: Deref<Target = ::stm32f411::gpioa::RegisterBlock>
+{
+    /// Configures `cs` as an output, deselected (high)
+    pub fn new(spi: &'a Spi<'a, S, D>, port: &'a P, cs: Pin<P>) -> Self {
+        cs.set(port, Io::High);
+        cs.set_mode(port, Mode::Output);
+
+        SpiDmaDevice { spi: spi, port: port, cs: cs }
+    }
+
+    /// Selects the device and starts sending `buffer`, see
+    /// `Spi::send_transfer`
+    ///
+    /// CS is left alone if the TX stream is busy.
+    pub fn send<W, B>(&self, buffer: &'static mut B)
+        -> ::core::result::Result<Transfer<'a, D, B>, dma2::Error>
+        where W: Word,
+              B: Unsize<[W]>
+    {
+        if self.spi.tx_stream()?.is_enabled() {
+            return Err(dma2::Error::InUse);
+        }
+
+        self.cs.set(self.port, Io::Low);
+        let transfer = self.spi.send_transfer::<W, B>(buffer);
+        if transfer.is_err() {
+            self.cs.set(self.port, Io::High);
+        }
+        transfer
+    }
+
+    /// Waits for the end of `transfer`, then for the last frame to leave the
+    /// bus, and deselects the device
+    pub fn finish<B>(&self, transfer: Transfer<'a, D, B>)
+        -> (&'static mut B, ::core::result::Result<(), dma2::Error>)
+    {
+        let result = transfer.wait();
+        self.release();
+        result
+    }
+
+    /// Selects the device and starts a prepared exchange
+    pub fn start_exchange<W>(&self, xfer: &mut PreparedTransfer<'a, S, D, W>)
+        -> ::core::result::Result<(), dma2::Error>
+        where W: 'static + Word
+    {
+        self.cs.set(self.port, Io::Low);
+        let result = xfer.start();
+        if result.is_err() {
+            self.cs.set(self.port, Io::High);
+        }
+        result
+    }
+
+    /// Like `PreparedTransfer::wait`, deselects the device once the
+    /// exchange is over
+    pub fn finish_exchange<'x, W>(&self, xfer: &'x mut PreparedTransfer<'a, S, D, W>)
+        -> nb::Result<&'x [W], dma2::Error>
+        where W: 'static + Word
+    {
+        match xfer.wait() {
+            Err(nb::Error::WouldBlock) => Err(nb::Error::WouldBlock),
+            result => {
+                self.release();
+                result
+            }
+        }
+    }
+
+    /// Raises CS once the shift register is empty
+    fn release(&self) {
+        // NOTE unbounded on purpose: the device is selected by this master,
+        // which clocks the last frame out in at most 16 SCK periods
+        while self.spi.poll_idle().is_err() {}
+        self.cs.set(self.port, Io::High);
+    }
+}
+
+impl<'a, S, D> ClockListener for Spi<'a, S, D>
+    where S: Any + SPI,
+          D: Any + DMA
+{
+    /// Keeps the SCK frequency, or picks the closest lower one; the
+    /// slowest prescaler if even that is too fast
+    fn clocks_changed(&self, old: &Clocks, new: &Clocks) {
+        let br = (self.reg.cr1.read().bits() >> 3) & 0b111;
+        let sck = S::pclk(old).0 >> (br + 1);
+
+        if self.set_frequency(new, Hertz(sck)).is_err() {
+            self.reg.cr1.modify(|r, w| unsafe { w.bits(r.bits() | (0b111 << 3)) });
+        }
+    }
+}
+
 impl<'a, S, D> hal::Spi<u8> for Spi<'a, S, D>
     where S: Any + SPI,
           D: Any + DMA
@@ -304,4 +1272,4 @@ impl<'a, S, D> hal::Spi<u8> for Spi<'a, S, D>
             Err(nb::Error::WouldBlock)
         }
     }
-}
\ No newline at end of file
+}
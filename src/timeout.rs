@@ -0,0 +1,64 @@
+//! Timeouts for non-blocking operations
+//!
+//! `block!` spins forever if the peripheral never answers (unplugged cable,
+//! bus stuck low, ...). `with_timeout` gives up once a timer expires:
+//!
+//! ``` ignore
+//! timer.set_timeout(Milliseconds(10));
+//! timer.restart();
+//! timer.resume();
+//! let byte = timeout::with_timeout(&timer, || serial.read())?;
+//! timer.pause();
+//! ```
+
+use core::fmt;
+
+use hal;
+use nb;
+
+/// Error of an operation run with a timeout
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error<E> {
+    /// The timer expired before the operation completed
+    Timeout,
+    /// The operation failed
+    Other(E),
+}
+
+impl<E> fmt::Display for Error<E>
+    where E: fmt::Display
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Timeout => f.write_str("operation timed out"),
+            Error::Other(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(e: E) -> Self {
+        Error::Other(e)
+    }
+}
+
+/// Retries `operation` until it completes, fails or `timer` expires
+///
+/// `timer` must already be running; its update flag is consumed if it
+/// expires.
+pub fn with_timeout<T, E, TIM, F>(timer: &TIM, mut operation: F) -> Result<T, Error<E>>
+    where TIM: hal::Timer,
+          F: FnMut() -> nb::Result<T, E>
+{
+    loop {
+        match operation() {
+            Ok(value) => return Ok(value),
+            Err(nb::Error::Other(e)) => return Err(Error::Other(e)),
+            Err(nb::Error::WouldBlock) => {}
+        }
+
+        if timer.wait().is_ok() {
+            return Err(Error::Timeout);
+        }
+    }
+}
@@ -1,4 +1,15 @@
 //! Timer
+//!
+//! `Timer::into_microsecond_base` turns a timer into a 1 MHz timebase, so
+//! delays, input captures and PWM periods can be given in microseconds
+//! without tick math:
+//!
+//! ``` ignore
+//! let us = Timer::new(tim3).into_microsecond_base(&clocks)?;
+//! us.delay_us(Microseconds(150));
+//! us.set_pwm_period(Microseconds(20_000))?;
+//! us.set_pulse(Channel::_1, Microseconds(1_500));
+//! ```
 
 use core::any::{Any, TypeId};
 use core::ops::Deref;
@@ -9,6 +20,13 @@ use hal;
 use nb::{self, Error};
 use stm32f411::{GPIOA, TIM1, TIM3, TIM4, gpioa, tim3, tim1};
 
+use clock_switch::ClockListener;
+use rcc::{ClockError, Clocks};
+use time::{Hertz, Microseconds};
+
+/// Longest PWM period of a microsecond timebase, the 16-bit counter range
+const MAX_PERIOD_US: u32 = 1 << 16;
+
 /// Channel associated to a timer
 #[derive(Clone, Copy, Debug)]
 pub enum Channel {
@@ -22,9 +40,83 @@ pub enum Channel {
     _4,
 }
 
+/// Interrupt event
+#[derive(Clone, Copy, Debug)]
+pub enum Event {
+    /// Update (overflow / underflow)
+    Update,
+    /// Capture / compare on channel 1
+    Cc1,
+    /// Capture / compare on channel 2
+    Cc2,
+    /// Capture / compare on channel 3
+    Cc3,
+    /// Capture / compare on channel 4
+    Cc4,
+    /// Trigger input
+    Trigger,
+    /// Break input, only on advanced timers (TIM1)
+    Break,
+}
+
+impl Channel {
+    pub(crate) fn index(self) -> u32 {
+        match self {
+            Channel::_1 => 0,
+            Channel::_2 => 1,
+            Channel::_3 => 2,
+            Channel::_4 => 3,
+        }
+    }
+}
+
+impl Event {
+    /// Bit of the event in DIER and SR
+    fn mask(self) -> u32 {
+        match self {
+            Event::Update => 1 << 0,
+            Event::Cc1 => 1 << 1,
+            Event::Cc2 => 1 << 2,
+            Event::Cc3 => 1 << 3,
+            Event::Cc4 => 1 << 4,
+            Event::Trigger => 1 << 6,
+            Event::Break => 1 << 7,
+        }
+    }
+}
+
 pub unsafe trait TIMBase {
     fn init(&self, timeout: ::apb1::Ticks);
     fn set_timeout(&self, timeout: ::apb1::Ticks);
+
+    /// Sets (`enable`) or clears the `mask` bits of DIER
+    fn set_dier(&self, mask: u32, enable: bool);
+    /// Reads SR
+    fn sr_bits(&self) -> u32;
+    /// Clears the `mask` flags of SR
+    fn clear_sr(&self, mask: u32);
+
+    /// Loads PSC and ARR right away (update event) and starts counting
+    fn start_with(&self, psc: u16, arr: u16);
+    /// Sets ARR, takes effect at the next update
+    fn set_arr(&self, arr: u16);
+    /// Reads ARR
+    fn arr_bits(&self) -> u16;
+    /// Sets PSC, takes effect at the next update
+    fn set_psc(&self, psc: u16);
+    /// Reads PSC
+    fn psc_bits(&self) -> u16;
+    /// Reads CNT
+    fn counter(&self) -> u16;
+    /// Writes the 8-bit CCMR field of `channel`
+    fn set_ccmr(&self, channel: Channel, bits: u32);
+    /// Writes the 4-bit CCER field of `channel`, enabling the outputs of
+    /// advanced timers if needed
+    fn set_ccer(&self, channel: Channel, bits: u32);
+    /// Reads the capture / compare register of `channel`
+    fn ccr_bits(&self, channel: Channel) -> u16;
+    /// Writes the capture / compare register of `channel`
+    fn set_ccr(&self, channel: Channel, value: u16);
 }
 
 unsafe impl TIMBase for tim3::RegisterBlock {
@@ -49,6 +141,101 @@ unsafe impl TIMBase for tim3::RegisterBlock {
             self.arr.write(|w| w.arr_l().bits(arr));
         }
     }
+
+    fn set_dier(&self, mask: u32, enable: bool) {
+        let mask = mask & !Event::Break.mask();
+        self.dier.modify(|r, w| unsafe {
+            if enable {
+                w.bits(r.bits() | mask)
+            } else {
+                w.bits(r.bits() & !mask)
+            }
+        });
+    }
+
+    fn sr_bits(&self) -> u32 {
+        self.sr.read().bits()
+    }
+
+    fn clear_sr(&self, mask: u32) {
+        // rc_w0: writing 1 leaves the other flags untouched
+        self.sr.write(|w| unsafe { w.bits(!mask) });
+    }
+
+    fn start_with(&self, psc: u16, arr: u16) {
+        unsafe {
+            self.cr1.write(|w| w.bits(0));
+            self.psc.write(|w| w.bits(u32(psc)));
+            self.arr.write(|w| w.bits(u32(arr)));
+            // UG, then drop the update flag it raised
+            self.egr.write(|w| w.bits(1));
+            self.sr.write(|w| w.bits(!Event::Update.mask()));
+            // ARPE, CEN
+            self.cr1.write(|w| w.bits((1 << 7) | 1));
+        }
+    }
+
+    fn set_arr(&self, arr: u16) {
+        self.arr.write(|w| unsafe { w.bits(u32(arr)) });
+    }
+
+    fn arr_bits(&self) -> u16 {
+        self.arr.read().bits() as u16
+    }
+
+    fn set_psc(&self, psc: u16) {
+        self.psc.write(|w| unsafe { w.bits(u32(psc)) });
+    }
+
+    fn psc_bits(&self) -> u16 {
+        self.psc.read().bits() as u16
+    }
+
+    fn counter(&self) -> u16 {
+        self.cnt.read().bits() as u16
+    }
+
+    fn set_ccmr(&self, channel: Channel, bits: u32) {
+        let shift = 8 * (channel.index() % 2);
+        let mask = !(0xFF << shift);
+        if channel.index() < 2 {
+            self.ccmr1_output.modify(|r, w| unsafe {
+                w.bits((r.bits() & mask) | (bits << shift))
+            });
+        } else {
+            self.ccmr2_output.modify(|r, w| unsafe {
+                w.bits((r.bits() & mask) | (bits << shift))
+            });
+        }
+    }
+
+    fn set_ccer(&self, channel: Channel, bits: u32) {
+        let shift = 4 * channel.index();
+        self.ccer.modify(|r, w| unsafe {
+            w.bits((r.bits() & !(0xF << shift)) | (bits << shift))
+        });
+    }
+
+    fn ccr_bits(&self, channel: Channel) -> u16 {
+        (match channel {
+            Channel::_1 => self.ccr1.read().bits(),
+            Channel::_2 => self.ccr2.read().bits(),
+            Channel::_3 => self.ccr3.read().bits(),
+            Channel::_4 => self.ccr4.read().bits(),
+        }) as u16
+    }
+
+    fn set_ccr(&self, channel: Channel, value: u16) {
+        let value = u32(value);
+        unsafe {
+            match channel {
+                Channel::_1 => self.ccr1.write(|w| w.bits(value)),
+                Channel::_2 => self.ccr2.write(|w| w.bits(value)),
+                Channel::_3 => self.ccr3.write(|w| w.bits(value)),
+                Channel::_4 => self.ccr4.write(|w| w.bits(value)),
+            }
+        }
+    }
 }
 
 unsafe impl TIMBase for tim1::RegisterBlock {
@@ -67,6 +254,101 @@ unsafe impl TIMBase for tim1::RegisterBlock {
             self.arr.write(|w| w.arr().bits(arr));
         }
     }
+
+    fn set_dier(&self, mask: u32, enable: bool) {
+        let mask = mask & 0xFF;
+        self.dier.modify(|r, w| unsafe {
+            if enable {
+                w.bits(r.bits() | mask)
+            } else {
+                w.bits(r.bits() & !mask)
+            }
+        });
+    }
+
+    fn sr_bits(&self) -> u32 {
+        self.sr.read().bits()
+    }
+
+    fn clear_sr(&self, mask: u32) {
+        // rc_w0: writing 1 leaves the other flags untouched
+        self.sr.write(|w| unsafe { w.bits(!mask) });
+    }
+
+    fn start_with(&self, psc: u16, arr: u16) {
+        unsafe {
+            self.cr1.write(|w| w.bits(0));
+            self.psc.write(|w| w.bits(u32(psc)));
+            self.arr.write(|w| w.bits(u32(arr)));
+            self.egr.write(|w| w.bits(1));
+            self.sr.write(|w| w.bits(!Event::Update.mask()));
+            self.cr1.write(|w| w.bits((1 << 7) | 1));
+        }
+    }
+
+    fn set_arr(&self, arr: u16) {
+        self.arr.write(|w| unsafe { w.bits(u32(arr)) });
+    }
+
+    fn arr_bits(&self) -> u16 {
+        self.arr.read().bits() as u16
+    }
+
+    fn set_psc(&self, psc: u16) {
+        self.psc.write(|w| unsafe { w.bits(u32(psc)) });
+    }
+
+    fn psc_bits(&self) -> u16 {
+        self.psc.read().bits() as u16
+    }
+
+    fn counter(&self) -> u16 {
+        self.cnt.read().bits() as u16
+    }
+
+    fn set_ccmr(&self, channel: Channel, bits: u32) {
+        let shift = 8 * (channel.index() % 2);
+        let mask = !(0xFF << shift);
+        if channel.index() < 2 {
+            self.ccmr1_output.modify(|r, w| unsafe {
+                w.bits((r.bits() & mask) | (bits << shift))
+            });
+        } else {
+            self.ccmr2_output.modify(|r, w| unsafe {
+                w.bits((r.bits() & mask) | (bits << shift))
+            });
+        }
+    }
+
+    fn set_ccer(&self, channel: Channel, bits: u32) {
+        let shift = 4 * channel.index();
+        self.ccer.modify(|r, w| unsafe {
+            w.bits((r.bits() & !(0xF << shift)) | (bits << shift))
+        });
+        // MOE, the outputs of advanced timers stay off without it
+        self.bdtr.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 15)) });
+    }
+
+    fn ccr_bits(&self, channel: Channel) -> u16 {
+        (match channel {
+            Channel::_1 => self.ccr1.read().bits(),
+            Channel::_2 => self.ccr2.read().bits(),
+            Channel::_3 => self.ccr3.read().bits(),
+            Channel::_4 => self.ccr4.read().bits(),
+        }) as u16
+    }
+
+    fn set_ccr(&self, channel: Channel, value: u16) {
+        let value = u32(value);
+        unsafe {
+            match channel {
+                Channel::_1 => self.ccr1.write(|w| w.bits(value)),
+                Channel::_2 => self.ccr2.write(|w| w.bits(value)),
+                Channel::_3 => self.ccr3.write(|w| w.bits(value)),
+                Channel::_4 => self.ccr4.write(|w| w.bits(value)),
+            }
+        }
+    }
 }
 
 pub unsafe trait TIM<T>: Deref<Target = T>
@@ -75,6 +357,10 @@ pub unsafe trait TIM<T>: Deref<Target = T>
     /// IMPLEMENTATION DETAIL
     type GPIO: Deref<Target = gpioa::RegisterBlock>;
 
+    /// Counter clock of the instance, twice the bus clock when the bus is
+    /// prescaled
+    fn timclk(clocks: &Clocks) -> Hertz;
+
     fn init_(&self, timeout: ::apb1::Ticks) {
         self.init(timeout);
     }
@@ -86,14 +372,26 @@ pub unsafe trait TIM<T>: Deref<Target = T>
 
 unsafe impl TIM<tim3::RegisterBlock> for TIM3 {
     type GPIO = GPIOA;
+
+    fn timclk(clocks: &Clocks) -> Hertz {
+        clocks.timclk1()
+    }
 }
 
 unsafe impl TIM<tim3::RegisterBlock> for TIM4 {
     type GPIO = GPIOA;
+
+    fn timclk(clocks: &Clocks) -> Hertz {
+        clocks.timclk1()
+    }
 }
 
 unsafe impl TIM<tim1::RegisterBlock> for TIM1 {
     type GPIO = GPIOA;
+
+    fn timclk(clocks: &Clocks) -> Hertz {
+        clocks.timclk2()
+    }
 }
 
 
@@ -115,6 +413,18 @@ impl<'a, T, R> Timer<'a, T, R>
     pub const fn new(tim: &'a T) -> Self {
         Timer(tim, PhantomData)
     }
+
+    /// Runs `f` on the timer registers, for features this driver lacks
+    ///
+    /// Safe to change: the input capture / output compare setup (CCMRx,
+    /// CCER, CCRx), the slave mode controller (SMCR) and the DMA enables of
+    /// DIER. PSC, ARR, CEN and UIE belong to the timeout, change them
+    /// through `init` / `pause` / `resume`.
+    pub fn with_raw<F, Q>(&self, f: F) -> Q
+        where F: FnOnce(&T) -> Q
+    {
+        f(self.0)
+    }
     /// Initializes the timer with a periodic timeout of `frequency` Hz
     ///
     /// NOTE After initialization, the timer will be in the paused state.
@@ -123,6 +433,205 @@ impl<'a, T, R> Timer<'a, T, R>
     {
         self.0.init_(period.into());
     }
+
+    /// Starts listening for an interrupt `event`
+    ///
+    /// `Event::Break` is ignored on timers without a break input
+    pub fn listen(&self, event: Event) {
+        self.0.set_dier(event.mask(), true);
+    }
+
+    /// Stops listening for an interrupt `event`
+    pub fn unlisten(&self, event: Event) {
+        self.0.set_dier(event.mask(), false);
+    }
+
+    /// Checks if the `event` flag is set
+    pub fn is_pending(&self, event: Event) -> bool {
+        self.0.sr_bits() & event.mask() != 0
+    }
+
+    /// Clears the `event` flag, call this from the interrupt handler
+    pub fn clear_interrupt(&self, event: Event) {
+        self.0.clear_sr(event.mask());
+    }
+
+    /// Makes the counter tick at exactly 1 MHz and starts it
+    ///
+    /// The counter runs freely over its 16-bit range until a PWM period is
+    /// set. Fails if the timer clock isn't a whole number of MHz.
+    pub fn into_microsecond_base(self, clocks: &Clocks)
+        -> Result<MicroTimer<'a, T, R>, ClockError>
+    {
+        let timclk = <T as TIM<R>>::timclk(clocks).0;
+        if timclk < 1_000_000 || timclk % 1_000_000 != 0 {
+            return Err(ClockError::FrequencyOutOfRange);
+        }
+        let psc = u16(timclk / 1_000_000 - 1).map_err(|_| ClockError::FrequencyOutOfRange)?;
+
+        self.0.start_with(psc, 0xFFFF);
+        Ok(MicroTimer(self.0, PhantomData))
+    }
+}
+
+impl<'a, T, R> ClockListener for Timer<'a, T, R>
+    where R: TIMBase, T: Any + TIM<R>
+{
+    /// Keeps the timeout period, within the prescaler resolution
+    fn clocks_changed(&self, old: &Clocks, new: &Clocks) {
+        let old_timclk = u64::from(<T as TIM<R>>::timclk(old).0);
+        if old_timclk == 0 {
+            return;
+        }
+
+        // `set_timeout` programs `period = (PSC + 1) * ARR`
+        let period = (u64::from(self.0.psc_bits()) + 1) * u64::from(self.0.arr_bits());
+        let period = period * u64::from(<T as TIM<R>>::timclk(new).0) / old_timclk;
+        let period = if period < 1 {
+            1
+        } else if period > u64::from(u32::max_value()) {
+            u32::max_value()
+        } else {
+            period as u32
+        };
+
+        let psc = period >> 16;
+        self.0.set_psc(psc as u16);
+        self.0.set_arr((period / (psc + 1)) as u16);
+    }
+}
+
+/// Timer counting microseconds, see `Timer::into_microsecond_base`
+pub struct MicroTimer<'a, T, R>(pub &'a T, PhantomData<R>) where T: 'a;
+
+impl<'a, T, R> MicroTimer<'a, T, R>
+    where R: TIMBase, T: Any + TIM<R>
+{
+    /// Starts listening for an interrupt `event`, see `Timer::listen`
+    pub fn listen(&self, event: Event) {
+        self.0.set_dier(event.mask(), true);
+    }
+
+    /// Stops listening for an interrupt `event`
+    pub fn unlisten(&self, event: Event) {
+        self.0.set_dier(event.mask(), false);
+    }
+
+    /// Checks if the `event` flag is set
+    pub fn is_pending(&self, event: Event) -> bool {
+        self.0.sr_bits() & event.mask() != 0
+    }
+
+    /// Clears the `event` flag, call this from the interrupt handler
+    pub fn clear_interrupt(&self, event: Event) {
+        self.0.clear_sr(event.mask());
+    }
+
+    /// Counter value; wraps at the PWM period, or every 65.536 ms
+    pub fn now(&self) -> Microseconds {
+        Microseconds(u32(self.0.counter()))
+    }
+
+    /// Time since `since`, a value returned by `now` or `capture_us`
+    ///
+    /// Only correct for intervals shorter than the counter period.
+    pub fn elapsed(&self, since: Microseconds) -> Microseconds {
+        let period = u32(self.0.arr_bits()) + 1;
+        let now = u32(self.0.counter());
+        Microseconds((now + period - since.0 % period) % period)
+    }
+
+    /// Busy-waits for `us`
+    pub fn delay_us(&self, us: Microseconds) {
+        let period = u32(self.0.arr_bits()) + 1;
+        // Short enough steps that a wrap-around can't be missed
+        let step = period / 2;
+
+        let mut remaining = us.0;
+        while remaining > 0 {
+            let chunk = if remaining < step { remaining } else { step };
+            let start = u32(self.0.counter());
+            loop {
+                let now = u32(self.0.counter());
+                let elapsed = (now + period - start) % period;
+                if elapsed >= chunk {
+                    break;
+                }
+            }
+            remaining -= chunk;
+        }
+    }
+
+    /// Captures the counter on the rising edges of `channel`'s input
+    pub fn enable_capture(&self, channel: Channel) {
+        // CCxS = TIx input, no filter, no prescaler
+        self.0.set_ccmr(channel, 0b01);
+        // CCxE, rising edge
+        self.0.set_ccer(channel, 0b0001);
+    }
+
+    /// Captures the counter on both edges of `channel`'s input, pulse widths
+    /// are the difference of two consecutive captures
+    pub fn enable_capture_both_edges(&self, channel: Channel) {
+        self.0.set_ccmr(channel, 0b01);
+        // CCxE, CCxP | CCxNP = both edges
+        self.0.set_ccer(channel, 0b1011);
+    }
+
+    /// Timestamp of the last edge captured on `channel`
+    ///
+    /// See `enable_capture`
+    pub fn capture_us(&self, channel: Channel) -> nb::Result<Microseconds, !> {
+        let flag = match channel {
+            Channel::_1 => Event::Cc1,
+            Channel::_2 => Event::Cc2,
+            Channel::_3 => Event::Cc3,
+            Channel::_4 => Event::Cc4,
+        }.mask();
+
+        if self.0.sr_bits() & flag == 0 {
+            Err(Error::WouldBlock)
+        } else {
+            // Reading CCR clears the flag
+            Ok(Microseconds(u32(self.0.ccr_bits(channel))))
+        }
+    }
+
+    /// Sets the PWM period, from 2 us to 65.536 ms
+    pub fn set_pwm_period(&self, period: Microseconds) -> Result<(), ClockError> {
+        if period.0 < 2 || period.0 > MAX_PERIOD_US {
+            return Err(ClockError::FrequencyOutOfRange);
+        }
+        self.0.set_arr((period.0 - 1) as u16);
+        Ok(())
+    }
+
+    /// Drives `channel` high for `width` at the start of every period (PWM
+    /// mode 1); widths of a period or longer keep it high
+    pub fn set_pulse(&self, channel: Channel, width: Microseconds) {
+        let width = if width.0 > 0xFFFF { 0xFFFF } else { width.0 as u16 };
+        self.0.set_ccr(channel, width);
+        // OCxM = PWM mode 1, OCxPE
+        self.0.set_ccmr(channel, (0b110 << 4) | (1 << 3));
+        // CCxE, active high
+        self.0.set_ccer(channel, 0b0001);
+    }
+}
+
+impl<'a, T, R> ClockListener for MicroTimer<'a, T, R>
+    where R: TIMBase, T: Any + TIM<R>
+{
+    /// Keeps the 1 MHz count, if the new timer clock is a whole number of
+    /// MHz; otherwise the counter runs at the old prescaler
+    fn clocks_changed(&self, _old: &Clocks, new: &Clocks) {
+        let timclk = <T as TIM<R>>::timclk(new).0;
+        if timclk < 1_000_000 || timclk % 1_000_000 != 0 {
+            return;
+        }
+        if let Ok(psc) = u16(timclk / 1_000_000 - 1) {
+            self.0.set_psc(psc);
+        }
+    }
 }
 
 impl<'a, T> hal::Timer for Timer<'a, T, tim3::RegisterBlock>
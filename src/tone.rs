@@ -0,0 +1,353 @@
+//! DMA driven tone generator
+//!
+//! Plays a list of tones on a TIM1 PWM channel (a buzzer or a small speaker
+//! driver) without CPU load: the tones are encoded into a table of ARR /
+//! RCR / CCRx values that DMA2 stream 5 (channel 6, TIM1_UP) copies into
+//! the timer with a DMAR burst at every update event. The repetition
+//! counter makes each table entry last up to 256 periods, so a note is one
+//! or two entries, not one per period.
+//!
+//! ``` ignore
+//! static TABLE: Buffer<[u16; 192]> = Buffer::new([0; 192], DMAStream::Stream5);
+//!
+//! let mut tone = Tone::new(tim1, &streams.s5, Channel::_1)?;
+//! tone.init(&clocks)?;
+//!
+//! tone.play(TABLE, &[
+//!     ToneStep::new(Hertz(440), Milliseconds(200)),
+//!     ToneStep::rest(Milliseconds(50)),
+//!     ToneStep::new(Hertz(880), Milliseconds(200)),
+//! ])?;
+//!
+//! // DMA2_STREAM5 task: the melody is over
+//! match tone.wait(TABLE) { .. }
+//! ```
+//!
+//! Sirens and chirps are short steps, see `sweep`.
+//!
+//! The timer is dedicated to the generator: every update reloads ARR and
+//! the compare registers of the channels up to the one used, the others
+//! are zeroed.
+
+use core::cmp;
+use core::fmt;
+use core::marker::Unsize;
+
+use cast::u16;
+use hal;
+use nb;
+use static_ref::Static;
+use stm32f411::{DMA2, TIM1};
+
+use dma2::{self, Buffer, DMAStream, Dma};
+use pwm2::Pwm;
+use rcc::{ClockError, Clocks};
+use time::{Hertz, Milliseconds};
+use timer::Channel;
+
+/// Timer tick rate, ARR resolves 1 kHz to 0.1%
+const TICK: u32 = 1_000_000;
+
+/// Lowest tone, ARR is 16-bit
+pub const MIN_FREQUENCY: u32 = 16;
+/// Highest tone
+pub const MAX_FREQUENCY: u32 = 100_000;
+
+/// Periods one table entry can last, the limit of the 8-bit repetition
+/// counter
+const MAX_REPETITIONS: u64 = 256;
+
+/// Period of the silent entries, 1 ms
+const REST_PERIOD: u32 = TICK / 1000;
+
+/// DMAR burst start: ARR, then RCR and CCR1 ..
+const DBA_ARR: u32 = 11;
+
+/// Tone generator error
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// A frequency outside `MIN_FREQUENCY ..= MAX_FREQUENCY`
+    Frequency,
+    /// The steps don't fit in the table
+    TooLong,
+    /// The DMA handle isn't DMA2 stream 5, the one TIM1_UP is wired to
+    WrongStream,
+    /// DMA error
+    Dma(dma2::Error),
+    #[doc(hidden)]
+    _Extensible,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Frequency => f.write_str("tone frequency out of range"),
+            Error::TooLong => f.write_str("tone table too small"),
+            Error::WrongStream => f.write_str("TIM1_UP requests are routed to DMA2 stream 5"),
+            Error::Dma(ref e) => e.fmt(f),
+            Error::_Extensible => f.write_str("unknown error"),
+        }
+    }
+}
+
+/// A tone, or a rest
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ToneStep {
+    /// 0 for a rest
+    pub frequency: Hertz,
+    pub duration: Milliseconds,
+}
+
+impl ToneStep {
+    /// `frequency` for `duration`
+    pub const fn new(frequency: Hertz, duration: Milliseconds) -> Self {
+        ToneStep { frequency: frequency, duration: duration }
+    }
+
+    /// Silence for `duration`
+    pub const fn rest(duration: Milliseconds) -> Self {
+        ToneStep { frequency: Hertz(0), duration: duration }
+    }
+}
+
+/// Fills `steps` with a linear sweep from `from` to `to` lasting
+/// `duration`
+///
+/// More steps make a smoother sweep but a longer table. A siren is a sweep
+/// up followed by a sweep down.
+pub fn sweep(from: Hertz, to: Hertz, duration: Milliseconds, steps: &mut [ToneStep]) {
+    let n = steps.len() as i64;
+    if n == 0 {
+        return;
+    }
+
+    let step = Milliseconds(cmp::max(duration.0 / n as u32, 1));
+    let from = i64::from(from.0);
+    let to = i64::from(to.0);
+    for (i, s) in steps.iter_mut().enumerate() {
+        let frequency = if n == 1 { from } else { from + (to - from) * i as i64 / (n - 1) };
+        *s = ToneStep::new(Hertz(frequency as u32), step);
+    }
+}
+
+/// Tone generator on a TIM1 channel
+pub struct Tone<'a> {
+    tim: &'a TIM1,
+    dma: &'a Dma<'a, DMA2>,
+    channel: Channel,
+    tick: u32,
+}
+
+impl<'a> Tone<'a> {
+    /// `dma` must be DMA2 stream 5, the one TIM1_UP is wired to; `channel`
+    /// must be routed to the buzzer. Fails with `WrongStream` on another
+    /// stream.
+    pub fn new(tim: &'a TIM1, dma: &'a Dma<'a, DMA2>, channel: Channel) -> Result<Self, Error> {
+        match dma.stream() {
+            DMAStream::Stream5 => {}
+            _ => return Err(Error::WrongStream),
+        }
+
+        Ok(Tone { tim: tim, dma: dma, channel: channel, tick: TICK })
+    }
+
+    /// Configures the timer and the DMA stream, output silent
+    ///
+    /// The TIM1 and DMA2 clocks must be enabled. The stream raises its
+    /// interrupt (TCIE / TEIE) when a `play` is over.
+    pub fn init(&mut self, clocks: &Clocks) -> Result<(), ClockError> {
+        let timclk = clocks.timclk2().0;
+        if timclk < TICK {
+            return Err(ClockError::FrequencyOutOfRange);
+        }
+        let psc = u16(timclk / TICK - 1).map_err(|_| ClockError::FrequencyOutOfRange)?;
+        self.tick = timclk / (u32::from(psc) + 1);
+
+        let tim = self.tim;
+        tim.cr1.write(|w| unsafe { w.bits(0) });
+        tim.dier.write(|w| unsafe { w.bits(0) });
+        tim.psc.write(|w| unsafe { w.psc().bits(psc) });
+
+        // PWM mode 1 with preload (OCxM = 0b110, OCxPE)
+        let index = self.channel.index();
+        let shift = (index % 2) * 8;
+        let mode = (0b110 << 4 | 1 << 3) << shift;
+        let mask = 0xFF << shift;
+        if index < 2 {
+            tim.ccmr1_output.modify(|r, w| unsafe { w.bits((r.bits() & !mask) | mode) });
+        } else {
+            tim.ccmr2_output.modify(|r, w| unsafe { w.bits((r.bits() & !mask) | mode) });
+        }
+        self.load(&self.silence());
+        hal::Pwm::enable(&Pwm(tim), self.channel);
+        tim.bdtr.modify(|_, w| w.moe().set_bit());
+
+        // DBL: ARR, RCR and CCR1 up to our channel
+        tim.dcr.write(|w| unsafe { w.bits(((2 + index) << 8) | DBA_ARR) });
+        // ARPE, URS: UG doesn't request a DMA transfer
+        tim.cr1.write(|w| unsafe { w.bits((1 << 7) | (1 << 2)) });
+
+        // CHSEL = 6, PL = high, half-word sized, MINC, memory to peripheral,
+        // TCIE, TEIE
+        self.dma.disable();
+        while self.dma.is_enabled() {}
+        self.dma.reg.scr(DMAStream::Stream5).write(|w| unsafe {
+            w.bits((6 << 25) | (0b10 << 16) | (0b01 << 13) | (0b01 << 11) | (1 << 10) |
+                   (0b01 << 6) | (1 << 4) | (1 << 2))
+        });
+
+        Ok(())
+    }
+
+    /// Starts playing `steps`, encoded into `table`
+    ///
+    /// Each step takes `2 + n` half-words of the table for every 256
+    /// periods it lasts (`n` being the channel number, 1 - 4), plus two
+    /// silent entries at the end. `wait` the previous `play` out first.
+    pub fn play<B>(&self, table: &Static<Buffer<B>>, steps: &[ToneStep]) -> Result<(), Error>
+        where B: Unsize<[u16]>
+    {
+        if self.dma.is_enabled() {
+            return Err(Error::Dma(dma2::Error::InUse));
+        }
+        if steps.is_empty() {
+            return Ok(());
+        }
+
+        let entries = {
+            let out: &mut [u16] = &mut *table.borrow_mut();
+            self.encode(steps, out)?
+        };
+        let words = self.words();
+        let len = u16((entries - 2) * words).map_err(|_| Error::TooLong)?;
+
+        table.check_stream(self.dma.stream()).map_err(Error::Dma)?;
+        let table: &[u16] = table.try_lock().map_err(Error::Dma)?;
+        let tim = self.tim;
+        tim.cr1.modify(|r, w| unsafe { w.bits(r.bits() & !1) });
+        tim.dier.write(|w| unsafe { w.bits(0) });
+
+        // The DMA writes the preload registers at the end of an entry, they
+        // take effect at the end of the next one: the first entry is loaded
+        // by UG and the second one preloaded by hand
+        self.load(&table[..words]);
+        tim.egr.write(|w| unsafe { w.bits(1) });
+        self.load(&table[words..2 * words]);
+        tim.sr.write(|w| unsafe { w.bits(0) });
+
+        self.dma.clear_flags();
+        self.dma.set_config(
+            table[2 * words..].as_ptr() as u32,
+            &tim.dmar as *const _ as u32,
+            len,
+        );
+        self.dma.enable();
+
+        // UDE
+        tim.dier.write(|w| unsafe { w.bits(1 << 8) });
+        tim.cr1.modify(|r, w| unsafe { w.bits(r.bits() | 1) });
+        Ok(())
+    }
+
+    /// Checks if the last step is over and stops the timer
+    ///
+    /// Can be called from the DMA2_STREAM5 interrupt handler.
+    pub fn wait<B>(&self, table: &Static<Buffer<B>>) -> nb::Result<(), dma2::Error> {
+        let result = table.release(self.dma.reg);
+        match result {
+            Err(nb::Error::WouldBlock) => {}
+            _ => self.stop(),
+        }
+        result
+    }
+
+    /// Checks if steps are being played
+    pub fn is_playing(&self) -> bool {
+        self.tim.cr1.read().cen().bit_is_set()
+    }
+
+    /// Stops the timer, output silent
+    ///
+    /// Stopping in the middle of a `play` leaves the DMA stream running:
+    /// `wait` still has to release the table.
+    pub fn stop(&self) {
+        let tim = self.tim;
+        tim.dier.write(|w| unsafe { w.bits(0) });
+        tim.cr1.modify(|r, w| unsafe { w.bits(r.bits() & !1) });
+        self.load(&self.silence());
+        tim.egr.write(|w| unsafe { w.bits(1) });
+    }
+
+    /// Encodes `steps` and the trailing silence into `out`, returns the
+    /// number of entries
+    fn encode(&self, steps: &[ToneStep], out: &mut [u16]) -> Result<usize, Error> {
+        let words = self.words();
+        let mut entries = 0;
+        {
+            let mut push = |period: u32, periods: u64, duty: u32| {
+                let mut left = periods;
+                while left > 0 {
+                    let n = cmp::min(left, MAX_REPETITIONS);
+                    let entry = match out.get_mut(entries * words..(entries + 1) * words) {
+                        Some(entry) => entry,
+                        None => return Err(Error::TooLong),
+                    };
+                    for word in entry.iter_mut() {
+                        *word = 0;
+                    }
+                    entry[0] = (period - 1) as u16;
+                    entry[1] = (n - 1) as u16;
+                    entry[words - 1] = duty as u16;
+                    entries += 1;
+                    left -= n;
+                }
+                Ok(())
+            };
+
+            for step in steps {
+                let ms = u64::from(step.duration.0);
+                let f = step.frequency.0;
+                if f == 0 {
+                    push(REST_PERIOD, cmp::max(ms, 1), 0)?;
+                } else if f < MIN_FREQUENCY || f > MAX_FREQUENCY {
+                    return Err(Error::Frequency);
+                } else {
+                    let period = self.tick / f;
+                    let periods = cmp::max(u64::from(f) * ms / 1000, 1);
+                    push(period, periods, period / 2)?;
+                }
+            }
+
+            // The transfer complete interrupt fires when the last entry is
+            // fetched, as the one before it starts
+            push(REST_PERIOD, 1, 0)?;
+            push(REST_PERIOD, 1, 0)?;
+        }
+        Ok(entries)
+    }
+
+    /// Writes an entry straight into ARR, RCR and the compare register
+    fn load(&self, entry: &[u16]) {
+        let tim = self.tim;
+        let words = self.words();
+        tim.arr.write(|w| unsafe { w.bits(u32::from(entry[0])) });
+        tim.rcr.write(|w| unsafe { w.bits(u32::from(entry[1])) });
+        let duty = entry[words - 1];
+        match self.channel {
+            Channel::_1 => tim.ccr1.write(|w| unsafe { w.bits(u32::from(duty)) }),
+            Channel::_2 => tim.ccr2.write(|w| unsafe { w.bits(u32::from(duty)) }),
+            Channel::_3 => tim.ccr3.write(|w| unsafe { w.bits(u32::from(duty)) }),
+            Channel::_4 => tim.ccr4.write(|w| unsafe { w.bits(u32::from(duty)) }),
+        }
+    }
+
+    /// A silent entry
+    fn silence(&self) -> [u16; 6] {
+        [(REST_PERIOD - 1) as u16, 0, 0, 0, 0, 0]
+    }
+
+    /// Half-words per table entry
+    fn words(&self) -> usize {
+        3 + self.channel.index() as usize
+    }
+}
@@ -0,0 +1,154 @@
+//! HID boot protocol keyboards and mice
+//!
+//! The boot protocol has fixed report formats, so no report descriptor
+//! parsing is needed. Poll faster than the endpoint interval (usually
+//! 8 - 10 ms) to not miss reports.
+
+use nb;
+
+use super::{Device, Endpoint, Error, SetupPacket, UsbHost};
+
+const HID_CLASS: u8 = 3;
+const BOOT_SUBCLASS: u8 = 1;
+const KEYBOARD_PROTOCOL: u8 = 1;
+const MOUSE_PROTOCOL: u8 = 2;
+
+// Class requests
+const SET_REPORT: u8 = 0x09;
+const SET_IDLE: u8 = 0x0A;
+const SET_PROTOCOL: u8 = 0x0B;
+
+/// Boot keyboard input report
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct KeyboardReport {
+    /// Left / right Ctrl, Shift, Alt, GUI bits
+    pub modifiers: u8,
+    /// Usage IDs of the pressed keys, `0` for none
+    pub keys: [u8; 6],
+}
+
+impl KeyboardReport {
+    /// Whether the key with usage ID `key` is pressed
+    pub fn is_pressed(&self, key: u8) -> bool {
+        key != 0 && self.keys.iter().any(|k| *k == key)
+    }
+
+    /// The keyboard reports more keys than it can tell apart
+    pub fn is_rollover(&self) -> bool {
+        self.keys.iter().all(|k| *k == 0x01)
+    }
+}
+
+/// Boot mouse input report
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MouseReport {
+    /// Button bits, left = bit 0
+    pub buttons: u8,
+    pub x: i8,
+    pub y: i8,
+    /// Only on mice that send it in boot mode, `0` otherwise
+    pub wheel: i8,
+}
+
+/// Boot protocol keyboard
+pub struct BootKeyboard {
+    interface: u8,
+    ep: Endpoint,
+}
+
+impl BootKeyboard {
+    /// Switches the first boot keyboard interface of `device` to the boot
+    /// protocol
+    pub fn new(host: &UsbHost, device: &Device) -> Result<Self, Error> {
+        let (interface, ep) = boot_interface(host, device, KEYBOARD_PROTOCOL)?;
+        Ok(BootKeyboard { interface: interface, ep: ep })
+    }
+
+    /// Reads a report, `WouldBlock` if nothing changed
+    pub fn poll(&mut self, host: &UsbHost, device: &Device) -> nb::Result<KeyboardReport, Error> {
+        let mut buffer = [0; 8];
+        if host.interrupt_in(device, &mut self.ep, &mut buffer)? < 3 {
+            return Err(nb::Error::Other(Error::Descriptor));
+        }
+
+        let mut keys = [0; 6];
+        keys.copy_from_slice(&buffer[2..8]);
+        Ok(KeyboardReport { modifiers: buffer[0], keys: keys })
+    }
+
+    /// Sets the Num / Caps / Scroll Lock LEDs (bits 0 - 2)
+    pub fn set_leds(&self, host: &UsbHost, device: &Device, leds: u8) -> Result<(), Error> {
+        host.control_out(device, &SetupPacket {
+            request_type: 0x21,
+            request: SET_REPORT,
+            // Output report, ID 0
+            value: 0x0200,
+            index: u16::from(self.interface),
+            length: 1,
+        }, &[leds])
+    }
+}
+
+/// Boot protocol mouse
+pub struct BootMouse {
+    ep: Endpoint,
+}
+
+impl BootMouse {
+    /// Switches the first boot mouse interface of `device` to the boot
+    /// protocol
+    pub fn new(host: &UsbHost, device: &Device) -> Result<Self, Error> {
+        let (_, ep) = boot_interface(host, device, MOUSE_PROTOCOL)?;
+        Ok(BootMouse { ep: ep })
+    }
+
+    /// Reads a report, `WouldBlock` if the mouse didn't move
+    pub fn poll(&mut self, host: &UsbHost, device: &Device) -> nb::Result<MouseReport, Error> {
+        let mut buffer = [0; 8];
+        let n = host.interrupt_in(device, &mut self.ep, &mut buffer)?;
+        if n < 3 {
+            return Err(nb::Error::Other(Error::Descriptor));
+        }
+
+        Ok(MouseReport {
+            buttons: buffer[0],
+            x: buffer[1] as i8,
+            y: buffer[2] as i8,
+            wheel: if n > 3 { buffer[3] as i8 } else { 0 },
+        })
+    }
+}
+
+/// Finds the boot interface with `protocol`, selects the boot protocol and
+/// only reports changes
+fn boot_interface(host: &UsbHost, device: &Device, protocol: u8)
+    -> Result<(u8, Endpoint), Error>
+{
+    let iface = device.find_interface(HID_CLASS, Some(BOOT_SUBCLASS), Some(protocol))
+        .ok_or(Error::Unsupported)?;
+    let ep = iface.ep_in.ok_or(Error::Descriptor)?;
+    let index = u16::from(iface.number);
+
+    host.control_out(device, &SetupPacket {
+        request_type: 0x21,
+        request: SET_PROTOCOL,
+        // Boot protocol
+        value: 0,
+        index: index,
+        length: 0,
+    }, &[])?;
+
+    // Infinite idle rate; optional, some mice stall it
+    match host.control_out(device, &SetupPacket {
+        request_type: 0x21,
+        request: SET_IDLE,
+        value: 0,
+        index: index,
+        length: 0,
+    }, &[]) {
+        Ok(()) | Err(Error::Stall) => {}
+        Err(e) => return Err(e),
+    }
+
+    Ok((iface.number, ep))
+}
@@ -0,0 +1,907 @@
+//! USB OTG FS host
+//!
+//! Minimal, polled host for a single full or low speed device plugged
+//! straight into the port, no hubs. Transfers block (bounded by a timeout)
+//! and use host channel 0 one at a time, so the driver needs no interrupts
+//! and no DMA. Class drivers for HID boot keyboards / mice and mass storage
+//! devices are in `hid` and `msc`.
+//!
+//! ``` ignore
+//! let host = UsbHost::new(otg_fs_global, &clocks);
+//! host.init(rcc, gpioa)?;
+//!
+//! while !host.is_connected() {}
+//! let device = host.enumerate()?;
+//! let mut keyboard = BootKeyboard::new(&host, &device)?;
+//! loop {
+//!     if let Ok(report) = keyboard.poll(&host, &device) {
+//!         ..
+//!     }
+//! }
+//! ```
+//!
+//! The 48 MHz clock must be exact, build with the `usb` feature to have
+//! `Cfgr::freeze` check it. VBUS is not switched by the driver: boards that
+//! gate it with a GPIO must turn it on before `enumerate`.
+//!
+//! NOTE The OTG FIFOs aren't described by the device crate, so this module
+//! accesses the core through raw offsets; the `OTG_FS_GLOBAL` reference
+//! only serves as the ownership token.
+
+use core::{cmp, fmt, ptr};
+
+use nb;
+use stm32f411::{GPIOA, OTG_FS_GLOBAL, RCC};
+
+use clock_switch::ClockListener;
+use delay::CyclesToTime;
+use gpio::{AF10, Pin, Speed};
+use rcc::Clocks;
+use time::Microseconds;
+
+pub mod hid;
+pub mod msc;
+
+/// Alternate function of the OTG FS pins (PA11 = DM, PA12 = DP)
+pub const OTG_FS_AF: AF10 = AF10;
+
+/// Address given to the device by `enumerate`
+pub const DEVICE_ADDRESS: u8 = 1;
+
+/// Largest configuration descriptor `enumerate` keeps
+pub const CONFIG_SIZE: usize = 256;
+
+const OTG_FS: u32 = 0x5000_0000;
+
+// Core global registers
+const GUSBCFG: u32 = 0x00C;
+const GRSTCTL: u32 = 0x010;
+const GINTSTS: u32 = 0x014;
+const GRXSTSP: u32 = 0x020;
+const GRXFSIZ: u32 = 0x024;
+const HNPTXFSIZ: u32 = 0x028;
+const HNPTXSTS: u32 = 0x02C;
+const GCCFG: u32 = 0x038;
+const HPTXFSIZ: u32 = 0x100;
+
+// Host mode registers
+const HCFG: u32 = 0x400;
+const HFIR: u32 = 0x404;
+const HFNUM: u32 = 0x408;
+const HPTXSTS: u32 = 0x410;
+const HPRT: u32 = 0x440;
+const HCCHAR0: u32 = 0x500;
+const HCINT0: u32 = 0x508;
+const HCTSIZ0: u32 = 0x510;
+const PCGCCTL: u32 = 0xE00;
+const FIFO0: u32 = 0x1000;
+
+// GUSBCFG
+const PHYSEL: u32 = 1 << 6;
+const FHMOD: u32 = 1 << 29;
+
+// GRSTCTL
+const CSRST: u32 = 1 << 0;
+const RXFFLSH: u32 = 1 << 4;
+const TXFFLSH: u32 = 1 << 5;
+const AHBIDL: u32 = 1 << 31;
+
+// GINTSTS
+const RXFLVL: u32 = 1 << 4;
+
+// GCCFG
+const PWRDWN: u32 = 1 << 16;
+const NOVBUSSENS: u32 = 1 << 21;
+
+// HPRT
+const PCSTS: u32 = 1 << 0;
+const PCDET: u32 = 1 << 1;
+const PENA: u32 = 1 << 2;
+const PENCHNG: u32 = 1 << 3;
+const POCCHNG: u32 = 1 << 5;
+const PRST: u32 = 1 << 8;
+const PPWR: u32 = 1 << 12;
+/// Write-1-to-clear bits (and `PENA`, which writing 1 clears) that must be
+/// masked out of read-modify-write cycles
+const HPRT_W1C: u32 = PCDET | PENA | PENCHNG | POCCHNG;
+
+// HCCHAR
+const EPDIR_IN: u32 = 1 << 15;
+const LSDEV: u32 = 1 << 17;
+const ODDFRM: u32 = 1 << 29;
+const CHDIS: u32 = 1 << 30;
+const CHENA: u32 = 1 << 31;
+
+// HCINT
+const XFRC: u32 = 1 << 0;
+const CHH: u32 = 1 << 1;
+const STALL: u32 = 1 << 3;
+const NAK: u32 = 1 << 4;
+const TXERR: u32 = 1 << 7;
+const BBERR: u32 = 1 << 8;
+const FRMOR: u32 = 1 << 9;
+const DTERR: u32 = 1 << 10;
+const HCINT_ALL: u32 = 0x7FF;
+
+// GRXSTSP packet status
+const PKTSTS_IN_DATA: u32 = 0b0010;
+
+// FIFO layout, in words: RX, non-periodic TX, periodic TX
+const RX_FIFO_WORDS: u32 = 128;
+const NPTX_FIFO_WORDS: u32 = 96;
+const PTX_FIFO_WORDS: u32 = 96;
+
+/// Busy-poll iterations before a transfer gives up
+const TIMEOUT: u32 = 1_000_000;
+
+// Standard requests
+const GET_DESCRIPTOR: u8 = 6;
+const SET_ADDRESS: u8 = 5;
+const SET_CONFIGURATION: u8 = 9;
+const CLEAR_FEATURE: u8 = 1;
+
+// Descriptor types
+const DEVICE: u8 = 1;
+const CONFIGURATION: u8 = 2;
+const INTERFACE: u8 = 4;
+const ENDPOINT: u8 = 5;
+
+/// Host error
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// No device on the port
+    NotConnected,
+    /// The device didn't complete the transfer in time
+    Timeout,
+    /// The endpoint answered with a STALL handshake
+    Stall,
+    /// CRC, bit stuffing, babble or data toggle error
+    Transfer,
+    /// A descriptor is malformed or doesn't fit `CONFIG_SIZE`
+    Descriptor,
+    /// The device has no interface the class driver supports
+    Unsupported,
+    /// A mass storage command completed with a failed status
+    CommandFailed,
+    /// The mass storage device reported a phase error
+    PhaseError,
+    /// The buffer isn't one block long
+    BufferLength,
+    #[doc(hidden)]
+    _Extensible,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Error::NotConnected => "no USB device connected",
+            Error::Timeout => "USB transfer timeout",
+            Error::Stall => "USB endpoint stalled",
+            Error::Transfer => "USB transfer error",
+            Error::Descriptor => "malformed USB descriptor",
+            Error::Unsupported => "unsupported USB device",
+            Error::CommandFailed => "mass storage command failed",
+            Error::PhaseError => "mass storage phase error",
+            Error::BufferLength => "buffer length doesn't match the block size",
+            Error::_Extensible => "unknown error",
+        })
+    }
+}
+
+/// Data PID of a transfer stage
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Pid {
+    Data0 = 0b00,
+    Data1 = 0b10,
+    Setup = 0b11,
+}
+
+impl Pid {
+    fn from_bits(bits: u32) -> Self {
+        if bits == 0b10 { Pid::Data1 } else { Pid::Data0 }
+    }
+}
+
+/// Endpoint transfer type
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EndpointType {
+    Control = 0b00,
+    Isochronous = 0b01,
+    Bulk = 0b10,
+    Interrupt = 0b11,
+}
+
+/// Device endpoint, with its data toggle
+#[derive(Clone, Copy, Debug)]
+pub struct Endpoint {
+    /// Endpoint number
+    pub number: u8,
+    /// Direction, `true` for IN
+    pub input: bool,
+    pub kind: EndpointType,
+    pub max_packet: u16,
+    /// Polling interval of interrupt endpoints, in frames
+    pub interval: u8,
+    toggle: Pid,
+}
+
+impl Endpoint {
+    /// `None` for a zero `wMaxPacketSize`, no data can go through it
+    fn parse(desc: &[u8]) -> Option<Self> {
+        let kind = match desc[3] & 0b11 {
+            0b00 => EndpointType::Control,
+            0b01 => EndpointType::Isochronous,
+            0b10 => EndpointType::Bulk,
+            _ => EndpointType::Interrupt,
+        };
+
+        let max_packet = u16::from(desc[4]) | (u16::from(desc[5] & 0x7) << 8);
+        if max_packet == 0 {
+            return None;
+        }
+
+        Some(Endpoint {
+            number: desc[2] & 0xF,
+            input: desc[2] & 0x80 != 0,
+            kind: kind,
+            max_packet: max_packet,
+            interval: desc[6],
+            toggle: Pid::Data0,
+        })
+    }
+
+    /// Restarts the data toggle at DATA0, e.g. after clearing a halt
+    pub fn reset_toggle(&mut self) {
+        self.toggle = Pid::Data0;
+    }
+}
+
+/// Interface of the active configuration, with its first IN and OUT
+/// endpoints
+#[derive(Clone, Copy, Debug)]
+pub struct Interface {
+    pub number: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub ep_in: Option<Endpoint>,
+    pub ep_out: Option<Endpoint>,
+}
+
+/// Setup packet of a control transfer
+#[derive(Clone, Copy, Debug)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl SetupPacket {
+    fn to_bytes(&self) -> [u8; 8] {
+        [
+            self.request_type,
+            self.request,
+            self.value as u8,
+            (self.value >> 8) as u8,
+            self.index as u8,
+            (self.index >> 8) as u8,
+            self.length as u8,
+            (self.length >> 8) as u8,
+        ]
+    }
+}
+
+/// Enumerated device
+pub struct Device {
+    address: u8,
+    low_speed: bool,
+    max_packet0: u16,
+    descriptor: [u8; 18],
+    config: [u8; CONFIG_SIZE],
+    config_len: usize,
+}
+
+impl Device {
+    /// Bus address
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    /// Whether the device is low speed (1.5 Mbit/s)
+    pub fn is_low_speed(&self) -> bool {
+        self.low_speed
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        u16::from(self.descriptor[8]) | (u16::from(self.descriptor[9]) << 8)
+    }
+
+    pub fn product_id(&self) -> u16 {
+        u16::from(self.descriptor[10]) | (u16::from(self.descriptor[11]) << 8)
+    }
+
+    /// Device class, `0` means it's defined per interface
+    pub fn class(&self) -> u8 {
+        self.descriptor[4]
+    }
+
+    /// Raw device descriptor
+    pub fn device_descriptor(&self) -> &[u8] {
+        &self.descriptor
+    }
+
+    /// Raw configuration descriptor, with the interface and endpoint
+    /// descriptors that follow it
+    pub fn config_descriptor(&self) -> &[u8] {
+        &self.config[..self.config_len]
+    }
+
+    /// First interface that matches `class`, `subclass` and `protocol`
+    ///
+    /// `None` matches any subclass / protocol. Endpoints with a zero
+    /// `wMaxPacketSize` are skipped.
+    pub fn find_interface(&self, class: u8, subclass: Option<u8>, protocol: Option<u8>)
+        -> Option<Interface>
+    {
+        let mut found: Option<Interface> = None;
+
+        let mut desc = self.config_descriptor();
+        while desc.len() >= 2 && desc[0] >= 2 && desc[0] as usize <= desc.len() {
+            let len = desc[0] as usize;
+            match desc[1] {
+                INTERFACE if len >= 9 => {
+                    if found.is_some() {
+                        break;
+                    }
+                    if desc[5] == class && subclass.map_or(true, |s| s == desc[6]) &&
+                        protocol.map_or(true, |p| p == desc[7])
+                    {
+                        found = Some(Interface {
+                            number: desc[2],
+                            class: desc[5],
+                            subclass: desc[6],
+                            protocol: desc[7],
+                            ep_in: None,
+                            ep_out: None,
+                        });
+                    }
+                }
+                ENDPOINT if len >= 7 => if let Some(ref mut iface) = found {
+                    if let Some(ep) = Endpoint::parse(desc) {
+                        if ep.input && iface.ep_in.is_none() {
+                            iface.ep_in = Some(ep);
+                        } else if !ep.input && iface.ep_out.is_none() {
+                            iface.ep_out = Some(ep);
+                        }
+                    }
+                },
+                _ => {}
+            }
+            desc = &desc[len..];
+        }
+
+        found
+    }
+
+    fn control_endpoint(&self) -> Endpoint {
+        Endpoint {
+            number: 0,
+            input: false,
+            kind: EndpointType::Control,
+            max_packet: self.max_packet0,
+            interval: 0,
+            toggle: Pid::Data0,
+        }
+    }
+}
+
+/// OTG FS core in host mode
+pub struct UsbHost<'a> {
+    _otg: &'a OTG_FS_GLOBAL,
+    delay: CyclesToTime,
+}
+
+impl<'a> UsbHost<'a> {
+    pub fn new(otg: &'a OTG_FS_GLOBAL, clocks: &Clocks) -> Self {
+        UsbHost {
+            _otg: otg,
+            delay: CyclesToTime::new(clocks),
+        }
+    }
+
+    /// Resets the core into host mode and powers the port
+    ///
+    /// Also routes PA11 / PA12 to the core. Fails with `Timeout` if the
+    /// core doesn't come out of reset, e.g. without its 48 MHz clock.
+    pub fn init(&self, rcc: &RCC, gpioa: &GPIOA) -> Result<(), Error> {
+        Pin::<GPIOA>::new(11).set_alternate(gpioa, OTG_FS_AF, Speed::High);
+        Pin::<GPIOA>::new(12).set_alternate(gpioa, OTG_FS_AF, Speed::High);
+
+        // OTGFSEN
+        rcc.ahb2enr.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 7)) });
+
+        self.wait(GRSTCTL, AHBIDL, AHBIDL)?;
+        self.write(GRSTCTL, CSRST);
+        self.wait(GRSTCTL, CSRST, 0)?;
+
+        // Transceiver on, no VBUS sensing, forced host mode
+        self.write(GCCFG, PWRDWN | NOVBUSSENS);
+        self.modify(GUSBCFG, |r| r | PHYSEL | FHMOD);
+        self.write(PCGCCTL, 0);
+        self.delay_ms(50);
+
+        self.set_full_speed_clock(true);
+
+        self.write(GRXFSIZ, RX_FIFO_WORDS);
+        self.write(HNPTXFSIZ, (NPTX_FIFO_WORDS << 16) | RX_FIFO_WORDS);
+        self.write(HPTXFSIZ, (PTX_FIFO_WORDS << 16) | (RX_FIFO_WORDS + NPTX_FIFO_WORDS));
+
+        // All TX FIFOs, then the RX FIFO
+        self.write(GRSTCTL, TXFFLSH | (0x10 << 6));
+        self.wait(GRSTCTL, TXFFLSH, 0)?;
+        self.write(GRSTCTL, RXFFLSH);
+        self.wait(GRSTCTL, RXFFLSH, 0)?;
+
+        self.write(HCINT0, HCINT_ALL);
+        self.modify(HPRT, |r| (r & !HPRT_W1C) | PPWR);
+        Ok(())
+    }
+
+    /// Whether a device is attached to the port
+    pub fn is_connected(&self) -> bool {
+        self.read(HPRT) & PCSTS != 0
+    }
+
+    /// Drives a bus reset and waits for the port to be enabled
+    ///
+    /// Returns `true` if the device is low speed
+    pub fn reset_port(&self) -> Result<bool, Error> {
+        if !self.is_connected() {
+            return Err(Error::NotConnected);
+        }
+
+        self.modify(HPRT, |r| (r & !HPRT_W1C) | PRST);
+        self.delay_ms(15);
+        self.modify(HPRT, |r| r & !HPRT_W1C & !PRST);
+        self.delay_ms(20);
+
+        let mut timeout = TIMEOUT;
+        while self.read(HPRT) & PENA == 0 {
+            timeout -= 1;
+            if timeout == 0 {
+                return Err(Error::Timeout);
+            }
+        }
+        // Acknowledge the port change flags
+        self.modify(HPRT, |r| (r & !HPRT_W1C) | PCDET | PENCHNG);
+
+        // PSPD
+        let low_speed = (self.read(HPRT) >> 17) & 0b11 == 0b10;
+        Ok(low_speed)
+    }
+
+    /// Resets the device, gives it `DEVICE_ADDRESS` and selects its first
+    /// configuration
+    pub fn enumerate(&self) -> Result<Device, Error> {
+        let mut low_speed = self.reset_port()?;
+        if low_speed {
+            // The PHY clock must follow the device speed, which takes a
+            // second reset to settle
+            self.set_full_speed_clock(false);
+            low_speed = self.reset_port()?;
+        }
+
+        let mut device = Device {
+            address: 0,
+            low_speed: low_speed,
+            max_packet0: 8,
+            descriptor: [0; 18],
+            config: [0; CONFIG_SIZE],
+            config_len: 0,
+        };
+
+        // Learn bMaxPacketSize0 first, the rest of the descriptor may take
+        // more than one packet
+        {
+            let mut header = [0; 8];
+            self.get_descriptor(&device, DEVICE, &mut header)?;
+            device.max_packet0 = match header[7] {
+                8 | 16 | 32 | 64 => u16::from(header[7]),
+                _ => return Err(Error::Descriptor),
+            };
+        }
+
+        self.control_out(&device, &SetupPacket {
+            request_type: 0x00,
+            request: SET_ADDRESS,
+            value: u16::from(DEVICE_ADDRESS),
+            index: 0,
+            length: 0,
+        }, &[])?;
+        device.address = DEVICE_ADDRESS;
+        // SET_ADDRESS recovery interval
+        self.delay_ms(2);
+
+        let mut descriptor = [0; 18];
+        if self.get_descriptor(&device, DEVICE, &mut descriptor)? != 18 {
+            return Err(Error::Descriptor);
+        }
+        device.descriptor = descriptor;
+
+        let mut config = [0; CONFIG_SIZE];
+        if self.get_descriptor(&device, CONFIGURATION, &mut config[..9])? != 9 {
+            return Err(Error::Descriptor);
+        }
+        let total = usize::from(config[2]) | (usize::from(config[3]) << 8);
+        if total < 9 || total > CONFIG_SIZE {
+            return Err(Error::Descriptor);
+        }
+        device.config_len = self.get_descriptor(&device, CONFIGURATION, &mut config[..total])?;
+        device.config = config;
+
+        self.control_out(&device, &SetupPacket {
+            request_type: 0x00,
+            request: SET_CONFIGURATION,
+            value: u16::from(config[5]),
+            index: 0,
+            length: 0,
+        }, &[])?;
+
+        Ok(device)
+    }
+
+    /// Control transfer with an IN data stage, returns the number of bytes
+    /// received
+    pub fn control_in(&self, device: &Device, setup: &SetupPacket, buffer: &mut [u8])
+        -> Result<usize, Error>
+    {
+        let mut ep = device.control_endpoint();
+        let len = cmp::min(buffer.len(), usize::from(setup.length));
+
+        ep.toggle = Pid::Setup;
+        self.transfer_out(device, &mut ep, &setup.to_bytes())?;
+
+        ep.toggle = Pid::Data1;
+        ep.input = true;
+        let n = self.transfer_in(device, &mut ep, &mut buffer[..len], true)
+            .map_err(|e| match e {
+                nb::Error::Other(e) => e,
+                nb::Error::WouldBlock => Error::Timeout,
+            })?;
+
+        ep.toggle = Pid::Data1;
+        ep.input = false;
+        self.transfer_out(device, &mut ep, &[])?;
+
+        Ok(n)
+    }
+
+    /// Control transfer with an optional OUT data stage
+    pub fn control_out(&self, device: &Device, setup: &SetupPacket, data: &[u8])
+        -> Result<(), Error>
+    {
+        let mut ep = device.control_endpoint();
+
+        ep.toggle = Pid::Setup;
+        self.transfer_out(device, &mut ep, &setup.to_bytes())?;
+
+        if !data.is_empty() {
+            ep.toggle = Pid::Data1;
+            self.transfer_out(device, &mut ep, data)?;
+        }
+
+        ep.toggle = Pid::Data1;
+        ep.input = true;
+        self.transfer_in(device, &mut ep, &mut [], true)
+            .map_err(|e| match e {
+                nb::Error::Other(e) => e,
+                nb::Error::WouldBlock => Error::Timeout,
+            })?;
+
+        Ok(())
+    }
+
+    /// Reads from a bulk IN endpoint, waiting while the device NAKs
+    pub fn bulk_in(&self, device: &Device, ep: &mut Endpoint, buffer: &mut [u8])
+        -> Result<usize, Error>
+    {
+        self.transfer_in(device, ep, buffer, true).map_err(|e| match e {
+            nb::Error::Other(e) => e,
+            nb::Error::WouldBlock => Error::Timeout,
+        })
+    }
+
+    /// Writes to a bulk OUT endpoint
+    pub fn bulk_out(&self, device: &Device, ep: &mut Endpoint, data: &[u8]) -> Result<(), Error> {
+        self.transfer_out(device, ep, data)
+    }
+
+    /// Polls an interrupt IN endpoint once
+    ///
+    /// `WouldBlock` if the device has nothing to report (NAK)
+    pub fn interrupt_in(&self, device: &Device, ep: &mut Endpoint, buffer: &mut [u8])
+        -> nb::Result<usize, Error>
+    {
+        self.transfer_in(device, ep, buffer, false)
+    }
+
+    /// Clears the halt condition of `ep` and restarts its data toggle
+    pub fn clear_halt(&self, device: &Device, ep: &mut Endpoint) -> Result<(), Error> {
+        self.control_out(device, &SetupPacket {
+            request_type: 0x02,
+            request: CLEAR_FEATURE,
+            // ENDPOINT_HALT
+            value: 0,
+            index: u16::from(ep.number) | if ep.input { 0x80 } else { 0 },
+            length: 0,
+        }, &[])?;
+        ep.reset_toggle();
+        Ok(())
+    }
+
+    fn get_descriptor(&self, device: &Device, kind: u8, buffer: &mut [u8])
+        -> Result<usize, Error>
+    {
+        let length = buffer.len() as u16;
+        self.control_in(device, &SetupPacket {
+            request_type: 0x80,
+            request: GET_DESCRIPTOR,
+            value: u16::from(kind) << 8,
+            index: 0,
+            length: length,
+        }, buffer)
+    }
+
+    fn transfer_in(&self, device: &Device, ep: &mut Endpoint, buffer: &mut [u8], retry: bool)
+        -> nb::Result<usize, Error>
+    {
+        let mps = usize::from(ep.max_packet);
+        if mps == 0 {
+            return Err(nb::Error::Other(Error::Descriptor));
+        }
+        let packets = cmp::max(1, (buffer.len() + mps - 1) / mps);
+
+        self.write(HCINT0, HCINT_ALL);
+        self.write(HCTSIZ0, ((ep.toggle as u32) << 29) | ((packets as u32) << 19) |
+                   (packets * mps) as u32);
+        self.enable_channel(device, ep, true);
+
+        let mut received = 0;
+        for _ in 0..TIMEOUT {
+            if self.read(GINTSTS) & RXFLVL != 0 {
+                let status = self.read(GRXSTSP);
+                let count = ((status >> 4) & 0x7FF) as usize;
+                if (status >> 17) & 0xF == PKTSTS_IN_DATA && count > 0 {
+                    let end = cmp::min(received + count, buffer.len());
+                    self.read_fifo(&mut buffer[received..end], count);
+                    received = end;
+
+                    // The channel stops after each packet in slave mode
+                    if (self.read(HCTSIZ0) >> 19) & 0x3FF > 0 {
+                        self.modify(HCCHAR0, |r| (r & !CHDIS) | CHENA);
+                    }
+                }
+            }
+
+            let hcint = self.read(HCINT0);
+            if hcint & XFRC != 0 {
+                self.write(HCINT0, HCINT_ALL);
+                ep.toggle = Pid::from_bits((self.read(HCTSIZ0) >> 29) & 0b11);
+                return Ok(received);
+            } else if hcint & STALL != 0 {
+                self.halt_channel();
+                return Err(nb::Error::Other(Error::Stall));
+            } else if hcint & (TXERR | BBERR | FRMOR | DTERR) != 0 {
+                self.halt_channel();
+                return Err(nb::Error::Other(Error::Transfer));
+            } else if hcint & NAK != 0 {
+                self.write(HCINT0, NAK);
+                if retry {
+                    self.modify(HCCHAR0, |r| (r & !CHDIS) | CHENA);
+                } else {
+                    self.halt_channel();
+                    return Err(nb::Error::WouldBlock);
+                }
+            }
+        }
+
+        self.halt_channel();
+        Err(nb::Error::Other(Error::Timeout))
+    }
+
+    fn transfer_out(&self, device: &Device, ep: &mut Endpoint, data: &[u8]) -> Result<(), Error> {
+        let mps = usize::from(ep.max_packet);
+        if mps == 0 {
+            return Err(Error::Descriptor);
+        }
+        let periodic = match ep.kind {
+            EndpointType::Interrupt | EndpointType::Isochronous => true,
+            _ => false,
+        };
+
+        let mut sent = 0;
+        for _ in 0..TIMEOUT {
+            let rest = &data[sent..];
+            let packets = cmp::max(1, (rest.len() + mps - 1) / mps);
+
+            self.write(HCINT0, HCINT_ALL);
+            self.write(HCTSIZ0, ((ep.toggle as u32) << 29) | ((packets as u32) << 19) |
+                       rest.len() as u32);
+            self.enable_channel(device, ep, false);
+
+            for packet in rest.chunks(mps) {
+                let words = ((packet.len() + 3) / 4) as u32;
+                let status = if periodic { HPTXSTS } else { HNPTXSTS };
+                let mut timeout = TIMEOUT;
+                while self.read(status) & 0xFFFF < words {
+                    timeout -= 1;
+                    if timeout == 0 {
+                        self.halt_channel();
+                        self.flush_tx(periodic);
+                        return Err(Error::Timeout);
+                    }
+                }
+                self.write_fifo(packet);
+            }
+
+            let mut timeout = TIMEOUT;
+            let hcint = loop {
+                let hcint = self.read(HCINT0);
+                if hcint & (XFRC | STALL | NAK | TXERR | BBERR | FRMOR | DTERR) != 0 {
+                    break hcint;
+                }
+                timeout -= 1;
+                if timeout == 0 {
+                    self.halt_channel();
+                    self.flush_tx(periodic);
+                    return Err(Error::Timeout);
+                }
+            };
+
+            if hcint & XFRC != 0 {
+                self.write(HCINT0, HCINT_ALL);
+                if ep.toggle != Pid::Setup {
+                    ep.toggle = Pid::from_bits((self.read(HCTSIZ0) >> 29) & 0b11);
+                }
+                return Ok(());
+            }
+
+            self.halt_channel();
+            self.flush_tx(periodic);
+            if hcint & STALL != 0 {
+                return Err(Error::Stall);
+            } else if hcint & NAK == 0 {
+                return Err(Error::Transfer);
+            }
+
+            // NAK: resend what the device didn't acknowledge
+            let tsiz = self.read(HCTSIZ0);
+            let remaining = ((tsiz >> 19) & 0x3FF) as usize;
+            sent += (packets - cmp::min(remaining, packets)) * mps;
+            sent = cmp::min(sent, data.len());
+            if ep.toggle != Pid::Setup {
+                ep.toggle = Pid::from_bits((tsiz >> 29) & 0b11);
+            }
+        }
+
+        Err(Error::Timeout)
+    }
+
+    fn enable_channel(&self, device: &Device, ep: &Endpoint, input: bool) {
+        let mut hcchar = u32::from(ep.max_packet) | (u32::from(ep.number) << 11) |
+            ((ep.kind as u32) << 18) | (1 << 20) | (u32::from(device.address) << 22) | CHENA;
+        if input {
+            hcchar |= EPDIR_IN;
+        }
+        if device.low_speed {
+            hcchar |= LSDEV;
+        }
+        // Periodic transfers go out in the next frame
+        if ep.kind == EndpointType::Interrupt && self.read(HFNUM) & 1 == 0 {
+            hcchar |= ODDFRM;
+        }
+        self.write(HCCHAR0, hcchar);
+    }
+
+    fn halt_channel(&self) {
+        if self.read(HCCHAR0) & CHENA != 0 {
+            self.modify(HCCHAR0, |r| r | CHDIS | CHENA);
+            let mut timeout = TIMEOUT;
+            while self.read(HCINT0) & CHH == 0 && timeout > 0 {
+                // Halting an IN channel may leave a status entry behind
+                if self.read(GINTSTS) & RXFLVL != 0 {
+                    let status = self.read(GRXSTSP);
+                    self.read_fifo(&mut [], ((status >> 4) & 0x7FF) as usize);
+                }
+                timeout -= 1;
+            }
+        }
+        self.write(HCINT0, HCINT_ALL);
+    }
+
+    fn flush_tx(&self, periodic: bool) {
+        // TXFNUM: 0 = non-periodic, 1 = periodic
+        let fifo = if periodic { 1 } else { 0 };
+        self.write(GRSTCTL, TXFFLSH | (fifo << 6));
+        // Only called on the way out of a failed transfer, which already
+        // reports an error
+        let _ = self.wait(GRSTCTL, TXFFLSH, 0);
+    }
+
+    fn set_full_speed_clock(&self, full_speed: bool) {
+        // FSLSPCS = 48 MHz or 6 MHz, FSLSS, frame interval of 1 ms
+        if full_speed {
+            self.write(HCFG, 0b01 | (1 << 2));
+            self.write(HFIR, 48_000);
+        } else {
+            self.write(HCFG, 0b10 | (1 << 2));
+            self.write(HFIR, 6_000);
+        }
+    }
+
+    /// Pops `count` bytes from the RX FIFO into `buffer`, dropping what
+    /// doesn't fit
+    fn read_fifo(&self, buffer: &mut [u8], count: usize) {
+        let mut i = 0;
+        while i < count {
+            let word = self.read(FIFO0);
+            for byte in 0..4 {
+                if i + byte < buffer.len() && i + byte < count {
+                    buffer[i + byte] = (word >> (8 * byte)) as u8;
+                }
+            }
+            i += 4;
+        }
+    }
+
+    fn write_fifo(&self, packet: &[u8]) {
+        for chunk in packet.chunks(4) {
+            let mut word = 0;
+            for (i, byte) in chunk.iter().enumerate() {
+                word |= u32::from(*byte) << (8 * i);
+            }
+            self.write(FIFO0, word);
+        }
+    }
+
+    /// Waits for the `mask` bits of register `offset` to read `value`
+    fn wait(&self, offset: u32, mask: u32, value: u32) -> Result<(), Error> {
+        let mut timeout = TIMEOUT;
+        while self.read(offset) & mask != value {
+            timeout -= 1;
+            if timeout == 0 {
+                return Err(Error::Timeout);
+            }
+        }
+        Ok(())
+    }
+
+    fn delay_ms(&self, ms: u32) {
+        self.delay.delay_us(Microseconds(ms * 1_000));
+    }
+
+    fn read(&self, offset: u32) -> u32 {
+        unsafe { ptr::read_volatile((OTG_FS + offset) as *const u32) }
+    }
+
+    fn write(&self, offset: u32, value: u32) {
+        unsafe { ptr::write_volatile((OTG_FS + offset) as *mut u32, value) }
+    }
+
+    fn modify<F>(&self, offset: u32, f: F)
+        where F: FnOnce(u32) -> u32
+    {
+        let value = self.read(offset);
+        self.write(offset, f(value));
+    }
+}
+
+impl<'a> ClockListener for UsbHost<'a> {
+    /// Keeps the reset and settle delays; the core itself needs the 48 MHz
+    /// clock of the new profile
+    fn clocks_changed(&self, old: &Clocks, new: &Clocks) {
+        self.delay.clocks_changed(old, new);
+    }
+}
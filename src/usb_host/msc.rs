@@ -0,0 +1,240 @@
+//! Mass storage, Bulk-Only Transport with the SCSI transparent command set
+//!
+//! Enough for USB flash drives and card readers: the first LUN is used and
+//! blocks are read / written one at a time.
+//!
+//! ``` ignore
+//! let mut disk = MassStorage::new(&host, &device)?;
+//! let mut block = [0; 512];
+//! disk.read_block(&host, &device, 0, &mut block)?;
+//! ```
+
+use super::{Device, Endpoint, Error, SetupPacket, UsbHost};
+
+const MSC_CLASS: u8 = 8;
+const SCSI_SUBCLASS: u8 = 6;
+const BOT_PROTOCOL: u8 = 0x50;
+
+// Class requests
+const BOT_RESET: u8 = 0xFF;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CBW_SIZE: usize = 31;
+const CSW_SIZE: usize = 13;
+
+// CSW status
+const STATUS_PASSED: u8 = 0;
+const STATUS_FAILED: u8 = 1;
+
+// SCSI commands
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2A;
+
+/// `TEST UNIT READY` attempts while the medium spins up
+const READY_RETRIES: u32 = 100;
+
+/// Data stage of a command
+enum Data<'b> {
+    None,
+    In(&'b mut [u8]),
+    Out(&'b [u8]),
+}
+
+/// Bulk-Only mass storage device
+pub struct MassStorage {
+    interface: u8,
+    bulk_in: Endpoint,
+    bulk_out: Endpoint,
+    tag: u32,
+    block_size: u32,
+    blocks: u32,
+}
+
+impl MassStorage {
+    /// Waits for the medium to be ready and reads its capacity
+    pub fn new(host: &UsbHost, device: &Device) -> Result<Self, Error> {
+        let iface = device.find_interface(MSC_CLASS, Some(SCSI_SUBCLASS), Some(BOT_PROTOCOL))
+            .ok_or(Error::Unsupported)?;
+
+        let mut msc = MassStorage {
+            interface: iface.number,
+            bulk_in: iface.ep_in.ok_or(Error::Descriptor)?,
+            bulk_out: iface.ep_out.ok_or(Error::Descriptor)?,
+            tag: 0,
+            block_size: 0,
+            blocks: 0,
+        };
+
+        let mut inquiry = [0; 36];
+        msc.command(host, device, &[INQUIRY, 0, 0, 0, 36, 0], Data::In(&mut inquiry))?;
+
+        let mut retries = READY_RETRIES;
+        loop {
+            match msc.command(host, device, &[TEST_UNIT_READY, 0, 0, 0, 0, 0], Data::None) {
+                Ok(_) => break,
+                Err(Error::CommandFailed) if retries > 0 => {
+                    // Clears the unit attention condition
+                    let mut sense = [0; 18];
+                    msc.command(host, device, &[REQUEST_SENSE, 0, 0, 0, 18, 0],
+                                Data::In(&mut sense))?;
+                    retries -= 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut capacity = [0; 8];
+        msc.command(host, device, &[READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                    Data::In(&mut capacity))?;
+        let last = be32(&capacity[0..4]);
+        msc.blocks = last.wrapping_add(1);
+        msc.block_size = be32(&capacity[4..8]);
+        if msc.block_size == 0 {
+            return Err(Error::Descriptor);
+        }
+
+        Ok(msc)
+    }
+
+    /// Number of blocks
+    pub fn blocks(&self) -> u32 {
+        self.blocks
+    }
+
+    /// Block size in bytes, usually 512
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    /// Reads block `lba` into `buffer`, which must be `block_size` long
+    pub fn read_block(&mut self, host: &UsbHost, device: &Device, lba: u32, buffer: &mut [u8])
+        -> Result<(), Error>
+    {
+        if buffer.len() != self.block_size as usize {
+            return Err(Error::BufferLength);
+        }
+        let cb = rw10(READ_10, lba);
+        self.command(host, device, &cb, Data::In(buffer)).map(|_| ())
+    }
+
+    /// Writes `data`, which must be `block_size` long, to block `lba`
+    pub fn write_block(&mut self, host: &UsbHost, device: &Device, lba: u32, data: &[u8])
+        -> Result<(), Error>
+    {
+        if data.len() != self.block_size as usize {
+            return Err(Error::BufferLength);
+        }
+        let cb = rw10(WRITE_10, lba);
+        self.command(host, device, &cb, Data::Out(data)).map(|_| ())
+    }
+
+    /// Bulk-Only Mass Storage Reset followed by clearing both endpoints,
+    /// the recovery after a phase error
+    pub fn reset(&mut self, host: &UsbHost, device: &Device) -> Result<(), Error> {
+        host.control_out(device, &SetupPacket {
+            request_type: 0x21,
+            request: BOT_RESET,
+            value: 0,
+            index: u16::from(self.interface),
+            length: 0,
+        }, &[])?;
+        host.clear_halt(device, &mut self.bulk_in)?;
+        host.clear_halt(device, &mut self.bulk_out)
+    }
+
+    /// Runs one command, returns the residue
+    fn command(&mut self, host: &UsbHost, device: &Device, cb: &[u8], data: Data)
+        -> Result<u32, Error>
+    {
+        self.tag = self.tag.wrapping_add(1);
+
+        let (length, input) = match data {
+            Data::None => (0, false),
+            Data::In(ref buffer) => (buffer.len() as u32, true),
+            Data::Out(buffer) => (buffer.len() as u32, false),
+        };
+
+        let mut cbw = [0; CBW_SIZE];
+        put_le32(&mut cbw[0..4], CBW_SIGNATURE);
+        put_le32(&mut cbw[4..8], self.tag);
+        put_le32(&mut cbw[8..12], length);
+        cbw[12] = if input { 0x80 } else { 0 };
+        cbw[13] = 0;
+        cbw[14] = cb.len() as u8;
+        cbw[15..15 + cb.len()].copy_from_slice(cb);
+        host.bulk_out(device, &mut self.bulk_out, &cbw)?;
+
+        // A stalled data stage still ends with a CSW
+        let result = match data {
+            Data::None => Ok(()),
+            Data::In(buffer) => host.bulk_in(device, &mut self.bulk_in, buffer).map(|_| ()),
+            Data::Out(buffer) => host.bulk_out(device, &mut self.bulk_out, buffer),
+        };
+        match result {
+            Ok(()) => {}
+            Err(Error::Stall) => if input {
+                host.clear_halt(device, &mut self.bulk_in)?;
+            } else {
+                host.clear_halt(device, &mut self.bulk_out)?;
+            },
+            Err(e) => return Err(e),
+        }
+
+        let mut csw = [0; CSW_SIZE];
+        let n = match host.bulk_in(device, &mut self.bulk_in, &mut csw) {
+            Err(Error::Stall) => {
+                host.clear_halt(device, &mut self.bulk_in)?;
+                host.bulk_in(device, &mut self.bulk_in, &mut csw)?
+            }
+            result => result?,
+        };
+
+        if n != CSW_SIZE || le32(&csw[0..4]) != CSW_SIGNATURE || le32(&csw[4..8]) != self.tag {
+            return Err(Error::PhaseError);
+        }
+
+        match csw[12] {
+            STATUS_PASSED => Ok(le32(&csw[8..12])),
+            STATUS_FAILED => Err(Error::CommandFailed),
+            _ => Err(Error::PhaseError),
+        }
+    }
+}
+
+/// READ(10) / WRITE(10) command block for one block at `lba`
+fn rw10(opcode: u8, lba: u32) -> [u8; 10] {
+    [
+        opcode,
+        0,
+        (lba >> 24) as u8,
+        (lba >> 16) as u8,
+        (lba >> 8) as u8,
+        lba as u8,
+        0,
+        0,
+        1,
+        0,
+    ]
+}
+
+fn be32(bytes: &[u8]) -> u32 {
+    (u32::from(bytes[0]) << 24) | (u32::from(bytes[1]) << 16) | (u32::from(bytes[2]) << 8) |
+        u32::from(bytes[3])
+}
+
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from(bytes[0]) | (u32::from(bytes[1]) << 8) | (u32::from(bytes[2]) << 16) |
+        (u32::from(bytes[3]) << 24)
+}
+
+fn put_le32(bytes: &mut [u8], value: u32) {
+    bytes[0] = value as u8;
+    bytes[1] = (value >> 8) as u8;
+    bytes[2] = (value >> 16) as u8;
+    bytes[3] = (value >> 24) as u8;
+}
@@ -0,0 +1,225 @@
+//! Interrupt driven wake ups, for executors
+//!
+//! The drivers already expose non-blocking `nb` operations; an executor
+//! that polls them in a loop burns the CPU until the peripheral is done.
+//! With this layer a task polls once, registers a `Waker` and sleeps; the
+//! peripheral interrupt disables its event and calls the waker, and the
+//! executor polls the task again:
+//!
+//! ``` ignore
+//! fn wake_task(task: usize) {
+//!     READY.fetch_or(1 << task, Ordering::Release);
+//! }
+//!
+//! // task 3
+//! match serial.poll_read(&Waker::new(wake_task, 3)) {
+//!     Ok(byte) => ..,
+//!     Err(nb::Error::WouldBlock) => return, // polled again once woken
+//!     Err(nb::Error::Other(e)) => ..,
+//! }
+//!
+//! // USART2 interrupt
+//! Serial::new(usart2).on_wake_interrupt();
+//! // DMA2_STREAM4 interrupt
+//! dma.on_wake_interrupt();
+//! ```
+//!
+//! Every DMA stream and every USART direction has one waker slot, holding
+//! the waker of the last poll; registering replaces the previous one.
+//!
+//! The compiler this crate builds with predates `core::future`, so the
+//! `embedded-hal-async` traits can't be implemented. A future adapter only
+//! has to call these `poll_*` methods with a waker that wakes its task.
+
+use core::any::{Any, TypeId};
+use core::cell::Cell;
+
+use cortex_m::interrupt;
+use hal;
+use nb;
+use stm32f411::{DMA1, DMA2, USART1, USART2, USART6};
+
+use dma2::{self, DMA, Dma, DMAStream, Event};
+use serial::{self, Serial, Usart};
+
+/// Callback that makes an executor poll a task again
+#[derive(Clone, Copy)]
+pub struct Waker {
+    wake: fn(usize),
+    data: usize,
+}
+
+impl Waker {
+    /// Waker that calls `wake(data)`, e.g. with a task index
+    pub const fn new(wake: fn(usize), data: usize) -> Self {
+        Waker { wake: wake, data: data }
+    }
+
+    /// Calls the wake function, from the interrupt
+    pub fn wake(&self) {
+        (self.wake)(self.data)
+    }
+}
+
+/// Waker of one interrupt source
+pub struct WakerSlot {
+    waker: Cell<Option<Waker>>,
+}
+
+// NOTE(unsafe) the cell is only accessed in critical sections
+unsafe impl Sync for WakerSlot {}
+
+impl WakerSlot {
+    pub const fn new() -> Self {
+        WakerSlot { waker: Cell::new(None) }
+    }
+
+    /// Stores `waker`, replacing the one registered before
+    pub fn register(&self, waker: &Waker) {
+        interrupt::free(|_| self.waker.set(Some(*waker)));
+    }
+
+    /// Calls and removes the registered waker, if any
+    pub fn wake(&self) {
+        if let Some(waker) = interrupt::free(|_| self.waker.replace(None)) {
+            waker.wake();
+        }
+    }
+}
+
+static DMA1_WAKERS: [WakerSlot; 8] = [
+    WakerSlot::new(), WakerSlot::new(), WakerSlot::new(), WakerSlot::new(),
+    WakerSlot::new(), WakerSlot::new(), WakerSlot::new(), WakerSlot::new(),
+];
+static DMA2_WAKERS: [WakerSlot; 8] = [
+    WakerSlot::new(), WakerSlot::new(), WakerSlot::new(), WakerSlot::new(),
+    WakerSlot::new(), WakerSlot::new(), WakerSlot::new(), WakerSlot::new(),
+];
+/// USART1, USART2, USART6
+static RX_WAKERS: [WakerSlot; 3] = [WakerSlot::new(), WakerSlot::new(), WakerSlot::new()];
+static TX_WAKERS: [WakerSlot; 3] = [WakerSlot::new(), WakerSlot::new(), WakerSlot::new()];
+
+fn dma_slot<U>(reg: &U, stream: DMAStream) -> &'static WakerSlot
+    where U: Any
+{
+    if reg.get_type_id() == TypeId::of::<DMA1>() {
+        &DMA1_WAKERS[stream as usize]
+    } else {
+        &DMA2_WAKERS[stream as usize]
+    }
+}
+
+fn usart_index<U>(usart: &U) -> usize
+    where U: Any
+{
+    if usart.get_type_id() == TypeId::of::<USART1>() {
+        0
+    } else if usart.get_type_id() == TypeId::of::<USART2>() {
+        1
+    } else {
+        debug_assert!(usart.get_type_id() == TypeId::of::<USART6>());
+        2
+    }
+}
+
+impl<'a, U> Dma<'a, U>
+    where U: Any + DMA
+{
+    /// Checks for the end of the transfer, registering `waker` if it's not
+    /// over
+    ///
+    /// Clears the flags once the transfer is over. Enables the transfer
+    /// complete and error interrupts while pending; the stream interrupt
+    /// must call `on_wake_interrupt`.
+    pub fn poll_complete(&self, waker: &Waker) -> nb::Result<(), dma2::Error> {
+        match self.check_complete() {
+            Err(nb::Error::WouldBlock) => {}
+            result => return result,
+        }
+
+        dma_slot(self.reg, self.stream()).register(waker);
+        self.listen(Event::TransferComplete);
+        self.listen(Event::TransferError);
+
+        // The transfer may have ended before the interrupts were enabled
+        self.check_complete()
+    }
+
+    /// Wakes the task waiting for this stream, call this from the stream
+    /// interrupt
+    ///
+    /// The flags are left for `poll_complete`.
+    pub fn on_wake_interrupt(&self) {
+        self.unlisten(Event::TransferComplete);
+        self.unlisten(Event::TransferError);
+        dma_slot(self.reg, self.stream()).wake();
+    }
+
+    fn check_complete(&self) -> nb::Result<(), dma2::Error> {
+        if self.has_transfer_error() {
+            self.clear_flags();
+            Err(nb::Error::Other(dma2::Error::Transfer))
+        } else if self.is_transfer_complete() {
+            self.clear_flags();
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+}
+
+impl<'a, U> Serial<'a, U>
+    where U: Any + Usart
+{
+    /// Reads a byte, registering `waker` if none was received
+    ///
+    /// Enables the RXNE interrupt (also raised by an overrun) while
+    /// pending; the USART interrupt must call `on_wake_interrupt`.
+    pub fn poll_read(&self, waker: &Waker) -> nb::Result<u8, serial::Error> {
+        match hal::serial::Read::read(self) {
+            Err(nb::Error::WouldBlock) => {}
+            result => return result,
+        }
+
+        RX_WAKERS[usart_index(self.0)].register(waker);
+        self.listen(serial::Event::Rxne);
+
+        hal::serial::Read::read(self)
+    }
+
+    /// Writes `byte`, registering `waker` if the transmitter is busy
+    ///
+    /// Enables the TXE interrupt while pending.
+    pub fn poll_write(&self, byte: u8, waker: &Waker) -> nb::Result<(), serial::Error> {
+        match hal::serial::Write::<u8>::write(self, byte) {
+            Err(nb::Error::WouldBlock) => {}
+            result => return result,
+        }
+
+        TX_WAKERS[usart_index(self.0)].register(waker);
+        self.listen(serial::Event::Txe);
+
+        hal::serial::Write::<u8>::write(self, byte)
+    }
+
+    /// Wakes the tasks waiting for this USART, call this from the USART
+    /// interrupt
+    ///
+    /// Only the directions that are ready wake; the data and flags are
+    /// left for `poll_read` / `poll_write`.
+    pub fn on_wake_interrupt(&self) {
+        let usart = self.0;
+        let cr1 = usart.cr1.read();
+        let sr = usart.sr.read();
+        let index = usart_index(usart);
+
+        if cr1.rxneie().bit_is_set() && (sr.rxne().bit_is_set() || sr.ore().bit_is_set()) {
+            self.unlisten(serial::Event::Rxne);
+            RX_WAKERS[index].wake();
+        }
+        if cr1.txeie().bit_is_set() && sr.txe().bit_is_set() {
+            self.unlisten(serial::Event::Txe);
+            TX_WAKERS[index].wake();
+        }
+    }
+}
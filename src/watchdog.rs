@@ -0,0 +1,123 @@
+//! Independent watchdog (IWDG)
+//!
+//! Besides the plain driver this module offers `WatchdogGuard`, for long
+//! blocking operations (flash erase, multi-block SD writes) during which the
+//! main loop can't feed the watchdog:
+//!
+//! ``` ignore
+//! // main loop
+//! {
+//!     let guard = WatchdogGuard::new();
+//!     for block in blocks {
+//!         write_block(block);
+//!         guard.checkpoint();
+//!     }
+//! }
+//!
+//! // periodic timer interrupt, faster than the watchdog timeout
+//! watchdog::feed_guarded(&iwdg);
+//! ```
+//!
+//! The interrupt only feeds while a guard is alive *and* the guarded code
+//! made progress since the last tick, a stuck operation still resets the
+//! chip.
+
+use core::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+
+use stm32f411::IWDG;
+
+use time::Milliseconds;
+
+/// Nominal LSI frequency, the actual one is 17 - 47 kHz
+const LSI: u32 = 32_000;
+
+const KEY_RELOAD: u32 = 0xAAAA;
+const KEY_UNLOCK: u32 = 0x5555;
+const KEY_START: u32 = 0xCCCC;
+
+/// Live guards
+static GUARDS: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Bumped by `WatchdogGuard::checkpoint`
+static CHECKPOINT: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Value of `CHECKPOINT` seen by the last `feed_guarded`
+static SEEN: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Independent watchdog
+pub struct Iwdg<'a>(pub &'a IWDG);
+
+impl<'a> Iwdg<'a> {
+    /// Starts the watchdog, it can't be stopped afterwards
+    ///
+    /// The timeout is based on the nominal LSI frequency, leave some margin.
+    /// Timeouts above ~32 s are clamped.
+    pub fn start(&self, timeout: Milliseconds) {
+        let iwdg = self.0;
+
+        // Smallest prescaler (/4 << pr) that fits the 12-bit reload value
+        let mut pr = 0;
+        let mut reload = timeout.0 * (LSI / 1_000) / 4;
+        while reload > 0xFFF && pr < 6 {
+            pr += 1;
+            reload /= 2;
+        }
+        let reload = if reload > 0xFFF { 0xFFF } else if reload == 0 { 1 } else { reload };
+
+        iwdg.kr.write(|w| unsafe { w.bits(KEY_START) });
+        iwdg.kr.write(|w| unsafe { w.bits(KEY_UNLOCK) });
+        while iwdg.sr.read().bits() != 0 {}
+        iwdg.pr.write(|w| unsafe { w.bits(pr) });
+        iwdg.rlr.write(|w| unsafe { w.bits(reload) });
+        while iwdg.sr.read().bits() != 0 {}
+        self.feed();
+    }
+
+    /// Reloads the counter
+    pub fn feed(&self) {
+        self.0.kr.write(|w| unsafe { w.bits(KEY_RELOAD) });
+    }
+}
+
+/// Allows `feed_guarded` to feed the watchdog while alive
+pub struct WatchdogGuard {
+    _0: (),
+}
+
+impl WatchdogGuard {
+    pub fn new() -> Self {
+        // Don't let a stale `SEEN` look like a stall, nor like progress
+        SEEN.store(CHECKPOINT.load(Ordering::Relaxed), Ordering::Relaxed);
+        CHECKPOINT.fetch_add(1, Ordering::Relaxed);
+        GUARDS.fetch_add(1, Ordering::Release);
+        WatchdogGuard { _0: () }
+    }
+
+    /// Reports progress, call this more often than `feed_guarded` runs
+    pub fn checkpoint(&self) {
+        CHECKPOINT.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for WatchdogGuard {
+    fn drop(&mut self) {
+        GUARDS.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Feeds the watchdog if a `WatchdogGuard` is alive and checkpointed since
+/// the last call. Returns whether it fed
+///
+/// Call this from a periodic interrupt
+pub fn feed_guarded(iwdg: &Iwdg) -> bool {
+    if GUARDS.load(Ordering::Acquire) == 0 {
+        return false;
+    }
+
+    let checkpoint = CHECKPOINT.load(Ordering::Relaxed);
+    if SEEN.swap(checkpoint, Ordering::Relaxed) == checkpoint {
+        // Stalled
+        return false;
+    }
+
+    iwdg.feed();
+    true
+}